@@ -1,34 +1,181 @@
+use crate::metrics::Counters;
 use crate::pinger::PingReply;
 use crate::Config;
-use log::info;
+use log::{debug, info, warn};
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Instant;
 
 #[derive(Copy, Clone)]
 pub struct ReflectorStats {
     pub down_ewma: f64,
     pub up_ewma: f64,
+    // Smoothed deviation of RTT samples from the EWMA, only tracked for the recent stats
+    pub jitter: f64,
     pub last_receive_time_s: Instant,
+    // Replies seen since the stats were last started over, only tracked for the recent stats
+    pub replies: u32,
+}
+
+/// A consistent view of everything we know about a single reflector
+#[derive(Clone, Copy, Debug)]
+pub struct ReflectorSnapshot {
+    pub reflector: IpAddr,
+    pub active: bool,
+    pub baseline_down_ms: f64,
+    pub baseline_up_ms: f64,
+    pub recent_down_ms: f64,
+    pub recent_up_ms: f64,
+    pub jitter_ms: f64,
+    pub last_receive_age_s: f64,
+    pub reachable: bool,
+}
+
+/*
+ * Joins the baseline/recent maps and the peer list into one snapshot per reflector.
+ * All three locks are held at the same time so the result isn't torn between updates,
+ * taken in the order documented in main.
+ */
+pub fn reflector_snapshot(
+    owd_baseline: &Mutex<HashMap<IpAddr, ReflectorStats>>,
+    owd_recent: &Mutex<HashMap<IpAddr, ReflectorStats>>,
+    reflector_peers: &RwLock<Vec<IpAddr>>,
+    stale_after_s: f64,
+) -> Vec<ReflectorSnapshot> {
+    let owd_baseline = owd_baseline.lock().unwrap();
+    let owd_recent = owd_recent.lock().unwrap();
+    let peers = reflector_peers.read().unwrap();
+    let now_t = Instant::now();
+
+    let mut snapshot: Vec<ReflectorSnapshot> = owd_baseline
+        .iter()
+        .filter_map(|(reflector, baseline)| {
+            let recent = owd_recent.get(reflector)?;
+            let age = now_t
+                .duration_since(recent.last_receive_time_s)
+                .as_secs_f64();
+
+            Some(ReflectorSnapshot {
+                reflector: *reflector,
+                active: peers.contains(reflector),
+                baseline_down_ms: baseline.down_ewma,
+                baseline_up_ms: baseline.up_ewma,
+                recent_down_ms: recent.down_ewma,
+                recent_up_ms: recent.up_ewma,
+                jitter_ms: recent.jitter,
+                last_receive_age_s: age,
+                reachable: age < stale_after_s,
+            })
+        })
+        .collect();
+
+    snapshot.sort_by_key(|entry| entry.reflector);
+    snapshot
 }
 
 pub struct Baseliner {
     pub config: Config,
+    pub counters: Arc<Counters>,
     pub owd_baseline: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
     pub owd_recent: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub reflector_peers_lock: Arc<RwLock<Vec<IpAddr>>>,
     pub reselect_trigger: Sender<bool>,
     pub start_time: Instant,
     pub stats_receiver: Receiver<PingReply>,
 }
 
+pub struct BaselinerState {
+    slow_factor: f64,
+    fast_factor: f64,
+    // Used instead of fast_factor with the adaptive fast EWMA, for a rising or a flat delay
+    fast_factor_rising: f64,
+    fast_factor_flat: f64,
+    // Each reflector's previous RTT sample, and how many samples in a row it's been rising for
+    delay_trend: HashMap<IpAddr, (f64, u32)>,
+    // When each reflector's recent delay first settled at a shifted level
+    regime_shift_since: HashMap<IpAddr, Instant>,
+    lastprune_t: Instant,
+    lastsanity_t: Instant,
+}
+
+// How often the baselines are checked for implausible values
+const BASELINE_SANITY_INTERVAL_S: f64 = 60.0;
+// Samples in a row with a rising delay before it's taken as a trend rather than noise
+const RISING_TREND_SAMPLES: u32 = 3;
+
 fn ewma_factor(tick: f64, dur: f64) -> f64 {
     ((0.5_f64).ln() / (dur / tick)).exp()
 }
 
 impl Baseliner {
-    pub fn run(&self) -> anyhow::Result<()> {
+    /*
+     * Entries are added for every reflector we ever hear from, so with many reselections
+     * over a long run the maps would keep growing. Drop the ones we haven't heard from in
+     * a while, unless they're current peers that might just be having a bad moment.
+     */
+    fn prune_stale(&self, now_t: Instant) {
+        let peers = self.reflector_peers_lock.read().unwrap().clone();
+        let is_stale = |reflector: &IpAddr, stats: &ReflectorStats| {
+            !peers.contains(reflector)
+                && now_t
+                    .duration_since(stats.last_receive_time_s)
+                    .as_secs_f64()
+                    > self.config.owd_prune_age
+        };
+
+        let mut owd_baseline_map = self.owd_baseline.lock().unwrap();
+        let mut owd_recent_map = self.owd_recent.lock().unwrap();
+        let before = owd_recent_map.len();
+
+        owd_recent_map.retain(|reflector, stats| !is_stale(reflector, stats));
+        owd_baseline_map.retain(|reflector, _| owd_recent_map.contains_key(reflector));
+
+        if owd_recent_map.len() != before {
+            debug!(
+                "Pruned {} stale reflectors from the OWD maps",
+                before - owd_recent_map.len()
+            );
+        }
+    }
+
+    /*
+     * Drift or a misbehaving reflector clock can leave a baseline somewhere it will never
+     * recover from on its own. Forget any reflector whose baseline is non-finite, or whose
+     * round trip (down + up) is negative or beyond the configured bound. The individual
+     * directions may legitimately be far off either way with ICMP timestamps, as the
+     * reflector's clock isn't synchronised with ours, so only the sum is checked.
+     * The next reply from the reflector starts it over with fresh baselines.
+     */
+    fn sanitize_baselines(&self) {
+        let mut owd_baseline_map = self.owd_baseline.lock().unwrap();
+        let mut owd_recent_map = self.owd_recent.lock().unwrap();
+
+        let implausible: Vec<IpAddr> = owd_baseline_map
+            .iter()
+            .filter(|(_, stats)| {
+                !stats.down_ewma.is_finite()
+                    || !stats.up_ewma.is_finite()
+                    || stats.down_ewma + stats.up_ewma < 0.0
+                    || stats.down_ewma + stats.up_ewma > self.config.baseline_max_ms
+            })
+            .map(|(reflector, _)| *reflector)
+            .collect();
+
+        for reflector in implausible {
+            let stats = owd_baseline_map.remove(&reflector).unwrap();
+            owd_recent_map.remove(&reflector);
+            warn!(
+                "Reflector {} has an implausible baseline (down {} ms, up {} ms), resetting it",
+                reflector, stats.down_ewma, stats.up_ewma
+            );
+        }
+    }
+
+    /// Starts the per-run state, with `start_t` as the time the first reply is processed from
+    pub fn new_state(&self, start_t: Instant) -> BaselinerState {
         /*
          * 135 seconds to decay to 50% for the slow factor and
          * 0.4 seconds to decay to 50% for the fast factor.
@@ -37,99 +184,494 @@ impl Baseliner {
          * aren't bloat related, with less sensitivity (bigger numbers) we smooth through quick spikes
          * but take longer to respond to real bufferbloat
          */
-        let slow_factor = ewma_factor(self.config.tick_interval, 135.0);
-        let fast_factor = ewma_factor(self.config.tick_interval, 0.4);
+        BaselinerState {
+            slow_factor: ewma_factor(self.config.tick_interval, 135.0),
+            fast_factor: ewma_factor(self.config.tick_interval, 0.4),
+            fast_factor_rising: ewma_factor(self.config.tick_interval, self.config.fast_ewma_min),
+            fast_factor_flat: ewma_factor(self.config.tick_interval, self.config.fast_ewma_max),
+            delay_trend: HashMap::new(),
+            regime_shift_since: HashMap::new(),
+            lastprune_t: start_t,
+            lastsanity_t: start_t,
+        }
+    }
+
+    /*
+     * Updates the baselines with a single reply. All timing is taken from the reply
+     * itself rather than the clock, so recorded replies can be fed through here too.
+     */
+    pub fn process(&self, state: &mut BaselinerState, time_data: PingReply) {
+        if self.config.owd_prune_age > 0.0
+            && time_data
+                .last_receive_time_s
+                .duration_since(state.lastprune_t)
+                .as_secs_f64()
+                > self.config.owd_prune_age
+        {
+            self.prune_stale(time_data.last_receive_time_s);
+            let owd_recent_map = self.owd_recent.lock().unwrap();
+            state
+                .regime_shift_since
+                .retain(|reflector, _| owd_recent_map.contains_key(reflector));
+            state
+                .delay_trend
+                .retain(|reflector, _| owd_recent_map.contains_key(reflector));
+            drop(owd_recent_map);
+            state.lastprune_t = time_data.last_receive_time_s;
+        }
 
-        loop {
-            let time_data = self.stats_receiver.recv()?;
+        if self.config.baseline_max_ms > 0.0
+            && time_data
+                .last_receive_time_s
+                .duration_since(state.lastsanity_t)
+                .as_secs_f64()
+                > BASELINE_SANITY_INTERVAL_S
+        {
+            self.sanitize_baselines();
+            state.lastsanity_t = time_data.last_receive_time_s;
+        }
 
-            let mut owd_baseline_map = self.owd_baseline.lock().unwrap();
-            let mut owd_recent_map = self.owd_recent.lock().unwrap();
+        let mut owd_baseline_map = self.owd_baseline.lock().unwrap();
+        let mut owd_recent_map = self.owd_recent.lock().unwrap();
 
-            let owd_baseline_new = ReflectorStats {
-                down_ewma: time_data.down_time,
-                up_ewma: time_data.down_time,
-                last_receive_time_s: time_data.last_receive_time_s,
-            };
+        let owd_baseline_new = ReflectorStats {
+            down_ewma: time_data.down_time,
+            up_ewma: time_data.down_time,
+            jitter: 0.0,
+            last_receive_time_s: time_data.last_receive_time_s,
+            replies: 0,
+        };
 
-            let owd_recent_new = ReflectorStats {
-                down_ewma: time_data.down_time,
-                up_ewma: time_data.down_time,
-                last_receive_time_s: time_data.last_receive_time_s,
-            };
+        let owd_recent_new = ReflectorStats {
+            down_ewma: time_data.down_time,
+            up_ewma: time_data.down_time,
+            jitter: 0.0,
+            last_receive_time_s: time_data.last_receive_time_s,
+            replies: 0,
+        };
 
-            let mut owd_baseline = owd_baseline_map
-                .entry(time_data.reflector)
-                .or_insert(owd_baseline_new);
+        let owd_baseline = owd_baseline_map
+            .entry(time_data.reflector)
+            .or_insert(owd_baseline_new);
 
-            let mut owd_recent = owd_recent_map
-                .entry(time_data.reflector)
-                .or_insert(owd_recent_new);
+        let owd_recent = owd_recent_map
+            .entry(time_data.reflector)
+            .or_insert(owd_recent_new);
 
-            if time_data
+        // After a long enough silence, the old baseline can't be trusted anymore, start over
+        if time_data
+            .last_receive_time_s
+            .duration_since(owd_baseline.last_receive_time_s)
+            .as_secs_f64()
+            > self.config.baseline_reset
+            || time_data
                 .last_receive_time_s
-                .duration_since(owd_baseline.last_receive_time_s)
+                .duration_since(owd_recent.last_receive_time_s)
                 .as_secs_f64()
-                > 30.0
-                || time_data
-                    .last_receive_time_s
-                    .duration_since(owd_recent.last_receive_time_s)
-                    .as_secs_f64()
-                    > 30.0
-            {
-                owd_baseline.down_ewma = time_data.down_time;
-                owd_baseline.up_ewma = time_data.up_time;
-                owd_baseline.last_receive_time_s = time_data.last_receive_time_s;
-                owd_recent.down_ewma = time_data.down_time;
-                owd_recent.up_ewma = time_data.up_time;
-                owd_recent.last_receive_time_s = time_data.last_receive_time_s;
-            }
-
+                > self.config.baseline_reset
+        {
+            owd_baseline.down_ewma = time_data.down_time;
+            owd_baseline.up_ewma = time_data.up_time;
             owd_baseline.last_receive_time_s = time_data.last_receive_time_s;
+            owd_recent.down_ewma = time_data.down_time;
+            owd_recent.up_ewma = time_data.up_time;
             owd_recent.last_receive_time_s = time_data.last_receive_time_s;
+            owd_recent.replies = 0;
+        }
 
-            // if this reflection is more than 5 seconds higher than baseline... mark it no good and trigger a reselection
-            if time_data.up_time > owd_baseline.up_ewma + 5000.0
-                || time_data.down_time > owd_baseline.down_ewma + 5000.0
-            {
-                // mark the data as bad by setting the receive time to the time autorate was started
-                owd_baseline.last_receive_time_s = self.start_time;
-                owd_recent.last_receive_time_s = self.start_time;
-                info!(
-                    "Reflector {} has OWD > 5 seconds more than baseline, triggering reselection",
-                    time_data.reflector
-                );
-                // If reselection is disabled this would trigger an error
-                // so just ignore the result
-                let _ = self.reselect_trigger.send(true);
-            } else {
-                owd_baseline.down_ewma = owd_baseline.down_ewma * slow_factor
-                    + (1.0 - slow_factor) * time_data.down_time;
-                owd_baseline.up_ewma =
-                    owd_baseline.up_ewma * slow_factor + (1.0 - slow_factor) * time_data.up_time;
-
-                owd_recent.down_ewma =
-                    owd_recent.down_ewma * fast_factor + (1.0 - fast_factor) * time_data.down_time;
-                owd_recent.up_ewma =
-                    owd_recent.up_ewma * fast_factor + (1.0 - fast_factor) * time_data.up_time;
-
-                if owd_baseline.down_ewma > owd_recent.down_ewma {
-                    owd_baseline.down_ewma = owd_recent.down_ewma;
+        owd_baseline.last_receive_time_s = time_data.last_receive_time_s;
+        owd_recent.last_receive_time_s = time_data.last_receive_time_s;
+        owd_recent.replies = owd_recent.replies.saturating_add(1);
+
+        // if this reflection is more than 5 seconds higher than baseline... mark it no good and trigger a reselection
+        if time_data.up_time > owd_baseline.up_ewma + 5000.0
+            || time_data.down_time > owd_baseline.down_ewma + 5000.0
+        {
+            // mark the data as bad by setting the receive time to the time autorate was started
+            owd_baseline.last_receive_time_s = self.start_time;
+            owd_recent.last_receive_time_s = self.start_time;
+            info!(
+                "Reflector {} has OWD > 5 seconds more than baseline, triggering reselection",
+                time_data.reflector
+            );
+            // If reselection is disabled this would trigger an error
+            // so just ignore the result
+            let _ = self.reselect_trigger.send(true);
+        } else {
+            // Same smoothing as RFC 3550 uses for interarrival jitter
+            let deviation = (time_data.down_time + time_data.up_time)
+                - (owd_recent.down_ewma + owd_recent.up_ewma);
+            owd_recent.jitter += (deviation.abs() - owd_recent.jitter) / 16.0;
+
+            /*
+             * A fixed fast EWMA is a compromise between reacting quickly to the onset of
+             * bloat and smoothing over noise. Optionally pick per sample instead: when the
+             * delay has risen several samples in a row, it's most likely a queue building,
+             * so follow it closely. Otherwise lean towards smoothing.
+             */
+            let fast_factor = if self.config.adaptive_fast_ewma {
+                let rtt = time_data.down_time + time_data.up_time;
+                let (last_rtt, rising) = state
+                    .delay_trend
+                    .entry(time_data.reflector)
+                    .or_insert((rtt, 0));
+                *rising = if rtt > *last_rtt { *rising + 1 } else { 0 };
+                *last_rtt = rtt;
+
+                if *rising >= RISING_TREND_SAMPLES {
+                    state.fast_factor_rising
+                } else {
+                    state.fast_factor_flat
                 }
+            } else {
+                state.fast_factor
+            };
+
+            owd_baseline.down_ewma = owd_baseline.down_ewma * state.slow_factor
+                + (1.0 - state.slow_factor) * time_data.down_time;
+            owd_baseline.up_ewma = owd_baseline.up_ewma * state.slow_factor
+                + (1.0 - state.slow_factor) * time_data.up_time;
 
-                if owd_baseline.up_ewma > owd_recent.up_ewma {
-                    owd_baseline.up_ewma = owd_recent.up_ewma;
+            owd_recent.down_ewma =
+                owd_recent.down_ewma * fast_factor + (1.0 - fast_factor) * time_data.down_time;
+            owd_recent.up_ewma =
+                owd_recent.up_ewma * fast_factor + (1.0 - fast_factor) * time_data.up_time;
+
+            if owd_baseline.down_ewma > owd_recent.down_ewma {
+                owd_baseline.down_ewma = owd_recent.down_ewma;
+            }
+
+            if owd_baseline.up_ewma > owd_recent.up_ewma {
+                owd_baseline.up_ewma = owd_recent.up_ewma;
+            }
+
+            /*
+             * Anycast/CDN reflectors can move to a different PoP, which shows up as a
+             * clean step in delay. Bufferbloat is noisy, while a path change settles at
+             * its new level with little jitter. If a reflector has sat well above its
+             * baseline with low jitter for long enough, treat it as its new normal and
+             * re-baseline just that reflector.
+             */
+            if self.config.regime_change_ms > 0.0 {
+                let shift = (owd_recent.down_ewma + owd_recent.up_ewma)
+                    - (owd_baseline.down_ewma + owd_baseline.up_ewma);
+
+                if shift > self.config.regime_change_ms && owd_recent.jitter < shift / 4.0 {
+                    let since = *state
+                        .regime_shift_since
+                        .entry(time_data.reflector)
+                        .or_insert(time_data.last_receive_time_s);
+
+                    if time_data
+                        .last_receive_time_s
+                        .duration_since(since)
+                        .as_secs_f64()
+                        > self.config.regime_change_duration
+                    {
+                        warn!(
+                            "Reflector {} baseline shifted by {:.1} ms, re-baselining it",
+                            time_data.reflector, shift
+                        );
+                        owd_baseline.down_ewma = owd_recent.down_ewma;
+                        owd_baseline.up_ewma = owd_recent.up_ewma;
+                        state.regime_shift_since.remove(&time_data.reflector);
+                        self.counters.regime_changes.fetch_add(1, Ordering::Relaxed);
+                        self.counters.event(
+                            "regime_change",
+                            format!(
+                                "Reflector {} baseline shifted by {:.1} ms",
+                                time_data.reflector, shift
+                            ),
+                        );
+                    }
+                } else {
+                    state.regime_shift_since.remove(&time_data.reflector);
                 }
             }
+        }
 
-            info!(
-                "Reflector {} up baseline = {} down baseline = {}",
-                time_data.reflector, owd_baseline.up_ewma, owd_baseline.down_ewma
-            );
-            info!(
-                "Reflector {} up recent = {} down recent = {}",
-                time_data.reflector, owd_recent.up_ewma, owd_recent.down_ewma
-            );
+        info!(
+            "Reflector {} up baseline = {} down baseline = {}",
+            time_data.reflector, owd_baseline.up_ewma, owd_baseline.down_ewma
+        );
+        info!(
+            "Reflector {} up recent = {} down recent = {}",
+            time_data.reflector, owd_recent.up_ewma, owd_recent.down_ewma
+        );
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let mut state = self.new_state(Instant::now());
+
+        // The channel only closes once the listener has stopped, so we're done too
+        while let Ok(time_data) = self.stats_receiver.recv() {
+            self.process(&mut state, time_data);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    fn baseliner(config: Config) -> Baseliner {
+        let (reselect_trigger, _) = channel();
+        let (_, stats_receiver) = channel();
+        Baseliner {
+            config,
+            counters: Arc::new(Counters::default()),
+            owd_baseline: Arc::new(Mutex::new(HashMap::new())),
+            owd_recent: Arc::new(Mutex::new(HashMap::new())),
+            reflector_peers_lock: Arc::new(RwLock::new(Vec::new())),
+            reselect_trigger,
+            start_time: Instant::now(),
+            stats_receiver,
+        }
+    }
+
+    fn reply(reflector: IpAddr, delay: f64, last_receive_time_s: Instant) -> PingReply {
+        PingReply {
+            reflector,
+            seq: 0,
+            rtt: 0,
+            current_time: 0,
+            down_time: delay,
+            up_time: delay,
+            originate_timestamp: 0,
+            receive_timestamp: 0,
+            transmit_timestamp: 0,
+            last_receive_time_s,
+        }
+    }
+
+    /*
+     * Feeds a reply every tick with the given one-way delays, carrying on from `start_t`,
+     * and returns when the next one would've been
+     */
+    fn feed(
+        baseliner: &Baseliner,
+        state: &mut BaselinerState,
+        reflector: IpAddr,
+        delays: impl IntoIterator<Item = f64>,
+        start_t: Instant,
+    ) -> Instant {
+        let tick = Duration::from_secs_f64(baseliner.config.tick_interval);
+        let mut now_t = start_t;
+        for delay in delays {
+            baseliner.process(state, reply(reflector, delay, now_t));
+            now_t += tick;
+        }
+        now_t
+    }
+
+    fn stats(down: f64, up: f64, last_receive_time_s: Instant) -> ReflectorStats {
+        ReflectorStats {
+            down_ewma: down,
+            up_ewma: up,
+            jitter: 0.0,
+            last_receive_time_s,
+            replies: 0,
+        }
+    }
+
+    #[test]
+    fn snapshot_joins_the_maps_and_peers() {
+        let now_t = Instant::now();
+        let stale_t = now_t - Duration::from_secs(10);
+        let [peer, candidate, half_known] =
+            ["192.0.2.3", "192.0.2.1", "192.0.2.2"].map(|r| r.parse::<IpAddr>().unwrap());
+        let owd_baseline = Mutex::new(HashMap::from([
+            (peer, stats(10.0, 11.0, now_t)),
+            (candidate, stats(20.0, 21.0, stale_t)),
+            (half_known, stats(30.0, 31.0, now_t)),
+        ]));
+        let owd_recent = Mutex::new(HashMap::from([
+            (peer, stats(12.0, 13.0, now_t)),
+            (candidate, stats(22.0, 23.0, stale_t)),
+        ]));
+        let peers = RwLock::new(vec![peer]);
+
+        let snapshot = reflector_snapshot(&owd_baseline, &owd_recent, &peers, 1.0);
+
+        // Sorted by address, and only for reflectors with both baseline and recent stats
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].reflector, candidate);
+        assert!(!snapshot[0].active);
+        assert!(!snapshot[0].reachable);
+        assert_eq!(snapshot[1].reflector, peer);
+        assert!(snapshot[1].active);
+        assert!(snapshot[1].reachable);
+        assert_eq!(
+            (snapshot[1].baseline_down_ms, snapshot[1].recent_up_ms),
+            (10.0, 13.0)
+        );
+    }
+
+    #[test]
+    fn shifted_delay_is_rebaselined() {
+        let mut config = test_config();
+        config.regime_change_ms = 5.0;
+        config.regime_change_duration = 10.0;
+        let baseliner = baseliner(config);
+        let reflector: IpAddr = "192.0.2.1".parse().unwrap();
+        let start_t = Instant::now();
+        let mut state = baseliner.new_state(start_t);
+
+        let now_t = feed(&baseliner, &mut state, reflector, [10.0; 20], start_t);
+        // A steady step up, like an anycast reflector moving to a further away PoP
+        feed(&baseliner, &mut state, reflector, [20.0; 100], now_t);
+
+        let baseline = baseliner.owd_baseline.lock().unwrap()[&reflector];
+        assert!((baseline.down_ewma - 20.0).abs() < 0.1);
+        assert!((baseline.up_ewma - 20.0).abs() < 0.1);
+        assert_eq!(baseliner.counters.regime_changes.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn noisy_delay_isnt_rebaselined() {
+        let mut config = test_config();
+        config.regime_change_ms = 5.0;
+        config.regime_change_duration = 10.0;
+        let baseliner = baseliner(config);
+        let reflector: IpAddr = "192.0.2.1".parse().unwrap();
+        let start_t = Instant::now();
+        let mut state = baseliner.new_state(start_t);
+
+        let now_t = feed(&baseliner, &mut state, reflector, [10.0; 20], start_t);
+        // Bloat jumps around rather than settling
+        let bloat = (0..100).map(|i| if i % 2 == 0 { 15.0 } else { 45.0 });
+        feed(&baseliner, &mut state, reflector, bloat, now_t);
+
+        let baseline = baseliner.owd_baseline.lock().unwrap()[&reflector];
+        assert!(baseline.down_ewma < 15.0);
+        assert_eq!(baseliner.counters.regime_changes.load(Ordering::Relaxed), 0);
+    }
+
+    // The recent delay at the end of a queue building up, from 10 to 60 ms over 10 ticks
+    fn recent_after_onset(adaptive_fast_ewma: bool) -> f64 {
+        let mut config = test_config();
+        config.adaptive_fast_ewma = adaptive_fast_ewma;
+        let baseliner = baseliner(config);
+        let reflector: IpAddr = "192.0.2.1".parse().unwrap();
+        let start_t = Instant::now();
+        let mut state = baseliner.new_state(start_t);
+
+        let now_t = feed(&baseliner, &mut state, reflector, [10.0; 20], start_t);
+        let onset = (1..=10).map(|tick| 10.0 + 5.0 * tick as f64);
+        feed(&baseliner, &mut state, reflector, onset, now_t);
+
+        let recent = baseliner.owd_recent.lock().unwrap()[&reflector];
+        recent.down_ewma
+    }
+
+    #[test]
+    fn adaptive_ewma_follows_the_onset_closer() {
+        let fixed = recent_after_onset(false);
+        let adaptive = recent_after_onset(true);
+        assert!(adaptive > fixed);
+        assert!(60.0 - adaptive < (60.0 - fixed) / 2.0);
+    }
+
+    #[test]
+    fn short_gap_keeps_the_baseline() {
+        let mut config = test_config();
+        config.baseline_reset = 60.0;
+        let baseliner = baseliner(config);
+        let reflector: IpAddr = "192.0.2.1".parse().unwrap();
+        let start_t = Instant::now();
+        let mut state = baseliner.new_state(start_t);
+
+        let now_t = feed(&baseliner, &mut state, reflector, [10.0; 20], start_t);
+        // Quiet for a while, e.g. rate limited, but not long enough to start over
+        let now_t = feed(
+            &baseliner,
+            &mut state,
+            reflector,
+            [100.0],
+            now_t + Duration::from_secs(45),
+        );
+        let baseline = baseliner.owd_baseline.lock().unwrap()[&reflector];
+        assert!(baseline.down_ewma < 11.0);
+
+        feed(
+            &baseliner,
+            &mut state,
+            reflector,
+            [100.0],
+            now_t + Duration::from_secs(61),
+        );
+        let baseline = baseliner.owd_baseline.lock().unwrap()[&reflector];
+        assert_eq!(baseline.down_ewma, 100.0);
+    }
+
+    #[test]
+    fn implausible_baseline_is_reset() {
+        let mut config = test_config();
+        config.baseline_max_ms = 1000.0;
+        let baseliner = baseliner(config);
+        let [skewed, corrupt, negative] =
+            ["192.0.2.1", "192.0.2.2", "192.0.2.3"].map(|r| r.parse::<IpAddr>().unwrap());
+        let now_t = Instant::now();
+        {
+            let mut owd_baseline = baseliner.owd_baseline.lock().unwrap();
+            let mut owd_recent = baseliner.owd_recent.lock().unwrap();
+            // A reflector clock that's 20 s off, but with a sensible round trip
+            owd_baseline.insert(skewed, stats(20_010.0, -19_990.0, now_t));
+            owd_baseline.insert(corrupt, stats(900.0, 900.0, now_t));
+            owd_baseline.insert(negative, stats(-10.0, 5.0, now_t));
+            for reflector in [skewed, corrupt, negative] {
+                owd_recent.insert(reflector, stats(10.0, 10.0, now_t));
+            }
+        }
+
+        baseliner.sanitize_baselines();
+
+        let owd_baseline = baseliner.owd_baseline.lock().unwrap();
+        let owd_recent = baseliner.owd_recent.lock().unwrap();
+        assert!(owd_baseline.contains_key(&skewed));
+        assert!(owd_recent.contains_key(&skewed));
+        for reflector in [corrupt, negative] {
+            assert!(!owd_baseline.contains_key(&reflector));
+            assert!(!owd_recent.contains_key(&reflector));
+        }
+    }
+
+    #[test]
+    fn stale_non_peer_is_pruned() {
+        let mut config = test_config();
+        config.owd_prune_age = 600.0;
+        let baseliner = baseliner(config);
+        let [peer, stale, fresh] =
+            ["192.0.2.1", "192.0.2.2", "192.0.2.3"].map(|r| r.parse::<IpAddr>().unwrap());
+        *baseliner.reflector_peers_lock.write().unwrap() = vec![peer];
+        let last_t = Instant::now();
+        let now_t = last_t + Duration::from_secs(601);
+        {
+            let mut owd_baseline = baseliner.owd_baseline.lock().unwrap();
+            let mut owd_recent = baseliner.owd_recent.lock().unwrap();
+            // A silent peer is kept, it might just be having a bad moment
+            for (reflector, last_receive_time_s) in
+                [(peer, last_t), (stale, last_t), (fresh, now_t)]
+            {
+                owd_baseline.insert(reflector, stats(10.0, 10.0, last_receive_time_s));
+                owd_recent.insert(reflector, stats(10.0, 10.0, last_receive_time_s));
+            }
+        }
+
+        baseliner.prune_stale(now_t);
+
+        let owd_baseline = baseliner.owd_baseline.lock().unwrap();
+        let owd_recent = baseliner.owd_recent.lock().unwrap();
+        for reflector in [peer, fresh] {
+            assert!(owd_baseline.contains_key(&reflector));
+            assert!(owd_recent.contains_key(&reflector));
         }
+        assert!(!owd_baseline.contains_key(&stale));
+        assert!(!owd_recent.contains_key(&stale));
     }
 }
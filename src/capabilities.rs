@@ -0,0 +1,76 @@
+use crate::MeasurementType;
+use log::warn;
+use rustix::thread::{capabilities, CapabilityFlags};
+use std::env;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CapabilityError {
+    #[error("Missing {name}, which is needed to {purpose}. Either run sqm-autorate as root, or grant it with: setcap cap_net_raw,cap_net_admin+ep {binary}")]
+    Missing {
+        name: &'static str,
+        purpose: &'static str,
+        binary: String,
+    },
+}
+
+/*
+ * Without the right capabilities we'd otherwise fail somewhere deep inside the socket
+ * or Netlink code with an opaque "Operation not permitted", so check up front and
+ * tell the user exactly what's missing. Root normally has every capability in its
+ * effective set, so this covers that case too.
+ */
+pub fn check_capabilities(measurement_type: MeasurementType, shaping: bool) -> anyhow::Result<()> {
+    let effective = match capabilities(None) {
+        Ok(val) => val.effective,
+        Err(e) => {
+            warn!("Couldn't query process capabilities, skipping check: {}", e);
+            return Ok(());
+        }
+    };
+
+    let mut required = Vec::new();
+
+    if shaping {
+        required.push((
+            CapabilityFlags::NET_ADMIN,
+            "CAP_NET_ADMIN",
+            "change the shaper rates on the qdiscs",
+        ));
+    }
+
+    match measurement_type {
+        MeasurementType::Icmp | MeasurementType::IcmpTimestamps => required.push((
+            CapabilityFlags::NET_RAW,
+            "CAP_NET_RAW",
+            "open the raw ICMP sockets used for probing reflectors",
+        )),
+        MeasurementType::Ntp => required.push((
+            CapabilityFlags::NET_RAW,
+            "CAP_NET_RAW",
+            "open the raw UDP socket used for receiving NTP replies",
+        )),
+        MeasurementType::TcpTimestamps => required.push((
+            CapabilityFlags::NET_RAW,
+            "CAP_NET_RAW",
+            "open the raw TCP sockets used for probing reflectors",
+        )),
+    }
+
+    for (flag, name, purpose) in required {
+        if !effective.contains(flag) {
+            let binary = env::current_exe()
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| "/path/to/sqm-autorate".to_string());
+
+            return Err(CapabilityError::Missing {
+                name,
+                purpose,
+                binary,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use crate::time::Time;
+use rustix::thread::ClockId;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const HOURS_PER_DAY: usize = 24;
+/*
+ * Weight of each new safe rate sample in its hour's estimate. Samples come in every tick
+ * the link is busy without bloat, so this takes a good few minutes of load to move the
+ * estimate much, and one unusual evening doesn't undo what was learned before it.
+ */
+const LEARNING_RATE: f64 = 0.02;
+
+// The hour of the day in UTC the profile is currently in, 0 - 23
+pub fn current_hour() -> usize {
+    (Time::new(ClockId::Realtime).get_time_since_midnight() / 3_600_000) as usize % HOURS_PER_DAY
+}
+
+/*
+ * The capacity the link has been seen to sustain without bloat, for each hour of the day.
+ * Shared media like cable tend to slow down at the same times every day, so this lets the
+ * controller start out from what the link usually manages at this time, instead of
+ * learning the evening slowdown over again every evening. Hours are in UTC, so with DST
+ * the pattern shifts by a bucket twice a year, which it soon learns its way out of.
+ */
+#[derive(Clone, Debug)]
+pub struct CapacityProfile {
+    // kbit/s per hour, 0 for hours nothing was learned for yet
+    download: [f64; HOURS_PER_DAY],
+    upload: [f64; HOURS_PER_DAY],
+}
+
+impl Default for CapacityProfile {
+    fn default() -> Self {
+        Self {
+            download: [0.0; HOURS_PER_DAY],
+            upload: [0.0; HOURS_PER_DAY],
+        }
+    }
+}
+
+fn learn_rate(estimate: &mut f64, sample: f64) {
+    if *estimate > 0.0 {
+        *estimate += LEARNING_RATE * (sample - *estimate);
+    } else {
+        *estimate = sample;
+    }
+}
+
+impl CapacityProfile {
+    /// Loads a saved profile, or starts an empty one if there isn't one yet
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let mut profile = Self::default();
+        if !Path::new(path).exists() {
+            return Ok(profile);
+        }
+
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .from_path(path)?;
+
+        for record in reader.records() {
+            let record = record?;
+            let hour: usize = record[0].parse()?;
+            if hour >= HOURS_PER_DAY {
+                continue;
+            }
+            profile.download[hour] = record[1].parse()?;
+            profile.upload[hour] = record[2].parse()?;
+        }
+
+        Ok(profile)
+    }
+
+    // Written next to the file and renamed into place, so a crash never leaves half a profile
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut out = String::from("hour,download_kbit,upload_kbit\n");
+        for hour in 0..HOURS_PER_DAY {
+            let _ = writeln!(
+                out,
+                "{},{},{}",
+                hour,
+                self.download[hour].round(),
+                self.upload[hour].round()
+            );
+        }
+
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(tmp_path.as_str(), out)?;
+        fs::rename(tmp_path.as_str(), path)
+    }
+
+    pub fn learn_download(&mut self, hour: usize, rate: f64) {
+        learn_rate(&mut self.download[hour % HOURS_PER_DAY], rate);
+    }
+
+    pub fn learn_upload(&mut self, hour: usize, rate: f64) {
+        learn_rate(&mut self.upload[hour % HOURS_PER_DAY], rate);
+    }
+
+    // The learned rates for the hour, if there's anything learned for both directions yet
+    pub fn rates(&self, hour: usize) -> Option<(f64, f64)> {
+        let (download, upload) = (
+            self.download[hour % HOURS_PER_DAY],
+            self.upload[hour % HOURS_PER_DAY],
+        );
+        (download > 0.0 && upload > 0.0).then_some((download, upload))
+    }
+}
@@ -1,35 +1,51 @@
 use anyhow::Result;
-#[cfg(feature = "uci")]
-use log::warn;
-use log::Level;
+use log::{info, warn, Level};
 #[cfg(feature = "uci")]
 use rust_uci::Uci;
-use std::fs::File;
-use std::io::BufRead;
+use rustix::thread::ClockId;
 use std::net::IpAddr;
 use std::path::Path;
+#[cfg(not(feature = "uci"))]
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::{env, io};
+use std::{env, fs, io};
 
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum ConfigError {
+    #[error("Couldn't create output directory `{0}`: {1}")]
+    CreateOutputDir(String, io::Error),
+    #[error("Invalid delta aggregation")]
+    InvalidDeltaAggregation(String),
+    #[error("Invalid CPU list")]
+    InvalidCpuList(String),
+    #[error("Invalid CAKE flow mode")]
+    InvalidFlowMode(String),
+    #[error("Invalid probe clock")]
+    InvalidProbeClock(String),
     #[error("Invalid measurement type")]
     InvalidMeasurementType(String),
+    #[error(
+        "IPv6 reflector `{0}` can't be used with icmp-timestamps, ICMPv6 has no timestamp message"
+    )]
+    Ipv6TimestampsUnsupported(String),
+    #[error("Invalid scheduling policy")]
+    InvalidSchedPolicy(String),
+    #[error("Invalid stats format")]
+    InvalidStatsFormat(String),
+    #[error("Invalid warmup shape")]
+    InvalidWarmupShape(String),
+    #[error("Value for key `{0}` is out of range, {1}")]
+    OutOfRange(String, String),
     #[error("Couldn't parse value for key: `{0}`: invalid value")]
     ParseError(String),
     #[error("No config value found for key: `{0}`")]
     MissingValue(String),
 }
 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
-}
+// Far beyond any useful history, but keeps the periodic speed history dump to a sane size
+const MAX_SPEED_HIST_SIZE: u32 = 10000;
 
 #[derive(Clone, Copy, Debug)]
 pub enum MeasurementType {
@@ -43,13 +59,210 @@ impl FromStr for MeasurementType {
     type Err = ConfigError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        return match s.to_lowercase().as_str() {
+        match s.to_lowercase().as_str() {
             "icmp" => Ok(MeasurementType::Icmp),
             "icmp-timestamps" => Ok(MeasurementType::IcmpTimestamps),
             "ntp" => Ok(MeasurementType::Ntp),
             "tcp-timestamps" => Ok(MeasurementType::TcpTimestamps),
             &_ => Err(ConfigError::InvalidMeasurementType(s.to_string())),
-        };
+        }
+    }
+}
+
+/*
+ * CAKE's flow isolation modes, with the values the kernel expects. For the dual modes,
+ * the host that matters is the one inside the network: on a NAT router that's the
+ * destination for download (ingress) and the source for upload (egress), so dual-dsthost
+ * suits the download qdisc and dual-srchost the upload one. triple-isolate works
+ * either way, which is why it's CAKE's default.
+ *
+ * Each direction can be given its own mode. A mode given for both is applied as-is,
+ * except for the dual modes, where each direction gets the one that suits it.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CakeFlowMode {
+    FlowBlind = 0,
+    SrcHost = 1,
+    DstHost = 2,
+    Hosts = 3,
+    Flows = 4,
+    DualSrcHost = 5,
+    DualDstHost = 6,
+    TripleIsolate = 7,
+}
+
+impl CakeFlowMode {
+    pub fn for_download(self) -> Self {
+        match self {
+            CakeFlowMode::DualSrcHost => CakeFlowMode::DualDstHost,
+            mode => mode,
+        }
+    }
+
+    pub fn for_upload(self) -> Self {
+        match self {
+            CakeFlowMode::DualDstHost => CakeFlowMode::DualSrcHost,
+            mode => mode,
+        }
+    }
+}
+
+impl FromStr for CakeFlowMode {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "flowblind" => Ok(CakeFlowMode::FlowBlind),
+            "srchost" => Ok(CakeFlowMode::SrcHost),
+            "dsthost" => Ok(CakeFlowMode::DstHost),
+            "hosts" => Ok(CakeFlowMode::Hosts),
+            "flows" => Ok(CakeFlowMode::Flows),
+            "dual-srchost" => Ok(CakeFlowMode::DualSrcHost),
+            "dual-dsthost" => Ok(CakeFlowMode::DualDstHost),
+            "triple-isolate" => Ok(CakeFlowMode::TripleIsolate),
+            &_ => Err(ConfigError::InvalidFlowMode(s.to_string())),
+        }
+    }
+}
+
+/*
+ * How the per-reflector deltas are combined into the delay signal. A fixed index into the
+ * sorted deltas means a different quantile depending on how many reflectors answered,
+ * while the median stays the middle one however many there are.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeltaAggregation {
+    Index,
+    Median,
+}
+
+impl FromStr for DeltaAggregation {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "index" => Ok(DeltaAggregation::Index),
+            "median" => Ok(DeltaAggregation::Median),
+            &_ => Err(ConfigError::InvalidDeltaAggregation(s.to_string())),
+        }
+    }
+}
+
+/*
+ * The clock used to time echo probes. The raw monotonic clock isn't slewed by NTP, so its
+ * rate stays put while NTP is correcting the time, which could otherwise bias sub-ms delay
+ * measurements a little. In exchange it isn't disciplined at all and drifts relative to real
+ * time, which doesn't matter for RTTs measured over a fraction of a second.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProbeClock {
+    Monotonic,
+    MonotonicRaw,
+}
+
+impl ProbeClock {
+    pub fn clock_id(self) -> ClockId {
+        match self {
+            ProbeClock::Monotonic => ClockId::Monotonic,
+            ProbeClock::MonotonicRaw => ClockId::MonotonicRaw,
+        }
+    }
+}
+
+impl FromStr for ProbeClock {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "monotonic" => Ok(ProbeClock::Monotonic),
+            "monotonic-raw" => Ok(ProbeClock::MonotonicRaw),
+            &_ => Err(ConfigError::InvalidProbeClock(s.to_string())),
+        }
+    }
+}
+
+/*
+ * How the sender and listener threads are scheduled. The default leaves them as normal
+ * threads, where the priority is a nice value. With the realtime policies the priority
+ * is the realtime one, and they'll run ahead of everything that isn't realtime.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SchedPolicy {
+    Other,
+    Fifo,
+    RoundRobin,
+}
+
+impl FromStr for SchedPolicy {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "other" => Ok(SchedPolicy::Other),
+            "fifo" => Ok(SchedPolicy::Fifo),
+            "rr" => Ok(SchedPolicy::RoundRobin),
+            &_ => Err(ConfigError::InvalidSchedPolicy(s.to_string())),
+        }
+    }
+}
+
+// How each tick's stats are written to the stats file and FIFO
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatsFormat {
+    Csv,
+    // One JSON object per line
+    Json,
+}
+
+impl FromStr for StatsFormat {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(StatsFormat::Csv),
+            "json" => Ok(StatsFormat::Json),
+            &_ => Err(ConfigError::InvalidStatsFormat(s.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WarmupShape {
+    Step,
+    Linear,
+    Exponential,
+}
+
+impl FromStr for WarmupShape {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "step" => Ok(WarmupShape::Step),
+            "linear" => Ok(WarmupShape::Linear),
+            "exponential" => Ok(WarmupShape::Exponential),
+            &_ => Err(ConfigError::InvalidWarmupShape(s.to_string())),
+        }
+    }
+}
+
+/// A comma separated list of CPU numbers, e.g. `1` or `2,3`. Empty for none
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CpuList(pub Vec<usize>);
+
+impl FromStr for CpuList {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|cpu| !cpu.is_empty())
+            .map(|cpu| {
+                cpu.parse::<usize>()
+                    .map_err(|_| ConfigError::InvalidCpuList(s.to_string()))
+            })
+            .collect::<Result<Vec<usize>, ConfigError>>()
+            .map(CpuList)
     }
 }
 
@@ -59,37 +272,159 @@ pub struct Config {
     pub download_interface: String,
     pub upload_interface: String,
     pub download_base_kbits: f64,
+    pub download_hard_min_kbits: f64,
     pub download_min_kbits: f64,
     pub upload_base_kbits: f64,
+    pub upload_hard_min_kbits: f64,
     pub upload_min_kbits: f64,
 
     // Output section
+    pub control_socket: String,
+    pub journal_stats: bool,
     pub log_level: Level,
+    pub log_rate_limit: u32,
+    pub log_per_packet: bool,
+    pub metrics_listen: String,
+    pub parse_error_log_rate: u64,
+    pub prom_textfile: String,
+    pub prom_textfile_interval: f64,
+    pub replay_file: String,
+    pub replay_output: String,
+    pub self_test_duration: f64,
     pub speed_hist_file: String,
+    pub speed_hist_interval: f64,
+    pub state_file: String,
+    pub state_file_interval: f64,
+    pub stats_append: bool,
+    pub stats_fifo: String,
     pub stats_file: String,
+    pub stats_format: StatsFormat,
+    pub stats_on_change_only: bool,
+    pub summary_interval: f64,
     pub suppress_statistics: bool,
+    pub survey_interval: f64,
+    pub survey_report: String,
 
     // Advanced section
+    pub adaptive_fast_ewma: bool,
+    pub adaptive_min_duration: f64,
+    pub adaptive_min_rate: bool,
+    pub baseline_max_ms: f64,
+    pub baseline_reset: f64,
+    pub capacity_profile: String,
+    pub circuit_breaker_window: f64,
+    pub combined_pinger: bool,
+    pub coordinated_backoff: bool,
+    pub cpu_affinity: CpuList,
+    pub delta_aggregation: DeltaAggregation,
+    pub delta_smoothing: f64,
+    pub disable_reselection: bool,
+    pub download_cake_flow_mode: Option<CakeFlowMode>,
     pub download_delay_ms: f64,
+    pub download_delta_index: usize,
+    pub download_min_change_interval: f64,
+    pub download_reflectors: String,
+    pub echo_match_payload: bool,
+    pub echo_upload_share: f64,
+    pub fast_ewma_max: f64,
+    pub fast_ewma_min: f64,
+    pub first_hop_interval: f64,
     pub high_load_level: f64,
+    pub idle_duration: f64,
+    pub idle_load: f64,
+    pub idle_probe_slowdown: f64,
+    pub idle_reflectors: usize,
+    pub initial_probe_sample: u32,
+    pub initial_probe_timeout: f64,
+    pub load_from_capacity: bool,
     pub min_change_interval: f64,
+    pub max_active_probes: usize,
+    pub max_rate_asymmetry: f64,
+    pub max_reflector_weight: f64,
+    pub max_unstable_reselections: u32,
     pub measurement_type: MeasurementType,
     pub num_reflectors: u8,
+    pub owd_prune_age: f64,
+    pub peer_cache_file: String,
+    pub peer_cache_max_age: f64,
+    pub probe_clock: ProbeClock,
+    pub probe_loss_timeout: f64,
+    pub probe_priority: i32,
+    pub probe_sched_policy: SchedPolicy,
+    pub qdisc_check_interval: f64,
+    pub qdisc_floor_kbits: f64,
+    pub rank_by_baseline: bool,
+    pub rate_step_kbits: f64,
+    pub rebaseline_duration: f64,
+    pub setup_ingress: bool,
+    pub reference_interval: f64,
+    pub reference_reflectors: String,
     pub reflector_list_file: String,
+    pub regime_change_duration: f64,
+    pub regime_change_ms: f64,
+    pub reselect_candidates: u32,
+    pub reselect_min_replies: u32,
+    pub reselect_warmup_ticks: u32,
+    pub rng_seed: u64,
     pub speed_hist_size: u32,
+    pub speed_test_duration: f64,
+    pub speed_test_load: f64,
+    pub tcp_probe_port: u16,
     pub tick_interval: f64,
+    pub upload_cake_flow_mode: Option<CakeFlowMode>,
     pub upload_delay_ms: f64,
+    pub upload_delta_index: usize,
+    pub upload_min_change_interval: f64,
+    pub upload_reflectors: String,
+    pub use_default_reflectors: bool,
+    pub utilisation_window: u32,
+    pub warmup_duration: f64,
+    pub warmup_shape: WarmupShape,
+    pub worker_restarts: u32,
 }
 
 impl Config {
     pub fn new() -> Result<Self> {
-        Ok(Self {
+        /*
+         * Seconds between probe rounds, in which each peer reflector is probed once. The
+         * baseliner's EWMAs are tuned for a sample this often, and a reflector that hasn't
+         * replied within two ticks counts as stale. Read up front, as some of the other
+         * defaults are derived from it.
+         */
+        let tick_interval = Self::get::<f64>(
+            "SQMA_TICK_INTERVAL",
+            "sqm-autorate.@advanced_settings[0].tick_interval",
+            Some(0.5),
+        )?;
+        let min_change_interval = Self::get::<f64>(
+            "SQMA_MIN_CHANGE_INTERVAL",
+            "sqm-autorate.@advanced_settings[0].min_change_interval",
+            Some(0.5),
+        )?;
+        // Left empty, the flow mode the qdiscs were set up with is kept
+        let cake_flow_mode = Self::get_flow_mode(
+            "SQMA_CAKE_FLOW_MODE",
+            "sqm-autorate.@advanced_settings[0].cake_flow_mode",
+        )?;
+        // Where the files we write go by default
+        let output_dir = Self::get::<String>(
+            "SQMA_OUTPUT_DIR",
+            "sqm-autorate.@output[0].output_dir",
+            Some(Self::default_output_dir()),
+        )?;
+
+        let config = Self {
             // Network section
             download_base_kbits: Self::get::<f64>(
                 "SQMA_DOWNLOAD_BASE_KBITS",
                 "sqm-autorate.@network[0].download_base_kbits",
                 None,
             )?,
+            download_hard_min_kbits: Self::get::<f64>(
+                "SQMA_DOWNLOAD_HARD_MIN_KBITS",
+                "sqm-autorate.@network[0].download_hard_min_kbits",
+                Some(256.0),
+            )?,
             download_interface: Self::get::<String>(
                 "SQMA_DOWNLOAD_INTERFACE",
                 "sqm-autorate.@network[0].download_interface",
@@ -105,6 +440,11 @@ impl Config {
                 "sqm-autorate.@network[0].upload_base_kbits",
                 None,
             )?,
+            upload_hard_min_kbits: Self::get::<f64>(
+                "SQMA_UPLOAD_HARD_MIN_KBITS",
+                "sqm-autorate.@network[0].upload_hard_min_kbits",
+                Some(256.0),
+            )?,
             upload_interface: Self::get::<String>(
                 "SQMA_UPLOAD_INTERFACE",
                 "sqm-autorate.@network[0].upload_interface",
@@ -116,73 +456,697 @@ impl Config {
                 None,
             )?,
             // Output section
+            control_socket: Self::get::<String>(
+                "SQMA_CONTROL_SOCKET",
+                "sqm-autorate.@output[0].control_socket",
+                Some(String::new()),
+            )?,
+            // Only has an effect when built with the systemd feature
+            journal_stats: Self::get::<bool>(
+                "SQMA_JOURNAL_STATS",
+                "sqm-autorate.@output[0].journal_stats",
+                Some(false),
+            )?,
             log_level: Self::get::<Level>(
                 "SQMA_LOG_LEVEL",
                 "sqm-autorate.@output[0].log_level",
                 Some(Level::Error),
             )?,
+            // Log messages per second, anything beyond that is dropped and counted. 0 for no limit
+            log_rate_limit: Self::get::<u32>(
+                "SQMA_LOG_RATE_LIMIT",
+                "sqm-autorate.@output[0].log_rate_limit",
+                Some(0),
+            )?,
+            // Log every reply at debug level, which is a lot with many reflectors
+            log_per_packet: Self::get::<bool>(
+                "SQMA_LOG_PER_PACKET",
+                "sqm-autorate.@output[0].log_per_packet",
+                Some(false),
+            )?,
+            // host:port to serve Prometheus metrics on, only with the metrics-http feature
+            metrics_listen: Self::get::<String>(
+                "SQMA_METRICS_LISTEN",
+                "sqm-autorate.@output[0].metrics_listen",
+                Some(String::new()),
+            )?,
+            // Log one in this many reply parse failures, 0 turns it off
+            parse_error_log_rate: Self::get::<u64>(
+                "SQMA_PARSE_ERROR_LOG_RATE",
+                "sqm-autorate.@output[0].parse_error_log_rate",
+                Some(100),
+            )?,
+            prom_textfile: Self::get::<String>(
+                "SQMA_PROM_TEXTFILE",
+                "sqm-autorate.@output[0].prom_textfile",
+                Some(String::new()),
+            )?,
+            prom_textfile_interval: Self::get::<f64>(
+                "SQMA_PROM_TEXTFILE_INTERVAL",
+                "sqm-autorate.@output[0].prom_textfile_interval",
+                Some(15.0),
+            )?,
+            // Setting a recording to replay runs it through the controller offline, then exits
+            replay_file: Self::get::<String>(
+                "SQMA_REPLAY_FILE",
+                "sqm-autorate.@output[0].replay_file",
+                Some(String::new()),
+            )?,
+            replay_output: Self::get::<String>(
+                "SQMA_REPLAY_OUTPUT",
+                "sqm-autorate.@output[0].replay_output",
+                Some(Self::output_path(&output_dir, "sqm-replay.csv")),
+            )?,
+            // Runs a loaded latency self-test for this long, then exits. 0 disables
+            self_test_duration: Self::get::<f64>(
+                "SQMA_SELF_TEST_DURATION",
+                "sqm-autorate.@output[0].self_test_duration",
+                Some(0.0),
+            )?,
             speed_hist_file: Self::get::<String>(
                 "SQMA_SPEED_HIST_FILE",
                 "sqm-autorate.@output[0].speed_hist_file",
-                Some("/tmp/sqm-speedhist.csv".parse()?),
+                Some(Self::output_path(&output_dir, "sqm-speedhist.csv")),
+            )?,
+            speed_hist_interval: Self::get::<f64>(
+                "SQMA_SPEEDHIST_INTERVAL_S",
+                "sqm-autorate.@output[0].speed_hist_interval",
+                Some(300.0),
+            )?,
+            // A JSON snapshot of the current state, for tools that would rather poll a file
+            state_file: Self::get::<String>(
+                "SQMA_STATE_FILE",
+                "sqm-autorate.@output[0].state_file",
+                Some(String::new()),
+            )?,
+            state_file_interval: Self::get::<f64>(
+                "SQMA_STATE_FILE_INTERVAL",
+                "sqm-autorate.@output[0].state_file_interval",
+                Some(5.0),
+            )?,
+            stats_append: Self::get::<bool>(
+                "SQMA_STATS_APPEND",
+                "sqm-autorate.@output[0].stats_append",
+                Some(false),
+            )?,
+            // A named pipe that gets each tick's stats, dropped if nobody's reading it
+            stats_fifo: Self::get::<String>(
+                "SQMA_STATS_FIFO",
+                "sqm-autorate.@output[0].stats_fifo",
+                Some(String::new()),
             )?,
             stats_file: Self::get::<String>(
                 "SQMA_STATS_FILE",
                 "sqm-autorate.@output[0].stats_file",
-                Some("/tmp/sqm-autorate.csv".parse()?),
+                Some(Self::output_path(&output_dir, "sqm-autorate.csv")),
+            )?,
+            // "csv", or "json" for a JSON object per line with named fields
+            stats_format: Self::get::<StatsFormat>(
+                "SQMA_STATS_FORMAT",
+                "sqm-autorate.@output[0].stats_format",
+                Some(StatsFormat::Csv),
+            )?,
+            stats_on_change_only: Self::get::<bool>(
+                "SQMA_STATS_ON_CHANGE_ONLY",
+                "sqm-autorate.@output[0].stats_on_change_only",
+                Some(false),
+            )?,
+            // Logs a one-line summary this often, 0 disables
+            summary_interval: Self::get::<f64>(
+                "SQMA_SUMMARY_INTERVAL",
+                "sqm-autorate.@output[0].summary_interval",
+                Some(0.0),
             )?,
             suppress_statistics: Self::get::<bool>(
                 "SQMA_SUPPRESS_STATISTICS",
                 "sqm-autorate.@output[0].suppress_statistics",
                 Some(false),
             )?,
+            survey_interval: Self::get::<f64>(
+                "SQMA_SURVEY_INTERVAL",
+                "sqm-autorate.@output[0].survey_interval",
+                Some(60.0),
+            )?,
+            // Setting a report path switches to measurement-only mode, which never shapes
+            survey_report: Self::get::<String>(
+                "SQMA_SURVEY_REPORT",
+                "sqm-autorate.@output[0].survey_report",
+                Some(String::new()),
+            )?,
             // Advanced section
+            // Varies the recent delay EWMA between fast_ewma_min and fast_ewma_max with the trend
+            adaptive_fast_ewma: Self::get::<bool>(
+                "SQMA_ADAPTIVE_FAST_EWMA",
+                "sqm-autorate.@advanced_settings[0].adaptive_fast_ewma",
+                Some(false),
+            )?,
+            adaptive_min_duration: Self::get::<f64>(
+                "SQMA_ADAPTIVE_MIN_DURATION",
+                "sqm-autorate.@advanced_settings[0].adaptive_min_duration",
+                Some(30.0),
+            )?,
+            adaptive_min_rate: Self::get::<bool>(
+                "SQMA_ADAPTIVE_MIN_RATE",
+                "sqm-autorate.@advanced_settings[0].adaptive_min_rate",
+                Some(false),
+            )?,
+            // Round trip baselines beyond this many ms are considered corrupt and reset, 0 disables the check
+            baseline_max_ms: Self::get::<f64>(
+                "SQMA_BASELINE_MAX_MS",
+                "sqm-autorate.@advanced_settings[0].baseline_max_ms",
+                Some(10000.0),
+            )?,
+            /*
+             * A reflector that's been silent for longer than this has its baselines re-seeded
+             * from the next reply. The ratecontroller stops using a reflector's data well before
+             * that (after 2 ticks), so raising this only keeps the learned baseline around for
+             * reflectors that go quiet for a while, e.g. from ICMP rate limiting. Stale reflectors
+             * are pruned after owd_prune_age, so there's no point setting it any higher than that.
+             */
+            baseline_reset: Self::get::<f64>(
+                "SQMA_BASELINE_RESET_S",
+                "sqm-autorate.@advanced_settings[0].baseline_reset",
+                Some(30.0),
+            )?,
+            // Learns the usual capacity for each hour of the day and keeps it in this file, empty disables
+            capacity_profile: Self::get::<String>(
+                "SQMA_CAPACITY_PROFILE",
+                "sqm-autorate.@advanced_settings[0].capacity_profile",
+                Some(String::new()),
+            )?,
+            circuit_breaker_window: Self::get::<f64>(
+                "SQMA_CIRCUIT_BREAKER_WINDOW",
+                "sqm-autorate.@advanced_settings[0].circuit_breaker_window",
+                Some(0.0),
+            )?,
+            // Send the probes and read the replies from one thread, instead of one thread each
+            combined_pinger: Self::get::<bool>(
+                "SQMA_COMBINED_PINGER",
+                "sqm-autorate.@advanced_settings[0].combined_pinger",
+                Some(false),
+            )?,
+            coordinated_backoff: Self::get::<bool>(
+                "SQMA_COORDINATED_BACKOFF",
+                "sqm-autorate.@advanced_settings[0].coordinated_backoff",
+                Some(false),
+            )?,
+            // CPUs to pin the sender and listener threads to, empty leaves it to the scheduler
+            cpu_affinity: Self::get::<CpuList>(
+                "SQMA_CPU_AFFINITY",
+                "sqm-autorate.@advanced_settings[0].cpu_affinity",
+                Some(CpuList::default()),
+            )?,
+            // "index" uses the {download,upload}_delta_index'th lowest delta, "median" the median
+            delta_aggregation: Self::get::<DeltaAggregation>(
+                "SQMA_DELTA_AGGREGATION",
+                "sqm-autorate.@advanced_settings[0].delta_aggregation",
+                Some(DeltaAggregation::Index),
+            )?,
+            // Time constant in seconds for smoothing the delay delta over time, 0 uses it raw
+            delta_smoothing: Self::get::<f64>(
+                "SQMA_DELTA_SMOOTHING",
+                "sqm-autorate.@advanced_settings[0].delta_smoothing",
+                Some(0.0),
+            )?,
+            /*
+             * Sticks with the initial peers for good, for users who list exactly the reflectors
+             * they want. Set use_default_reflectors to false and num_reflectors to at least the
+             * size of the list to probe all of them. Any number of peers down to a single one works.
+             */
+            disable_reselection: Self::get::<bool>(
+                "SQMA_DISABLE_RESELECTION",
+                "sqm-autorate.@advanced_settings[0].disable_reselection",
+                Some(false),
+            )?,
+            download_cake_flow_mode: Self::get_flow_mode(
+                "SQMA_DOWNLOAD_CAKE_FLOW_MODE",
+                "sqm-autorate.@advanced_settings[0].download_cake_flow_mode",
+            )?
+            .or(cake_flow_mode.map(CakeFlowMode::for_download)),
             download_delay_ms: Self::get::<f64>(
                 "SQMA_DOWNLOAD_DELAY_MS",
                 "sqm-autorate.@advanced_settings[0].download_delay_ms",
                 Some(15.0),
             )?,
+            /*
+             * Which of the sorted per-reflector deltas (lowest first) is used as the
+             * delay signal for each direction. A higher index is more conservative,
+             * which can suit the thinner direction of an asymmetric link.
+             */
+            download_delta_index: Self::get::<usize>(
+                "SQMA_DOWNLOAD_DELTA_INDEX",
+                "sqm-autorate.@advanced_settings[0].download_delta_index",
+                Some(2),
+            )?,
+            download_min_change_interval: Self::get::<f64>(
+                "SQMA_DOWNLOAD_MIN_CHANGE_INTERVAL",
+                "sqm-autorate.@advanced_settings[0].download_min_change_interval",
+                Some(min_change_interval),
+            )?,
+            /*
+             * Comma separated list of the reflectors whose delay counts toward the download
+             * delta, for reflectors that timestamp one direction well and the other badly.
+             * They're added to the pool, but only count while they're among the peers.
+             * Empty counts every peer.
+             */
+            download_reflectors: Self::get::<String>(
+                "SQMA_DOWNLOAD_REFLECTORS",
+                "sqm-autorate.@advanced_settings[0].download_reflectors",
+                Some(String::new()),
+            )?,
+            /*
+             * Echo the probed address back in the payload and match replies by that instead
+             * of their source address, for reflectors behind ECMP or NAT that reply from a
+             * different address. The sequence number can't be used for this, as it's shared
+             * by all the reflectors probed in a round.
+             */
+            echo_match_payload: Self::get::<bool>(
+                "SQMA_ECHO_MATCH_PAYLOAD",
+                "sqm-autorate.@advanced_settings[0].echo_match_payload",
+                Some(false),
+            )?,
+            /*
+             * ICMP echo only gives us the RTT, which is split between the directions.
+             * This is the share of it attributed to upload. Purely a heuristic for when
+             * the bloat is known to be mostly in one direction, e.g. DOCSIS upload.
+             * It has no effect with measurement types that give real one-way delays.
+             */
+            echo_upload_share: Self::get::<f64>(
+                "SQMA_ECHO_UPLOAD_SHARE",
+                "sqm-autorate.@advanced_settings[0].echo_upload_share",
+                Some(0.5),
+            )?,
+            // Half-lives in seconds for the adaptive fast EWMA, for a noisy or flat and a rising delay
+            fast_ewma_max: Self::get::<f64>(
+                "SQMA_FAST_EWMA_MAX",
+                "sqm-autorate.@advanced_settings[0].fast_ewma_max",
+                Some(1.0),
+            )?,
+            fast_ewma_min: Self::get::<f64>(
+                "SQMA_FAST_EWMA_MIN",
+                "sqm-autorate.@advanced_settings[0].fast_ewma_min",
+                Some(0.1),
+            )?,
+            // Seconds between TTL=1 probes timing the first router on the way out, 0 disables them
+            first_hop_interval: Self::get::<f64>(
+                "SQMA_FIRST_HOP_INTERVAL",
+                "sqm-autorate.@advanced_settings[0].first_hop_interval",
+                Some(0.0),
+            )?,
             high_load_level: Self::get::<f64>(
                 "SQMA_HIGH_LOAD_LEVEL",
                 "sqm-autorate.@advanced_settings[0].high_load_level",
                 Some(0.8),
             )?,
+            idle_duration: Self::get::<f64>(
+                "SQMA_IDLE_DURATION",
+                "sqm-autorate.@advanced_settings[0].idle_duration",
+                Some(300.0),
+            )?,
+            // Below this load in both directions for idle_duration, probing is scaled back. 0 disables
+            idle_load: Self::get::<f64>(
+                "SQMA_IDLE_LOAD",
+                "sqm-autorate.@advanced_settings[0].idle_load",
+                Some(0.0),
+            )?,
+            idle_probe_slowdown: Self::get::<f64>(
+                "SQMA_IDLE_PROBE_SLOWDOWN",
+                "sqm-autorate.@advanced_settings[0].idle_probe_slowdown",
+                Some(4.0),
+            )?,
+            idle_reflectors: Self::get::<usize>(
+                "SQMA_IDLE_REFLECTORS",
+                "sqm-autorate.@advanced_settings[0].idle_reflectors",
+                Some(2),
+            )?,
+            /*
+             * Picks the initial peers by probing this many reflectors from the pool once at
+             * startup and taking the fastest, rather than going with the defaults until the
+             * first reselection. 0 disables this.
+             */
+            initial_probe_sample: Self::get::<u32>(
+                "SQMA_INITIAL_PROBE_SAMPLE",
+                "sqm-autorate.@advanced_settings[0].initial_probe_sample",
+                Some(0),
+            )?,
+            // Seconds to wait for replies to the initial probes
+            initial_probe_timeout: Self::get::<f64>(
+                "SQMA_INITIAL_PROBE_TIMEOUT",
+                "sqm-autorate.@advanced_settings[0].initial_probe_timeout",
+                Some(1.0),
+            )?,
+            // Judge high load against the learned capacity rather than the current rate
+            load_from_capacity: Self::get::<bool>(
+                "SQMA_LOAD_FROM_CAPACITY",
+                "sqm-autorate.@advanced_settings[0].load_from_capacity",
+                Some(false),
+            )?,
+            // Upper bound on reflectors probed per tick, 0 probes all of them
+            max_active_probes: Self::get::<usize>(
+                "SQMA_MAX_ACTIVE_PROBES",
+                "sqm-autorate.@advanced_settings[0].max_active_probes",
+                Some(0),
+            )?,
+            // Ratio between the base rates beyond which it's most likely a typo, 0 disables
+            max_rate_asymmetry: Self::get::<f64>(
+                "SQMA_MAX_RATE_ASYMMETRY",
+                "sqm-autorate.@advanced_settings[0].max_rate_asymmetry",
+                Some(100.0),
+            )?,
+            /*
+             * The most any one reflector can weigh in the delay signal, between 0 and 1. It
+             * then takes at least 1 / weight reflectors seeing a delay increase to act on it,
+             * so one very close reflector's glitch can't trigger a backoff by itself.
+             * 1 leaves the reflectors uncapped.
+             */
+            max_reflector_weight: Self::get::<f64>(
+                "SQMA_MAX_REFLECTOR_WEIGHT",
+                "sqm-autorate.@advanced_settings[0].max_reflector_weight",
+                Some(1.0),
+            )?,
+            // Reselections in a row re-triggered right away before warning and backing off, 0 disables
+            max_unstable_reselections: Self::get::<u32>(
+                "SQMA_MAX_UNSTABLE_RESELECTIONS",
+                "sqm-autorate.@advanced_settings[0].max_unstable_reselections",
+                Some(10),
+            )?,
             measurement_type: Self::get::<MeasurementType>(
                 "SQMA_MEASUREMENT_TYPE",
                 "sqm-autorate.@advanced_settings[0].measurement_type",
                 Some(MeasurementType::IcmpTimestamps),
             )?,
-            min_change_interval: Self::get::<f64>(
-                "SQMA_MIN_CHANGE_INTERVAL",
-                "sqm-autorate.@advanced_settings[0].min_change_interval",
-                Some(0.5),
-            )?,
+            min_change_interval,
             num_reflectors: Self::get::<u8>(
                 "SQMA_NUM_REFLECTORS",
                 "sqm-autorate.@advanced_settings[0].num_reflectors",
                 Some(5),
             )?,
+            // Seconds without replies before a non-peer reflector is forgotten, 0 disables pruning
+            owd_prune_age: Self::get::<f64>(
+                "SQMA_OWD_PRUNE_AGE",
+                "sqm-autorate.@advanced_settings[0].owd_prune_age",
+                Some(600.0),
+            )?,
+            /*
+             * Keeps the peers, and which reflectors answer the probes, in this file across
+             * restarts, so a restart starts out with the peers it left off with rather than
+             * finding out all over again. Empty disables.
+             */
+            peer_cache_file: Self::get::<String>(
+                "SQMA_PEER_CACHE_FILE",
+                "sqm-autorate.@advanced_settings[0].peer_cache_file",
+                Some(String::new()),
+            )?,
+            // Seconds after which a peer cache is too stale to start from, 0 for no limit
+            peer_cache_max_age: Self::get::<f64>(
+                "SQMA_PEER_CACHE_MAX_AGE",
+                "sqm-autorate.@advanced_settings[0].peer_cache_max_age",
+                Some(86400.0),
+            )?,
+            probe_clock: Self::get::<ProbeClock>(
+                "SQMA_PROBE_CLOCK",
+                "sqm-autorate.@advanced_settings[0].probe_clock",
+                Some(ProbeClock::Monotonic),
+            )?,
+            // An unanswered probe counts as lost after this many seconds, 0 disables loss tracking
+            probe_loss_timeout: Self::get::<f64>(
+                "SQMA_PROBE_LOSS_TIMEOUT",
+                "sqm-autorate.@advanced_settings[0].probe_loss_timeout",
+                Some(2.0),
+            )?,
+            // Nice value, or realtime priority with the fifo and rr policies. 0 with other leaves it
+            probe_priority: Self::get::<i32>(
+                "SQMA_PROBE_PRIORITY",
+                "sqm-autorate.@advanced_settings[0].probe_priority",
+                Some(0),
+            )?,
+            probe_sched_policy: Self::get::<SchedPolicy>(
+                "SQMA_PROBE_SCHED_POLICY",
+                "sqm-autorate.@advanced_settings[0].probe_sched_policy",
+                Some(SchedPolicy::Other),
+            )?,
+            qdisc_check_interval: Self::get::<f64>(
+                "SQMA_QDISC_CHECK_INTERVAL",
+                "sqm-autorate.@advanced_settings[0].qdisc_check_interval",
+                Some(10.0),
+            )?,
+            /*
+             * CAKE doesn't shape sensibly below a few hundred kbit, so a glitch in the
+             * measurements could throttle the link into uselessness. Rates below this are
+             * raised to it no matter what the min rates say. 0 disables the floor.
+             */
+            qdisc_floor_kbits: Self::get::<f64>(
+                "SQMA_QDISC_FLOOR_KBITS",
+                "sqm-autorate.@advanced_settings[0].qdisc_floor_kbits",
+                Some(0.0),
+            )?,
+            rank_by_baseline: Self::get::<bool>(
+                "SQMA_RANK_BY_BASELINE",
+                "sqm-autorate.@advanced_settings[0].rank_by_baseline",
+                Some(false),
+            )?,
+            // Only set rates that are a multiple of this many kbit/s, 0 sets them to the kbit
+            rate_step_kbits: Self::get::<f64>(
+                "SQMA_RATE_STEP_KBITS",
+                "sqm-autorate.@advanced_settings[0].rate_step_kbits",
+                Some(0.0),
+            )?,
+            /*
+             * How long the reflector selector waits for new candidates to build up
+             * baselines before ranking them. This used to be hard-coded to roughly
+             * three ticks (tick_interval * PI), which is kept as the default.
+             */
+            rebaseline_duration: Self::get::<f64>(
+                "SQMA_REBASELINE_DURATION",
+                "sqm-autorate.@advanced_settings[0].rebaseline_duration",
+                Some(tick_interval * std::f64::consts::PI),
+            )?,
+            /*
+             * Create the IFB and CAKE qdiscs ourselves, for systems without the sqm scripts.
+             * The upload interface is taken as the WAN interface, and its ingress traffic
+             * is redirected to an IFB named after the download interface.
+             */
+            setup_ingress: Self::get::<bool>(
+                "SQMA_SETUP_INGRESS",
+                "sqm-autorate.@advanced_settings[0].setup_ingress",
+                Some(false),
+            )?,
+            reference_interval: Self::get::<f64>(
+                "SQMA_REFERENCE_INTERVAL",
+                "sqm-autorate.@advanced_settings[0].reference_interval",
+                Some(5.0),
+            )?,
+            reference_reflectors: Self::get::<String>(
+                "SQMA_REFERENCE_REFLECTORS",
+                "sqm-autorate.@advanced_settings[0].reference_reflectors",
+                Some(String::new()),
+            )?,
             reflector_list_file: Self::get::<String>(
                 "SQMA_REFLECTOR_LIST_FILE",
                 "sqm-autorate.@advanced_settings[0].reflector_list_file",
-                Some("/etc/sqm-autorate/reflectors-icmp.csv".parse()?),
+                Some(Self::default_config_path("reflectors-icmp.csv")),
             )?,
+            regime_change_duration: Self::get::<f64>(
+                "SQMA_REGIME_CHANGE_DURATION",
+                "sqm-autorate.@advanced_settings[0].regime_change_duration",
+                Some(10.0),
+            )?,
+            regime_change_ms: Self::get::<f64>(
+                "SQMA_REGIME_CHANGE_MS",
+                "sqm-autorate.@advanced_settings[0].regime_change_ms",
+                Some(0.0),
+            )?,
+            reselect_candidates: Self::get::<u32>(
+                "SQMA_RESELECT_CANDIDATES",
+                "sqm-autorate.@advanced_settings[0].reselect_candidates",
+                Some(20),
+            )?,
+            // Replies a candidate needs during rebaselining to be ranked at all
+            reselect_min_replies: Self::get::<u32>(
+                "SQMA_RESELECT_MIN_REPLIES",
+                "sqm-autorate.@advanced_settings[0].reselect_min_replies",
+                Some(1),
+            )?,
+            // Ticks a newly selected reflector's delta is left out of the decision for
+            reselect_warmup_ticks: Self::get::<u32>(
+                "SQMA_RESELECT_WARMUP_TICKS",
+                "sqm-autorate.@advanced_settings[0].reselect_warmup_ticks",
+                Some(0),
+            )?,
+            // Makes the random choices repeatable when set, 0 seeds from OS entropy
+            rng_seed: Self::get::<u64>(
+                "SQMA_RNG_SEED",
+                "sqm-autorate.@advanced_settings[0].rng_seed",
+                Some(0),
+            )?,
+            /*
+             * Each entry is a pair of f64 per direction, so memory use is negligible, but the
+             * whole history is written out on every speed_hist_interval, one row per entry.
+             */
             speed_hist_size: Self::get::<u32>(
                 "SQMA_SPEED_HIST_SIZE",
                 "sqm-autorate.@advanced_settings[0].speed_hist_size",
                 Some(100),
             )?,
-            tick_interval: Self::get::<f64>(
-                "SQMA_TICK_INTERVAL",
-                "sqm-autorate.@advanced_settings[0].tick_interval",
-                Some(0.5),
+            speed_test_duration: Self::get::<f64>(
+                "SQMA_SPEED_TEST_DURATION",
+                "sqm-autorate.@advanced_settings[0].speed_test_duration",
+                Some(5.0),
+            )?,
+            speed_test_load: Self::get::<f64>(
+                "SQMA_SPEED_TEST_LOAD",
+                "sqm-autorate.@advanced_settings[0].speed_test_load",
+                Some(0.0),
             )?,
+            // Reflectors need to be listening on this port for the tcp-timestamps measurement type
+            tcp_probe_port: Self::get::<u16>(
+                "SQMA_TCP_PROBE_PORT",
+                "sqm-autorate.@advanced_settings[0].tcp_probe_port",
+                Some(443),
+            )?,
+            tick_interval,
+            upload_cake_flow_mode: Self::get_flow_mode(
+                "SQMA_UPLOAD_CAKE_FLOW_MODE",
+                "sqm-autorate.@advanced_settings[0].upload_cake_flow_mode",
+            )?
+            .or(cake_flow_mode.map(CakeFlowMode::for_upload)),
             upload_delay_ms: Self::get::<f64>(
                 "SQMA_UPLOAD_DELAY_MS",
                 "sqm-autorate.@advanced_settings[0].upload_delay_ms",
                 Some(15.0),
             )?,
-        })
+            upload_delta_index: Self::get::<usize>(
+                "SQMA_UPLOAD_DELTA_INDEX",
+                "sqm-autorate.@advanced_settings[0].upload_delta_index",
+                Some(2),
+            )?,
+            upload_min_change_interval: Self::get::<f64>(
+                "SQMA_UPLOAD_MIN_CHANGE_INTERVAL",
+                "sqm-autorate.@advanced_settings[0].upload_min_change_interval",
+                Some(min_change_interval),
+            )?,
+            // Same as download_reflectors, for the upload delta
+            upload_reflectors: Self::get::<String>(
+                "SQMA_UPLOAD_REFLECTORS",
+                "sqm-autorate.@advanced_settings[0].upload_reflectors",
+                Some(String::new()),
+            )?,
+            // Add the built-in reflectors to the peers and pool alongside the user's list
+            use_default_reflectors: Self::get::<bool>(
+                "SQMA_USE_DEFAULT_REFLECTORS",
+                "sqm-autorate.@advanced_settings[0].use_default_reflectors",
+                Some(true),
+            )?,
+            utilisation_window: Self::get::<u32>(
+                "SQMA_UTILISATION_WINDOW",
+                "sqm-autorate.@advanced_settings[0].utilisation_window",
+                Some(1),
+            )?,
+            warmup_duration: Self::get::<f64>(
+                "SQMA_WARMUP_DURATION",
+                "sqm-autorate.@advanced_settings[0].warmup_duration",
+                Some(30.0),
+            )?,
+            warmup_shape: Self::get::<WarmupShape>(
+                "SQMA_WARMUP_SHAPE",
+                "sqm-autorate.@advanced_settings[0].warmup_shape",
+                Some(WarmupShape::Step),
+            )?,
+            // How many times a failed worker is restarted before giving up
+            worker_restarts: Self::get::<u32>(
+                "SQMA_WORKER_RESTARTS",
+                "sqm-autorate.@advanced_settings[0].worker_restarts",
+                Some(5),
+            )?,
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !(0.0..=1.0).contains(&self.echo_upload_share) {
+            return Err(ConfigError::OutOfRange(
+                "SQMA_ECHO_UPLOAD_SHARE".to_string(),
+                "must be between 0.0 and 1.0".to_string(),
+            ));
+        }
+
+        if self.fast_ewma_min <= 0.0 || self.fast_ewma_min > self.fast_ewma_max {
+            return Err(ConfigError::OutOfRange(
+                "SQMA_FAST_EWMA_MIN".to_string(),
+                "must be above 0 and no more than SQMA_FAST_EWMA_MAX".to_string(),
+            ));
+        }
+
+        let priority_range = match self.probe_sched_policy {
+            SchedPolicy::Other => -20..=19,
+            SchedPolicy::Fifo | SchedPolicy::RoundRobin => 1..=99,
+        };
+        if !priority_range.contains(&self.probe_priority) {
+            return Err(ConfigError::OutOfRange(
+                "SQMA_PROBE_PRIORITY".to_string(),
+                format!(
+                    "must be between {} and {} for the {:?} policy",
+                    priority_range.start(),
+                    priority_range.end(),
+                    self.probe_sched_policy
+                ),
+            ));
+        }
+
+        if !(1..=MAX_SPEED_HIST_SIZE).contains(&self.speed_hist_size) {
+            return Err(ConfigError::OutOfRange(
+                "SQMA_SPEED_HIST_SIZE".to_string(),
+                format!("must be between 1 and {}", MAX_SPEED_HIST_SIZE),
+            ));
+        }
+
+        // Everything runs off the tick, and a zero one would spin
+        if !(self.tick_interval > 0.0 && self.tick_interval.is_finite()) {
+            return Err(ConfigError::OutOfRange(
+                "SQMA_TICK_INTERVAL".to_string(),
+                "must be above 0".to_string(),
+            ));
+        }
+
+        // Below 1 would probe faster while idle rather than slower
+        if !(self.idle_probe_slowdown >= 1.0 && self.idle_probe_slowdown.is_finite()) {
+            return Err(ConfigError::OutOfRange(
+                "SQMA_IDLE_PROBE_SLOWDOWN".to_string(),
+                "must be 1 or more".to_string(),
+            ));
+        }
+
+        if !(self.max_reflector_weight > 0.0 && self.max_reflector_weight <= 1.0) {
+            return Err(ConfigError::OutOfRange(
+                "SQMA_MAX_REFLECTOR_WEIGHT".to_string(),
+                "must be above 0 and no more than 1".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Very asymmetric links are common enough (1000/50 cable, say), but a ratio far beyond
+     * that usually means a digit too many or too few in one of the base rates. That's not
+     * necessarily wrong, so only point it out.
+     */
+    pub fn check_rate_asymmetry(&self) {
+        let ratio = self.download_base_kbits.max(self.upload_base_kbits)
+            / self.download_base_kbits.min(self.upload_base_kbits);
+        info!(
+            "Base rates {}/{} kbit (D/U), asymmetry ratio {:.1}",
+            self.download_base_kbits, self.upload_base_kbits, ratio
+        );
+
+        if self.max_rate_asymmetry > 0.0 && ratio > self.max_rate_asymmetry {
+            warn!(
+                "The base rates differ by a factor of {:.1}, check SQMA_DOWNLOAD_BASE_KBITS and SQMA_UPLOAD_BASE_KBITS for a typo",
+                ratio
+            );
+        }
     }
 
     fn get<T: FromStr>(env_key: &str, uci_key: &str, default: Option<T>) -> Result<T, ConfigError> {
@@ -200,6 +1164,14 @@ impl Config {
         }
     }
 
+    // An empty value leaves the flow mode alone
+    fn get_flow_mode(env_key: &str, uci_key: &str) -> Result<Option<CakeFlowMode>, ConfigError> {
+        match Self::get::<String>(env_key, uci_key, Some(String::new()))?.as_str() {
+            "" => Ok(None),
+            mode => Ok(Some(mode.parse::<CakeFlowMode>()?)),
+        }
+    }
+
     fn get_value(env_key: &str, uci_key: &str) -> Option<String> {
         if let Ok(val) = env::var(env_key) {
             return Some(val);
@@ -212,6 +1184,81 @@ impl Config {
         None
     }
 
+    /*
+     * Without UCI there's no OpenWrt install to lean on, so all configuration comes from
+     * the SQMA_* environment variables. Defaults for files we read are then looked up in
+     * $XDG_CONFIG_HOME/sqm-autorate (or ~/.config/sqm-autorate), falling back to the
+     * working directory, and files we write go to the system temporary directory.
+     */
+    #[cfg(feature = "uci")]
+    fn default_config_path(file: &str) -> String {
+        format!("/etc/sqm-autorate/{}", file)
+    }
+
+    #[cfg(not(feature = "uci"))]
+    fn default_config_path(file: &str) -> String {
+        let config_dir = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+        match config_dir {
+            Some(dir) => dir
+                .join("sqm-autorate")
+                .join(file)
+                .to_string_lossy()
+                .into_owned(),
+            None => file.to_string(),
+        }
+    }
+
+    #[cfg(feature = "uci")]
+    fn default_output_dir() -> String {
+        "/tmp".to_string()
+    }
+
+    #[cfg(not(feature = "uci"))]
+    fn default_output_dir() -> String {
+        env::temp_dir().to_string_lossy().into_owned()
+    }
+
+    fn output_path(output_dir: &str, file: &str) -> String {
+        Path::new(output_dir)
+            .join(file)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /*
+     * Creating a file doesn't create the directory it's in, so make sure every configured
+     * output path has somewhere to go before anything tries to write there.
+     */
+    pub fn create_output_dirs(&self) -> Result<(), ConfigError> {
+        for path in [
+            &self.capacity_profile,
+            &self.control_socket,
+            &self.peer_cache_file,
+            &self.prom_textfile,
+            &self.replay_output,
+            &self.speed_hist_file,
+            &self.state_file,
+            &self.stats_fifo,
+            &self.stats_file,
+            &self.survey_report,
+        ] {
+            let dir = match Path::new(path).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => dir,
+                _ => continue,
+            };
+
+            fs::create_dir_all(dir)
+                .map_err(|e| ConfigError::CreateOutputDir(dir.to_string_lossy().into_owned(), e))?;
+            info!("Created output directory {}", dir.display());
+        }
+
+        Ok(())
+    }
+
     #[cfg(feature = "uci")]
     fn get_from_uci(key: &str) -> Option<String> {
         let mut uci = match Uci::new() {
@@ -237,23 +1284,310 @@ impl Config {
     }
 
     pub fn load_reflectors(&self) -> Result<Vec<IpAddr>> {
-        let lines = read_lines(self.reflector_list_file.clone())?;
+        /*
+         * The first row is always treated as a header, and only the first column is used.
+         * Quoted fields, CRLF line endings and lines starting with '#' are handled by the
+         * CSV reader, so lists exported from spreadsheets can be used as-is. The reader only
+         * knows about whole-line comments, so anything after a '#' further on is cut off here.
+         */
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .comment(Some(b'#'))
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_path(self.reflector_list_file.as_str())?;
 
         let mut reflectors: Vec<IpAddr> = Vec::with_capacity(50);
 
-        let mut first = true;
+        for record in reader.records() {
+            let record = record?;
+            let column = record
+                .get(0)
+                .and_then(|column| column.split('#').next())
+                .map(str::trim);
+            let reflector = match column {
+                Some(column) if !column.is_empty() => IpAddr::from_str(column)?,
+                _ => continue,
+            };
+
+            // There's no ICMPv6 counterpart to the ICMP timestamp request, so these
+            // would never produce any data. Refuse them outright rather than failing silently.
+            if reflector.is_ipv6() {
+                if let MeasurementType::IcmpTimestamps = self.measurement_type {
+                    return Err(
+                        ConfigError::Ipv6TimestampsUnsupported(reflector.to_string()).into(),
+                    );
+                }
+            }
+
+            reflectors.push(reflector);
+        }
+
+        Ok(reflectors)
+    }
 
-        for line in lines {
-            if first {
-                first = false;
+    /// The built-in reflectors, used alongside or instead of the user's list
+    pub fn default_reflectors() -> Vec<IpAddr> {
+        [
+            "9.9.9.9",
+            "8.238.120.14",
+            "74.82.42.42",
+            "194.242.2.2",
+            "208.67.222.222",
+            "94.140.14.14",
+        ]
+        .iter()
+        .map(|reflector| IpAddr::from_str(reflector).unwrap())
+        .collect()
+    }
+
+    fn parse_reflector_list(&self, list: &str) -> Result<Vec<IpAddr>> {
+        let mut reflectors: Vec<IpAddr> = Vec::new();
+
+        for column in list.split(',').map(str::trim) {
+            if column.is_empty() {
                 continue;
             }
 
-            let line = line?;
-            let columns: Vec<&str> = line.split(',').collect();
-            reflectors.push(IpAddr::from_str(columns[0])?);
+            let reflector = IpAddr::from_str(column)?;
+            if reflector.is_ipv6() {
+                if let MeasurementType::IcmpTimestamps = self.measurement_type {
+                    return Err(
+                        ConfigError::Ipv6TimestampsUnsupported(reflector.to_string()).into(),
+                    );
+                }
+            }
+
+            reflectors.push(reflector);
         }
 
         Ok(reflectors)
     }
+
+    /// Parses the comma separated list of reference reflectors, which is empty when the feature is disabled
+    pub fn load_reference_reflectors(&self) -> Result<Vec<IpAddr>> {
+        self.parse_reflector_list(self.reference_reflectors.as_str())
+    }
+
+    /// The reflectors each direction's delta is limited to, download first. Empty for every peer
+    pub fn load_direction_reflectors(&self) -> Result<(Vec<IpAddr>, Vec<IpAddr>)> {
+        Ok((
+            self.parse_reflector_list(self.download_reflectors.as_str())?,
+            self.parse_reflector_list(self.upload_reflectors.as_str())?,
+        ))
+    }
+}
+
+/*
+ * A config with only the required settings given, and everything else at its defaults.
+ * The tests adjust the fields they're about from there.
+ */
+#[cfg(test)]
+pub fn test_config() -> Config {
+    for (key, value) in [
+        ("SQMA_DOWNLOAD_INTERFACE", "ifb-test"),
+        ("SQMA_UPLOAD_INTERFACE", "eth-test"),
+        ("SQMA_DOWNLOAD_BASE_KBITS", "60000"),
+        ("SQMA_UPLOAD_BASE_KBITS", "5000"),
+        ("SQMA_DOWNLOAD_MIN_KBITS", "10000"),
+        ("SQMA_UPLOAD_MIN_KBITS", "1000"),
+    ] {
+        env::set_var(key, value);
+    }
+
+    Config::new().expect("Error creating the test config")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::capture_logs;
+
+    #[test]
+    fn speed_hist_size_out_of_range() {
+        let mut config = test_config();
+
+        for size in [0, MAX_SPEED_HIST_SIZE + 1] {
+            config.speed_hist_size = size;
+            assert!(matches!(
+                config.validate(),
+                Err(ConfigError::OutOfRange(key, _)) if key == "SQMA_SPEED_HIST_SIZE"
+            ));
+        }
+
+        config.speed_hist_size = MAX_SPEED_HIST_SIZE;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn numeric_options_out_of_range() {
+        type Setter = fn(&mut Config, f64);
+        let options: [(&str, Setter, &[f64], f64); 3] = [
+            (
+                "SQMA_TICK_INTERVAL",
+                |config, val| config.tick_interval = val,
+                &[0.0, -0.5, f64::NAN],
+                0.05,
+            ),
+            (
+                "SQMA_IDLE_PROBE_SLOWDOWN",
+                |config, val| config.idle_probe_slowdown = val,
+                &[0.0, 0.5, f64::INFINITY],
+                1.0,
+            ),
+            (
+                "SQMA_MAX_REFLECTOR_WEIGHT",
+                |config, val| config.max_reflector_weight = val,
+                &[0.0, -0.1, 1.5, f64::NAN],
+                0.25,
+            ),
+        ];
+
+        for (name, set, invalid, valid) in options {
+            let mut config = test_config();
+            for val in invalid {
+                set(&mut config, *val);
+                assert!(
+                    matches!(
+                        config.validate(),
+                        Err(ConfigError::OutOfRange(ref key, _)) if key == name
+                    ),
+                    "{} = {}",
+                    name,
+                    val
+                );
+            }
+
+            set(&mut config, valid);
+            assert!(config.validate().is_ok(), "{} = {}", name, valid);
+        }
+    }
+
+    #[test]
+    fn reflector_list_parsing() {
+        let path = env::temp_dir().join(format!("sqma-reflectors-{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "ip,comment\r\n\
+             # Whole-line comment\r\n\
+             \r\n\
+             \"9.9.9.9\",\"Quad9, anycast\"\r\n\
+             1.1.1.1 # Cloudflare\r\n\
+             \x20 # Indented comment\r\n\
+             2001:db8::1\r\n",
+        )
+        .unwrap();
+
+        let mut config = test_config();
+        config.reflector_list_file = path.to_string_lossy().into_owned();
+        config.measurement_type = MeasurementType::Icmp;
+        let reflectors = config.load_reflectors();
+        fs::remove_file(&path).unwrap();
+
+        let expected: Vec<IpAddr> = ["9.9.9.9", "1.1.1.1", "2001:db8::1"]
+            .iter()
+            .map(|reflector| reflector.parse().unwrap())
+            .collect();
+        assert_eq!(reflectors.unwrap(), expected);
+    }
+
+    #[test]
+    fn nested_output_path_is_created() {
+        let base = env::temp_dir().join(format!("sqma-output-{}", std::process::id()));
+        let mut config = test_config();
+        config.stats_file = base
+            .join("nested/deeper/stats.csv")
+            .to_string_lossy()
+            .into_owned();
+
+        config.create_output_dirs().unwrap();
+        assert!(base.join("nested/deeper").is_dir());
+
+        // Somewhere a directory can't go is an error naming it
+        fs::write(base.join("file"), "").unwrap();
+        config.stats_file = base.join("file/stats.csv").to_string_lossy().into_owned();
+        assert!(matches!(
+            config.create_output_dirs(),
+            Err(ConfigError::CreateOutputDir(dir, _)) if dir == base.join("file").to_string_lossy()
+        ));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn extreme_asymmetry_warns() {
+        let mut config = test_config();
+        config.max_rate_asymmetry = 50.0;
+
+        // 1000/50 cable is fine, an extra digit on the download isn't
+        config.download_base_kbits = 1_000_000.0;
+        config.upload_base_kbits = 50_000.0;
+        let logs = capture_logs(|| config.check_rate_asymmetry());
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].starts_with("INFO"));
+
+        config.download_base_kbits = 10_000_000.0;
+        let logs = capture_logs(|| config.check_rate_asymmetry());
+        assert_eq!(logs.len(), 2);
+        assert!(logs[1].starts_with("WARN The base rates differ by a factor of 200.0"));
+    }
+
+    #[test]
+    fn ipv6_rejected_with_timestamps() {
+        let mut config = test_config();
+        config.reference_reflectors = "9.9.9.9, 2001:db8::1".to_string();
+
+        config.measurement_type = MeasurementType::IcmpTimestamps;
+        assert!(matches!(
+            config
+                .load_reference_reflectors()
+                .unwrap_err()
+                .downcast_ref::<ConfigError>(),
+            Some(ConfigError::Ipv6TimestampsUnsupported(reflector)) if reflector == "2001:db8::1"
+        ));
+
+        config.measurement_type = MeasurementType::Icmp;
+        assert_eq!(config.load_reference_reflectors().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn dual_flow_mode_suits_each_direction() {
+        for mode in [CakeFlowMode::DualSrcHost, CakeFlowMode::DualDstHost] {
+            assert_eq!(mode.for_download(), CakeFlowMode::DualDstHost);
+            assert_eq!(mode.for_upload(), CakeFlowMode::DualSrcHost);
+        }
+
+        let mode = CakeFlowMode::TripleIsolate;
+        assert_eq!((mode.for_download(), mode.for_upload()), (mode, mode));
+    }
+
+    #[test]
+    fn rebaseline_duration_defaults_from_the_tick() {
+        let config = test_config();
+        assert_eq!(
+            config.rebaseline_duration,
+            config.tick_interval * std::f64::consts::PI
+        );
+    }
+
+    #[cfg(not(feature = "uci"))]
+    #[test]
+    fn default_paths_without_uci() {
+        let config = test_config();
+        let temp_dir = env::temp_dir();
+
+        // Output goes to the temporary directory, and the reflectors are looked for with the user's config
+        assert_eq!(
+            Path::new(&config.stats_file),
+            temp_dir.join("sqm-autorate.csv")
+        );
+        assert_eq!(
+            Path::new(&config.speed_hist_file),
+            temp_dir.join("sqm-speedhist.csv")
+        );
+        assert!(
+            Path::new(&Config::default_config_path("reflectors-icmp.csv"))
+                .ends_with("sqm-autorate/reflectors-icmp.csv")
+        );
+    }
 }
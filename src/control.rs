@@ -0,0 +1,207 @@
+use crate::baseliner::reflector_snapshot;
+use crate::metrics::SafeRates;
+use crate::{Config, ReflectorStats};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{AddrParseError, IpAddr};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+
+/*
+ * Clients are handled one at a time, so one that goes quiet for this long is dropped rather
+ * than holding up everyone else. It's long enough to type a command in by hand.
+ */
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum ControlError {
+    #[error("Invalid IP address")]
+    InvalidAddress(#[from] AddrParseError),
+    #[error("Missing argument for command `{0}`")]
+    MissingArgument(String),
+    #[error("Unknown command `{0}`")]
+    UnknownCommand(String),
+}
+
+/*
+ * A line-based control socket for poking at the daemon while it's running.
+ * Each line received is one command. The response is zero or more lines of output,
+ * followed by a status line of either "OK ..." or "ERR ...".
+ * Nothing changed through here is persisted.
+ */
+pub struct ControlSocket {
+    pub config: Config,
+    pub disabled_reflectors: Arc<RwLock<HashSet<IpAddr>>>,
+    pub owd_baseline: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub owd_recent: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub reflector_peers_lock: Arc<RwLock<Vec<IpAddr>>>,
+    pub safe_rates: Arc<Mutex<SafeRates>>,
+}
+
+impl ControlSocket {
+    fn disable_reflector(&self, reflector: IpAddr) -> String {
+        self.disabled_reflectors.write().unwrap().insert(reflector);
+
+        // Take it out of the active set right away rather than waiting for the next reselection
+        let mut peers = self.reflector_peers_lock.write().unwrap();
+        peers.retain(|peer| *peer != reflector);
+
+        info!("Reflector {} disabled via control socket", reflector);
+        format!("OK disabled {}", reflector)
+    }
+
+    fn enable_reflector(&self, reflector: IpAddr) -> String {
+        self.disabled_reflectors.write().unwrap().remove(&reflector);
+
+        info!("Reflector {} enabled via control socket", reflector);
+        format!("OK enabled {}", reflector)
+    }
+
+    fn show_reflectors(&self) -> String {
+        let snapshot = reflector_snapshot(
+            &self.owd_baseline,
+            &self.owd_recent,
+            &self.reflector_peers_lock,
+            self.config.tick_interval * 2.0,
+        );
+
+        let mut out = String::new();
+        for entry in snapshot.iter() {
+            let _ = writeln!(
+                out,
+                "{} active={} reachable={} age={:.1}s baseline_down={:.1} baseline_up={:.1} recent_down={:.1} recent_up={:.1} jitter={:.1}",
+                entry.reflector,
+                entry.active,
+                entry.reachable,
+                entry.last_receive_age_s,
+                entry.baseline_down_ms,
+                entry.baseline_up_ms,
+                entry.recent_down_ms,
+                entry.recent_up_ms,
+                entry.jitter_ms
+            );
+        }
+        let _ = write!(out, "OK {} reflectors", snapshot.len());
+
+        out
+    }
+
+    fn show_safe_rates(&self) -> String {
+        let safe_rates = self.safe_rates.lock().unwrap().clone();
+
+        let mut out = String::new();
+        for (direction, rates, index) in [
+            ("down", &safe_rates.download, safe_rates.download_index),
+            ("up", &safe_rates.upload, safe_rates.upload_index),
+        ] {
+            let rates: Vec<String> = rates.iter().map(|rate| rate.to_string()).collect();
+            let _ = writeln!(
+                out,
+                "{} next={} rates={}",
+                direction,
+                index,
+                rates.join(",")
+            );
+        }
+        let _ = write!(out, "OK {} slots", safe_rates.download.len());
+
+        out
+    }
+
+    fn handle_command(&self, line: &str) -> Result<String, ControlError> {
+        let mut parts = line.split_whitespace();
+        let command = parts.next().unwrap_or_default();
+        let mut argument = || {
+            parts
+                .next()
+                .ok_or_else(|| ControlError::MissingArgument(command.to_string()))
+        };
+
+        match command {
+            "disable-reflector" => Ok(self.disable_reflector(IpAddr::from_str(argument()?)?)),
+            "enable-reflector" => Ok(self.enable_reflector(IpAddr::from_str(argument()?)?)),
+            "show-reflectors" => Ok(self.show_reflectors()),
+            "show-safe-rates" => Ok(self.show_safe_rates()),
+            _ => Err(ControlError::UnknownCommand(command.to_string())),
+        }
+    }
+
+    fn handle_client(&self, stream: UnixStream) -> anyhow::Result<()> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+
+        for line in reader.lines() {
+            let line = line?;
+            let response = match self.handle_command(line.trim()) {
+                Ok(val) => val,
+                Err(e) => format!("ERR {}", e),
+            };
+            writeln!(writer, "{}", response)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        // A socket file left behind by a previous run would make bind fail
+        let _ = fs::remove_file(self.config.control_socket.as_str());
+        let listener = UnixListener::bind(self.config.control_socket.as_str())?;
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(val) => val,
+                Err(e) => {
+                    warn!("Failed to accept control socket connection: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.handle_client(stream) {
+                warn!("Error handling control socket client: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn control_socket() -> ControlSocket {
+        ControlSocket {
+            config: test_config(),
+            disabled_reflectors: Arc::new(RwLock::new(HashSet::new())),
+            owd_baseline: Arc::new(Mutex::new(HashMap::new())),
+            owd_recent: Arc::new(Mutex::new(HashMap::new())),
+            reflector_peers_lock: Arc::new(RwLock::new(Vec::new())),
+            safe_rates: Arc::new(Mutex::new(SafeRates::default())),
+        }
+    }
+
+    #[test]
+    fn safe_rates_are_shown() {
+        let control = control_socket();
+        *control.safe_rates.lock().unwrap() = SafeRates {
+            download: vec![50000.0, 52000.0, 0.0],
+            download_index: 2,
+            upload: vec![4000.0, 4100.0, 0.0],
+            upload_index: 1,
+        };
+
+        assert_eq!(
+            control.handle_command("show-safe-rates").unwrap(),
+            "down next=2 rates=50000,52000,0\nup next=1 rates=4000,4100,0\nOK 3 slots"
+        );
+    }
+}
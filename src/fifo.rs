@@ -0,0 +1,110 @@
+use log::{info, warn};
+use rustix::fs::{cwd, mknodat, FileType, Mode, OFlags};
+use rustix::io::Errno;
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/*
+ * Writes lines to a named pipe for whatever might be reading it, without ever waiting for a
+ * reader. Opening the write end with O_NONBLOCK fails with ENXIO while nobody has the
+ * pipe open for reading, and writes fail with EAGAIN instead of blocking once the pipe is
+ * full. Either way the line is dropped, and opening is retried with the next one.
+ */
+pub struct StatsFifo {
+    path: String,
+    file: Option<File>,
+}
+
+impl StatsFifo {
+    pub fn new(path: &str) -> io::Result<Self> {
+        if !Path::new(path).exists() {
+            mknodat(
+                cwd(),
+                path,
+                FileType::Fifo,
+                Mode::RUSR | Mode::WUSR | Mode::RGRP | Mode::ROTH,
+                0,
+            )?;
+            info!("Created stats FIFO at {}", path);
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            file: None,
+        })
+    }
+
+    fn open(&self) -> io::Result<Option<File>> {
+        match File::options()
+            .write(true)
+            .custom_flags(OFlags::NONBLOCK.bits() as i32)
+            .open(self.path.as_str())
+        {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.raw_os_error() == Some(Errno::NXIO.raw_os_error()) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn send(&mut self, line: &str) {
+        if self.file.is_none() {
+            match self.open() {
+                Ok(file) => self.file = file,
+                Err(e) => {
+                    warn!("Failed to open stats FIFO {}: {}", self.path, e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(file) = self.file.as_mut() {
+            match file.write(line.as_bytes()) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                // The reader went away, reopen once there's a new one
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => self.file = None,
+                Err(e) => {
+                    warn!("Failed to write to stats FIFO {}: {}", self.path, e);
+                    self.file = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::{env, fs};
+
+    #[test]
+    fn writes_never_wait_for_a_reader() {
+        let path = env::temp_dir().join(format!("sqma-fifo-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut fifo = StatsFifo::new(path).unwrap();
+
+        // Nobody's reading, so the line is dropped rather than waiting for someone to
+        fifo.send("dropped\n");
+        assert!(fifo.file.is_none());
+
+        let mut reader = File::options()
+            .read(true)
+            .custom_flags(OFlags::NONBLOCK.bits() as i32)
+            .open(path)
+            .unwrap();
+        fifo.send("1,2\n");
+        // Nor does a reader that's fallen behind hold things up once the pipe is full
+        let line = "x".repeat(1024);
+        for _ in 0..1024 {
+            fifo.send(&line);
+        }
+
+        let mut received = vec![0; 4];
+        reader.read_exact(&mut received).unwrap();
+        assert_eq!(received, b"1,2\n");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+use crate::Config;
+use etherparse::TransportSlice::Icmpv4;
+use etherparse::{IcmpEchoHeader, Icmpv4Header, Icmpv4Type, Ipv4Header, SlicedPacket};
+use log::debug;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/*
+ * The probes carry a different identifier from the regular ones, so that the main listener,
+ * which sees every ICMP packet too, can never take a reply to one of them for its own.
+ */
+const FIRST_HOP_ID_FLIP: u16 = 0x8000;
+
+/// The latest delay to the first hop, and which router that is. No gateway while it isn't answering
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FirstHopMetrics {
+    pub delay_ms: f64,
+    pub gateway: Option<IpAddr>,
+}
+
+/*
+ * A Time Exceeded message carries the IP header of the packet that expired, followed by
+ * at least the first 8 bytes of what it carried, which for our probes is the whole echo
+ * request header. Returns the router that sent it, and the sequence number of the probe,
+ * if it's one of ours to the given target.
+ */
+fn parse_time_exceeded(id: u16, target: Ipv4Addr, buf: &[u8]) -> Option<(IpAddr, u16)> {
+    let packet = SlicedPacket::from_ip(buf).ok()?;
+    let router = match packet.ip {
+        Some(etherparse::InternetSlice::Ipv4(ip, _)) => IpAddr::V4(ip.source_addr()),
+        _ => return None,
+    };
+    let icmp = match packet.transport {
+        Some(Icmpv4(icmp)) => icmp,
+        _ => return None,
+    };
+    if !matches!(icmp.icmp_type(), Icmpv4Type::TimeExceeded(_)) {
+        return None;
+    }
+
+    let (original_ip, original_payload) = Ipv4Header::from_slice(icmp.payload()).ok()?;
+    if Ipv4Addr::from(original_ip.destination) != target {
+        return None;
+    }
+
+    let (original_icmp, _) = Icmpv4Header::from_slice(original_payload).ok()?;
+    match original_icmp.icmp_type {
+        Icmpv4Type::EchoRequest(echo) if echo.id == id => Some((router, echo.seq)),
+        _ => None,
+    }
+}
+
+/*
+ * Sends echo requests with a TTL of 1 towards one of the reflectors, which the first router
+ * on the way answers with Time Exceeded. The delay to it is the part of the path that's
+ * ours, so comparing it to the delay to the reflectors tells whether bloat is on our link
+ * or further upstream. This is only reported, it doesn't feed into the rate control.
+ */
+pub struct FirstHopProber {
+    pub config: Config,
+    pub id: u16,
+    pub metrics: Arc<Mutex<FirstHopMetrics>>,
+    pub reflector_peers_lock: Arc<RwLock<Vec<IpAddr>>>,
+}
+
+impl FirstHopProber {
+    // Waits for the Time Exceeded for this probe, until the deadline
+    fn wait_reply(
+        &self,
+        socket: &Socket,
+        target: Ipv4Addr,
+        seq: u16,
+        deadline_t: Instant,
+    ) -> io::Result<Option<IpAddr>> {
+        let id = self.id ^ FIRST_HOP_ID_FLIP;
+        let mut buffer = [MaybeUninit::<u8>::uninit(); 1500];
+
+        loop {
+            let now_t = Instant::now();
+            if now_t >= deadline_t {
+                return Ok(None);
+            }
+            socket.set_read_timeout(Some(deadline_t - now_t))?;
+
+            let received = match socket.recv(&mut buffer) {
+                Ok(val) => val,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            // recv() initialized this much of the buffer
+            let buf = unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, received) };
+
+            match parse_time_exceeded(id, target, buf) {
+                Some((router, reply_seq)) if reply_seq == seq => return Ok(Some(router)),
+                _ => continue,
+            }
+        }
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let interval = Duration::from_secs_f64(self.config.first_hop_interval);
+        let id = self.id ^ FIRST_HOP_ID_FLIP;
+        let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
+        socket.set_ttl(1)?;
+
+        let mut seq: u16 = 0;
+
+        loop {
+            let start_t = Instant::now();
+
+            // Any reflector will do, the probes never get past the first hop
+            let target = self
+                .reflector_peers_lock
+                .read()
+                .unwrap()
+                .iter()
+                .find_map(|reflector| match reflector {
+                    IpAddr::V4(addr) => Some(*addr),
+                    IpAddr::V6(_) => None,
+                });
+
+            if let Some(target) = target {
+                seq = seq.wrapping_add(1);
+                let hdr = Icmpv4Header::with_checksum(
+                    Icmpv4Type::EchoRequest(IcmpEchoHeader { id, seq }),
+                    &[],
+                );
+                let mut packet = Vec::<u8>::with_capacity(hdr.header_len());
+                hdr.write(&mut packet).expect("Error writing packet");
+
+                let sent_t = Instant::now();
+                socket.send_to(&packet, &SocketAddrV4::new(target, 0).into())?;
+
+                let reply = self.wait_reply(&socket, target, seq, sent_t + interval)?;
+                let mut metrics = self.metrics.lock().unwrap();
+                match reply {
+                    Some(router) => {
+                        metrics.delay_ms = sent_t.elapsed().as_secs_f64() * 1000.0;
+                        metrics.gateway = Some(router);
+                        debug!("First hop {} delay: {} ms", router, metrics.delay_ms);
+                    }
+                    None => {
+                        debug!("No Time Exceeded from the first hop towards {}", target);
+                        *metrics = FirstHopMetrics::default();
+                    }
+                }
+            }
+
+            if let Some(remaining) = interval.checked_sub(start_t.elapsed()) {
+                sleep(remaining);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::icmpv4::TimeExceededCode;
+    use etherparse::IpNumber;
+
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+    const TARGET: Ipv4Addr = Ipv4Addr::new(9, 9, 9, 9);
+
+    // What the router sends back for an expired probe: its IP header and the start of ours
+    fn time_exceeded(id: u16, seq: u16, target: Ipv4Addr) -> Vec<u8> {
+        let probe =
+            Icmpv4Header::with_checksum(Icmpv4Type::EchoRequest(IcmpEchoHeader { id, seq }), &[]);
+        let mut original = Vec::new();
+        Ipv4Header::new(
+            probe.header_len() as u16,
+            0,
+            IpNumber::Icmp as u8,
+            [192, 168, 1, 2],
+            target.octets(),
+        )
+        .write(&mut original)
+        .unwrap();
+        probe.write(&mut original).unwrap();
+
+        let icmp = Icmpv4Header::with_checksum(
+            Icmpv4Type::TimeExceeded(TimeExceededCode::TtlExceededInTransit),
+            &original,
+        );
+        let mut packet = Vec::new();
+        Ipv4Header::new(
+            (icmp.header_len() + original.len()) as u16,
+            64,
+            IpNumber::Icmp as u8,
+            ROUTER.octets(),
+            [192, 168, 1, 2],
+        )
+        .write(&mut packet)
+        .unwrap();
+        icmp.write(&mut packet).unwrap();
+        packet.extend_from_slice(&original);
+        packet
+    }
+
+    #[test]
+    fn time_exceeded_is_matched_to_the_probe() {
+        let id = 1234 ^ FIRST_HOP_ID_FLIP;
+
+        assert_eq!(
+            parse_time_exceeded(id, TARGET, &time_exceeded(id, 7, TARGET)),
+            Some((IpAddr::V4(ROUTER), 7))
+        );
+        // A regular probe expiring on the way, or one of ours towards another target
+        assert_eq!(
+            parse_time_exceeded(id, TARGET, &time_exceeded(1234, 7, TARGET)),
+            None
+        );
+        assert_eq!(
+            parse_time_exceeded(id, TARGET, &time_exceeded(id, 7, Ipv4Addr::new(1, 1, 1, 1))),
+            None
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use std::fmt::Write as _;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/*
+ * Minimal client for journald's native protocol: one datagram per entry, made up of
+ * KEY=value lines. That's enough for the numeric stats we send, values containing
+ * newlines would need the protocol's binary encoding instead.
+ *
+ * Field names must be upper case letters, digits and underscores, and must not start
+ * with an underscore (those are reserved for trusted fields added by journald itself).
+ * All of ours are prefixed with SQMA_, so they can be queried with e.g.
+ * `journalctl SYSLOG_IDENTIFIER=sqm-autorate -o json` or filtered on fields like `SQMA_DL_RATE_KBIT`.
+ */
+pub struct JournalWriter {
+    socket: UnixDatagram,
+}
+
+impl JournalWriter {
+    pub fn new() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+
+        Ok(Self { socket })
+    }
+
+    pub fn send(&self, message: &str, fields: &[(&str, f64)]) -> io::Result<()> {
+        let mut entry = String::new();
+        let _ = writeln!(entry, "MESSAGE={}", message);
+        // LOG_INFO
+        let _ = writeln!(entry, "PRIORITY=6");
+        let _ = writeln!(entry, "SYSLOG_IDENTIFIER=sqm-autorate");
+
+        for (name, value) in fields {
+            let _ = writeln!(entry, "SQMA_{}={}", name, value);
+        }
+
+        self.socket.send(entry.as_bytes())?;
+        Ok(())
+    }
+}
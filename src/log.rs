@@ -1,26 +1,76 @@
-use std::time::SystemTime;
+use std::fmt::Arguments;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
 use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
 use time::format_description::FormatItem;
-use time::formatting::Formattable;
 use time::macros::format_description;
-use time::OffsetDateTime;
+
+use crate::time::time_format;
 
 const LOG_DATETIME_FORMAT: &[FormatItem] = format_description!(
     "[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour \
          sign:mandatory]:[offset_minute]:[offset_second]"
 );
 
-#[derive(Clone, Copy)]
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+
+/*
+ * Counts the messages logged in the current one second window. Once the limit is hit,
+ * the rest of the window's messages are dropped, and how many were is logged along
+ * with the first message of a later window.
+ */
+struct RateLimit {
+    logged: u32,
+    suppressed: u64,
+    window_start_t: Instant,
+}
+
 pub struct SimpleLogger {
     pub level: Level,
+    // Messages per second, 0 for no limit
+    pub rate_limit: u32,
+    rate_state: Mutex<RateLimit>,
 }
 
-fn time_format<T>(dt: T, format: &(impl Formattable + ?Sized)) -> String
-where
-    T: Into<OffsetDateTime>,
-{
-    dt.into().format(format).unwrap()
+impl SimpleLogger {
+    fn write(level: Level, location: (&str, u32), args: &Arguments) {
+        // Formatted up front, as a failure to do so is logged from in there
+        let timestamp = time_format(SystemTime::now(), &LOG_DATETIME_FORMAT);
+        println!(
+            "{} {:5} {}:{}: {}",
+            timestamp, level, location.0, location.1, args
+        );
+    }
+
+    /*
+     * Whether the message fits within the rate limit, and how many messages were dropped in
+     * the window before, if this is the first one of a new window. The count is reported by
+     * the caller once the lock is released, as writing can log a warning of its own.
+     */
+    fn admit(&self, now_t: Instant) -> (bool, u64) {
+        if self.rate_limit == 0 {
+            return (true, 0);
+        }
+
+        let mut state = self.rate_state.lock().unwrap();
+        let mut suppressed = 0;
+
+        if now_t.duration_since(state.window_start_t) >= RATE_LIMIT_WINDOW {
+            suppressed = state.suppressed;
+            state.logged = 0;
+            state.suppressed = 0;
+            state.window_start_t = now_t;
+        }
+
+        if state.logged >= self.rate_limit {
+            state.suppressed += 1;
+            return (false, suppressed);
+        }
+
+        state.logged += 1;
+        (true, suppressed)
+    }
 }
 
 impl log::Log for SimpleLogger {
@@ -29,14 +79,26 @@ impl log::Log for SimpleLogger {
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            println!(
-                "{} {:5} {}:{}: {}",
-                time_format(SystemTime::now(), &LOG_DATETIME_FORMAT),
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let (admitted, suppressed) = self.admit(Instant::now());
+        if suppressed > 0 {
+            Self::write(
+                Level::Warn,
+                (file!(), line!()),
+                &format_args!(
+                    "{} log messages suppressed, more than {} per second",
+                    suppressed, self.rate_limit
+                ),
+            );
+        }
+        if admitted {
+            Self::write(
                 record.level(),
-                record.file().unwrap(),
-                record.line().unwrap(),
-                record.args()
+                (record.file().unwrap(), record.line().unwrap()),
+                record.args(),
             );
         }
     }
@@ -44,7 +106,95 @@ impl log::Log for SimpleLogger {
     fn flush(&self) {}
 }
 
-pub fn init(level: Level) -> Result<(), SetLoggerError> {
-    log::set_boxed_logger(Box::new(SimpleLogger { level }))
-        .map(|()| log::set_max_level(LevelFilter::Trace))
+pub fn init(level: Level, rate_limit: u32) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(SimpleLogger {
+        level,
+        rate_limit,
+        rate_state: Mutex::new(RateLimit {
+            logged: 0,
+            suppressed: 0,
+            window_start_t: Instant::now(),
+        }),
+    }))
+    .map(|()| log::set_max_level(LevelFilter::Trace))
+}
+
+/*
+ * Runs f and returns what it logged, as "LEVEL message" lines. Only the calling thread's
+ * messages are kept, so tests running in parallel don't see each other's.
+ */
+#[cfg(test)]
+pub fn capture_logs(f: impl FnOnce()) -> Vec<String> {
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    thread_local! {
+        static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    }
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            CAPTURED.with(|captured| {
+                if let Some(lines) = captured.borrow_mut().as_mut() {
+                    lines.push(format!("{} {}", record.level(), record.args()));
+                }
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&CaptureLogger).expect("Error setting the test logger");
+        log::set_max_level(LevelFilter::Trace);
+    });
+
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    f();
+    CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logger(rate_limit: u32, start_t: Instant) -> SimpleLogger {
+        SimpleLogger {
+            level: Level::Debug,
+            rate_limit,
+            rate_state: Mutex::new(RateLimit {
+                logged: 0,
+                suppressed: 0,
+                window_start_t: start_t,
+            }),
+        }
+    }
+
+    #[test]
+    fn burst_is_coalesced() {
+        let start_t = Instant::now();
+        let logger = logger(2, start_t);
+
+        let admitted: Vec<bool> = (0..5).map(|_| logger.admit(start_t).0).collect();
+        assert_eq!(admitted, [true, true, false, false, false]);
+
+        // The first message of the next window goes through, and reports the dropped ones
+        assert_eq!(logger.admit(start_t + RATE_LIMIT_WINDOW), (true, 3));
+        assert_eq!(logger.admit(start_t + RATE_LIMIT_WINDOW), (true, 0));
+    }
+
+    #[test]
+    fn no_limit() {
+        let start_t = Instant::now();
+        let logger = logger(0, start_t);
+
+        assert!((0..100).all(|_| logger.admit(start_t) == (true, 0)));
+    }
 }
@@ -1,107 +1,504 @@
 extern crate core;
 
 mod baseliner;
+mod capabilities;
+mod capacity_profile;
 mod config;
+mod control;
 mod endian;
+mod fifo;
+mod first_hop;
+#[cfg(feature = "systemd")]
+mod journal;
 mod log;
+mod metrics;
+#[cfg(feature = "metrics-http")]
+mod metrics_http;
 mod netlink;
+mod peer_cache;
 mod pinger;
 mod pinger_icmp;
 mod pinger_icmp_ts;
+mod pinger_ntp;
+mod pinger_tcp_ts;
+mod random;
 mod ratecontroller;
 mod reflector_selector;
+#[cfg(feature = "reload")]
+mod reflector_watcher;
+mod replay;
+mod sched;
+mod self_test;
+mod state_file;
+mod survey;
 mod time;
 
 use crate::baseliner::{Baseliner, ReflectorStats};
-use ::log::{debug, info};
-use std::collections::HashMap;
+use ::log::{debug, info, warn};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
-use std::str::FromStr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread::sleep;
+use std::thread::JoinHandle;
 use std::time::Duration;
 use std::time::Instant;
-use std::{process, thread};
+use std::{io, process, thread};
 
 use crate::config::{Config, MeasurementType};
+use crate::control::ControlSocket;
+use crate::first_hop::{FirstHopMetrics, FirstHopProber};
+use crate::metrics::{Counters, MetricsTextfileWriter, RateMetrics, SafeRates, SummaryLogger};
 use crate::netlink::Netlink;
-use crate::pinger::{PingListener, PingSender};
+use crate::peer_cache::PeerCache;
+use crate::pinger::{send_and_listen, IdleProbing, LossTracking, PingListener, PingSender};
 use crate::pinger_icmp::{PingerICMPEchoListener, PingerICMPEchoSender};
 use crate::pinger_icmp_ts::{PingerICMPTimestampListener, PingerICMPTimestampSender};
-use crate::ratecontroller::{Ratecontroller, StatsDirection};
+use crate::pinger_ntp::{PingerNTPListener, PingerNTPSender};
+use crate::pinger_tcp_ts::{PingerTCPTimestampListener, PingerTCPTimestampSender};
+use crate::random::{new_rng, RngStream};
+use crate::ratecontroller::{resume_rate, shaper_rate, Ratecontroller, StatsDirection};
 use crate::reflector_selector::ReflectorSelector;
+use crate::replay::Replay;
+use crate::self_test::SelfTest;
+use crate::state_file::StateFileWriter;
+use crate::survey::ReflectorSurvey;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Gives whatever made a worker fail a moment to clear up before it is restarted
+#[cfg(not(test))]
+const WORKER_RESTART_DELAY: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const WORKER_RESTART_DELAY: Duration = Duration::from_millis(10);
+// How often the main thread checks for a signal to shut down, or a worker that gave up
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+fn create_pingers(config: &Config) -> (Box<dyn PingListener + Send>, Box<dyn PingSender + Send>) {
+    match config.measurement_type {
+        MeasurementType::Icmp => (
+            Box::new(PingerICMPEchoListener {
+                clock: config.probe_clock.clock_id(),
+                match_payload: config.echo_match_payload,
+                upload_share: config.echo_upload_share,
+            }) as Box<dyn PingListener + Send>,
+            Box::new(PingerICMPEchoSender {
+                clock: config.probe_clock.clock_id(),
+                embed_reflector: config.echo_match_payload,
+            }) as Box<dyn PingSender + Send>,
+        ),
+        MeasurementType::IcmpTimestamps => (
+            Box::new(PingerICMPTimestampListener {}) as Box<dyn PingListener + Send>,
+            Box::new(PingerICMPTimestampSender {}) as Box<dyn PingSender + Send>,
+        ),
+        MeasurementType::Ntp => (
+            Box::new(PingerNTPListener {}) as Box<dyn PingListener + Send>,
+            Box::new(PingerNTPSender {}) as Box<dyn PingSender + Send>,
+        ),
+        MeasurementType::TcpTimestamps => (
+            Box::new(PingerTCPTimestampListener {
+                clock: config.probe_clock.clock_id(),
+                port: config.tcp_probe_port,
+                upload_share: config.echo_upload_share,
+            }) as Box<dyn PingListener + Send>,
+            Box::new(PingerTCPTimestampSender {
+                clock: config.probe_clock.clock_id(),
+                port: config.tcp_probe_port,
+                sources: Mutex::default(),
+            }) as Box<dyn PingSender + Send>,
+        ),
+    }
+}
+
+/*
+ * Measurement-only mode: probe every reflector in the list with the usual pinger and
+ * baseliner, and periodically write a ranked report. No qdiscs are touched.
+ */
+fn run_survey(config: Config) -> anyhow::Result<()> {
+    let reflectors = config.load_reflectors()?;
+    info!(
+        "Surveying {} reflectors, writing report to {}",
+        reflectors.len(),
+        config.survey_report
+    );
+
+    let id = (process::id() & 0xFFFF) as u16;
+    let owd_baseline = Arc::new(Mutex::new(HashMap::<IpAddr, ReflectorStats>::new()));
+    let owd_recent = Arc::new(Mutex::new(HashMap::<IpAddr, ReflectorStats>::new()));
+    let counters = Arc::new(Counters::default());
+    let next_seq = Arc::new(AtomicU16::new(0));
+    let reflector_peers_lock = Arc::new(RwLock::new(reflectors));
+
+    let (baseliner_stats_sender, baseliner_stats_receiver) = channel();
+    // Nothing listens for reselection requests here, the baseliner ignores send errors
+    let (reselect_sender, _) = channel();
+    let (mut pinger_receiver, mut pinger_sender) = create_pingers(&config);
+
+    let baseliner = Baseliner {
+        config: config.clone(),
+        counters: counters.clone(),
+        owd_baseline: owd_baseline.clone(),
+        owd_recent: owd_recent.clone(),
+        reflector_peers_lock: reflector_peers_lock.clone(),
+        reselect_trigger: reselect_sender,
+        start_time: Instant::now(),
+        stats_receiver: baseliner_stats_receiver,
+    };
+    let survey = ReflectorSurvey {
+        config: config.clone(),
+        owd_baseline,
+        owd_recent,
+        reflector_peers_lock: reflector_peers_lock.clone(),
+    };
+
+    let reflector_peers_lock_clone = reflector_peers_lock.clone();
+    let next_seq_clone = next_seq.clone();
+    let parse_error_log_rate = config.parse_error_log_rate;
+    let log_per_packet = config.log_per_packet;
+    let measurement_type = config.measurement_type;
+    let receiver_handle = thread::Builder::new().name("receiver".to_string()).spawn(
+        move || -> anyhow::Result<()> {
+            pinger_receiver.listen(
+                id,
+                measurement_type,
+                reflector_peers_lock_clone,
+                Vec::new(),
+                Duration::from_secs_f64(config.tick_interval),
+                baseliner_stats_sender,
+                next_seq_clone,
+                counters,
+                parse_error_log_rate,
+                log_per_packet,
+                LossTracking::default(),
+                // The survey runs until it's killed
+                Arc::default(),
+            )
+        },
+    )?;
+    let baseliner_handle = thread::Builder::new()
+        .name("baseliner".to_string())
+        .spawn(move || -> anyhow::Result<()> { baseliner.run() })?;
+    let sender_handle = thread::Builder::new().name("sender".to_string()).spawn(
+        move || -> anyhow::Result<()> {
+            pinger_sender.send(
+                id,
+                measurement_type,
+                reflector_peers_lock,
+                Duration::from_secs_f64(config.tick_interval),
+                config.max_active_probes,
+                next_seq,
+                IdleProbing::default(),
+                LossTracking::default(),
+                Arc::default(),
+            )
+        },
+    )?;
+    let survey_handle = thread::Builder::new()
+        .name("survey".to_string())
+        .spawn(move || survey.run())?;
+
+    for thread in [
+        receiver_handle,
+        sender_handle,
+        baseliner_handle,
+        survey_handle,
+    ] {
+        thread.join().expect("Error happened in thread")?;
+    }
+
+    Ok(())
+}
+
+/*
+ * The reflector pool and the initial peers. The user's reflectors always make it into the
+ * pool, and a random handful of them into the initial peers. The built-in defaults are
+ * added to the pool too unless turned off, or used on their own if the list is empty.
+ * They only make it into the initial peers when the user's list is too short to pick
+ * num_reflectors from.
+ */
+fn initial_peers(
+    config: &Config,
+    reflectors: &[IpAddr],
+    rng: &mut impl Rng,
+) -> (Vec<IpAddr>, Vec<IpAddr>) {
+    let mut pool = reflectors.to_vec();
+    let mut peers: Vec<IpAddr> = reflectors
+        .choose_multiple(rng, config.num_reflectors as usize)
+        .copied()
+        .collect();
+
+    if config.use_default_reflectors || reflectors.is_empty() {
+        let defaults: Vec<IpAddr> = Config::default_reflectors()
+            .into_iter()
+            .filter(|reflector| !pool.contains(reflector))
+            .collect();
+        let missing = (config.num_reflectors as usize).saturating_sub(peers.len());
+        peers.extend(defaults.choose_multiple(rng, missing));
+        pool.extend(defaults);
+    }
+
+    (pool, peers)
+}
+
+// The message a worker panicked with, as an error
+fn panicked(payload: Box<dyn Any + Send>) -> anyhow::Error {
+    let message = match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "unknown panic".to_string()),
+    };
+    anyhow::anyhow!("panicked: {}", message)
+}
+
+/*
+ * Runs a worker on its own thread, and if it fails, logs the error and starts it over
+ * with a fresh instance, up to `restarts` times over the lifetime of the process.
+ * Only for workers that can be rebuilt from the shared state, ones that own the
+ * receiving end of a channel can't be recreated without their peers noticing.
+ *
+ * A panic counts as a failure too, though release builds abort on panic, so there it
+ * still takes the whole process down. A panic while holding a lock poisons it, and the
+ * restarted worker will most likely just fail again on that until the budget is used up.
+ */
+fn spawn_supervised(
+    name: &str,
+    restarts: u32,
+    mut worker: impl FnMut() -> anyhow::Result<()> + Send + 'static,
+) -> io::Result<JoinHandle<anyhow::Result<()>>> {
+    let worker_name = name.to_string();
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            let mut failures = 0;
+            loop {
+                let result = panic::catch_unwind(AssertUnwindSafe(&mut worker))
+                    .unwrap_or_else(|payload| Err(panicked(payload)));
+                match result {
+                    Err(e) if failures < restarts => {
+                        failures += 1;
+                        warn!(
+                            "Worker {} failed, restarting ({}/{}): {:#}",
+                            worker_name, failures, restarts, e
+                        );
+                        sleep(WORKER_RESTART_DELAY);
+                    }
+                    result => return result,
+                }
+            }
+        })
+}
 
 fn main() -> anyhow::Result<()> {
     println!("Starting sqm-autorate version {}", VERSION);
 
-    let config = Config::new()?;
-    log::init(config.log_level)?;
-    let mut reflectors = config.load_reflectors()?;
+    let mut config = Config::new()?;
+    log::init(config.log_level, config.log_rate_limit)?;
+    config.create_output_dirs()?;
+    config.check_rate_asymmetry();
+
+    // Replaying a recording doesn't touch the network, so it needs no capabilities
+    if !config.replay_file.is_empty() {
+        return Replay { config }.run();
+    }
+
+    capabilities::check_capabilities(config.measurement_type, config.survey_report.is_empty())?;
+
+    if !config.survey_report.is_empty() {
+        return run_survey(config);
+    }
+
+    let mut netlink = Netlink::new();
+
+    if config.download_interface == "auto" || config.upload_interface == "auto" {
+        let (download_interface, upload_interface) = netlink.detect_shaped_interfaces()?;
+
+        if config.download_interface == "auto" {
+            info!("Auto-detected download interface: {}", download_interface);
+            config.download_interface = download_interface;
+        }
+
+        if config.upload_interface == "auto" {
+            info!("Auto-detected upload interface: {}", upload_interface);
+            config.upload_interface = upload_interface;
+        }
+    }
+
+    if config.setup_ingress {
+        info!(
+            "Setting up ingress shaping from {} through {}",
+            config.upload_interface, config.download_interface
+        );
+        netlink.setup_ingress_shaping(
+            config.upload_interface.as_str(),
+            config.download_interface.as_str(),
+            config.download_min_kbits as u64,
+            config.upload_min_kbits as u64,
+        )?;
+    }
+
+    let reflectors = config.load_reflectors()?;
+    let reference_reflectors = config.load_reference_reflectors()?;
+    let (download_reflectors, upload_reflectors) = config.load_direction_reflectors()?;
     let start_t = Instant::now();
 
     // The identifier field in ICMP is only 2 bytes
     // so take the last 2 bytes of the PID as the ID
     let id = (process::id() & 0xFFFF) as u16;
 
-    // Create data structures shared by different threads
+    /*
+     * Create data structures shared by different threads. Anything that holds more than
+     * one of these locks at a time takes them in this order: owd_baseline, owd_recent,
+     * the reflector peers, then disabled_reflectors. Otherwise a writer queued on the peers
+     * between two readers can leave them waiting on each other.
+     */
     let owd_baseline = Arc::new(Mutex::new(HashMap::<IpAddr, ReflectorStats>::new()));
     let owd_recent = Arc::new(Mutex::new(HashMap::<IpAddr, ReflectorStats>::new()));
+    let counters = Arc::new(Counters::default());
+    let next_seq = Arc::new(AtomicU16::new(0));
+    let rate_metrics = Arc::new(Mutex::new(RateMetrics::default()));
+    let safe_rates = Arc::new(Mutex::new(SafeRates::default()));
     let reflector_peers_lock = Arc::new(RwLock::new(Vec::<IpAddr>::new()));
-    let mut reflector_pool = Vec::<IpAddr>::new();
-    let reflector_pool_size = reflectors.len();
-
-    let default_reflectors = [
-        IpAddr::from_str("9.9.9.9")?,
-        IpAddr::from_str("8.238.120.14")?,
-        IpAddr::from_str("74.82.42.42")?,
-        IpAddr::from_str("194.242.2.2")?,
-        IpAddr::from_str("208.67.222.222")?,
-        IpAddr::from_str("94.140.14.14")?,
-    ];
-
-    match reflector_pool_size > 5 {
-        true => {
-            let mut peers = reflector_peers_lock.write().unwrap();
-            peers.append(default_reflectors.to_vec().as_mut());
-            reflector_pool.append(reflectors.as_mut());
+    let disabled_reflectors = Arc::new(RwLock::new(HashSet::<IpAddr>::new()));
+    let idle = Arc::new(AtomicBool::new(false));
+
+    let peer_cache = if config.peer_cache_file.is_empty() {
+        PeerCache::default()
+    } else {
+        // A cache that can't be trusted is only a missed warm start, so start over
+        PeerCache::load(
+            config.peer_cache_file.as_str(),
+            peer_cache::now_s(),
+            config.peer_cache_max_age,
+        )
+        .unwrap_or_else(|e| {
+            warn!("Ignoring the peer cache, starting a new one: {}", e);
+            PeerCache::default()
+        })
+    };
+
+    let mut rng = new_rng(config.rng_seed, RngStream::InitialPeers);
+    let (mut reflector_pool, picked_peers) = initial_peers(&config, &reflectors, &mut rng);
+    {
+        let mut peers = reflector_peers_lock.write().unwrap();
+        *peers = picked_peers;
+
+        // The reflectors a direction is limited to are no use unless they can be selected
+        for reflector in download_reflectors.iter().chain(upload_reflectors.iter()) {
+            if !reflector_pool.contains(reflector) {
+                reflector_pool.push(*reflector);
+            }
         }
-        false => {
-            let mut peers = reflector_peers_lock.write().unwrap();
-            peers.append(default_reflectors.to_vec().as_mut());
+
+        /*
+         * Start out with the peers the last run left off with, if they're cached. Otherwise,
+         * with a pool that's larger than what we need, start out with the fastest of a sample
+         * instead. Either way, whatever that doesn't fill is topped up with the peers picked
+         * above, and if nothing replied at all they're kept as they are.
+         */
+        let cached_peers = peer_cache.warm_start(
+            config.measurement_type,
+            &reflector_pool,
+            &peers,
+            config.num_reflectors as usize,
+        );
+        let silent = peer_cache.silent(config.measurement_type);
+
+        if !cached_peers.is_empty() {
+            for reflector in cached_peers.iter() {
+                info!("Initial peer from the cache: {}", reflector);
+            }
+            *peers = cached_peers;
+        } else if config.initial_probe_sample > 0
+            && reflector_pool.len() > config.num_reflectors as usize
+        {
+            // Known not to answer, so not worth a probe
+            let candidates: Vec<IpAddr> = reflector_pool
+                .iter()
+                .filter(|reflector| !silent.contains(reflector))
+                .copied()
+                .collect();
+            let sample: Vec<IpAddr> = candidates
+                .choose_multiple(&mut rng, config.initial_probe_sample as usize)
+                .copied()
+                .collect();
+            let (listener, sender) = create_pingers(&config);
+            let mut fastest = reflector_selector::probe_initial_peers(
+                &config,
+                listener.as_ref(),
+                sender.as_ref(),
+                id,
+                &sample,
+            )?;
+
+            if fastest.is_empty() {
+                warn!("None of the probed reflectors replied, keeping the initial peers");
+            } else {
+                for reflector in peers.iter() {
+                    if fastest.len() >= config.num_reflectors as usize {
+                        break;
+                    }
+                    if !fastest.contains(reflector) {
+                        fastest.push(*reflector);
+                    }
+                }
+                for reflector in fastest.iter() {
+                    info!("Initial peer: {}", reflector);
+                }
+                *peers = fastest;
+            }
         }
     }
 
     let (baseliner_stats_sender, baseliner_stats_receiver) = channel();
     let (reselect_sender, reselect_receiver) = channel();
 
-    let (mut pinger_receiver, mut pinger_sender) = match config.measurement_type {
-        MeasurementType::Icmp => (
-            Box::new(PingerICMPEchoListener {}) as Box<dyn PingListener + Send>,
-            Box::new(PingerICMPEchoSender {}) as Box<dyn PingSender + Send>,
-        ),
-        MeasurementType::IcmpTimestamps => (
-            Box::new(PingerICMPTimestampListener {}) as Box<dyn PingListener + Send>,
-            Box::new(PingerICMPTimestampSender {}) as Box<dyn PingSender + Send>,
-        ),
-        MeasurementType::Ntp | MeasurementType::TcpTimestamps => {
-            todo!()
-        }
-    };
-
     let baseliner = Baseliner {
         config: config.clone(),
+        counters: counters.clone(),
         owd_baseline: owd_baseline.clone(),
         owd_recent: owd_recent.clone(),
+        reflector_peers_lock: reflector_peers_lock.clone(),
         reselect_trigger: reselect_sender.clone(),
         start_time: start_t,
         stats_receiver: baseliner_stats_receiver,
     };
 
-    let down_qdisc = Netlink::qdisc_from_ifname(config.download_interface.as_str())?;
-    let up_qdisc = Netlink::qdisc_from_ifname(config.upload_interface.as_str())?;
+    let down_qdisc = netlink.qdisc_from_ifname(config.download_interface.as_str())?;
+    let up_qdisc = netlink.qdisc_from_ifname(config.upload_interface.as_str())?;
+
+    let download_start_kbits = config.download_min_kbits.max(config.qdisc_floor_kbits);
+    let upload_start_kbits = config.upload_min_kbits.max(config.qdisc_floor_kbits);
+
+    /*
+     * Whatever the shaper was left at, read before it's dropped to the minimum below. A rate
+     * the rate controller would resume from is what an earlier run that didn't get to shut
+     * down cleanly left behind, so it picks up from there. Base is what a clean shutdown
+     * restores, and what the sqm scripts usually set up, so that starts cold.
+     */
+    let left_rate = |rate: Option<u64>, start_rate: f64, base_rate: f64| {
+        resume_rate(rate, start_rate, base_rate)
+            .filter(|rate| *rate < base_rate)
+            .map(|rate| rate as u64)
+    };
+    let left_rates = (
+        left_rate(
+            shaper_rate(&mut netlink, down_qdisc),
+            download_start_kbits,
+            config.download_base_kbits,
+        ),
+        left_rate(
+            shaper_rate(&mut netlink, up_qdisc),
+            upload_start_kbits,
+            config.upload_base_kbits,
+        ),
+    );
 
     /* Set initial TC values to minimum
      * so there should be no initial bufferbloat to
@@ -109,10 +506,19 @@ fn main() -> anyhow::Result<()> {
      */
     info!(
         "Setting shaper rates to minimum (D/L): {} / {}",
-        config.download_min_kbits, config.upload_min_kbits
+        download_start_kbits, upload_start_kbits
     );
-    Netlink::set_qdisc_rate(down_qdisc, config.download_min_kbits as u64)?;
-    Netlink::set_qdisc_rate(up_qdisc, config.upload_min_kbits as u64)?;
+    netlink.set_qdisc_rate(down_qdisc, download_start_kbits as u64)?;
+    netlink.set_qdisc_rate(up_qdisc, upload_start_kbits as u64)?;
+
+    if let Some(flow_mode) = config.download_cake_flow_mode {
+        info!("Setting download CAKE flow mode to {:?}", flow_mode);
+        netlink.set_qdisc_flow_mode(down_qdisc, flow_mode as u32)?;
+    }
+    if let Some(flow_mode) = config.upload_cake_flow_mode {
+        info!("Setting upload CAKE flow mode to {:?}", flow_mode);
+        netlink.set_qdisc_flow_mode(up_qdisc, flow_mode as u32)?;
+    }
 
     // Sleep for a few seconds to give the shaper a chance
     // to control the queue if load is heavy
@@ -123,41 +529,288 @@ fn main() -> anyhow::Result<()> {
     );
     sleep(settle_sleep_time);
 
-    let reflector_peers_lock_clone = reflector_peers_lock.clone();
-    let receiver_handle = thread::Builder::new().name("receiver".to_string()).spawn(
-        move || -> anyhow::Result<()> {
-            pinger_receiver.listen(
-                id,
-                config.measurement_type,
-                reflector_peers_lock_clone,
-                baseliner_stats_sender,
-            )
-        },
-    )?;
+    /*
+     * SIGTERM and SIGINT stop the workers instead of killing the process outright, so the
+     * shaper can be set back to the base rates, instead of staying wherever it was last set.
+     * A second signal exits right away, in case stopping gets stuck.
+     */
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, shutdown.clone())?;
+        signal_hook::flag::register(signal, shutdown.clone())?;
+    }
+
+    // Shared by the sender and the listener, so replies can be matched up with their probes
+    let loss_tracking = LossTracking {
+        counters: counters.clone(),
+        outstanding: Arc::new(Mutex::new(HashMap::new())),
+        timeout: Duration::from_secs_f64(config.probe_loss_timeout),
+    };
+
     let baseliner_handle = thread::Builder::new()
         .name("baseliner".to_string())
         .spawn(move || -> anyhow::Result<()> { baseliner.run() })?;
-    let reflector_peers_lock_clone = reflector_peers_lock.clone();
-    let sender_handle = thread::Builder::new().name("sender".to_string()).spawn(
-        move || -> anyhow::Result<()> {
-            pinger_sender.send(id, config.measurement_type, reflector_peers_lock_clone)
-        },
-    )?;
+    // The probing threads stop on shutdown, the rest are left to go down with the process
+    let mut threads = vec![baseliner_handle];
+    let mut background = Vec::new();
+
+    let idle_probing = IdleProbing {
+        idle: idle.clone(),
+        slowdown: config.idle_probe_slowdown,
+        max_probes: config.idle_reflectors,
+    };
+    if config.combined_pinger {
+        let config_clone = config.clone();
+        let reflector_peers_lock_clone = reflector_peers_lock.clone();
+        let reference_reflectors_clone = reference_reflectors.clone();
+        let counters_clone = counters.clone();
+        let next_seq_clone = next_seq.clone();
+        let shutdown_clone = shutdown.clone();
+        let pinger_handle = spawn_supervised("pinger", config.worker_restarts, move || {
+            sched::pin_current_thread(&config_clone.cpu_affinity.0);
+            sched::set_current_thread_priority(
+                config_clone.probe_sched_policy,
+                config_clone.probe_priority,
+            );
+            let (pinger_receiver, pinger_sender) = create_pingers(&config_clone);
+            send_and_listen(
+                pinger_receiver.as_ref(),
+                pinger_sender.as_ref(),
+                id,
+                config_clone.measurement_type,
+                reflector_peers_lock_clone.clone(),
+                reference_reflectors_clone.clone(),
+                Duration::from_secs_f64(config_clone.tick_interval),
+                config_clone.max_active_probes,
+                next_seq_clone.clone(),
+                idle_probing.clone(),
+                loss_tracking.clone(),
+                baseliner_stats_sender.clone(),
+                counters_clone.clone(),
+                config_clone.parse_error_log_rate,
+                config_clone.log_per_packet,
+                shutdown_clone.clone(),
+            )
+        })?;
+        threads.push(pinger_handle);
+    } else {
+        let config_clone = config.clone();
+        let reflector_peers_lock_clone = reflector_peers_lock.clone();
+        let reference_reflectors_clone = reference_reflectors.clone();
+        let counters_clone = counters.clone();
+        let loss_tracking_clone = loss_tracking.clone();
+        let next_seq_clone = next_seq.clone();
+        let shutdown_clone = shutdown.clone();
+        let receiver_handle = spawn_supervised("receiver", config.worker_restarts, move || {
+            sched::pin_current_thread(&config_clone.cpu_affinity.0);
+            sched::set_current_thread_priority(
+                config_clone.probe_sched_policy,
+                config_clone.probe_priority,
+            );
+            let (mut pinger_receiver, _) = create_pingers(&config_clone);
+            pinger_receiver.listen(
+                id,
+                config_clone.measurement_type,
+                reflector_peers_lock_clone.clone(),
+                reference_reflectors_clone.clone(),
+                Duration::from_secs_f64(config_clone.tick_interval),
+                baseliner_stats_sender.clone(),
+                next_seq_clone.clone(),
+                counters_clone.clone(),
+                config_clone.parse_error_log_rate,
+                config_clone.log_per_packet,
+                loss_tracking_clone.clone(),
+                shutdown_clone.clone(),
+            )
+        })?;
+        let reflector_peers_lock_clone = reflector_peers_lock.clone();
+        let next_seq_clone = next_seq.clone();
+        let config_clone = config.clone();
+        let shutdown_clone = shutdown.clone();
+        let sender_handle = spawn_supervised("sender", config.worker_restarts, move || {
+            sched::pin_current_thread(&config_clone.cpu_affinity.0);
+            sched::set_current_thread_priority(
+                config_clone.probe_sched_policy,
+                config_clone.probe_priority,
+            );
+            let (_, mut pinger_sender) = create_pingers(&config_clone);
+            pinger_sender.send(
+                id,
+                config_clone.measurement_type,
+                reflector_peers_lock_clone.clone(),
+                Duration::from_secs_f64(config_clone.tick_interval),
+                config_clone.max_active_probes,
+                next_seq_clone.clone(),
+                idle_probing.clone(),
+                loss_tracking.clone(),
+                shutdown_clone.clone(),
+            )
+        })?;
+        threads.push(receiver_handle);
+        threads.push(sender_handle);
+    }
 
-    let mut threads = vec![receiver_handle, sender_handle, baseliner_handle];
+    if !reference_reflectors.is_empty() {
+        // The reference set is only used for cross-checking, so it's probed much less often
+        let (_, mut reference_sender) = create_pingers(&config);
+        let reference_lock = Arc::new(RwLock::new(reference_reflectors.clone()));
+        let reference_interval = Duration::from_secs_f64(config.reference_interval);
+        let next_seq_clone = next_seq.clone();
+        let shutdown_clone = shutdown.clone();
+        let reference_handle = thread::Builder::new()
+            .name("reference-sender".to_string())
+            .spawn(move || -> anyhow::Result<()> {
+                reference_sender.send(
+                    id,
+                    config.measurement_type,
+                    reference_lock,
+                    reference_interval,
+                    0,
+                    next_seq_clone,
+                    IdleProbing::default(),
+                    LossTracking::default(),
+                    shutdown_clone,
+                )
+            })?;
+        threads.push(reference_handle);
+    }
 
-    if reflector_pool_size > 5 {
+    // Reselection only makes sense if there's more to choose from than we need
+    let reselection_active =
+        !config.disable_reselection && reflector_pool.len() > config.num_reflectors as usize;
+    if !reselection_active {
+        info!("Reflector reselection disabled, sticking with the initial peers");
+        // Otherwise the requests to reselect would pile up in the channel with nobody to take them
+        drop(reselect_receiver);
+    } else {
         let reflector_selector = ReflectorSelector {
             config: config.clone(),
+            counters: counters.clone(),
+            disabled_reflectors: disabled_reflectors.clone(),
+            owd_baseline: owd_baseline.clone(),
             owd_recent: owd_recent.clone(),
+            peer_cache,
             reflector_peers_lock: reflector_peers_lock.clone(),
-            reflector_pool,
+            reflector_pool: Arc::new(RwLock::new(reflector_pool)),
             trigger_channel: reselect_receiver,
         };
+
+        #[cfg(feature = "reload")]
+        {
+            let watcher = reflector_watcher::ReflectorListWatcher {
+                config: config.clone(),
+                reflector_pool: reflector_selector.reflector_pool.clone(),
+            };
+            let watcher_handle = thread::Builder::new()
+                .name("reflector-watcher".to_string())
+                .spawn(move || watcher.run())?;
+            background.push(watcher_handle);
+        }
+
         let reselection_handle = thread::Builder::new()
             .name("reselection".to_string())
             .spawn(move || reflector_selector.run())?;
-        threads.push(reselection_handle);
+        background.push(reselection_handle);
+    }
+
+    if !config.control_socket.is_empty() {
+        let control_socket = ControlSocket {
+            config: config.clone(),
+            disabled_reflectors: disabled_reflectors.clone(),
+            owd_baseline: owd_baseline.clone(),
+            owd_recent: owd_recent.clone(),
+            reflector_peers_lock: reflector_peers_lock.clone(),
+            safe_rates: safe_rates.clone(),
+        };
+        let control_handle = thread::Builder::new()
+            .name("control".to_string())
+            .spawn(move || control_socket.run())?;
+        background.push(control_handle);
+    }
+
+    let first_hop = Arc::new(Mutex::new(FirstHopMetrics::default()));
+    if config.first_hop_interval > 0.0 {
+        let first_hop_prober = FirstHopProber {
+            config: config.clone(),
+            id,
+            metrics: first_hop.clone(),
+            reflector_peers_lock: reflector_peers_lock.clone(),
+        };
+        let first_hop_handle = thread::Builder::new()
+            .name("first-hop".to_string())
+            .spawn(move || first_hop_prober.run())?;
+        background.push(first_hop_handle);
+    }
+
+    if !config.prom_textfile.is_empty() {
+        let textfile_writer = MetricsTextfileWriter {
+            config: config.clone(),
+            first_hop: first_hop.clone(),
+            owd_baseline: owd_baseline.clone(),
+            owd_recent: owd_recent.clone(),
+            counters: counters.clone(),
+            rate_metrics: rate_metrics.clone(),
+            reflector_peers_lock: reflector_peers_lock.clone(),
+            safe_rates: safe_rates.clone(),
+        };
+        let textfile_handle = thread::Builder::new()
+            .name("prom-textfile".to_string())
+            .spawn(move || textfile_writer.run())?;
+        background.push(textfile_handle);
+    }
+
+    #[cfg(feature = "metrics-http")]
+    if !config.metrics_listen.is_empty() {
+        let metrics_server = metrics_http::MetricsServer {
+            config: config.clone(),
+            counters: counters.clone(),
+            first_hop: first_hop.clone(),
+            owd_baseline: owd_baseline.clone(),
+            owd_recent: owd_recent.clone(),
+            rate_metrics: rate_metrics.clone(),
+            reflector_peers_lock: reflector_peers_lock.clone(),
+            safe_rates: safe_rates.clone(),
+        };
+        let metrics_handle = thread::Builder::new()
+            .name("metrics-http".to_string())
+            .spawn(move || metrics_server.run())?;
+        background.push(metrics_handle);
+    }
+
+    #[cfg(not(feature = "metrics-http"))]
+    if !config.metrics_listen.is_empty() {
+        warn!("A metrics address was set, but sqm-autorate was built without metrics-http support");
+    }
+
+    if !config.state_file.is_empty() {
+        let state_file_writer = StateFileWriter {
+            config: config.clone(),
+            counters: counters.clone(),
+            owd_baseline: owd_baseline.clone(),
+            owd_recent: owd_recent.clone(),
+            rate_metrics: rate_metrics.clone(),
+            reflector_peers_lock: reflector_peers_lock.clone(),
+            safe_rates: safe_rates.clone(),
+        };
+        let state_file_handle = thread::Builder::new()
+            .name("state-file".to_string())
+            .spawn(move || state_file_writer.run())?;
+        background.push(state_file_handle);
+    }
+
+    if config.summary_interval > 0.0 {
+        let summary_logger = SummaryLogger {
+            config: config.clone(),
+            owd_baseline: owd_baseline.clone(),
+            owd_recent: owd_recent.clone(),
+            rate_metrics: rate_metrics.clone(),
+            reflector_peers_lock: reflector_peers_lock.clone(),
+            safe_rates: safe_rates.clone(),
+        };
+        let summary_handle = thread::Builder::new()
+            .name("summary".to_string())
+            .spawn(move || summary_logger.run())?;
+        background.push(summary_handle);
     }
 
     // Sleep 10 seconds before we start adjusting speeds
@@ -178,15 +831,20 @@ fn main() -> anyhow::Result<()> {
         StatsDirection::TX
     };
 
-    let mut ratecontroller = Ratecontroller::new(
-        config.clone(),
-        owd_baseline,
-        owd_recent,
-        reflector_peers_lock,
-        reselect_sender,
-        dl_direction,
-        ul_direction,
-    )?;
+    // Kept apart from the other threads, its result decides what the process exits with
+    let mut self_test_handle = None;
+    if config.self_test_duration > 0.0 {
+        let self_test = SelfTest {
+            config: config.clone(),
+            rate_metrics: rate_metrics.clone(),
+            shutdown: shutdown.clone(),
+        };
+        self_test_handle = Some(
+            thread::Builder::new()
+                .name("self-test".to_string())
+                .spawn(move || self_test.run())?,
+        );
+    }
 
     debug!(
         "Download direction: {}:{:?}",
@@ -198,15 +856,248 @@ fn main() -> anyhow::Result<()> {
         config.upload_interface, ul_direction
     );
 
-    let ratecontroller_handle = thread::Builder::new()
-        .name("ratecontroller".to_string())
-        .spawn(move || ratecontroller.run())?;
+    let config_clone = config.clone();
+    let shutdown_clone = shutdown.clone();
+    // Only for the first one, a restarted ratecontroller picks up from where the last one was
+    let mut left_rates = Some(left_rates);
+    let ratecontroller_handle =
+        spawn_supervised("ratecontroller", config.worker_restarts, move || {
+            Ratecontroller::new(
+                config_clone.clone(),
+                counters.clone(),
+                owd_baseline.clone(),
+                owd_recent.clone(),
+                rate_metrics.clone(),
+                safe_rates.clone(),
+                reflector_peers_lock.clone(),
+                reference_reflectors.clone(),
+                disabled_reflectors.clone(),
+                idle.clone(),
+                reselect_sender.clone(),
+                reselection_active,
+                shutdown_clone.clone(),
+                left_rates.take().unwrap_or_default(),
+                dl_direction,
+                ul_direction,
+            )?
+            .run()
+        })?;
 
-    threads.push(ratecontroller_handle);
+    // Wait for a signal, or for one of the workers to give up
+    while !shutdown.load(Ordering::Relaxed)
+        && !ratecontroller_handle.is_finished()
+        && !threads
+            .iter()
+            .chain(background.iter())
+            .any(|thread| thread.is_finished())
+    {
+        sleep(SHUTDOWN_CHECK_INTERVAL);
+    }
+    info!("Shutting down");
+    shutdown.store(true, Ordering::Relaxed);
+
+    /*
+     * The rate controller has to be stopped before the rates are restored, or it could still
+     * change them right after. Restore them even if a worker failed, as a link that's stuck
+     * throttled without anyone adjusting it is worse than an unshaped one.
+     */
+    let ratecontroller_result = ratecontroller_handle
+        .join()
+        .expect("Error happened in thread");
+    info!(
+        "Restoring shaper rates to base (D/L): {} / {}",
+        config.download_base_kbits, config.upload_base_kbits
+    );
+    if let Err(e) = netlink
+        .set_qdisc_rate(down_qdisc, config.download_base_kbits as u64)
+        .and_then(|_| netlink.set_qdisc_rate(up_qdisc, config.upload_base_kbits as u64))
+    {
+        warn!("Failed to restore the shaper rates: {}", e);
+    }
+    ratecontroller_result?;
 
     for thread in threads {
         thread.join().expect("Error happened in thread")?;
     }
+    // Any of these that stopped did so by failing
+    for thread in background.into_iter().filter(|thread| thread.is_finished()) {
+        thread.join().expect("Error happened in thread")?;
+    }
+
+    if let Some(self_test_handle) = self_test_handle {
+        if !self_test_handle.join().expect("Error happened in thread")? {
+            process::exit(1);
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::time::Time;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use rustix::thread::ClockId;
+
+    fn reflectors(count: u8) -> Vec<IpAddr> {
+        (1..=count).map(|i| IpAddr::from([192, 0, 2, i])).collect()
+    }
+
+    #[test]
+    fn defaults_only_top_up_the_peers() {
+        let mut config = test_config();
+        config.num_reflectors = 5;
+        config.use_default_reflectors = true;
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let (pool, peers) = initial_peers(&config, &reflectors(10), &mut rng);
+        assert_eq!(pool.len(), 10 + Config::default_reflectors().len());
+        assert_eq!(peers.len(), 5);
+        assert!(peers.iter().all(|peer| reflectors(10).contains(peer)));
+
+        let (pool, peers) = initial_peers(&config, &reflectors(3), &mut rng);
+        assert_eq!(pool.len(), 3 + Config::default_reflectors().len());
+        assert_eq!(peers.len(), 5);
+        assert!(reflectors(3)
+            .iter()
+            .all(|reflector| peers.contains(reflector)));
+    }
+
+    #[test]
+    fn defaults_left_out() {
+        let mut config = test_config();
+        config.num_reflectors = 5;
+        config.use_default_reflectors = false;
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let (pool, peers) = initial_peers(&config, &reflectors(3), &mut rng);
+        assert_eq!(pool, reflectors(3));
+        assert_eq!(peers.len(), 3);
+    }
+
+    #[test]
+    fn failed_worker_is_restarted() {
+        let runs = Arc::new(AtomicU16::new(0));
+        let runs_clone = runs.clone();
+        let handle = spawn_supervised("flaky", 2, move || {
+            match runs_clone.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(anyhow::anyhow!("netlink hiccup")),
+                1 => panic!("worker bug"),
+                _ => Ok(()),
+            }
+        })
+        .unwrap();
+
+        assert!(handle.join().unwrap().is_ok());
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn worker_gives_up_after_the_restarts() {
+        let runs = Arc::new(AtomicU16::new(0));
+        let runs_clone = runs.clone();
+        let handle = spawn_supervised("broken", 2, move || {
+            runs_clone.fetch_add(1, Ordering::Relaxed);
+            Err(anyhow::anyhow!("still broken"))
+        })
+        .unwrap();
+
+        assert_eq!(
+            handle.join().unwrap().unwrap_err().to_string(),
+            "still broken"
+        );
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+    }
+
+    // What a reflector sends back for an ICMPv6 echo request, checksum aside
+    fn echo_reply_to(probe: &[u8]) -> Vec<u8> {
+        let mut reply = probe.to_vec();
+        reply[0] = 129;
+        reply
+    }
+
+    #[test]
+    fn echo_rtt_is_split_as_configured() {
+        let mut config = test_config();
+        config.measurement_type = MeasurementType::Icmp;
+        config.echo_upload_share = 0.25;
+        let (listener, sender) = create_pingers(&config);
+        let reflector: IpAddr = "2001:db8::1".parse().unwrap();
+
+        let probe = sender.craft_packet(7, 1, reflector);
+        sleep(Duration::from_millis(20));
+        let reply = listener
+            .parse_packet(7, reflector, &echo_reply_to(&probe))
+            .unwrap();
+        assert!(reply.rtt >= 20);
+        assert_eq!(reply.up_time, reply.rtt as f64 * 0.25);
+        assert_eq!(reply.down_time, reply.rtt as f64 * 0.75);
+    }
+
+    #[test]
+    fn echo_probes_are_timed_by_the_configured_clock() {
+        let mut config = test_config();
+        config.measurement_type = MeasurementType::Icmp;
+        config.probe_clock = "monotonic-raw".parse().unwrap();
+        let (listener, sender) = create_pingers(&config);
+        let reflector: IpAddr = "2001:db8::1".parse().unwrap();
+        let now_ms = || Time::new(ClockId::MonotonicRaw).to_milliseconds();
+
+        // The stamp is from the raw clock, which drifts apart from the slewed one over time
+        let before_ms = now_ms();
+        let probe = sender.craft_packet(7, 1, reflector);
+        let stamp_ms = u64::from_ne_bytes(probe[8..16].try_into().unwrap());
+        assert!((before_ms..=now_ms()).contains(&stamp_ms));
+
+        // And the listener reads the same clock, or the RTT would be off by the difference
+        let reply = listener
+            .parse_packet(7, reflector, &echo_reply_to(&probe))
+            .unwrap();
+        assert!((0..=(now_ms() - before_ms) as i64).contains(&reply.rtt));
+    }
+
+    // Doesn't compile unless main's peer lock is the type everything that shares it expects
+    #[test]
+    fn peer_lock_is_shared_by_the_selector_and_the_workers() {
+        let config = test_config();
+        let reflector_peers_lock = Arc::new(RwLock::new(reflectors(2)));
+        let (reselect_sender, reselect_receiver) = channel();
+        let (_, stats_receiver) = channel();
+
+        let selector = ReflectorSelector {
+            config: config.clone(),
+            counters: Arc::default(),
+            disabled_reflectors: Arc::default(),
+            owd_baseline: Arc::default(),
+            owd_recent: Arc::default(),
+            peer_cache: PeerCache::default(),
+            reflector_peers_lock: reflector_peers_lock.clone(),
+            reflector_pool: Arc::new(RwLock::new(reflectors(4))),
+            trigger_channel: reselect_receiver,
+        };
+        let baseliner = Baseliner {
+            config,
+            counters: Arc::default(),
+            owd_baseline: Arc::default(),
+            owd_recent: Arc::default(),
+            reflector_peers_lock: reflector_peers_lock.clone(),
+            reselect_trigger: reselect_sender,
+            start_time: Instant::now(),
+            stats_receiver,
+        };
+
+        selector
+            .reflector_peers_lock
+            .write()
+            .unwrap()
+            .push(IpAddr::from([192, 0, 2, 3]));
+        assert_eq!(
+            *baseliner.reflector_peers_lock.read().unwrap(),
+            reflectors(3)
+        );
+        assert_eq!(*reflector_peers_lock.read().unwrap(), reflectors(3));
+    }
+}
@@ -0,0 +1,718 @@
+use crate::baseliner::{reflector_snapshot, ReflectorSnapshot};
+use crate::first_hop::FirstHopMetrics;
+use crate::reflector_selector::DropReason;
+use crate::{Config, ReflectorStats, VERSION};
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateMetrics {
+    pub download_rate_kbit: f64,
+    pub upload_rate_kbit: f64,
+    pub download_load: f64,
+    pub upload_load: f64,
+    pub delta_delay_down_ms: f64,
+    pub delta_delay_up_ms: f64,
+}
+
+/// The learned safe rate history for each direction, along with the next slot to be written
+#[derive(Clone, Debug, Default)]
+pub struct SafeRates {
+    pub download: Vec<f64>,
+    pub download_index: usize,
+    pub upload: Vec<f64>,
+    pub upload_index: usize,
+}
+
+impl SafeRates {
+    /*
+     * How far the current rate is below the best rate the link has been known to sustain
+     * without bloat. It's roughly the throughput given up to keep the latency down.
+     */
+    pub fn headroom(rates: &[f64], current_rate: f64) -> f64 {
+        let peak = rates.iter().copied().fold(0.0, f64::max);
+        (peak - current_rate).max(0.0)
+    }
+}
+
+// How many of the latest notable events are kept, for the state file
+const RECENT_EVENTS: usize = 20;
+
+/// Something notable the daemon did, like a reselection or a circuit breaker trip
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub time: SystemTime,
+    pub kind: &'static str,
+    pub detail: String,
+}
+
+/// Event counters updated by the worker threads
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub duplicate_replies: AtomicU64,
+    // The latest notable events, oldest first
+    pub events: Mutex<VecDeque<Event>>,
+    pub lost_probes: AtomicU64,
+    pub parse_errors: AtomicU64,
+    // Indexed by DropReason
+    pub reflector_drops: [AtomicU64; DropReason::ALL.len()],
+    pub regime_changes: AtomicU64,
+    // Probes that couldn't be sent, per reflector
+    pub send_errors: Mutex<HashMap<IpAddr, u64>>,
+    pub stale_replies: AtomicU64,
+}
+
+impl Counters {
+    pub fn event(&self, kind: &'static str, detail: String) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= RECENT_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(Event {
+            time: SystemTime::now(),
+            kind,
+            detail,
+        });
+    }
+
+    // Counts a probe that couldn't be sent, and returns how many have failed for the reflector
+    pub fn send_error(&self, reflector: IpAddr) -> u64 {
+        let mut send_errors = self.send_errors.lock().unwrap();
+        let failures = send_errors.entry(reflector).or_insert(0);
+        *failures += 1;
+        *failures
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Writes a gauge with a down and an up series for each reflector
+fn write_reflector_gauge(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    reflectors: &[&ReflectorSnapshot],
+    value: impl Fn(&ReflectorSnapshot) -> (f64, f64),
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for entry in reflectors.iter() {
+        let (down, up) = value(entry);
+        let _ = writeln!(
+            out,
+            "{}{{reflector=\"{}\",direction=\"down\"}} {}",
+            name, entry.reflector, down
+        );
+        let _ = writeln!(
+            out,
+            "{}{{reflector=\"{}\",direction=\"up\"}} {}",
+            name, entry.reflector, up
+        );
+    }
+}
+
+/// Renders the current state in the Prometheus text exposition format
+pub fn render(
+    rates: &RateMetrics,
+    counters: &Counters,
+    safe_rates: &SafeRates,
+    reflectors: &[ReflectorSnapshot],
+    first_hop: &FirstHopMetrics,
+) -> String {
+    let mut out = String::new();
+
+    // Always there whatever state the controller is in, so they only go missing with the exporter
+    write_gauge(
+        &mut out,
+        "sqm_autorate_up",
+        "Whether sqm-autorate is running",
+        1.0,
+    );
+    let _ = writeln!(
+        out,
+        "# HELP sqm_autorate_build_info The version of sqm-autorate that's running"
+    );
+    let _ = writeln!(out, "# TYPE sqm_autorate_build_info gauge");
+    let _ = writeln!(out, "sqm_autorate_build_info{{version=\"{}\"}} 1", VERSION);
+
+    write_gauge(
+        &mut out,
+        "sqma_download_rate_kbit",
+        "Current download shaper rate in kbit/s",
+        rates.download_rate_kbit,
+    );
+    write_gauge(
+        &mut out,
+        "sqma_upload_rate_kbit",
+        "Current upload shaper rate in kbit/s",
+        rates.upload_rate_kbit,
+    );
+    write_gauge(
+        &mut out,
+        "sqma_download_load",
+        "Download utilisation relative to the shaper rate",
+        rates.download_load,
+    );
+    write_gauge(
+        &mut out,
+        "sqma_upload_load",
+        "Upload utilisation relative to the shaper rate",
+        rates.upload_load,
+    );
+    write_gauge(
+        &mut out,
+        "sqma_delta_delay_down_ms",
+        "Download delay above baseline in milliseconds",
+        rates.delta_delay_down_ms,
+    );
+    write_gauge(
+        &mut out,
+        "sqma_delta_delay_up_ms",
+        "Upload delay above baseline in milliseconds",
+        rates.delta_delay_up_ms,
+    );
+
+    write_gauge(
+        &mut out,
+        "sqma_download_headroom_kbit",
+        "Download peak safe rate minus the current shaper rate in kbit/s",
+        SafeRates::headroom(&safe_rates.download, rates.download_rate_kbit),
+    );
+    write_gauge(
+        &mut out,
+        "sqma_upload_headroom_kbit",
+        "Upload peak safe rate minus the current shaper rate in kbit/s",
+        SafeRates::headroom(&safe_rates.upload, rates.upload_rate_kbit),
+    );
+
+    write_counter(
+        &mut out,
+        "sqma_duplicate_replies_total",
+        "Duplicate probe replies that were dropped",
+        counters.duplicate_replies.load(Ordering::Relaxed),
+    );
+    write_counter(
+        &mut out,
+        "sqma_lost_probes_total",
+        "Probes that went unanswered for longer than the loss timeout",
+        counters.lost_probes.load(Ordering::Relaxed),
+    );
+    write_counter(
+        &mut out,
+        "sqma_parse_errors_total",
+        "Replies from reflectors that couldn't be parsed",
+        counters.parse_errors.load(Ordering::Relaxed),
+    );
+    let _ = writeln!(
+        out,
+        "# HELP sqma_reflector_drops_total Reflectors left out of the peers at reselection, by reason"
+    );
+    let _ = writeln!(out, "# TYPE sqma_reflector_drops_total counter");
+    for reason in DropReason::ALL {
+        let _ = writeln!(
+            out,
+            "sqma_reflector_drops_total{{reason=\"{}\"}} {}",
+            reason.label(),
+            counters.reflector_drops[reason as usize].load(Ordering::Relaxed)
+        );
+    }
+    write_counter(
+        &mut out,
+        "sqma_regime_changes_total",
+        "Reflectors re-baselined after a sustained shift in delay",
+        counters.regime_changes.load(Ordering::Relaxed),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP sqma_send_errors_total Probes that couldn't be sent, per reflector"
+    );
+    let _ = writeln!(out, "# TYPE sqma_send_errors_total counter");
+    for (reflector, failures) in counters.send_errors.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "sqma_send_errors_total{{reflector=\"{}\"}} {}",
+            reflector, failures
+        );
+    }
+
+    write_counter(
+        &mut out,
+        "sqma_stale_replies_total",
+        "Replies dropped for sequence numbers outside the outstanding window",
+        counters.stale_replies.load(Ordering::Relaxed),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP sqma_safe_rate_kbit Learned safe rate history in kbit/s, per slot"
+    );
+    let _ = writeln!(out, "# TYPE sqma_safe_rate_kbit gauge");
+    let _ = writeln!(
+        out,
+        "# HELP sqma_safe_rate_index Next slot to be written in the safe rate history"
+    );
+    let _ = writeln!(out, "# TYPE sqma_safe_rate_index gauge");
+    for (direction, rates, index) in [
+        ("down", &safe_rates.download, safe_rates.download_index),
+        ("up", &safe_rates.upload, safe_rates.upload_index),
+    ] {
+        for (slot, rate) in rates.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "sqma_safe_rate_kbit{{direction=\"{}\",slot=\"{}\"}} {}",
+                direction, slot, rate
+            );
+        }
+        let _ = writeln!(
+            out,
+            "sqma_safe_rate_index{{direction=\"{}\"}} {}",
+            direction, index
+        );
+    }
+
+    /*
+     * Per-reflector series are only written for the current peers. Candidates and pruned
+     * reflectors come and go, and would leave an ever growing number of series behind.
+     */
+    let active: Vec<&ReflectorSnapshot> = reflectors.iter().filter(|entry| entry.active).collect();
+
+    write_reflector_gauge(
+        &mut out,
+        "sqma_reflector_owd_ms",
+        "Recent one-way delay per reflector in milliseconds",
+        &active,
+        |entry| (entry.recent_down_ms, entry.recent_up_ms),
+    );
+    write_reflector_gauge(
+        &mut out,
+        "sqma_reflector_baseline_ms",
+        "Baseline one-way delay per reflector in milliseconds",
+        &active,
+        |entry| (entry.baseline_down_ms, entry.baseline_up_ms),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP sqma_reflector_jitter_ms Delay jitter per reflector in milliseconds"
+    );
+    let _ = writeln!(out, "# TYPE sqma_reflector_jitter_ms gauge");
+    for entry in active.iter() {
+        let _ = writeln!(
+            out,
+            "sqma_reflector_jitter_ms{{reflector=\"{}\"}} {}",
+            entry.reflector, entry.jitter_ms
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP sqma_reflector_reachable Whether the reflector replied recently"
+    );
+    let _ = writeln!(out, "# TYPE sqma_reflector_reachable gauge");
+    for entry in active.iter() {
+        let _ = writeln!(
+            out,
+            "sqma_reflector_reachable{{reflector=\"{}\"}} {}",
+            entry.reflector, entry.reachable as u8
+        );
+    }
+
+    // Only there while the first hop is being probed and answering
+    if let Some(gateway) = first_hop.gateway {
+        let _ = writeln!(
+            out,
+            "# HELP sqma_first_hop_delay_ms Round trip time to the first router in milliseconds"
+        );
+        let _ = writeln!(out, "# TYPE sqma_first_hop_delay_ms gauge");
+        let _ = writeln!(
+            out,
+            "sqma_first_hop_delay_ms{{gateway=\"{}\"}} {}",
+            gateway, first_hop.delay_ms
+        );
+    }
+
+    out
+}
+
+/*
+ * node_exporter may read the file at any time, so write to a temporary
+ * file next to it and rename it into place to make the update atomic
+ */
+fn write_atomic(path: &str, body: &str) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(tmp_path.as_str(), body).and_then(|_| fs::rename(tmp_path.as_str(), path))
+}
+
+pub struct MetricsTextfileWriter {
+    pub config: Config,
+    pub first_hop: Arc<Mutex<FirstHopMetrics>>,
+    pub owd_baseline: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub owd_recent: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub counters: Arc<Counters>,
+    pub rate_metrics: Arc<Mutex<RateMetrics>>,
+    pub reflector_peers_lock: Arc<RwLock<Vec<IpAddr>>>,
+    pub safe_rates: Arc<Mutex<SafeRates>>,
+}
+
+impl MetricsTextfileWriter {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let sleep_time = Duration::from_secs_f64(self.config.prom_textfile_interval);
+
+        loop {
+            sleep(sleep_time);
+
+            let rates = *self.rate_metrics.lock().unwrap();
+            let safe_rates = self.safe_rates.lock().unwrap().clone();
+            let reflectors = reflector_snapshot(
+                &self.owd_baseline,
+                &self.owd_recent,
+                &self.reflector_peers_lock,
+                self.config.tick_interval * 2.0,
+            );
+            let first_hop = *self.first_hop.lock().unwrap();
+            let body = render(&rates, &self.counters, &safe_rates, &reflectors, &first_hop);
+
+            if let Err(e) = write_atomic(self.config.prom_textfile.as_str(), body.as_str()) {
+                warn!("Failed to write Prometheus textfile: {}", e);
+            }
+        }
+    }
+}
+
+/*
+ * A one-line heartbeat in the log, for when the full stats are more than is wanted.
+ * The rates are the current ones, while the delay is the worst seen since the last line,
+ * so short spikes in between still show up.
+ */
+pub struct SummaryLogger {
+    pub config: Config,
+    pub owd_baseline: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub owd_recent: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub rate_metrics: Arc<Mutex<RateMetrics>>,
+    pub reflector_peers_lock: Arc<RwLock<Vec<IpAddr>>>,
+    pub safe_rates: Arc<Mutex<SafeRates>>,
+}
+
+// What's been seen since the previous summary line
+struct SummaryWindow {
+    last_summary_t: Instant,
+    max_delta_down: f64,
+    max_delta_up: f64,
+}
+
+impl SummaryLogger {
+    // The next line, once the interval since the previous one is up
+    fn summary(&self, window: &mut SummaryWindow, now_t: Instant) -> Option<String> {
+        let rates = *self.rate_metrics.lock().unwrap();
+        window.max_delta_down = window.max_delta_down.max(rates.delta_delay_down_ms);
+        window.max_delta_up = window.max_delta_up.max(rates.delta_delay_up_ms);
+
+        if now_t.duration_since(window.last_summary_t).as_secs_f64() < self.config.summary_interval
+        {
+            return None;
+        }
+
+        let active = reflector_snapshot(
+            &self.owd_baseline,
+            &self.owd_recent,
+            &self.reflector_peers_lock,
+            self.config.tick_interval * 2.0,
+        )
+        .iter()
+        .filter(|entry| entry.reachable)
+        .count();
+
+        let safe_rates = self.safe_rates.lock().unwrap().clone();
+
+        let line = format!(
+            "Rates {:.0}/{:.0} kbit (D/U), headroom {:.0}/{:.0} kbit, {} active reflectors, max delay increase {:.1}/{:.1} ms",
+            rates.download_rate_kbit,
+            rates.upload_rate_kbit,
+            SafeRates::headroom(&safe_rates.download, rates.download_rate_kbit),
+            SafeRates::headroom(&safe_rates.upload, rates.upload_rate_kbit),
+            active,
+            window.max_delta_down,
+            window.max_delta_up
+        );
+
+        *window = SummaryWindow {
+            last_summary_t: now_t,
+            max_delta_down: 0.0,
+            max_delta_up: 0.0,
+        };
+        Some(line)
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let tick = Duration::from_secs_f64(self.config.tick_interval);
+        let mut window = SummaryWindow {
+            last_summary_t: Instant::now(),
+            max_delta_down: 0.0,
+            max_delta_up: 0.0,
+        };
+
+        loop {
+            sleep(tick);
+
+            if let Some(line) = self.summary(&mut window, Instant::now()) {
+                info!("{}", line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use std::env;
+
+    #[test]
+    fn up_and_build_info_without_controller_state() {
+        let body = render(
+            &RateMetrics::default(),
+            &Counters::default(),
+            &SafeRates::default(),
+            &[],
+            &FirstHopMetrics::default(),
+        );
+        let lines: Vec<&str> = body.lines().collect();
+
+        assert!(lines.contains(&"sqm_autorate_up 1"));
+        assert!(lines
+            .contains(&format!("sqm_autorate_build_info{{version=\"{}\"}} 1", VERSION).as_str()));
+    }
+
+    #[test]
+    fn render_snapshot() {
+        let rates = RateMetrics {
+            download_rate_kbit: 50000.0,
+            upload_rate_kbit: 4000.0,
+            download_load: 0.5,
+            upload_load: 0.25,
+            delta_delay_down_ms: 1.5,
+            delta_delay_up_ms: 2.0,
+        };
+        let counters = Counters::default();
+        counters.lost_probes.store(3, Ordering::Relaxed);
+        counters.reflector_drops[DropReason::ALL[0] as usize].store(1, Ordering::Relaxed);
+        let safe_rates = SafeRates {
+            download: vec![55000.0],
+            download_index: 0,
+            upload: vec![3000.0],
+            upload_index: 0,
+        };
+        let reflectors = [
+            ReflectorSnapshot {
+                reflector: "9.9.9.9".parse().unwrap(),
+                active: true,
+                baseline_down_ms: 10.0,
+                baseline_up_ms: 11.0,
+                recent_down_ms: 12.0,
+                recent_up_ms: 13.0,
+                jitter_ms: 0.5,
+                last_receive_age_s: 0.1,
+                reachable: true,
+            },
+            // Not a peer, so left out
+            ReflectorSnapshot {
+                reflector: "1.1.1.1".parse().unwrap(),
+                active: false,
+                baseline_down_ms: 20.0,
+                baseline_up_ms: 21.0,
+                recent_down_ms: 22.0,
+                recent_up_ms: 23.0,
+                jitter_ms: 1.0,
+                last_receive_age_s: 0.1,
+                reachable: true,
+            },
+        ];
+        let first_hop = FirstHopMetrics {
+            delay_ms: 0.75,
+            gateway: Some("192.168.1.1".parse().unwrap()),
+        };
+
+        let mut expected = format!(
+            "\
+# HELP sqm_autorate_up Whether sqm-autorate is running
+# TYPE sqm_autorate_up gauge
+sqm_autorate_up 1
+# HELP sqm_autorate_build_info The version of sqm-autorate that's running
+# TYPE sqm_autorate_build_info gauge
+sqm_autorate_build_info{{version=\"{}\"}} 1
+",
+            VERSION
+        );
+        expected.push_str(
+            "\
+# HELP sqma_download_rate_kbit Current download shaper rate in kbit/s
+# TYPE sqma_download_rate_kbit gauge
+sqma_download_rate_kbit 50000
+# HELP sqma_upload_rate_kbit Current upload shaper rate in kbit/s
+# TYPE sqma_upload_rate_kbit gauge
+sqma_upload_rate_kbit 4000
+# HELP sqma_download_load Download utilisation relative to the shaper rate
+# TYPE sqma_download_load gauge
+sqma_download_load 0.5
+# HELP sqma_upload_load Upload utilisation relative to the shaper rate
+# TYPE sqma_upload_load gauge
+sqma_upload_load 0.25
+# HELP sqma_delta_delay_down_ms Download delay above baseline in milliseconds
+# TYPE sqma_delta_delay_down_ms gauge
+sqma_delta_delay_down_ms 1.5
+# HELP sqma_delta_delay_up_ms Upload delay above baseline in milliseconds
+# TYPE sqma_delta_delay_up_ms gauge
+sqma_delta_delay_up_ms 2
+# HELP sqma_download_headroom_kbit Download peak safe rate minus the current shaper rate in kbit/s
+# TYPE sqma_download_headroom_kbit gauge
+sqma_download_headroom_kbit 5000
+# HELP sqma_upload_headroom_kbit Upload peak safe rate minus the current shaper rate in kbit/s
+# TYPE sqma_upload_headroom_kbit gauge
+sqma_upload_headroom_kbit 0
+# HELP sqma_duplicate_replies_total Duplicate probe replies that were dropped
+# TYPE sqma_duplicate_replies_total counter
+sqma_duplicate_replies_total 0
+# HELP sqma_lost_probes_total Probes that went unanswered for longer than the loss timeout
+# TYPE sqma_lost_probes_total counter
+sqma_lost_probes_total 3
+# HELP sqma_parse_errors_total Replies from reflectors that couldn't be parsed
+# TYPE sqma_parse_errors_total counter
+sqma_parse_errors_total 0
+# HELP sqma_reflector_drops_total Reflectors left out of the peers at reselection, by reason
+# TYPE sqma_reflector_drops_total counter
+",
+        );
+        for (i, reason) in DropReason::ALL.iter().enumerate() {
+            expected.push_str(&format!(
+                "sqma_reflector_drops_total{{reason=\"{}\"}} {}\n",
+                reason.label(),
+                (i == 0) as u8
+            ));
+        }
+        expected.push_str(
+            "\
+# HELP sqma_regime_changes_total Reflectors re-baselined after a sustained shift in delay
+# TYPE sqma_regime_changes_total counter
+sqma_regime_changes_total 0
+# HELP sqma_send_errors_total Probes that couldn't be sent, per reflector
+# TYPE sqma_send_errors_total counter
+# HELP sqma_stale_replies_total Replies dropped for sequence numbers outside the outstanding window
+# TYPE sqma_stale_replies_total counter
+sqma_stale_replies_total 0
+# HELP sqma_safe_rate_kbit Learned safe rate history in kbit/s, per slot
+# TYPE sqma_safe_rate_kbit gauge
+# HELP sqma_safe_rate_index Next slot to be written in the safe rate history
+# TYPE sqma_safe_rate_index gauge
+sqma_safe_rate_kbit{direction=\"down\",slot=\"0\"} 55000
+sqma_safe_rate_index{direction=\"down\"} 0
+sqma_safe_rate_kbit{direction=\"up\",slot=\"0\"} 3000
+sqma_safe_rate_index{direction=\"up\"} 0
+# HELP sqma_reflector_owd_ms Recent one-way delay per reflector in milliseconds
+# TYPE sqma_reflector_owd_ms gauge
+sqma_reflector_owd_ms{reflector=\"9.9.9.9\",direction=\"down\"} 12
+sqma_reflector_owd_ms{reflector=\"9.9.9.9\",direction=\"up\"} 13
+# HELP sqma_reflector_baseline_ms Baseline one-way delay per reflector in milliseconds
+# TYPE sqma_reflector_baseline_ms gauge
+sqma_reflector_baseline_ms{reflector=\"9.9.9.9\",direction=\"down\"} 10
+sqma_reflector_baseline_ms{reflector=\"9.9.9.9\",direction=\"up\"} 11
+# HELP sqma_reflector_jitter_ms Delay jitter per reflector in milliseconds
+# TYPE sqma_reflector_jitter_ms gauge
+sqma_reflector_jitter_ms{reflector=\"9.9.9.9\"} 0.5
+# HELP sqma_reflector_reachable Whether the reflector replied recently
+# TYPE sqma_reflector_reachable gauge
+sqma_reflector_reachable{reflector=\"9.9.9.9\"} 1
+# HELP sqma_first_hop_delay_ms Round trip time to the first router in milliseconds
+# TYPE sqma_first_hop_delay_ms gauge
+sqma_first_hop_delay_ms{gateway=\"192.168.1.1\"} 0.75
+",
+        );
+
+        assert_eq!(
+            render(&rates, &counters, &safe_rates, &reflectors, &first_hop),
+            expected
+        );
+    }
+
+    #[test]
+    fn textfile_is_replaced_atomically() {
+        let dir = env::temp_dir().join(format!("sqma-metrics-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sqm-autorate.prom");
+        let path = path.to_str().unwrap();
+
+        write_atomic(path, "sqm_autorate_up 1\n").unwrap();
+        write_atomic(path, "sqm_autorate_up 0\n").unwrap();
+
+        let files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        let contents = fs::read_to_string(path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, ["sqm-autorate.prom"]);
+        assert_eq!(contents, "sqm_autorate_up 0\n");
+    }
+
+    #[test]
+    fn headroom_is_from_the_peak() {
+        // The peak rather than the latest or the average of the history
+        let rates = [40000.0, 55000.0, 45000.0];
+        assert_eq!(SafeRates::headroom(&rates, 50000.0), 5000.0);
+        // Above anything seen so far there's nothing left to give up
+        assert_eq!(SafeRates::headroom(&rates, 60000.0), 0.0);
+        assert_eq!(SafeRates::headroom(&[], 50000.0), 0.0);
+    }
+
+    #[test]
+    fn summary_once_per_interval() {
+        let mut config = test_config();
+        config.summary_interval = 10.0;
+        let logger = SummaryLogger {
+            config,
+            owd_baseline: Arc::default(),
+            owd_recent: Arc::default(),
+            rate_metrics: Arc::default(),
+            reflector_peers_lock: Arc::default(),
+            safe_rates: Arc::default(),
+        };
+        let start_t = Instant::now();
+        let mut window = SummaryWindow {
+            last_summary_t: start_t,
+            max_delta_down: 0.0,
+            max_delta_up: 0.0,
+        };
+        let summary_at = |window: &mut SummaryWindow, secs: u64, delta_down_ms: f64| {
+            logger.rate_metrics.lock().unwrap().delta_delay_down_ms = delta_down_ms;
+            logger.summary(window, start_t + Duration::from_secs(secs))
+        };
+
+        // The spike in between still shows up in the line at the end of the interval
+        assert_eq!(summary_at(&mut window, 1, 5.0), None);
+        assert_eq!(summary_at(&mut window, 9, 2.0), None);
+        let line = summary_at(&mut window, 10, 1.0).unwrap();
+        assert!(line.ends_with("max delay increase 5.0/0.0 ms"));
+
+        assert_eq!(summary_at(&mut window, 19, 1.0), None);
+        let line = summary_at(&mut window, 20, 1.0).unwrap();
+        assert!(line.ends_with("max delay increase 1.0/0.0 ms"));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::baseliner::reflector_snapshot;
+use crate::first_hop::FirstHopMetrics;
+use crate::metrics::{render, Counters, RateMetrics, SafeRates};
+use crate::{Config, ReflectorStats};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+// A client that doesn't get its request out in this long is holding up everyone else
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/*
+ * Serves the same metrics as the Prometheus textfile over HTTP, rendered fresh for each
+ * scrape, for setups without node_exporter. Clients are handled one at a time, which is
+ * plenty for a scraper or two.
+ */
+pub struct MetricsServer {
+    pub config: Config,
+    pub counters: Arc<Counters>,
+    pub first_hop: Arc<Mutex<FirstHopMetrics>>,
+    pub owd_baseline: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub owd_recent: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub rate_metrics: Arc<Mutex<RateMetrics>>,
+    pub reflector_peers_lock: Arc<RwLock<Vec<IpAddr>>>,
+    pub safe_rates: Arc<Mutex<SafeRates>>,
+}
+
+impl MetricsServer {
+    fn render_metrics(&self) -> String {
+        let rates = *self.rate_metrics.lock().unwrap();
+        let safe_rates = self.safe_rates.lock().unwrap().clone();
+        let reflectors = reflector_snapshot(
+            &self.owd_baseline,
+            &self.owd_recent,
+            &self.reflector_peers_lock,
+            self.config.tick_interval * 2.0,
+        );
+        let first_hop = *self.first_hop.lock().unwrap();
+
+        render(&rates, &self.counters, &safe_rates, &reflectors, &first_hop)
+    }
+
+    fn handle_client(&self, stream: TcpStream) -> anyhow::Result<()> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // The headers don't matter, but they have to be read before responding
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+            header.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render_metrics()),
+            (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+            _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+        };
+
+        write!(
+            writer,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(self.config.metrics_listen.as_str())?;
+        info!(
+            "Serving Prometheus metrics on http://{}/metrics",
+            self.config.metrics_listen
+        );
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(val) => val,
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.handle_client(stream) {
+                warn!("Error handling metrics client: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+}
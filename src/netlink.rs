@@ -1,11 +1,17 @@
+use log::debug;
+use neli::attr::Attribute;
 use neli::consts::nl::{NlmF, NlmFFlags};
-use neli::consts::rtnl::{Arphrd, IffFlags, Ifla, RtAddrFamily, Rtm, Tca};
+use neli::consts::rtnl::{
+    Arphrd, Iff, IffFlags, Ifla, IflaInfo, RtAddrFamily, RtScope, RtTable, Rta, Rtm, RtmFFlags,
+    Rtn, Rtprot, Tca,
+};
 use neli::consts::socket::NlFamily;
-use neli::err::{NlError, SerError};
+use neli::err::{DeError, NlError, SerError, WrappedError};
 use neli::nl::{NlPayload, Nlmsghdr};
-use neli::rtnl::{Ifinfomsg, Rtattr, Tcmsg};
+use neli::rtnl::{Ifinfomsg, Rtattr, Rtmsg, Tcmsg};
 use neli::socket::NlSocketHandle;
 use neli::types::{Buffer, RtBuffer};
+use neli::{Size, ToBytes};
 use serde::Deserialize;
 use std::io;
 use std::str::Utf8Error;
@@ -15,6 +21,9 @@ use bincode::deserialize;
 
 #[derive(Debug, Error)]
 pub enum NetlinkError {
+    #[error("Couldn't auto-detect interfaces: {0}")]
+    AutodetectFailed(String),
+
     #[error("Couldn't deserialize to struct")]
     Deserialization(#[from] Box<bincode::ErrorKind>),
 
@@ -27,9 +36,18 @@ pub enum NetlinkError {
     #[error("Something went wrong while finding qdisc")]
     NlQdiscError(#[from] NlError<Rtm, Tcmsg>),
 
+    #[error("Something went wrong while looking up routes")]
+    NlRouteError(#[from] NlError<Rtm, Rtmsg>),
+
+    #[error("Couldn't parse Netlink attribute")]
+    NlAttributeError(#[from] DeError),
+
     #[error("Couldn't find CAKE qdisc on interface `{0}`")]
     NoQdiscFound(String),
 
+    #[error("Couldn't find the CAKE bandwidth on interface `{0}`")]
+    NoQdiscRate(String),
+
     #[error("Couldn't find interface statistics: `{0}`")]
     NoInterfaceStatsFound(String),
 
@@ -46,12 +64,71 @@ pub enum NetlinkError {
     WrongType { expected: Rtm, found: Rtm },
 }
 
-#[derive(Clone, Copy, Debug)]
+// How many times an operation is retried on a fresh socket after a transient error
+const NETLINK_RETRIES: usize = 3;
+
+fn is_transient_io(error: &WrappedError) -> bool {
+    match error {
+        WrappedError::IOError(e) => matches!(
+            e.kind(),
+            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+        ),
+        _ => false,
+    }
+}
+
+fn is_transient_nl<T, P>(error: &NlError<T, P>) -> bool {
+    match error {
+        NlError::Wrapped(e)
+        | NlError::Ser(SerError::Wrapped(e))
+        | NlError::De(DeError::Wrapped(e)) => is_transient_io(e),
+        _ => false,
+    }
+}
+
+impl NetlinkError {
+    /// EINTR/EAGAIN from the socket, which are worth retrying rather than giving up on
+    fn is_transient(&self) -> bool {
+        match self {
+            NetlinkError::OpenSocket(e) => matches!(
+                e.kind(),
+                io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+            ),
+            NetlinkError::Serialization(SerError::Wrapped(e)) => is_transient_io(e),
+            NetlinkError::NlInterfaceError(e) => is_transient_nl(e),
+            NetlinkError::NlQdiscError(e) => is_transient_nl(e),
+            _ => false,
+        }
+    }
+
+    /// An error the kernel sent back in reply to the request, rather than one talking to it
+    fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            NetlinkError::NlInterfaceError(NlError::Nlmsgerr(_))
+                | NetlinkError::NlQdiscError(NlError::Nlmsgerr(_))
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Qdisc {
+    handle: u32,
     ifindex: i32,
     parent: u32,
 }
 
+#[cfg(test)]
+impl Qdisc {
+    pub fn new(handle: u32, ifindex: i32, parent: u32) -> Self {
+        Qdisc {
+            handle,
+            ifindex,
+            parent,
+        }
+    }
+}
+
 #[derive(Deserialize, Copy, Clone, Default, Debug)]
 #[repr(C)]
 pub struct RtnlLinkStats64 {
@@ -83,11 +160,129 @@ pub struct RtnlLinkStats64 {
 
 pub enum TcaCake {
     BaseRate64 = 2,
+    FlowMode = 5,
+}
+
+// Handles and parents from linux/pkt_sched.h
+const TC_H_ROOT: u32 = 0xFFFF_FFFF;
+const TC_H_INGRESS: u32 = 0xFFFF_FFF1;
+const INGRESS_HANDLE: u32 = 0xFFFF_0000;
+
+/*
+ * Attributes and values for a u32 filter matching everything, with a mirred redirect
+ * action. That's what the sqm scripts use, and u32 is available on far more kernels
+ * than the matchall classifier.
+ */
+const TCA_U32_SEL: u16 = 5;
+const TCA_U32_ACT: u16 = 7;
+const TC_U32_TERMINAL: u8 = 1;
+// The first filter node in u32's root hash table, 800::800
+const U32_HANDLE: u32 = 0x8000_0800;
+const TCA_ACT_KIND: u16 = 1;
+const TCA_ACT_OPTIONS: u16 = 2;
+const TCA_MIRRED_PARMS: u16 = 2;
+const TC_ACT_STOLEN: i32 = 4;
+const TCA_EGRESS_REDIR: i32 = 1;
+const ETH_P_ALL: u16 = 0x0003;
+
+// Rtattr isn't Clone, but a request needs its own copy of the attributes for every attempt
+fn copy_rtattr(attr: &Rtattr<Tca, Buffer>) -> Rtattr<Tca, Buffer> {
+    Rtattr {
+        rta_len: attr.rta_len,
+        rta_type: attr.rta_type,
+        rta_payload: Buffer::from(attr.rta_payload.as_ref().to_vec()),
+    }
 }
 
-pub struct Netlink {}
+/*
+ * Runs a request on the socket, retrying transient errors. After any error the socket
+ * is dropped, as there's no telling what's still queued up on it, and neli's tracking
+ * of outstanding acks is off too. The next attempt connects a fresh one.
+ */
+fn request_with<S, T>(
+    socket: &mut Option<S>,
+    connect: impl Fn() -> io::Result<S>,
+    operation: &str,
+    f: impl Fn(&mut S) -> Result<T, NetlinkError>,
+) -> Result<T, NetlinkError> {
+    let mut attempt = 0;
+
+    loop {
+        if socket.is_none() {
+            match connect() {
+                Ok(connected) => *socket = Some(connected),
+                Err(e) => {
+                    let e = NetlinkError::OpenSocket(e);
+                    if e.is_transient() && attempt < NETLINK_RETRIES {
+                        attempt += 1;
+                        continue;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let result = f(socket.as_mut().unwrap());
+        if result.is_err() {
+            *socket = None;
+        }
+
+        match result {
+            Err(e) if e.is_transient() && attempt < NETLINK_RETRIES => {
+                attempt += 1;
+                debug!(
+                    "Transient Netlink error during {}, retrying ({}/{}): {}",
+                    operation, attempt, NETLINK_RETRIES, e
+                );
+            }
+            result => return result,
+        }
+    }
+}
+
+/*
+ * Holds on to a single Netlink socket, rather than connecting a new one for every
+ * request, which adds up with the ratecontroller reading the interface stats every tick.
+ * The socket is connected on first use.
+ */
+#[derive(Default)]
+pub struct Netlink {
+    socket: Option<NlSocketHandle>,
+}
 
 impl Netlink {
+    pub fn new() -> Self {
+        Netlink { socket: None }
+    }
+
+    fn request<T>(
+        &mut self,
+        operation: &str,
+        f: impl Fn(&mut NlSocketHandle) -> Result<T, NetlinkError>,
+    ) -> Result<T, NetlinkError> {
+        request_with(
+            &mut self.socket,
+            || NlSocketHandle::connect(NlFamily::Route, None, &[]),
+            operation,
+            f,
+        )
+    }
+
+    // Like request, for changes whose acks are only read to keep the socket in sync
+    fn request_ignoring_rejection(
+        &mut self,
+        operation: &str,
+        f: impl Fn(&mut NlSocketHandle) -> Result<(), NetlinkError>,
+    ) -> Result<(), NetlinkError> {
+        match self.request(operation, f) {
+            Err(e) if e.is_rejection() => {
+                debug!("Kernel rejected {}: {}", operation, e);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
     fn nl_interface_get(socket: &mut NlSocketHandle, ifname: &str) -> Result<(), NetlinkError> {
         let mut attrs = RtBuffer::new();
 
@@ -121,63 +316,288 @@ impl Netlink {
         Ok(())
     }
 
-    pub fn find_interface(ifname: &str) -> Result<i32, NetlinkError> {
-        let mut socket = NlSocketHandle::connect(NlFamily::Route, None, &[]).unwrap();
+    pub fn find_interface(&mut self, ifname: &str) -> Result<i32, NetlinkError> {
+        self.request("find_interface", |socket| {
+            Self::find_interface_once(socket, ifname)
+        })
+    }
+
+    /*
+     * Like the other requests, this reads the replies up to the ack even once it has what it
+     * wants. Anything left on the socket would be read as the reply to the next request.
+     */
+    fn find_interface_once(socket: &mut NlSocketHandle, ifname: &str) -> Result<i32, NetlinkError> {
+        Self::nl_interface_get(socket, ifname)?;
 
-        Self::nl_interface_get(&mut socket, ifname)?;
+        let mut found = None;
 
         for response in socket.iter(false) {
             let header: Nlmsghdr<Rtm, Ifinfomsg> = response?;
 
-            if header.nl_type != Rtm::Newlink {
-                return Err(NetlinkError::WrongType {
-                    expected: Rtm::Newlink,
-                    found: header.nl_type,
-                });
+            if let NlPayload::Payload(p) = header.nl_payload {
+                if header.nl_type != Rtm::Newlink {
+                    return Err(NetlinkError::WrongType {
+                        expected: Rtm::Newlink,
+                        found: header.nl_type,
+                    });
+                }
+
+                found.get_or_insert(p.ifi_index);
             }
+        }
+
+        found.ok_or_else(|| NetlinkError::InterfaceNotFound(ifname.to_string()))
+    }
+
+    pub fn get_interface_stats(&mut self, ifname: &str) -> Result<RtnlLinkStats64, NetlinkError> {
+        self.request("get_interface_stats", |socket| {
+            Self::get_interface_stats_once(socket, ifname)
+        })
+    }
+
+    fn get_interface_stats_once(
+        socket: &mut NlSocketHandle,
+        ifname: &str,
+    ) -> Result<RtnlLinkStats64, NetlinkError> {
+        Self::nl_interface_get(socket, ifname)?;
+
+        let mut found = None;
+
+        for response in socket.iter(false) {
+            let header: Nlmsghdr<Rtm, Ifinfomsg> = response?;
 
             if let NlPayload::Payload(p) = header.nl_payload {
-                return Ok(p.ifi_index);
+                if header.nl_type != Rtm::Newlink {
+                    return Err(NetlinkError::WrongType {
+                        expected: Rtm::Newlink,
+                        found: header.nl_type,
+                    });
+                }
+
+                for attr in p.rtattrs.iter() {
+                    if attr.rta_type == Ifla::Stats64 && found.is_none() {
+                        let buf = attr.rta_payload.as_ref();
+
+                        let stats: RtnlLinkStats64 = deserialize(buf)?;
+
+                        found = Some(stats);
+                    }
+                }
             }
         }
 
-        // we shouldn't reach here
-        Err(NetlinkError::InterfaceNotFound(ifname.to_string()))
+        found.ok_or_else(|| NetlinkError::NoInterfaceStatsFound(ifname.to_string()))
     }
 
-    pub fn get_interface_stats(ifname: &str) -> Result<RtnlLinkStats64, NetlinkError> {
-        let mut socket = NlSocketHandle::connect(NlFamily::Route, None, &[])?;
+    fn qdisc_kind(tc_msg: &Tcmsg) -> Result<&str, NetlinkError> {
+        let mut kind = "";
 
-        Self::nl_interface_get(&mut socket, ifname)?;
+        for attr in tc_msg.rtattrs.iter() {
+            if attr.rta_type == Tca::Kind {
+                let buff = attr.rta_payload.as_ref();
+                // Null terminator is valid UTF-8, but breaks comparison, so we remove it
+                kind = std::str::from_utf8(buff)?.trim_end_matches('\0');
+            }
+        }
+
+        Ok(kind)
+    }
+
+    fn cake_ifindexes(&mut self) -> Result<Vec<i32>, NetlinkError> {
+        self.request("cake_ifindexes", Self::cake_ifindexes_once)
+    }
+
+    fn cake_ifindexes_once(socket: &mut NlSocketHandle) -> Result<Vec<i32>, NetlinkError> {
+        let tc_msg = Tcmsg::new(
+            u8::from(RtAddrFamily::Unspecified),
+            0,
+            0,
+            0,
+            0,
+            RtBuffer::new(),
+        );
+
+        let nlhdr = Nlmsghdr::new(
+            None,
+            Rtm::Getqdisc,
+            NlmFFlags::new(&[NlmF::Request, NlmF::Dump]),
+            None,
+            None,
+            NlPayload::Payload(tc_msg),
+        );
+
+        socket.send(nlhdr)?;
+
+        let mut ifindexes = Vec::new();
 
         for response in socket.iter(false) {
-            let header: Nlmsghdr<Rtm, Ifinfomsg> = response?;
+            let header: Nlmsghdr<Rtm, Tcmsg> = response?;
 
-            if header.nl_type != Rtm::Newlink {
-                return Err(NetlinkError::WrongType {
-                    expected: Rtm::Newlink,
-                    found: header.nl_type,
-                });
+            if let NlPayload::Payload(p) = header.nl_payload {
+                if Self::qdisc_kind(&p)? == "cake" && !ifindexes.contains(&p.tcm_ifindex) {
+                    ifindexes.push(p.tcm_ifindex);
+                }
             }
+        }
+
+        Ok(ifindexes)
+    }
+
+    fn default_route_ifindex(&mut self) -> Result<Option<i32>, NetlinkError> {
+        self.request("default_route_ifindex", Self::default_route_ifindex_once)
+    }
+
+    fn default_route_ifindex_once(
+        socket: &mut NlSocketHandle,
+    ) -> Result<Option<i32>, NetlinkError> {
+        let rt_msg = Rtmsg {
+            rtm_family: RtAddrFamily::Inet,
+            rtm_dst_len: 0,
+            rtm_src_len: 0,
+            rtm_tos: 0,
+            rtm_table: RtTable::Unspec,
+            rtm_protocol: Rtprot::Unspec,
+            rtm_scope: RtScope::Universe,
+            rtm_type: Rtn::Unspec,
+            rtm_flags: RtmFFlags::empty(),
+            rtattrs: RtBuffer::new(),
+        };
+
+        let nlhdr = Nlmsghdr::new(
+            None,
+            Rtm::Getroute,
+            NlmFFlags::new(&[NlmF::Request, NlmF::Dump]),
+            None,
+            None,
+            NlPayload::Payload(rt_msg),
+        );
+
+        socket.send(nlhdr)?;
+
+        let mut ifindex = None;
+
+        for response in socket.iter(false) {
+            let header: Nlmsghdr<Rtm, Rtmsg> = response?;
 
             if let NlPayload::Payload(p) = header.nl_payload {
+                // The default route is the one in the main table with a zero-length destination
+                if p.rtm_dst_len != 0 || p.rtm_table != RtTable::Main {
+                    continue;
+                }
+
                 for attr in p.rtattrs.iter() {
-                    if attr.rta_type == Ifla::Stats64 {
-                        let buf = attr.rta_payload.as_ref();
+                    if attr.rta_type == Rta::Oif && ifindex.is_none() {
+                        ifindex = Some(attr.get_payload_as::<i32>()?);
+                    }
+                }
+            }
+        }
 
-                        let stats: RtnlLinkStats64 = deserialize(buf)?;
+        Ok(ifindex)
+    }
+
+    fn ifname_from_ifindex(&mut self, ifindex: i32) -> Result<String, NetlinkError> {
+        self.request("ifname_from_ifindex", |socket| {
+            Self::ifname_from_ifindex_once(socket, ifindex)
+        })
+    }
 
-                        return Ok(stats);
+    fn ifname_from_ifindex_once(
+        socket: &mut NlSocketHandle,
+        ifindex: i32,
+    ) -> Result<String, NetlinkError> {
+        let if_msg = Ifinfomsg::new(
+            RtAddrFamily::Unspecified,
+            Arphrd::None,
+            ifindex,
+            IffFlags::empty(),
+            IffFlags::empty(),
+            RtBuffer::new(),
+        );
+
+        let nlhdr = Nlmsghdr::new(
+            None,
+            Rtm::Getlink,
+            NlmFFlags::new(&[NlmF::Request, NlmF::Ack]),
+            None,
+            None,
+            NlPayload::Payload(if_msg),
+        );
+
+        socket.send(nlhdr)?;
+
+        let mut found = None;
+
+        for response in socket.iter(false) {
+            let header: Nlmsghdr<Rtm, Ifinfomsg> = response?;
+
+            if let NlPayload::Payload(p) = header.nl_payload {
+                for attr in p.rtattrs.iter() {
+                    if attr.rta_type == Ifla::Ifname && found.is_none() {
+                        let buff = attr.rta_payload.as_ref();
+                        found = Some(
+                            std::str::from_utf8(buff)?
+                                .trim_end_matches('\0')
+                                .to_string(),
+                        );
                     }
                 }
             }
         }
 
-        Err(NetlinkError::NoInterfaceStatsFound(ifname.to_string()))
+        found.ok_or_else(|| NetlinkError::InterfaceNotFound(ifindex.to_string()))
+    }
+
+    /*
+     * Guesses the (download, upload) interfaces for the common setup:
+     * CAKE on the interface holding the default route for upload, and
+     * CAKE on a single IFB interface for download. Anything else is
+     * reported as a failure, so the user can configure the names explicitly.
+     */
+    pub fn detect_shaped_interfaces(&mut self) -> Result<(String, String), NetlinkError> {
+        let wan_ifindex = self
+            .default_route_ifindex()?
+            .ok_or_else(|| NetlinkError::AutodetectFailed("no IPv4 default route".to_string()))?;
+        let wan_ifname = self.ifname_from_ifindex(wan_ifindex)?;
+        let cake_ifindexes = self.cake_ifindexes()?;
+
+        if !cake_ifindexes.contains(&wan_ifindex) {
+            return Err(NetlinkError::AutodetectFailed(format!(
+                "no CAKE qdisc on WAN interface {}",
+                wan_ifname
+            )));
+        }
+
+        let mut ifb_ifnames = Vec::new();
+        for ifindex in cake_ifindexes.into_iter().filter(|i| *i != wan_ifindex) {
+            let ifname = self.ifname_from_ifindex(ifindex)?;
+            if ifname.starts_with("ifb") {
+                ifb_ifnames.push(ifname);
+            }
+        }
+
+        match ifb_ifnames.len() {
+            1 => Ok((ifb_ifnames.remove(0), wan_ifname)),
+            0 => Err(NetlinkError::AutodetectFailed(
+                "no IFB interface with a CAKE qdisc found".to_string(),
+            )),
+            _ => Err(NetlinkError::AutodetectFailed(format!(
+                "multiple IFB interfaces with CAKE qdiscs found: {}",
+                ifb_ifnames.join(", ")
+            ))),
+        }
+    }
+
+    pub fn qdisc_from_ifindex(&mut self, ifindex: i32) -> Result<Qdisc, NetlinkError> {
+        self.request("qdisc_from_ifindex", |socket| {
+            Self::qdisc_from_ifindex_once(socket, ifindex)
+        })
     }
 
-    pub fn qdisc_from_ifindex(ifindex: i32) -> Result<Qdisc, NetlinkError> {
-        let mut socket = NlSocketHandle::connect(NlFamily::Route, None, &[])?;
+    fn qdisc_from_ifindex_once(
+        socket: &mut NlSocketHandle,
+        ifindex: i32,
+    ) -> Result<Qdisc, NetlinkError> {
         let tc_msg = Tcmsg::new(
             u8::from(RtAddrFamily::Unspecified),
             0,
@@ -200,6 +620,15 @@ impl Netlink {
             return Err(NetlinkError::Serialization(e));
         }
 
+        /*
+         * A dump of every qdisc on the system easily spans several netlink messages,
+         * and several recv() calls. The iterator keeps reading until the kernel sends
+         * Nlmsg::Done, so we see every part of the dump. Keep draining it even after a
+         * match, rather than returning with the rest of the dump still queued up on the
+         * socket, where it'd be read as the reply to whatever request comes next.
+         */
+        let mut found: Option<Qdisc> = None;
+
         for response in socket.iter(false) {
             let header: Nlmsghdr<Rtm, Tcmsg> = response?;
 
@@ -211,52 +640,122 @@ impl Netlink {
                     });
                 }
 
-                if p.tcm_ifindex == ifindex {
-                    let mut _type = "";
+                if found.is_none() && p.tcm_ifindex == ifindex && Self::qdisc_kind(&p)? == "cake" {
+                    found = Some(Qdisc {
+                        handle: p.tcm_handle,
+                        ifindex: p.tcm_ifindex,
+                        parent: p.tcm_parent,
+                    });
+                }
+            }
+        }
 
-                    for attr in p.rtattrs.iter() {
-                        if attr.rta_type == Tca::Kind {
-                            let buff = attr.rta_payload.as_ref();
-                            _type = std::str::from_utf8(buff)?.trim_end_matches('\0');
-                            // Null terminator is valid UTF-8, but breaks comparison, so we remove it
-                        }
-                    }
+        found.ok_or_else(|| NetlinkError::NoQdiscFound(ifindex.to_string()))
+    }
+
+    pub fn qdisc_from_ifname(&mut self, ifname: &str) -> Result<Qdisc, NetlinkError> {
+        let ifindex = self.find_interface(ifname)?;
+        self.qdisc_from_ifindex(ifindex)
+    }
 
-                    let type_to_look_for = "cake";
+    /// The bandwidth the CAKE qdisc is currently shaping to, in kbit/s
+    pub fn get_qdisc_rate(&mut self, qdisc: Qdisc) -> Result<u64, NetlinkError> {
+        self.request("get_qdisc_rate", |socket| {
+            Self::get_qdisc_rate_once(socket, qdisc)
+        })
+    }
+
+    fn get_qdisc_rate_once(socket: &mut NlSocketHandle, qdisc: Qdisc) -> Result<u64, NetlinkError> {
+        let tc_msg = Tcmsg::new(
+            u8::from(RtAddrFamily::Unspecified),
+            0,
+            0,
+            0,
+            0,
+            RtBuffer::new(),
+        );
+
+        let nlhdr = Nlmsghdr::new(
+            None,
+            Rtm::Getqdisc,
+            NlmFFlags::new(&[NlmF::Request, NlmF::Dump]),
+            None,
+            None,
+            NlPayload::Payload(tc_msg),
+        );
+
+        socket.send(nlhdr)?;
+
+        let mut bandwidth = None;
+
+        for response in socket.iter(false) {
+            let header: Nlmsghdr<Rtm, Tcmsg> = response?;
 
-                    if _type.eq(type_to_look_for) {
-                        let qdisc = Qdisc {
-                            ifindex: p.tcm_ifindex as i32,
-                            parent: p.tcm_parent,
-                        };
+            if let NlPayload::Payload(p) = header.nl_payload {
+                if bandwidth.is_some()
+                    || p.tcm_ifindex != qdisc.ifindex
+                    || p.tcm_handle != qdisc.handle
+                    || Self::qdisc_kind(&p)? != "cake"
+                {
+                    continue;
+                }
 
-                        return Ok(qdisc);
+                // The CAKE options are nested in TCA_OPTIONS, the same way set_cake_option_once sends them
+                for attr in p.rtattrs.iter() {
+                    if attr.rta_type == Tca::Options {
+                        let options = attr.get_attr_handle::<u16>()?;
+                        bandwidth = options
+                            .get_attr_payload_as::<u64>(TcaCake::BaseRate64 as u16)
+                            .ok();
                     }
                 }
             }
         }
 
-        Err(NetlinkError::NoQdiscFound(ifindex.to_string()))
+        // A bandwidth of 0 means CAKE isn't shaping at all, which is no rate to go by either
+        match bandwidth {
+            Some(bandwidth) if bandwidth > 0 => Ok(bandwidth * 8 / 1000),
+            _ => Err(NetlinkError::NoQdiscRate(qdisc.ifindex.to_string())),
+        }
     }
 
-    pub fn qdisc_from_ifname(ifname: &str) -> Result<Qdisc, NetlinkError> {
-        let ifindex = Netlink::find_interface(ifname)?;
-        Netlink::qdisc_from_ifindex(ifindex)
+    /*
+     * A rate change the kernel rejects, say because the qdisc has gone away in the
+     * meantime, is ignored. The periodic qdisc check picks up on a replaced qdisc.
+     */
+    pub fn set_qdisc_rate(
+        &mut self,
+        qdisc: Qdisc,
+        bandwidth_kbit: u64,
+    ) -> Result<(), NetlinkError> {
+        let bandwidth = bandwidth_kbit * 1000 / 8;
+        self.request_ignoring_rejection("set_qdisc_rate", |socket| {
+            Self::set_cake_option_once(socket, qdisc, TcaCake::BaseRate64, bandwidth)
+        })
     }
 
-    pub fn set_qdisc_rate(qdisc: Qdisc, bandwidth_kbit: u64) -> Result<(), NetlinkError> {
-        let mut socket = NlSocketHandle::connect(NlFamily::Route, None, &[])?;
-        let bandwidth = bandwidth_kbit * 1000 / 8;
+    pub fn set_qdisc_flow_mode(
+        &mut self,
+        qdisc: Qdisc,
+        flow_mode: u32,
+    ) -> Result<(), NetlinkError> {
+        self.request_ignoring_rejection("set_qdisc_flow_mode", |socket| {
+            Self::set_cake_option_once(socket, qdisc, TcaCake::FlowMode, flow_mode)
+        })
+    }
 
+    // Changes a single CAKE option, leaving the rest of the qdisc's configuration as it is
+    fn set_cake_option_once<P: Size + ToBytes>(
+        socket: &mut NlSocketHandle,
+        qdisc: Qdisc,
+        option: TcaCake,
+        value: P,
+    ) -> Result<(), NetlinkError> {
         let mut attrs = RtBuffer::new();
 
         let attr_type = Rtattr::new(None, Tca::Kind, "cake")?;
         let mut attr_options = Rtattr::new(None, Tca::Options, Buffer::from(Vec::new()))?;
-        attr_options.add_nested_attribute(&Rtattr::new(
-            None,
-            TcaCake::BaseRate64 as u16,
-            bandwidth,
-        )?)?;
+        attr_options.add_nested_attribute(&Rtattr::new(None, option as u16, value)?)?;
 
         attrs.push(attr_type);
         attrs.push(attr_options);
@@ -280,6 +779,450 @@ impl Netlink {
         );
 
         socket.send(nlhdr)?;
+        for response in socket.iter::<Rtm, Tcmsg>(false) {
+            response?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Sets up ingress shaping the way the sqm scripts do, for systems without them:
+     *
+     *   1. RTM_NEWLINK creates the IFB device (IFLA_LINKINFO/IFLA_INFO_KIND "ifb") and brings it up
+     *   2. RTM_NEWQDISC attaches an ingress qdisc (ffff:) to the WAN interface
+     *   3. RTM_NEWTFILTER adds a u32 filter on it matching everything, with a mirred action
+     *      redirecting all that arrives on the WAN interface to the egress of the IFB
+     *   4. RTM_NEWQDISC adds CAKE as the root qdisc on both, if it isn't there already
+     *
+     * Everything is created with NLM_F_EXCL and fixed handles, so anything that's already
+     * in place is left alone and running this again is harmless. Like with the rate changes,
+     * errors in the acks are ignored, a missing CAKE qdisc shows up when looking it up afterwards.
+     */
+    pub fn setup_ingress_shaping(
+        &mut self,
+        wan_ifname: &str,
+        ifb_ifname: &str,
+        download_kbit: u64,
+        upload_kbit: u64,
+    ) -> Result<(), NetlinkError> {
+        if self.find_interface(ifb_ifname).is_err() {
+            self.create_ifb(ifb_ifname)?;
+        }
+        let ifb_ifindex = self.find_interface(ifb_ifname)?;
+        let wan_ifindex = self.find_interface(wan_ifname)?;
+        self.set_link_up(ifb_ifindex)?;
+
+        self.send_tc(
+            Rtm::Newqdisc,
+            wan_ifindex,
+            INGRESS_HANDLE,
+            TC_H_INGRESS,
+            0,
+            "ingress",
+            None,
+        )?;
+        self.add_ingress_redirect(wan_ifindex, ifb_ifindex)?;
+
+        let cake_ifindexes = self.cake_ifindexes()?;
+        for (ifindex, bandwidth_kbit) in [(ifb_ifindex, download_kbit), (wan_ifindex, upload_kbit)]
+        {
+            if cake_ifindexes.contains(&ifindex) {
+                continue;
+            }
+            let mut options = Rtattr::new(None, Tca::Options, Buffer::from(Vec::new()))?;
+            options.add_nested_attribute(&Rtattr::new(
+                None,
+                TcaCake::BaseRate64 as u16,
+                bandwidth_kbit * 1000 / 8,
+            )?)?;
+            self.send_tc(
+                Rtm::Newqdisc,
+                ifindex,
+                0,
+                TC_H_ROOT,
+                0,
+                "cake",
+                Some(options),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn create_ifb(&mut self, ifname: &str) -> Result<(), NetlinkError> {
+        self.request("create_ifb", |socket| Self::create_ifb_once(socket, ifname))
+    }
+
+    fn create_ifb_once(socket: &mut NlSocketHandle, ifname: &str) -> Result<(), NetlinkError> {
+        let mut attrs = RtBuffer::new();
+        let mut attr_linkinfo = Rtattr::new(None, Ifla::Linkinfo, Buffer::from(Vec::new()))?;
+        attr_linkinfo.add_nested_attribute(&Rtattr::new(None, IflaInfo::Kind, "ifb")?)?;
+        attrs.push(Rtattr::new(None, Ifla::Ifname, ifname)?);
+        attrs.push(attr_linkinfo);
+
+        let if_msg = Ifinfomsg::new(
+            RtAddrFamily::Unspecified,
+            Arphrd::None,
+            0,
+            IffFlags::empty(),
+            IffFlags::empty(),
+            attrs,
+        );
+
+        let nlhdr = Nlmsghdr::new(
+            None,
+            Rtm::Newlink,
+            NlmFFlags::new(&[NlmF::Request, NlmF::Create, NlmF::Excl, NlmF::Ack]),
+            None,
+            None,
+            NlPayload::Payload(if_msg),
+        );
+
+        socket.send(nlhdr)?;
+        // Wait for the ack, so the interface exists by the time we look it up
+        for response in socket.iter::<Rtm, Ifinfomsg>(false) {
+            response?;
+        }
+
         Ok(())
     }
+
+    fn set_link_up(&mut self, ifindex: i32) -> Result<(), NetlinkError> {
+        self.request_ignoring_rejection("set_link_up", |socket| {
+            Self::set_link_up_once(socket, ifindex)
+        })
+    }
+
+    fn set_link_up_once(socket: &mut NlSocketHandle, ifindex: i32) -> Result<(), NetlinkError> {
+        let if_msg = Ifinfomsg::new(
+            RtAddrFamily::Unspecified,
+            Arphrd::None,
+            ifindex,
+            IffFlags::new(&[Iff::Up]),
+            IffFlags::new(&[Iff::Up]),
+            RtBuffer::new(),
+        );
+
+        let nlhdr = Nlmsghdr::new(
+            None,
+            Rtm::Newlink,
+            NlmFFlags::new(&[NlmF::Request, NlmF::Ack]),
+            None,
+            None,
+            NlPayload::Payload(if_msg),
+        );
+
+        socket.send(nlhdr)?;
+        for response in socket.iter::<Rtm, Ifinfomsg>(false) {
+            response?;
+        }
+
+        Ok(())
+    }
+
+    fn add_ingress_redirect(
+        &mut self,
+        wan_ifindex: i32,
+        ifb_ifindex: i32,
+    ) -> Result<(), NetlinkError> {
+        // struct tc_mirred: index, capab, action, refcnt, bindcnt, eaction, ifindex
+        let mut mirred_parms = Vec::with_capacity(28);
+        for value in [0, 0, TC_ACT_STOLEN, 0, 0, TCA_EGRESS_REDIR, ifb_ifindex] {
+            mirred_parms.extend_from_slice(&value.to_ne_bytes());
+        }
+
+        let mut attr_act_options = Rtattr::new(None, TCA_ACT_OPTIONS, Buffer::from(Vec::new()))?;
+        attr_act_options.add_nested_attribute(&Rtattr::new(
+            None,
+            TCA_MIRRED_PARMS,
+            Buffer::from(mirred_parms),
+        )?)?;
+
+        // Actions are a list, nested under their position starting from 1
+        let mut attr_action = Rtattr::new(None, 1_u16, Buffer::from(Vec::new()))?;
+        attr_action.add_nested_attribute(&Rtattr::new(None, TCA_ACT_KIND, "mirred")?)?;
+        attr_action.add_nested_attribute(&attr_act_options)?;
+
+        let mut attr_actions = Rtattr::new(None, TCA_U32_ACT, Buffer::from(Vec::new()))?;
+        attr_actions.add_nested_attribute(&attr_action)?;
+
+        /*
+         * struct tc_u32_sel with a single key, all zeroes apart from the flags and key count.
+         * A key with a zero mask matches any packet, same as `u32 match u32 0 0`.
+         */
+        let mut selector = vec![0_u8; 32];
+        selector[0] = TC_U32_TERMINAL;
+        selector[2] = 1;
+
+        let mut options = Rtattr::new(None, Tca::Options, Buffer::from(Vec::new()))?;
+        options.add_nested_attribute(&Rtattr::new(None, TCA_U32_SEL, Buffer::from(selector))?)?;
+        options.add_nested_attribute(&attr_actions)?;
+
+        // For filters, tcm_info holds the priority and the protocol in network byte order
+        let info = (1 << 16) | ETH_P_ALL.to_be() as u32;
+        self.send_tc(
+            Rtm::Newtfilter,
+            wan_ifindex,
+            U32_HANDLE,
+            INGRESS_HANDLE,
+            info,
+            "u32",
+            Some(options),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn send_tc(
+        &mut self,
+        msg_type: Rtm,
+        ifindex: i32,
+        handle: u32,
+        parent: u32,
+        info: u32,
+        kind: &str,
+        options: Option<Rtattr<Tca, Buffer>>,
+    ) -> Result<(), NetlinkError> {
+        self.request_ignoring_rejection("send_tc", |socket| {
+            Self::send_tc_once(
+                socket,
+                msg_type,
+                ifindex,
+                handle,
+                parent,
+                info,
+                kind,
+                options.as_ref().map(copy_rtattr),
+            )
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn send_tc_once(
+        socket: &mut NlSocketHandle,
+        msg_type: Rtm,
+        ifindex: i32,
+        handle: u32,
+        parent: u32,
+        info: u32,
+        kind: &str,
+        options: Option<Rtattr<Tca, Buffer>>,
+    ) -> Result<(), NetlinkError> {
+        let mut attrs = RtBuffer::new();
+        attrs.push(Rtattr::new(None, Tca::Kind, kind)?);
+        if let Some(options) = options {
+            attrs.push(options);
+        }
+
+        let tc_msg = Tcmsg::new(
+            u8::from(RtAddrFamily::Unspecified),
+            ifindex,
+            handle,
+            parent,
+            info,
+            attrs,
+        );
+
+        let nlhdr = Nlmsghdr::new(
+            None,
+            msg_type,
+            NlmFFlags::new(&[NlmF::Request, NlmF::Create, NlmF::Excl, NlmF::Ack]),
+            None,
+            None,
+            NlPayload::Payload(tc_msg),
+        );
+
+        socket.send(nlhdr)?;
+        for response in socket.iter::<Rtm, Tcmsg>(false) {
+            response?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The parts of Netlink rate control uses, so it can be run against a stand-in in tests
+pub trait Shaper: Send {
+    fn get_interface_stats(&mut self, ifname: &str) -> Result<RtnlLinkStats64, NetlinkError>;
+    fn get_qdisc_rate(&mut self, qdisc: Qdisc) -> Result<u64, NetlinkError>;
+    fn qdisc_from_ifname(&mut self, ifname: &str) -> Result<Qdisc, NetlinkError>;
+    fn set_qdisc_rate(&mut self, qdisc: Qdisc, bandwidth_kbit: u64) -> Result<(), NetlinkError>;
+}
+
+impl Shaper for Netlink {
+    fn get_interface_stats(&mut self, ifname: &str) -> Result<RtnlLinkStats64, NetlinkError> {
+        Netlink::get_interface_stats(self, ifname)
+    }
+
+    fn get_qdisc_rate(&mut self, qdisc: Qdisc) -> Result<u64, NetlinkError> {
+        Netlink::get_qdisc_rate(self, qdisc)
+    }
+
+    fn qdisc_from_ifname(&mut self, ifname: &str) -> Result<Qdisc, NetlinkError> {
+        Netlink::qdisc_from_ifname(self, ifname)
+    }
+
+    fn set_qdisc_rate(&mut self, qdisc: Qdisc, bandwidth_kbit: u64) -> Result<(), NetlinkError> {
+        Netlink::set_qdisc_rate(self, qdisc, bandwidth_kbit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use neli::consts::nl::Nlmsg;
+    use socket2::{Domain, Socket, Type};
+    use std::cell::Cell;
+    use std::io::Cursor;
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    fn interrupted() -> io::Error {
+        io::Error::from(io::ErrorKind::Interrupted)
+    }
+
+    #[test]
+    fn transient_errors_retry_on_a_fresh_socket() {
+        // Sockets are numbered in the order they're connected
+        let connects = Cell::new(0);
+        let connect = || {
+            connects.set(connects.get() + 1);
+            Ok(connects.get())
+        };
+        let mut socket = None;
+
+        let result = request_with(&mut socket, connect, "test", |socket| match *socket {
+            1 | 2 => Err(NetlinkError::OpenSocket(interrupted())),
+            socket => Ok(socket),
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(socket, Some(3));
+
+        // A working socket is kept for the next request
+        request_with(&mut socket, connect, "test", |_| Ok(())).unwrap();
+        assert_eq!(connects.get(), 3);
+    }
+
+    #[test]
+    fn retries_are_limited() {
+        let connects = Cell::new(0);
+        let connect = || {
+            connects.set(connects.get() + 1);
+            Ok(connects.get())
+        };
+        let mut socket = None;
+
+        let result: Result<(), _> = request_with(&mut socket, connect, "test", |_| {
+            Err(NetlinkError::OpenSocket(interrupted()))
+        });
+        assert!(result.is_err());
+        assert_eq!(connects.get(), NETLINK_RETRIES + 1);
+        assert_eq!(socket, None);
+
+        // Anything else isn't retried at all, but the socket is still dropped
+        connects.set(0);
+        let result: Result<(), _> = request_with(&mut socket, connect, "test", |_| {
+            Err(NetlinkError::NoQdiscFound("test".to_string()))
+        });
+        assert!(matches!(result, Err(NetlinkError::NoQdiscFound(_))));
+        assert_eq!(connects.get(), 1);
+        assert_eq!(socket, None);
+    }
+
+    #[test]
+    fn connect_is_retried() {
+        let connects = Cell::new(0);
+        let connect = || {
+            connects.set(connects.get() + 1);
+            match connects.get() {
+                1 => Err(interrupted()),
+                _ => Ok(connects.get()),
+            }
+        };
+        let mut socket = None;
+
+        assert_eq!(
+            request_with(&mut socket, connect, "test", |socket| Ok(*socket)).unwrap(),
+            2
+        );
+    }
+
+    // One message of a qdisc dump, as the kernel sends them
+    fn dump_message(nl_type: Rtm, payload: NlPayload<Rtm, Tcmsg>) -> Vec<u8> {
+        let nlhdr = Nlmsghdr::new(
+            None,
+            nl_type,
+            NlmFFlags::new(&[NlmF::Multi]),
+            None,
+            None,
+            payload,
+        );
+        let mut buffer = Cursor::new(Vec::new());
+        nlhdr.to_bytes(&mut buffer).unwrap();
+        buffer.into_inner()
+    }
+
+    fn newqdisc(ifindex: i32, handle: u32, kind: &str) -> Vec<u8> {
+        let mut attrs = RtBuffer::new();
+        attrs.push(Rtattr::new(None, Tca::Kind, kind).unwrap());
+        let tc_msg = Tcmsg::new(0, ifindex, handle, TC_H_ROOT, 0, attrs);
+        dump_message(Rtm::Newqdisc, NlPayload::Payload(tc_msg))
+    }
+
+    /*
+     * A socket with a dump of the qdiscs on four interfaces queued up on it, split over
+     * several datagrams like a dump too big for a single recv() is.
+     */
+    fn dump_socket() -> (NlSocketHandle, Socket) {
+        let (ours, kernel) = Socket::pair(Domain::UNIX, Type::DGRAM, None).unwrap();
+        let done = dump_message(Rtm::from(u16::from(Nlmsg::Done)), NlPayload::Empty);
+
+        for datagram in [
+            [
+                newqdisc(1, 0, "noqueue"),
+                newqdisc(2, 0x8001_0000, "fq_codel"),
+            ]
+            .concat(),
+            [
+                newqdisc(3, 0x8002_0000, "cake"),
+                newqdisc(4, 0x8003_0000, "cake"),
+            ]
+            .concat(),
+            done,
+        ] {
+            kernel.send(&datagram).unwrap();
+        }
+
+        let socket = unsafe { NlSocketHandle::from_raw_fd(ours.into_raw_fd()) };
+        (socket, kernel)
+    }
+
+    #[test]
+    fn qdisc_is_found_in_a_multi_part_dump() {
+        let (mut socket, _kernel) = dump_socket();
+
+        let qdisc = Netlink::qdisc_from_ifindex_once(&mut socket, 3).unwrap();
+        assert_eq!(qdisc, Qdisc::new(0x8002_0000, 3, TC_H_ROOT));
+
+        // The rest of the dump was read too, rather than left for the next request to trip over
+        socket.nonblock().unwrap();
+        assert!(socket.recv::<Rtm, Tcmsg>().unwrap().is_none());
+    }
+
+    #[test]
+    fn qdisc_missing_from_the_dump() {
+        let (mut socket, _kernel) = dump_socket();
+
+        // There's a qdisc on the interface, but it isn't CAKE
+        assert!(matches!(
+            Netlink::qdisc_from_ifindex_once(&mut socket, 2),
+            Err(NetlinkError::NoQdiscFound(_))
+        ));
+    }
+
+    #[test]
+    fn cake_interfaces_are_picked_from_the_dump() {
+        let (mut socket, _kernel) = dump_socket();
+
+        assert_eq!(Netlink::cake_ifindexes_once(&mut socket).unwrap(), [3, 4]);
+    }
 }
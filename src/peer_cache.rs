@@ -0,0 +1,438 @@
+use crate::config::MeasurementType;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+// Bumped whenever the format changes, so an older or newer file is ignored rather than misread
+const PEER_CACHE_VERSION: u32 = 1;
+const HEADER_PREFIX: &str = "# sqm-autorate peer cache";
+
+/*
+ * How long what was found out about a reflector is trusted for. Older entries are left
+ * out on load, so a reflector that was silent gets another chance, and one that answered
+ * has to prove itself again like any other.
+ */
+const CAPABILITY_MAX_AGE_S: u64 = 7 * 86400;
+
+// Seconds since the Unix epoch, which is what the cache is timestamped with
+pub fn now_s() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PeerCacheError {
+    #[error("The peer cache is corrupt: {0}")]
+    Corrupt(String),
+    #[error("The peer cache is version {0}, expected {PEER_CACHE_VERSION}")]
+    WrongVersion(u32),
+    #[error("The peer cache was written {0} s ago, more than the maximum age")]
+    TooOld(u64),
+}
+
+/*
+ * FNV-1a over the entries. It's only there to catch a truncated or hand-edited file,
+ * not to stand up to anyone set on tampering with it.
+ */
+fn checksum(body: &str) -> u64 {
+    body.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/*
+ * The header line is "# sqm-autorate peer cache v<version> written=<unix s> checksum=<hex>".
+ * Returns the version, when it was written, and the checksum.
+ */
+fn parse_header(header: &str) -> Option<(u32, u64, u64)> {
+    let mut fields = header.strip_prefix(HEADER_PREFIX)?.split_whitespace();
+    let version = fields.next()?.strip_prefix('v')?.parse().ok()?;
+    let written_s = fields.next()?.strip_prefix("written=")?.parse().ok()?;
+    let checksum = u64::from_str_radix(fields.next()?.strip_prefix("checksum=")?, 16).ok()?;
+    Some((version, written_s, checksum))
+}
+
+// The survey report names the measurement types the same way
+fn type_name(type_: MeasurementType) -> String {
+    format!("{:?}", type_)
+}
+
+/// Whether a reflector answers a type of probe, and when that was last checked
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Capability {
+    answers: bool,
+    peer: bool,
+    checked_s: u64,
+}
+
+/*
+ * The peers and which reflectors answer the probes, kept across restarts. It's kept per
+ * measurement type, as a reflector that only answers echo requests is no use for
+ * timestamps, and switching types shouldn't throw away what was found out about the other.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct PeerCache {
+    entries: HashMap<(IpAddr, String), Capability>,
+}
+
+impl PeerCache {
+    /*
+     * Loads a saved cache, or starts an empty one if there isn't one yet. A cache from
+     * another version, one that doesn't match its checksum, or one written more than
+     * max_age seconds ago is refused, as it could seed the peers with bad ones.
+     * A max_age of 0 accepts a cache of any age.
+     */
+    pub fn load(path: &str, now_s: u64, max_age: f64) -> anyhow::Result<Self> {
+        let mut cache = Self::default();
+        if !Path::new(path).exists() {
+            return Ok(cache);
+        }
+
+        let contents = fs::read_to_string(path)?;
+        let (header, body) = contents
+            .split_once('\n')
+            .ok_or_else(|| PeerCacheError::Corrupt("no entries".to_string()))?;
+        let (version, written_s, expected) = parse_header(header)
+            .ok_or_else(|| PeerCacheError::Corrupt("invalid header".to_string()))?;
+        if version != PEER_CACHE_VERSION {
+            return Err(PeerCacheError::WrongVersion(version).into());
+        }
+        if checksum(body) != expected {
+            return Err(PeerCacheError::Corrupt("checksum mismatch".to_string()).into());
+        }
+        let age_s = now_s.saturating_sub(written_s);
+        if max_age > 0.0 && age_s as f64 > max_age {
+            return Err(PeerCacheError::TooOld(age_s).into());
+        }
+
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(body.as_bytes());
+
+        for record in reader.records() {
+            let record = record?;
+            let capability = Capability {
+                peer: record[2].parse()?,
+                answers: record[3].parse()?,
+                checked_s: record[4].parse()?,
+            };
+            if now_s.saturating_sub(capability.checked_s) > CAPABILITY_MAX_AGE_S {
+                continue;
+            }
+            cache
+                .entries
+                .insert((record[0].parse()?, record[1].to_string()), capability);
+        }
+
+        Ok(cache)
+    }
+
+    // Written next to the file and renamed into place, so a crash never leaves half a cache
+    pub fn save(&self, path: &str, now_s: u64) -> io::Result<()> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut body = String::from("reflector,measurement_type,peer,answers,checked_s\n");
+        for ((reflector, type_), capability) in entries {
+            let _ = writeln!(
+                body,
+                "{},{},{},{},{}",
+                reflector, type_, capability.peer, capability.answers, capability.checked_s
+            );
+        }
+        let out = format!(
+            "{} v{} written={} checksum={:016x}\n{}",
+            HEADER_PREFIX,
+            PEER_CACHE_VERSION,
+            now_s,
+            checksum(&body),
+            body
+        );
+
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(tmp_path.as_str(), out)?;
+        fs::rename(tmp_path.as_str(), path)
+    }
+
+    fn matching(
+        &self,
+        type_: MeasurementType,
+        filter: fn(&Capability) -> bool,
+    ) -> impl Iterator<Item = IpAddr> + '_ {
+        let name = type_name(type_);
+        self.entries
+            .iter()
+            .filter(move |((_, entry_type), capability)| *entry_type == name && filter(capability))
+            .map(|((reflector, _), _)| *reflector)
+    }
+
+    /// The reflectors known not to answer this type of probe
+    pub fn silent(&self, type_: MeasurementType) -> HashSet<IpAddr> {
+        self.matching(type_, |capability| !capability.answers)
+            .collect()
+    }
+
+    /*
+     * The peers to start out with: the cached ones that are still in the pool, topped up
+     * with the initial peers that aren't known to be silent. Empty if the cache has no
+     * peers to offer, in which case the initial peers are better left as they are.
+     */
+    pub fn warm_start(
+        &self,
+        type_: MeasurementType,
+        pool: &[IpAddr],
+        initial_peers: &[IpAddr],
+        num_reflectors: usize,
+    ) -> Vec<IpAddr> {
+        let mut peers: Vec<IpAddr> = self
+            .matching(type_, |capability| capability.answers && capability.peer)
+            .filter(|reflector| pool.contains(reflector))
+            .collect();
+        if peers.is_empty() {
+            return peers;
+        }
+        peers.sort();
+        peers.truncate(num_reflectors);
+
+        let silent = self.silent(type_);
+        for reflector in initial_peers {
+            if peers.len() >= num_reflectors {
+                break;
+            }
+            if !peers.contains(reflector) && !silent.contains(reflector) {
+                peers.push(*reflector);
+            }
+        }
+
+        peers
+    }
+
+    /*
+     * Records what's known right now. The answering reflectors are checked as of now, while
+     * a silent one keeps the time it was first found silent, so it's given another chance
+     * once that ages out rather than being put off for good.
+     */
+    pub fn record(
+        &mut self,
+        type_: MeasurementType,
+        peers: &[IpAddr],
+        answering: &[IpAddr],
+        silent: &HashSet<IpAddr>,
+        now_s: u64,
+    ) {
+        let name = type_name(type_);
+        for ((_, entry_type), capability) in self.entries.iter_mut() {
+            if *entry_type == name {
+                capability.peer = false;
+            }
+        }
+
+        for reflector in answering {
+            self.entries.insert(
+                (*reflector, name.clone()),
+                Capability {
+                    answers: true,
+                    peer: peers.contains(reflector),
+                    checked_s: now_s,
+                },
+            );
+        }
+
+        for reflector in silent
+            .iter()
+            .filter(|reflector| !answering.contains(reflector))
+        {
+            let capability = self
+                .entries
+                .entry((*reflector, name.clone()))
+                .or_insert(Capability {
+                    answers: false,
+                    peer: false,
+                    checked_s: now_s,
+                });
+            if capability.answers {
+                *capability = Capability {
+                    answers: false,
+                    peer: false,
+                    checked_s: now_s,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn reflector(i: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, i])
+    }
+
+    fn cache_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!(
+                "sqma-peer-cache-{}-{}.csv",
+                name,
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // Saves a cache with a peer in it, lets f have a go at the file, and loads it back
+    fn reload(
+        name: &str,
+        max_age: f64,
+        load_after_s: u64,
+        f: impl FnOnce(String) -> String,
+    ) -> Option<PeerCacheError> {
+        let path = cache_path(name);
+        let written_s = 1_700_000_000;
+        let mut cache = PeerCache::default();
+        cache.record(
+            MeasurementType::Icmp,
+            &[reflector(1)],
+            &[reflector(1)],
+            &HashSet::new(),
+            written_s,
+        );
+        cache.save(&path, written_s).unwrap();
+        fs::write(&path, f(fs::read_to_string(&path).unwrap())).unwrap();
+
+        let result = PeerCache::load(&path, written_s + load_after_s, max_age);
+        fs::remove_file(&path).unwrap();
+        result
+            .err()
+            .map(|e| e.downcast::<PeerCacheError>().unwrap())
+    }
+
+    #[test]
+    fn intact_cache_is_accepted() {
+        assert_eq!(reload("intact", 3600.0, 60, |contents| contents), None);
+        // No maximum age
+        assert_eq!(
+            reload("no-max-age", 0.0, 86400 * 365, |contents| contents),
+            None
+        );
+    }
+
+    #[test]
+    fn corrupt_cache_is_refused() {
+        assert_eq!(
+            reload("tampered", 0.0, 0, |contents| contents
+                .replace("192.0.2.1", "192.0.2.9")),
+            Some(PeerCacheError::Corrupt("checksum mismatch".to_string()))
+        );
+        assert_eq!(
+            reload("truncated", 0.0, 0, |contents| contents
+                [..contents.len() - 3]
+                .to_string()),
+            Some(PeerCacheError::Corrupt("checksum mismatch".to_string()))
+        );
+        assert_eq!(
+            reload("no-header", 0.0, 0, |contents| contents
+                .split_once('\n')
+                .unwrap()
+                .1
+                .to_string()),
+            Some(PeerCacheError::Corrupt("invalid header".to_string()))
+        );
+    }
+
+    #[test]
+    fn wrong_version_cache_is_refused() {
+        assert_eq!(
+            reload("version", 0.0, 0, |contents| contents.replacen(
+                &format!("v{}", PEER_CACHE_VERSION),
+                "v99",
+                1
+            )),
+            Some(PeerCacheError::WrongVersion(99))
+        );
+    }
+
+    #[test]
+    fn old_cache_is_refused() {
+        assert_eq!(
+            reload("old", 3600.0, 3601, |contents| contents),
+            Some(PeerCacheError::TooOld(3601))
+        );
+    }
+
+    #[test]
+    fn cached_capabilities_are_honored_on_reload() {
+        let path = cache_path("reload");
+        let path = path.as_str();
+        let now_s = 1_700_000_000;
+
+        // Found silent long enough ago to be given another chance
+        let mut cache = PeerCache::default();
+        cache.record(
+            MeasurementType::Icmp,
+            &[],
+            &[],
+            &HashSet::from([reflector(6)]),
+            now_s - CAPABILITY_MAX_AGE_S - 1,
+        );
+        // 1 and 2 are peers answering echo, 3 doesn't, and 4 only answers timestamps
+        cache.record(
+            MeasurementType::Icmp,
+            &[reflector(1), reflector(2)],
+            &[reflector(1), reflector(2), reflector(5)],
+            &HashSet::from([reflector(3)]),
+            now_s,
+        );
+        cache.record(
+            MeasurementType::IcmpTimestamps,
+            &[reflector(4)],
+            &[reflector(4)],
+            &HashSet::from([reflector(1)]),
+            now_s,
+        );
+        cache.save(path, now_s).unwrap();
+
+        let loaded = PeerCache::load(path, now_s, 0.0).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            loaded.silent(MeasurementType::Icmp),
+            HashSet::from([reflector(3)])
+        );
+        assert_eq!(
+            loaded.silent(MeasurementType::IcmpTimestamps),
+            HashSet::from([reflector(1)])
+        );
+
+        let pool: Vec<IpAddr> = (1..=8).map(reflector).collect();
+        // The silent one is skipped when topping up, the one that answered isn't
+        assert_eq!(
+            loaded.warm_start(
+                MeasurementType::Icmp,
+                &pool,
+                &[reflector(3), reflector(6), reflector(7)],
+                4
+            ),
+            [1, 2, 6, 7].map(reflector)
+        );
+        assert_eq!(
+            loaded.warm_start(MeasurementType::IcmpTimestamps, &pool, &[], 4),
+            [reflector(4)]
+        );
+        // Nothing cached for this type, or the peers are gone from the pool
+        assert!(loaded
+            .warm_start(MeasurementType::Ntp, &pool, &pool, 4)
+            .is_empty());
+        assert!(loaded
+            .warm_start(MeasurementType::Icmp, &pool[4..], &pool, 4)
+            .is_empty());
+    }
+}
@@ -1,11 +1,16 @@
+use crate::metrics::Counters;
 use crate::MeasurementType;
 use etherparse::ReadError;
-use log::{debug, error};
+use log::{debug, warn};
+use rustix::io::{poll, PollFd, PollFlags};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::collections::{HashMap, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::{AsRawFd, BorrowedFd};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use std::sync::mpsc::Sender;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 use std::{io, thread};
 use thiserror::Error;
@@ -39,24 +44,267 @@ pub struct PingReply {
     pub last_receive_time_s: Instant,
 }
 
-fn open_socket(type_: MeasurementType) -> io::Result<Socket> {
+// How many rounds back replies are accepted for when loss tracking, and with it the timeout, is off
+const UNTRACKED_SEQ_WINDOW: usize = 64;
+// Past half of the sequence space, late replies can't be told apart from ones not sent yet
+const MAX_SEQ_WINDOW: usize = 32768;
+
+/*
+ * The sender bumps the sequence number once per round over all reflectors, so a reflector
+ * only has replies outstanding for as many rounds as fit in the loss timeout. Replies
+ * arriving later than that have been counted as lost already, and are long past being
+ * useful, so this bounds how many sequence numbers we remember per reflector.
+ */
+fn seq_window(timeout: Duration, round_duration: Duration) -> usize {
+    if timeout.is_zero() || round_duration.is_zero() {
+        return UNTRACKED_SEQ_WINDOW;
+    }
+
+    let rounds = (timeout.as_secs_f64() / round_duration.as_secs_f64()).ceil() as usize;
+    rounds.clamp(1, MAX_SEQ_WINDOW)
+}
+
+// How often the listener wakes up to check for a shutdown while no replies are coming in
+const SHUTDOWN_CHECK_MS: i32 = 500;
+
+// How long to hold off after poll() fails, rather than spinning on an error that persists
+const POLL_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/*
+ * Both ends share the next sequence number to be sent, so the listener can tell whether
+ * a reply is for a probe that's actually in flight. The counter wraps at 65535 -> 0, so
+ * the distance is computed with wrapping arithmetic: a sequence number that was sent
+ * most recently is 1 behind, anything more than the window behind is stale, and
+ * anything that hasn't been sent yet wraps around to a huge distance.
+ */
+fn is_outstanding(next_seq: u16, seq: u16, window: usize) -> bool {
+    let age = next_seq.wrapping_sub(seq) as usize;
+    (1..=window).contains(&age)
+}
+
+/// Remembers recently seen sequence numbers per reflector, so duplicated replies can be dropped
+struct ReplyDeduplicator {
+    seen: HashMap<IpAddr, VecDeque<u16>>,
+    window: usize,
+}
+
+impl ReplyDeduplicator {
+    fn new(window: usize) -> Self {
+        Self {
+            seen: HashMap::new(),
+            window,
+        }
+    }
+
+    fn is_duplicate(&mut self, reflector: IpAddr, seq: u16) -> bool {
+        let seen = self.seen.entry(reflector).or_default();
+
+        if seen.contains(&seq) {
+            return true;
+        }
+
+        seen.push_back(seq);
+        if seen.len() > self.window {
+            seen.pop_front();
+        }
+
+        false
+    }
+}
+
+/*
+ * Probes that have gone out but haven't been answered yet. One still unanswered after the
+ * timeout is counted as lost and forgotten, which also keeps the bookkeeping bounded to
+ * roughly one timeout's worth of probes. A timeout of zero turns the tracking off.
+ */
+#[derive(Clone, Default)]
+pub struct LossTracking {
+    pub counters: Arc<Counters>,
+    pub outstanding: Arc<Mutex<HashMap<(IpAddr, u16), Instant>>>,
+    pub timeout: Duration,
+}
+
+impl LossTracking {
+    fn sent(&self, reflector: IpAddr, seq: u16) {
+        if !self.timeout.is_zero() {
+            self.outstanding
+                .lock()
+                .unwrap()
+                .insert((reflector, seq), Instant::now());
+        }
+    }
+
+    fn answered(&self, reflector: IpAddr, seq: u16) {
+        if !self.timeout.is_zero() {
+            self.outstanding.lock().unwrap().remove(&(reflector, seq));
+        }
+    }
+
+    fn expire(&self) {
+        if self.timeout.is_zero() {
+            return;
+        }
+
+        let now_t = Instant::now();
+        let mut outstanding = self.outstanding.lock().unwrap();
+        let before = outstanding.len();
+        outstanding.retain(|_, sent_t| now_t.duration_since(*sent_t) < self.timeout);
+
+        let lost = (before - outstanding.len()) as u64;
+        if lost > 0 {
+            debug!("{} probes went unanswered", lost);
+            self.counters.lost_probes.fetch_add(lost, Ordering::Relaxed);
+        }
+    }
+}
+
+/*
+ * UDP and TCP probes are sent from a port in the dynamic range derived from the ID, so the
+ * listener can tell the replies to our probes apart from other traffic without sharing state.
+ */
+pub fn client_port(id: u16) -> u16 {
+    49152 + id % 16384
+}
+
+fn open_socket(
+    type_: MeasurementType,
+    domain: Domain,
+    id: u16,
+    listening: bool,
+) -> io::Result<Socket> {
     match type_ {
+        MeasurementType::Icmp if domain == Domain::IPV6 => {
+            Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
+        }
         MeasurementType::Icmp | MeasurementType::IcmpTimestamps => {
             Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))
         }
-        MeasurementType::Ntp => Socket::new(Domain::IPV4, Type::DGRAM, None),
-        _ => {
-            unimplemented!()
+        /*
+         * NTP replies go to the sender's UDP socket. The listener gets its own copy through a
+         * raw UDP socket, the same way it sees ICMP replies, and picks out ours by the port
+         * the sender is bound to, which both work out from the ID.
+         */
+        MeasurementType::Ntp if listening => {
+            Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP))
+        }
+        MeasurementType::Ntp => {
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+            let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, client_port(id));
+            socket.bind(&local.into())?;
+            // Nothing ever reads the replies from this one, so keep them from piling up
+            socket.set_recv_buffer_size(0)?;
+            Ok(socket)
+        }
+        /*
+         * The TCP header is built by hand, so there's no kernel socket involved at all.
+         * A raw TCP socket gets a copy of every incoming segment, which only the listener wants.
+         */
+        MeasurementType::TcpTimestamps => {
+            let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::TCP))?;
+            if !listening {
+                socket.set_recv_buffer_size(0)?;
+            }
+            Ok(socket)
+        }
+    }
+}
+
+/*
+ * Every protocol a measurement type needs gets its own socket, tagged with the
+ * address family it serves. The sender picks the socket matching each reflector,
+ * and the listener multiplexes over all of them from a single thread.
+ */
+fn open_sockets(
+    type_: MeasurementType,
+    id: u16,
+    listening: bool,
+) -> io::Result<Vec<(Domain, Socket)>> {
+    let mut sockets = vec![(
+        Domain::IPV4,
+        open_socket(type_, Domain::IPV4, id, listening)?,
+    )];
+
+    // Only ICMP echo has an IPv6 counterpart. Hosts without IPv6 just keep probing over IPv4
+    if matches!(type_, MeasurementType::Icmp) {
+        match open_socket(type_, Domain::IPV6, id, listening) {
+            Ok(socket) => sockets.push((Domain::IPV6, socket)),
+            Err(e) => warn!(
+                "Couldn't open ICMPv6 socket, IPv6 reflectors won't be probed: {}",
+                e
+            ),
+        }
+    }
+
+    Ok(sockets)
+}
+
+fn socket_for(sockets: &[(Domain, Socket)], reflector: IpAddr) -> Option<&Socket> {
+    let wanted = match reflector {
+        IpAddr::V4(_) => Domain::IPV4,
+        IpAddr::V6(_) => Domain::IPV6,
+    };
+
+    sockets
+        .iter()
+        .find(|(domain, _)| *domain == wanted)
+        .map(|(_, socket)| socket)
+}
+
+/*
+ * Blocks until at least one of the sockets has data waiting, or the timeout in ms passes,
+ * and returns the readable ones. A negative timeout waits indefinitely. poll() is used
+ * rather than epoll, as the set of sockets is tiny and fixed for the lifetime of the
+ * listener, so there's no registration state worth keeping around.
+ */
+fn wait_readable(sockets: &[(Domain, Socket)], timeout_ms: i32) -> io::Result<Vec<&Socket>> {
+    let mut fds: Vec<PollFd> = sockets
+        .iter()
+        .map(|(_, socket)| {
+            // The sockets outlive the PollFds, which are dropped before returning
+            let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+            PollFd::from_borrowed_fd(fd, PollFlags::IN)
+        })
+        .collect();
+
+    poll(&mut fds, timeout_ms)?;
+
+    Ok(sockets
+        .iter()
+        .zip(fds.iter())
+        .filter(|(_, fd)| fd.revents().contains(PollFlags::IN))
+        .map(|((_, socket), _)| socket)
+        .collect())
+}
+
+// A poll() interrupted by a signal is simply retried, anything else gets logged and backed off from
+fn poll_failed(e: io::Error) {
+    if e.kind() != io::ErrorKind::Interrupted {
+        warn!("Waiting for replies failed: {}", e);
+        thread::sleep(POLL_ERROR_BACKOFF);
+    }
+}
+
+fn probe_addr(reflector: IpAddr, port: u16) -> SockAddr {
+    match reflector.is_ipv4() {
+        true => {
+            let ip4 = Ipv4Addr::from_str(&reflector.to_string()).unwrap();
+            let sock4 = SocketAddrV4::new(ip4, port);
+            sock4.into()
+        }
+        false => {
+            let ip6 = Ipv6Addr::from_str(&reflector.to_string()).unwrap();
+            let sock6 = SocketAddrV6::new(ip6, port, 0, 0);
+            sock6.into()
         }
     }
 }
 
 trait ReadFrom {
-    fn read_from(&mut self) -> io::Result<(Vec<u8>, SockAddr)>;
+    fn read_from(&self) -> io::Result<(Vec<u8>, SockAddr)>;
 }
 
 impl ReadFrom for Socket {
-    fn read_from(&mut self) -> io::Result<(Vec<u8>, SockAddr)> {
+    fn read_from(&self) -> io::Result<(Vec<u8>, SockAddr)> {
         let mut buffer = Vec::with_capacity(4096);
         let (received, addr) = self.recv_from(buffer.spare_capacity_mut())?;
 
@@ -67,97 +315,1064 @@ impl ReadFrom for Socket {
     }
 }
 
+/*
+ * What's done with a packet once it's been read: picking out the replies to our probes and
+ * handing them to the baseliner. Kept apart from the reading, so the listener and the
+ * combined pinger share it.
+ */
+struct ReplyHandler {
+    counters: Arc<Counters>,
+    deduplicator: ReplyDeduplicator,
+    log_per_packet: bool,
+    loss_tracking: LossTracking,
+    next_seq: Arc<AtomicU16>,
+    parse_error_log_rate: u64,
+    reference_reflectors: Vec<IpAddr>,
+    reflectors_lock: Arc<RwLock<Vec<IpAddr>>>,
+    stats_sender: Sender<PingReply>,
+}
+
+impl ReplyHandler {
+    fn handle<L: PingListener + ?Sized>(
+        &mut self,
+        listener: &L,
+        id: u16,
+        buf: &[u8],
+        sender: SockAddr,
+    ) {
+        // etherparse doesn't like when the size in the header doesn't match the buffer
+        // so resize the buffer when actual packet size is known
+        //buf = buf[..size].as_mut();
+
+        let addr: IpAddr = sender.as_socket().unwrap().ip();
+
+        let reflectors = self.reflectors_lock.read().unwrap();
+        let reference_reflectors = &self.reference_reflectors;
+        let is_ours = |reflector: &IpAddr| {
+            reflectors.contains(reflector) || reference_reflectors.contains(reflector)
+        };
+        if !listener.reflector_from_payload() && !is_ours(&addr) {
+            return;
+        }
+
+        let reply = match listener.parse_packet(id, addr, buf) {
+            Ok(val) => val,
+            Err(e) => {
+                // parse_packet will throw an error if it's an unknown protocol etc.
+                // That's usually harmless, so only log a sample of them to make a
+                // reflector that consistently sends garbage visible without flooding the log
+                let failures = self.counters.parse_errors.fetch_add(1, Ordering::Relaxed) + 1;
+                if self.parse_error_log_rate > 0
+                    && failures.is_multiple_of(self.parse_error_log_rate)
+                {
+                    warn!(
+                        "Couldn't parse reply from {}: {} ({} parse failures so far)",
+                        addr, e, failures
+                    );
+                }
+                return;
+            }
+        };
+
+        // The reply may have come from a different address than the one probed
+        if listener.reflector_from_payload() {
+            if !is_ours(&reply.reflector) {
+                return;
+            }
+            if reply.reflector != addr {
+                debug!("Reply for {} arrived from {}", reply.reflector, addr);
+            }
+        }
+
+        if !is_outstanding(
+            self.next_seq.load(Ordering::Relaxed),
+            reply.seq,
+            self.deduplicator.window,
+        ) {
+            debug!(
+                "Dropping reply from {} outside the outstanding window (seq {})",
+                addr, reply.seq
+            );
+            self.counters.stale_replies.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        if self.deduplicator.is_duplicate(reply.reflector, reply.seq) {
+            debug!("Dropping duplicate reply from {} (seq {})", addr, reply.seq);
+            self.counters
+                .duplicate_replies
+                .fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.loss_tracking.answered(reply.reflector, reply.seq);
+
+        if self.log_per_packet {
+            debug!("Type: {:4}  | Reflector IP: {:>15}  | Seq: {:5}  | Current time: {:8}  |  Originate: {:8}  |  Received time: {:8}  |  Transmit time : {:8}  |  RTT: {:8}  | UL time: {:5}  | DL time: {:5}", "ICMP", addr.to_string(), reply.seq, reply.current_time, reply.originate_timestamp, reply.receive_timestamp, reply.transmit_timestamp, reply.rtt, reply.up_time, reply.down_time);
+        }
+        self.stats_sender.send(reply).unwrap();
+    }
+}
+
 pub trait PingListener {
+    #[allow(clippy::too_many_arguments)]
     fn listen(
         &mut self,
         id: u16,
         type_: MeasurementType,
         reflectors_lock: Arc<RwLock<Vec<IpAddr>>>,
+        reference_reflectors: Vec<IpAddr>,
+        round_duration: Duration,
         stats_sender: Sender<PingReply>,
+        next_seq: Arc<AtomicU16>,
+        counters: Arc<Counters>,
+        parse_error_log_rate: u64,
+        log_per_packet: bool,
+        loss_tracking: LossTracking,
+        shutdown: Arc<AtomicBool>,
     ) -> anyhow::Result<()> {
-        let socket = &mut open_socket(type_)?;
+        let sockets = open_sockets(type_, id, true)?;
+        let mut handler = ReplyHandler {
+            counters,
+            deduplicator: ReplyDeduplicator::new(seq_window(loss_tracking.timeout, round_duration)),
+            log_per_packet,
+            loss_tracking,
+            next_seq,
+            parse_error_log_rate,
+            reference_reflectors,
+            reflectors_lock,
+            stats_sender,
+        };
 
-        loop {
-            let (buf, sender) = match socket.read_from() {
+        while !shutdown.load(Ordering::Relaxed) {
+            let readable = match wait_readable(&sockets, SHUTDOWN_CHECK_MS) {
                 Ok(val) => val,
-                Err(_) => continue,
+                Err(e) => {
+                    poll_failed(e);
+                    continue;
+                }
             };
 
-            // etherparse doesn't like when the size in the header doesn't match the buffer
-            // so resize the buffer when actual packet size is known
-            //buf = buf[..size].as_mut();
+            for socket in readable {
+                let (buf, sender) = match socket.read_from() {
+                    Ok(val) => val,
+                    Err(_) => continue,
+                };
+                handler.handle(self, id, buf.as_slice(), sender);
+            }
+        }
 
-            let addr: IpAddr = sender.as_socket().unwrap().ip();
+        Ok(())
+    }
 
-            let reflectors = reflectors_lock.read().unwrap();
-            if !reflectors.contains(&addr) {
-                continue;
-            }
+    fn parse_packet(&self, id: u16, reflector: IpAddr, buf: &[u8]) -> Result<PingReply, PingError>;
 
-            let reply = match self.parse_packet(id, addr, buf.as_slice()) {
-                Ok(val) => val,
-                Err(_) => {
-                    // parse_packet will throw an error if it's an unknown protocol etc.
-                    // so just quietly move on
-                    continue;
-                }
-            };
+    /*
+     * Whether parse_packet takes the reflector from the probe echoed back in the reply,
+     * rather than the address the reply came from. With ECMP or NAT on the path, replies
+     * can come back from a different address than the one probed.
+     */
+    fn reflector_from_payload(&self) -> bool {
+        false
+    }
+}
 
-            debug!("Type: {:4}  | Reflector IP: {:>15}  | Seq: {:5}  | Current time: {:8}  |  Originate: {:8}  |  Received time: {:8}  |  Transmit time : {:8}  |  RTT: {:8}  | UL time: {:5}  | DL time: {:5}", "ICMP", addr.to_string(), reply.seq, reply.current_time, reply.originate_timestamp, reply.receive_timestamp, reply.transmit_timestamp, reply.rtt, reply.up_time, reply.down_time);
-            stats_sender.send(reply).unwrap();
+/// How far to scale back probing while the ratecontroller considers the link idle.
+/// The default is never flagged as idle, for senders that should always run at full rate.
+#[derive(Clone, Default)]
+pub struct IdleProbing {
+    pub idle: Arc<AtomicBool>,
+    pub slowdown: f64,
+    pub max_probes: usize,
+}
+
+/*
+ * When each probe goes out, and to which reflector. Shared by the sender, which sleeps
+ * until each probe is due, and the combined pinger, which waits for replies meanwhile.
+ */
+struct ProbeSchedule {
+    idle_probing: IdleProbing,
+    // The peers probed last round, and the new ones when they've changed since
+    last_peers: Vec<IpAddr>,
+    changed_peers: Option<Vec<IpAddr>>,
+    loss_tracking: LossTracking,
+    max_probes: usize,
+    next_send_t: Instant,
+    next_seq: Arc<AtomicU16>,
+    reflectors_lock: Arc<RwLock<Vec<IpAddr>>>,
+    round: VecDeque<IpAddr>,
+    round_duration: Duration,
+    round_robin_offset: usize,
+    seq: u16,
+    sleep_duration: Duration,
+}
+
+impl ProbeSchedule {
+    fn new(
+        reflectors_lock: Arc<RwLock<Vec<IpAddr>>>,
+        round_duration: Duration,
+        max_probes: usize,
+        next_seq: Arc<AtomicU16>,
+        idle_probing: IdleProbing,
+        loss_tracking: LossTracking,
+    ) -> Self {
+        Self {
+            idle_probing,
+            last_peers: Vec::new(),
+            changed_peers: None,
+            loss_tracking,
+            max_probes,
+            next_send_t: Instant::now(),
+            next_seq,
+            reflectors_lock,
+            round: VecDeque::new(),
+            round_duration,
+            round_robin_offset: 0,
+            seq: 0,
+            sleep_duration: round_duration,
         }
     }
 
-    fn parse_packet(&self, id: u16, reflector: IpAddr, buf: &[u8]) -> Result<PingReply, PingError>;
+    fn start_round(&mut self) {
+        let reflectors_unlocked = self.reflectors_lock.read().unwrap();
+        let mut reflectors = reflectors_unlocked.clone();
+        drop(reflectors_unlocked);
+
+        if reflectors != self.last_peers {
+            self.last_peers = reflectors.clone();
+            self.changed_peers = Some(reflectors.clone());
+        }
+
+        // While idle, probe fewer reflectors less often, until the traffic picks up again
+        let (round_duration, max_probes) = match self.idle_probing.idle.load(Ordering::Relaxed) {
+            true => (
+                self.round_duration.mul_f64(self.idle_probing.slowdown),
+                self.idle_probing.max_probes,
+            ),
+            false => (self.round_duration, self.max_probes),
+        };
+
+        /*
+         * Probe traffic scales with the size of the peer set, which can get large
+         * while candidates are being baselined. With a cap set, only probe that many
+         * per round, and rotate through the rest over the following rounds.
+         */
+        if max_probes > 0 && reflectors.len() > max_probes {
+            self.round_robin_offset %= reflectors.len();
+            reflectors.rotate_left(self.round_robin_offset);
+            reflectors.truncate(max_probes);
+            self.round_robin_offset += max_probes;
+        }
+        // Spread the probes for one round evenly over the round
+        self.sleep_duration = round_duration / reflectors.len().max(1) as u32;
+
+        self.loss_tracking.expire();
+
+        // One sequence number per round, shared with any other senders. Wraps around at u16::MAX
+        self.seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        self.round = reflectors.into();
+    }
+
+    /*
+     * When the next probe is due, and the reflector and sequence number it's for. A round
+     * without any reflectors still takes up its slot, so there's nothing to do until then.
+     *
+     * Probes are due on a fixed schedule rather than a fixed interval after the previous
+     * send. That way a late wakeup on a busy router only delays that one probe, instead of
+     * pushing all of the following ones back too. If we've fallen more than a slot behind,
+     * start over from now rather than firing off a burst of probes to catch up.
+     */
+    fn next_probe(&mut self) -> (Instant, Option<(IpAddr, u16)>) {
+        if self.round.is_empty() {
+            self.start_round();
+        }
+
+        let now_t = Instant::now();
+        if now_t > self.next_send_t && now_t - self.next_send_t > self.sleep_duration {
+            self.next_send_t = now_t;
+        }
+        let send_t = self.next_send_t;
+        self.next_send_t += self.sleep_duration;
+
+        (
+            send_t,
+            self.round
+                .pop_front()
+                .map(|reflector| (reflector, self.seq)),
+        )
+    }
+}
+
+fn send_probe<S: PingSender + ?Sized>(
+    sender: &S,
+    sockets: &[(Domain, Socket)],
+    id: u16,
+    seq: u16,
+    reflector: IpAddr,
+    loss_tracking: &LossTracking,
+) {
+    let addr = probe_addr(reflector, sender.port());
+
+    /*
+     * The packet (and with it the send timestamp) is crafted as the very last
+     * thing before the send. Anything done between taking the timestamp and
+     * the packet actually leaving would be counted as upload delay.
+     */
+    match socket_for(sockets, reflector) {
+        Some(socket) => {
+            let buf_v = sender.craft_packet(id, seq, reflector);
+            match socket.send_to(buf_v.as_slice(), &addr) {
+                Ok(_) => loss_tracking.sent(reflector, seq),
+                /*
+                 * A reflector that can't be reached, like an IPv6 one on a host without an
+                 * IPv6 route, only fails its own probes. The rest of the round goes ahead.
+                 */
+                Err(e) => {
+                    let failures = loss_tracking.counters.send_error(reflector);
+                    debug!(
+                        "Couldn't probe {}: {} ({} failures so far)",
+                        reflector, e, failures
+                    );
+                }
+            }
+        }
+        None => debug!("No socket available to probe {}", reflector),
+    }
 }
 
 pub trait PingSender {
+    #[allow(clippy::too_many_arguments)]
     fn send(
         &mut self,
         id: u16,
         type_: MeasurementType,
         reflectors_lock: Arc<RwLock<Vec<IpAddr>>>,
+        round_duration: Duration,
+        max_probes: usize,
+        next_seq: Arc<AtomicU16>,
+        idle_probing: IdleProbing,
+        loss_tracking: LossTracking,
+        shutdown: Arc<AtomicBool>,
     ) -> anyhow::Result<()> {
-        let socket = &open_socket(type_)?;
+        /*
+         * The sockets are held for as long as the sender runs. Reselection only changes which
+         * reflectors are probed, and each probe picks the socket for its reflector's address
+         * family, so a peer set that changes, or mixes IPv4 and IPv6, never needs new ones.
+         */
+        let sockets = open_sockets(type_, id, false)?;
+        let mut schedule = ProbeSchedule::new(
+            reflectors_lock,
+            round_duration,
+            max_probes,
+            next_seq,
+            idle_probing,
+            loss_tracking.clone(),
+        );
+
+        while !shutdown.load(Ordering::Relaxed) {
+            let (send_t, probe) = schedule.next_probe();
+            if let Some(peers) = schedule.changed_peers.take() {
+                self.peers_changed(&peers);
+            }
 
-        let mut seq: u16 = 0;
-        let tick_duration_ms: u16 = 500;
+            let now_t = Instant::now();
+            if send_t > now_t {
+                thread::sleep(send_t - now_t);
+            }
 
+            if let Some((reflector, seq)) = probe {
+                send_probe(self, &sockets, id, seq, reflector, &loss_tracking);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn craft_packet(&self, id: u16, seq: u16, reflector: IpAddr) -> Vec<u8>;
+
+    // The destination port for the probes, which raw sockets ignore
+    fn port(&self) -> u16 {
+        0
+    }
+
+    // Called before probing a peer set that differs from the last round's, e.g. after reselection
+    fn peers_changed(&self, _reflectors: &[IpAddr]) {}
+}
+
+/*
+ * Runs the sender and the listener from a single thread, waiting for replies in between
+ * the probes. With the two-thread design, each reply is handled as soon as it comes in,
+ * and a wedged sender or listener only takes down its own half. Here, a reply that comes
+ * in while a probe is being crafted and sent waits until that's done, which shows up as
+ * a little extra download delay, but the timestamps on both ends are taken by the same
+ * thread, and a small router saves a thread and its wakeups. Like the listener, this
+ * waits on the sockets with poll(), which for a handful of them is as cheap as epoll.
+ */
+#[allow(clippy::too_many_arguments)]
+pub fn send_and_listen(
+    listener: &dyn PingListener,
+    sender: &dyn PingSender,
+    id: u16,
+    type_: MeasurementType,
+    reflectors_lock: Arc<RwLock<Vec<IpAddr>>>,
+    reference_reflectors: Vec<IpAddr>,
+    round_duration: Duration,
+    max_probes: usize,
+    next_seq: Arc<AtomicU16>,
+    idle_probing: IdleProbing,
+    loss_tracking: LossTracking,
+    stats_sender: Sender<PingReply>,
+    counters: Arc<Counters>,
+    parse_error_log_rate: u64,
+    log_per_packet: bool,
+    shutdown: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let listen_sockets = open_sockets(type_, id, true)?;
+    // The raw ICMP sockets see the replies to what's sent on them, so they can do both
+    let send_sockets = match type_ {
+        MeasurementType::Icmp | MeasurementType::IcmpTimestamps => None,
+        _ => Some(open_sockets(type_, id, false)?),
+    };
+    let send_sockets = send_sockets.as_deref().unwrap_or(&listen_sockets);
+
+    let mut handler = ReplyHandler {
+        counters,
+        deduplicator: ReplyDeduplicator::new(seq_window(loss_tracking.timeout, round_duration)),
+        log_per_packet,
+        loss_tracking: loss_tracking.clone(),
+        next_seq: next_seq.clone(),
+        parse_error_log_rate,
+        reference_reflectors,
+        reflectors_lock: reflectors_lock.clone(),
+        stats_sender,
+    };
+    let mut schedule = ProbeSchedule::new(
+        reflectors_lock,
+        round_duration,
+        max_probes,
+        next_seq,
+        idle_probing,
+        loss_tracking.clone(),
+    );
+
+    while !shutdown.load(Ordering::Relaxed) {
+        let (send_t, probe) = schedule.next_probe();
+        if let Some(peers) = schedule.changed_peers.take() {
+            sender.peers_changed(&peers);
+        }
+
+        // Handle whatever comes in until the probe is due
         loop {
-            let reflectors_unlocked = reflectors_lock.read().unwrap();
-            let reflectors = reflectors_unlocked.clone();
-            drop(reflectors_unlocked);
-            let sleep_duration =
-                Duration::from_millis((tick_duration_ms / reflectors.len() as u16) as u64);
-
-            for reflector in reflectors.iter() {
-                let addr: SockAddr = match reflector.is_ipv4() {
-                    true => {
-                        let ip4 = Ipv4Addr::from_str(&*reflector.to_string()).unwrap();
-                        let sock4 = SocketAddrV4::new(ip4, 0);
-                        sock4.into()
-                    }
-                    false => {
-                        let ip6 = Ipv6Addr::from_str(&*reflector.to_string()).unwrap();
-                        let sock6 = SocketAddrV6::new(ip6, 0, 0, 0);
-                        sock6.into()
-                    }
+            let now_t = Instant::now();
+            if now_t >= send_t || shutdown.load(Ordering::Relaxed) {
+                break;
+            }
+            // Rounded up, so the last sliver of the wait doesn't turn into a busy loop
+            let timeout_ms =
+                ((send_t - now_t).as_micros().div_ceil(1000) as i32).min(SHUTDOWN_CHECK_MS);
+
+            let readable = match wait_readable(&listen_sockets, timeout_ms) {
+                Ok(val) => val,
+                Err(e) => {
+                    poll_failed(e);
+                    continue;
+                }
+            };
+
+            for socket in readable {
+                let (buf, reply_addr) = match socket.read_from() {
+                    Ok(val) => val,
+                    Err(_) => continue,
                 };
+                handler.handle(listener, id, buf.as_slice(), reply_addr);
+            }
+        }
 
-                let buf_v = self.craft_packet(id, seq);
-                let buf = buf_v.as_slice();
+        if let Some((reflector, seq)) = probe {
+            send_probe(sender, send_sockets, id, seq, reflector, &loss_tracking);
+        }
+    }
+
+    Ok(())
+}
 
-                socket.send_to(buf, &addr)?;
-                thread::sleep(sleep_duration);
+/*
+ * Sends a single probe to each of the reflectors, and returns the RTT in ms of each one
+ * that replied before the timeout. Meant for a quick look at a sample of reflectors before
+ * the regular sender and listener are running, so it opens sockets of its own for the
+ * duration, and none of the replies go to the baseliner.
+ */
+pub fn probe_once(
+    listener: &dyn PingListener,
+    sender: &dyn PingSender,
+    id: u16,
+    type_: MeasurementType,
+    reflectors: &[IpAddr],
+    timeout: Duration,
+) -> io::Result<HashMap<IpAddr, i64>> {
+    let send_sockets = open_sockets(type_, id, false)?;
+    let listen_sockets = open_sockets(type_, id, true)?;
+    let mut rtts = HashMap::new();
+
+    for reflector in reflectors.iter() {
+        if let Some(socket) = socket_for(&send_sockets, *reflector) {
+            let buf_v = sender.craft_packet(id, 0, *reflector);
+            if let Err(e) = socket.send_to(buf_v.as_slice(), &probe_addr(*reflector, sender.port()))
+            {
+                debug!("Couldn't probe {}: {}", reflector, e);
             }
+        }
+    }
+
+    let deadline_t = Instant::now() + timeout;
+    while rtts.len() < reflectors.len() {
+        let now_t = Instant::now();
+        if now_t >= deadline_t {
+            break;
+        }
+        // Rounded up, so the last sliver of the timeout doesn't turn into a busy loop
+        let timeout_ms = (deadline_t - now_t).as_micros().div_ceil(1000) as i32;
 
-            if seq == u16::MAX {
-                seq = 0;
-            } else {
-                seq += 1;
+        let readable = match wait_readable(&listen_sockets, timeout_ms) {
+            Ok(val) => val,
+            Err(e) => {
+                poll_failed(e);
+                continue;
             }
+        };
+
+        for socket in readable {
+            let (buf, sender) = match socket.read_from() {
+                Ok(val) => val,
+                Err(_) => continue,
+            };
+            let addr: IpAddr = sender.as_socket().unwrap().ip();
+            if !listener.reflector_from_payload() && !reflectors.contains(&addr) {
+                continue;
+            }
+
+            if let Ok(reply) = listener.parse_packet(id, addr, buf.as_slice()) {
+                if reflectors.contains(&reply.reflector) {
+                    rtts.entry(reply.reflector).or_insert(reply.rtt);
+                }
+            }
+        }
+    }
+
+    Ok(rtts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::baseliner::Baseliner;
+    use crate::config::test_config;
+    use crate::log::capture_logs;
+    use crate::pinger_icmp::{PingerICMPEchoListener, PingerICMPEchoSender};
+    use crate::time::Time;
+    use etherparse::{IcmpEchoHeader, Icmpv6Header, Icmpv6Type};
+    use rustix::thread::ClockId;
+    use std::sync::mpsc::{channel, Receiver};
+
+    struct TestSender {
+        port: u16,
+    }
+
+    impl PingSender for TestSender {
+        fn craft_packet(&self, _id: u16, _seq: u16, _reflector: IpAddr) -> Vec<u8> {
+            vec![0; 8]
         }
+
+        fn port(&self) -> u16 {
+            self.port
+        }
+    }
+
+    fn bind(domain: Domain, addr: &str) -> Socket {
+        let socket = Socket::new(domain, Type::DGRAM, None).unwrap();
+        socket
+            .bind(&addr.parse::<std::net::SocketAddr>().unwrap().into())
+            .unwrap();
+        socket
+    }
+
+    #[test]
+    fn failed_send_is_counted_per_reflector() {
+        // Broadcasting without SO_BROADCAST set is refused, like a reflector without a route
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        let sockets = vec![(Domain::IPV4, socket)];
+        let loss_tracking = LossTracking {
+            counters: Arc::new(Counters::default()),
+            outstanding: Arc::new(Mutex::new(HashMap::new())),
+            timeout: Duration::from_secs(1),
+        };
+        let unreachable = IpAddr::V4(Ipv4Addr::BROADCAST);
+
+        for seq in 0..3 {
+            send_probe(
+                &TestSender { port: 9 },
+                &sockets,
+                1,
+                seq,
+                unreachable,
+                &loss_tracking,
+            );
+        }
+
+        let send_errors = loss_tracking.counters.send_errors.lock().unwrap();
+        assert_eq!(send_errors.get(&unreachable), Some(&3));
+        assert!(loss_tracking.outstanding.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn mixed_peers_share_the_senders_sockets() {
+        let receiver_v4 = bind(Domain::IPV4, "127.0.0.1:0");
+        let port = receiver_v4
+            .local_addr()
+            .unwrap()
+            .as_socket()
+            .unwrap()
+            .port();
+        let receiver_v6 = bind(Domain::IPV6, &format!("[::1]:{}", port));
+        let sockets = vec![
+            (
+                Domain::IPV4,
+                Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap(),
+            ),
+            (
+                Domain::IPV6,
+                Socket::new(Domain::IPV6, Type::DGRAM, None).unwrap(),
+            ),
+        ];
+        let fds: Vec<i32> = sockets
+            .iter()
+            .map(|(_, socket)| socket.as_raw_fd())
+            .collect();
+        let localhost_v4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let localhost_v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let reflectors_lock = Arc::new(RwLock::new(vec![localhost_v4, localhost_v6]));
+        let loss_tracking = LossTracking {
+            counters: Arc::new(Counters::default()),
+            outstanding: Arc::new(Mutex::new(HashMap::new())),
+            timeout: Duration::from_secs(60),
+        };
+        let mut schedule = ProbeSchedule::new(
+            reflectors_lock.clone(),
+            Duration::ZERO,
+            0,
+            Arc::new(AtomicU16::new(0)),
+            IdleProbing::default(),
+            loss_tracking.clone(),
+        );
+        let mut send_round = |probes: usize| {
+            for _ in 0..probes {
+                let (_, probe) = schedule.next_probe();
+                let (reflector, seq) = probe.unwrap();
+                send_probe(
+                    &TestSender { port },
+                    &sockets,
+                    1,
+                    seq,
+                    reflector,
+                    &loss_tracking,
+                );
+            }
+        };
+
+        send_round(2);
+        // A reselection dropping the IPv4 peer only changes what the next round probes
+        *reflectors_lock.write().unwrap() = vec![localhost_v6];
+        send_round(1);
+
+        assert!(receiver_v4.read_from().is_ok());
+        for _ in 0..2 {
+            assert!(receiver_v6.read_from().is_ok());
+        }
+        assert_eq!(loss_tracking.outstanding.lock().unwrap().len(), 3);
+        assert_eq!(
+            sockets
+                .iter()
+                .map(|(_, socket)| socket.as_raw_fd())
+                .collect::<Vec<_>>(),
+            fds
+        );
+    }
+
+    fn schedule(reflectors: &[&str], round_duration: Duration, max_probes: usize) -> ProbeSchedule {
+        let reflectors = reflectors.iter().map(|r| r.parse().unwrap()).collect();
+        ProbeSchedule::new(
+            Arc::new(RwLock::new(reflectors)),
+            round_duration,
+            max_probes,
+            Arc::new(AtomicU16::new(0)),
+            IdleProbing::default(),
+            LossTracking::default(),
+        )
     }
 
-    fn craft_packet(&self, id: u16, seq: u16) -> Vec<u8>;
+    #[test]
+    fn probes_are_paced_by_deadline() {
+        let reflectors = ["192.0.2.1", "192.0.2.2", "192.0.2.3", "192.0.2.4"];
+        let mut schedule = schedule(&reflectors, Duration::from_secs(4), 0);
+
+        // Evenly spread over the round, however long it took to get to each one
+        let (first_t, probe) = schedule.next_probe();
+        assert_eq!(probe, Some(("192.0.2.1".parse().unwrap(), 0)));
+        for (slot, reflector) in reflectors.iter().enumerate().skip(1) {
+            let (send_t, probe) = schedule.next_probe();
+            assert_eq!(send_t - first_t, Duration::from_secs(slot as u64));
+            assert_eq!(probe, Some((reflector.parse().unwrap(), 0)));
+        }
+
+        // The next round has the next sequence number
+        let (_, probe) = schedule.next_probe();
+        assert_eq!(probe, Some(("192.0.2.1".parse().unwrap(), 1)));
+
+        // More than a slot behind starts over from now, rather than bursting to catch up
+        schedule.next_send_t -= Duration::from_secs(10);
+        let before_t = Instant::now();
+        let (send_t, _) = schedule.next_probe();
+        assert!(send_t >= before_t);
+    }
+
+    #[test]
+    fn capped_rounds_rotate_through_the_peers() {
+        let reflectors = [
+            "192.0.2.1",
+            "192.0.2.2",
+            "192.0.2.3",
+            "192.0.2.4",
+            "192.0.2.5",
+        ];
+        let mut schedule = schedule(&reflectors, Duration::ZERO, 2);
+
+        // Never more than the cap in a round, and every peer gets its turn
+        let mut probed = Vec::new();
+        for seq in 0..5 {
+            schedule.start_round();
+            assert_eq!(schedule.round.len(), 2);
+            assert_eq!(schedule.seq, seq);
+            probed.extend(schedule.round.drain(..));
+        }
+        let expected: Vec<IpAddr> = reflectors.iter().map(|r| r.parse().unwrap()).collect();
+        assert_eq!(probed, [expected.clone(), expected].concat());
+    }
+
+    #[test]
+    fn idle_rounds_are_fewer_and_further_between() {
+        let reflectors = ["192.0.2.1", "192.0.2.2", "192.0.2.3", "192.0.2.4"];
+        let mut schedule = schedule(&reflectors, Duration::from_secs(4), 0);
+        schedule.idle_probing = IdleProbing {
+            idle: Arc::new(AtomicBool::new(true)),
+            slowdown: 4.0,
+            max_probes: 2,
+        };
+
+        schedule.start_round();
+        assert_eq!(schedule.round.len(), 2);
+        assert_eq!(schedule.sleep_duration, Duration::from_secs(8));
+
+        // Back to every peer, every round, as soon as the link's active again
+        schedule.idle_probing.idle.store(false, Ordering::Relaxed);
+        schedule.start_round();
+        assert_eq!(schedule.round.len(), 4);
+        assert_eq!(schedule.sleep_duration, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn changed_peers_are_reported_once() {
+        let mut schedule = schedule(&["192.0.2.1", "192.0.2.2"], Duration::ZERO, 0);
+
+        schedule.next_probe();
+        assert_eq!(
+            schedule.changed_peers.take().map(|peers| peers.len()),
+            Some(2)
+        );
+        for _ in 0..3 {
+            schedule.next_probe();
+        }
+        assert!(schedule.changed_peers.is_none());
+
+        *schedule.reflectors_lock.write().unwrap() = vec!["192.0.2.3".parse().unwrap()];
+        schedule.next_probe();
+        assert_eq!(
+            schedule.changed_peers.take(),
+            Some(vec!["192.0.2.3".parse().unwrap()])
+        );
+    }
+
+    #[test]
+    fn unanswered_probe_is_lost_after_the_timeout() {
+        let loss_tracking = LossTracking {
+            timeout: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let [answered, unanswered] = ["192.0.2.1", "192.0.2.2"].map(|r| r.parse().unwrap());
+        loss_tracking.sent(answered, 1);
+        loss_tracking.sent(unanswered, 1);
+        loss_tracking.answered(answered, 1);
+
+        // Not yet, it might still be on its way
+        loss_tracking.expire();
+        assert_eq!(
+            loss_tracking.counters.lost_probes.load(Ordering::Relaxed),
+            0
+        );
+
+        std::thread::sleep(Duration::from_millis(30));
+        loss_tracking.expire();
+        assert_eq!(
+            loss_tracking.counters.lost_probes.load(Ordering::Relaxed),
+            1
+        );
+        assert!(loss_tracking.outstanding.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn seq_window_covers_the_timeout() {
+        let tick = Duration::from_millis(500);
+        assert_eq!(seq_window(Duration::from_secs(2), tick), 4);
+        assert_eq!(
+            seq_window(Duration::from_secs(2), Duration::from_millis(30)),
+            67
+        );
+        assert_eq!(seq_window(Duration::ZERO, tick), UNTRACKED_SEQ_WINDOW);
+        assert_eq!(
+            seq_window(Duration::from_secs(3600), Duration::from_millis(1)),
+            MAX_SEQ_WINDOW
+        );
+    }
+
+    #[test]
+    fn duplicate_reply_is_dropped() {
+        let mut deduplicator = ReplyDeduplicator::new(4);
+        let reflector = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+        assert!(!deduplicator.is_duplicate(reflector, 1));
+        assert!(deduplicator.is_duplicate(reflector, 1));
+        // Sequence numbers are per reflector
+        assert!(!deduplicator.is_duplicate(other, 1));
+
+        // Only as many as the window are remembered, older ones are left to is_outstanding
+        for seq in 2..=5 {
+            assert!(!deduplicator.is_duplicate(reflector, seq));
+        }
+        assert!(!deduplicator.is_duplicate(reflector, 1));
+        assert!(!is_outstanding(6, 1, 4));
+        assert!(is_outstanding(6, 2, 4));
+        // Wraps around, and what hasn't been sent yet isn't outstanding
+        assert!(is_outstanding(1, u16::MAX, 4));
+        assert!(!is_outstanding(6, 6, 4));
+    }
+
+    // A reply handler for the given peers, and the receiving end of what it passes on
+    fn reply_handler(peers: Vec<IpAddr>) -> (ReplyHandler, Receiver<PingReply>) {
+        let (stats_sender, stats_receiver) = channel();
+        let handler = ReplyHandler {
+            counters: Arc::new(Counters::default()),
+            deduplicator: ReplyDeduplicator::new(4),
+            log_per_packet: false,
+            loss_tracking: LossTracking::default(),
+            next_seq: Arc::new(AtomicU16::new(2)),
+            parse_error_log_rate: 0,
+            reference_reflectors: Vec::new(),
+            reflectors_lock: Arc::new(RwLock::new(peers)),
+            stats_sender,
+        };
+
+        (handler, stats_receiver)
+    }
+
+    fn icmp_listener() -> PingerICMPEchoListener {
+        PingerICMPEchoListener {
+            clock: ClockId::Monotonic,
+            match_payload: false,
+            upload_share: 0.5,
+        }
+    }
+
+    // An ICMPv6 echo reply to a probe sent just now, as the raw socket hands it over
+    fn echo_reply(id: u16, seq: u16) -> Vec<u8> {
+        let sent_ms = Time::new(ClockId::Monotonic).to_milliseconds();
+        let mut reply = Vec::new();
+        Icmpv6Header::new(Icmpv6Type::EchoReply(IcmpEchoHeader { id, seq }))
+            .write(&mut reply)
+            .unwrap();
+        reply.extend_from_slice(&sent_ms.to_ne_bytes());
+        reply
+    }
+
+    fn from(addr: &str) -> SockAddr {
+        SocketAddrV6::new(addr.parse().unwrap(), 0, 0, 0).into()
+    }
+
+    #[test]
+    fn duplicate_reply_updates_the_baseline_once() {
+        let reflector: IpAddr = "2001:db8::1".parse().unwrap();
+        let (mut handler, stats_receiver) = reply_handler(vec![reflector]);
+        let (reselect_trigger, _) = channel();
+        let (_, unused_receiver) = channel();
+        let baseliner = Baseliner {
+            config: test_config(),
+            counters: Arc::new(Counters::default()),
+            owd_baseline: Arc::new(Mutex::new(HashMap::new())),
+            owd_recent: Arc::new(Mutex::new(HashMap::new())),
+            reflector_peers_lock: handler.reflectors_lock.clone(),
+            reselect_trigger,
+            start_time: Instant::now(),
+            stats_receiver: unused_receiver,
+        };
+        let mut state = baseliner.new_state(Instant::now());
+
+        // The same echo reply, as delivered twice by the network
+        let reply = echo_reply(7, 1);
+        for _ in 0..2 {
+            handler.handle(&icmp_listener(), 7, &reply, from("2001:db8::1"));
+        }
+        let counters = handler.counters.clone();
+        drop(handler);
+        for reply in stats_receiver.iter() {
+            baseliner.process(&mut state, reply);
+        }
+
+        assert_eq!(baseliner.owd_recent.lock().unwrap()[&reflector].replies, 1);
+        assert!(baseliner
+            .owd_baseline
+            .lock()
+            .unwrap()
+            .contains_key(&reflector));
+        assert_eq!(counters.duplicate_replies.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn reply_from_another_address_is_matched_by_payload() {
+        let reflector: IpAddr = "2001:db8::1".parse().unwrap();
+        let (mut handler, stats_receiver) = reply_handler(vec![reflector]);
+        // e.g. an anycast reflector answering from a different address than the one probed
+        let mut reply = echo_reply(7, 1);
+        reply.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+
+        handler.handle(&icmp_listener(), 7, &reply, from("2001:db8::99"));
+        assert!(stats_receiver.try_recv().is_err());
+
+        let listener = PingerICMPEchoListener {
+            match_payload: true,
+            ..icmp_listener()
+        };
+        handler.handle(&listener, 7, &reply, from("2001:db8::99"));
+        assert_eq!(stats_receiver.try_recv().unwrap().reflector, reflector);
+    }
+
+    #[test]
+    fn per_packet_log_is_off_unless_asked_for() {
+        let (mut handler, stats_receiver) = reply_handler(vec!["2001:db8::1".parse().unwrap()]);
+
+        let logs = capture_logs(|| {
+            handler.handle(&icmp_listener(), 7, &echo_reply(7, 0), from("2001:db8::1"));
+        });
+        assert!(logs.is_empty());
+
+        handler.log_per_packet = true;
+        let logs = capture_logs(|| {
+            handler.handle(&icmp_listener(), 7, &echo_reply(7, 1), from("2001:db8::1"));
+        });
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].starts_with("DEBUG Type: ICMP"));
+        assert_eq!(stats_receiver.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn every_nth_parse_failure_is_logged() {
+        let (mut handler, stats_receiver) = reply_handler(vec!["2001:db8::1".parse().unwrap()]);
+        handler.parse_error_log_rate = 3;
+
+        let logs = capture_logs(|| {
+            for _ in 0..7 {
+                handler.handle(&icmp_listener(), 7, &[0; 3], from("2001:db8::1"));
+            }
+        });
+        assert_eq!(logs.len(), 2);
+        assert!(logs[0].starts_with("WARN Couldn't parse reply from 2001:db8::1"));
+        assert!(logs[0].ends_with("(3 parse failures so far)"));
+        assert!(logs[1].ends_with("(6 parse failures so far)"));
+        assert_eq!(handler.counters.parse_errors.load(Ordering::Relaxed), 7);
+        assert!(stats_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn both_sockets_are_waited_on() {
+        let sockets = vec![
+            (Domain::IPV4, bind(Domain::IPV4, "127.0.0.1:0")),
+            (Domain::IPV6, bind(Domain::IPV6, "[::1]:0")),
+        ];
+        let send_to = |index: usize| {
+            let (domain, socket) = &sockets[index];
+            let sender = Socket::new(*domain, Type::DGRAM, None).unwrap();
+            sender
+                .send_to(b"reply", &socket.local_addr().unwrap())
+                .unwrap();
+        };
+
+        assert!(wait_readable(&sockets, 0).unwrap().is_empty());
+
+        send_to(1);
+        let readable = wait_readable(&sockets, 1000).unwrap();
+        assert_eq!(readable.len(), 1);
+        assert_eq!(readable[0].as_raw_fd(), sockets[1].1.as_raw_fd());
+        readable[0].read_from().unwrap();
+
+        send_to(0);
+        send_to(1);
+        // Loopback delivery is synchronous, so both are there by the time poll() looks
+        let readable = wait_readable(&sockets, 1000).unwrap();
+        assert_eq!(readable.len(), 2);
+    }
+
+    // Raw sockets need CAP_NET_RAW, the same as the daemon itself
+    #[test]
+    #[ignore = "needs CAP_NET_RAW, run with --ignored"]
+    fn combined_pinger_gets_a_reply() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let (stats_sender, stats_receiver) = channel();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown_clone = shutdown.clone();
+        let pinger = thread::spawn(move || {
+            let listener = PingerICMPEchoListener {
+                clock: ClockId::Monotonic,
+                match_payload: false,
+                upload_share: 0.5,
+            };
+            let sender = PingerICMPEchoSender {
+                clock: ClockId::Monotonic,
+                embed_reflector: false,
+            };
+            send_and_listen(
+                &listener,
+                &sender,
+                0x5153,
+                MeasurementType::Icmp,
+                Arc::new(RwLock::new(vec![localhost])),
+                Vec::new(),
+                Duration::from_millis(50),
+                0,
+                Arc::new(AtomicU16::new(0)),
+                IdleProbing::default(),
+                LossTracking::default(),
+                stats_sender,
+                Arc::default(),
+                0,
+                false,
+                shutdown_clone,
+            )
+        });
+
+        let reply = stats_receiver.recv_timeout(Duration::from_secs(5));
+        shutdown.store(true, Ordering::Relaxed);
+        pinger.join().unwrap().unwrap();
+
+        let reply = reply.expect("No reply from localhost");
+        assert_eq!(reply.reflector, localhost);
+        assert!(reply.rtt >= 0);
+        assert_eq!(reply.down_time + reply.up_time, reply.rtt as f64);
+    }
 }
@@ -5,82 +5,193 @@ use crate::pinger::{PingError, PingListener, PingReply, PingSender};
 use crate::time::Time;
 use byteorder::*;
 use etherparse::TransportSlice::{Icmpv4, Icmpv6};
-use etherparse::{IcmpEchoHeader, Icmpv4Header, Icmpv4Type, SlicedPacket};
+use etherparse::{
+    IcmpEchoHeader, Icmpv4Header, Icmpv4Type, Icmpv6Header, Icmpv6Slice, Icmpv6Type, SlicedPacket,
+};
 use rustix::thread::ClockId;
 
-pub struct PingerICMPEchoListener {}
+pub struct PingerICMPEchoListener {
+    // Must be the same clock the sender stamps the probes with
+    pub clock: ClockId,
+    // Take the reflector from the probed address echoed back in the payload
+    pub match_payload: bool,
+    // Share of the RTT attributed to the upload direction
+    pub upload_share: f64,
+}
+
+pub struct PingerICMPEchoSender {
+    pub clock: ClockId,
+    // Append the probed address to the payload, for the listener to match the reply by
+    pub embed_reflector: bool,
+}
+
+// Recovers the probed address appended after the timestamp, if there is one
+fn embedded_reflector(payload: &[u8]) -> Option<IpAddr> {
+    match payload.len() {
+        12 => <[u8; 4]>::try_from(&payload[8..]).ok().map(IpAddr::from),
+        24 => <[u8; 16]>::try_from(&payload[8..]).ok().map(IpAddr::from),
+        _ => None,
+    }
+}
+
+impl PingerICMPEchoListener {
+    // The identifier check and timing are the same for ICMP and ICMPv6 echo replies
+    fn echo_reply(
+        &self,
+        id: u16,
+        reflector: IpAddr,
+        echo: IcmpEchoHeader,
+        payload: &[u8],
+    ) -> Result<PingReply, PingError> {
+        if echo.id != id {
+            return Err(PingError::WrongID {
+                expected: id,
+                found: echo.id,
+            });
+        }
 
-pub struct PingerICMPEchoSender {}
+        /*
+         * Reading advances the slice, and the probed address after the timestamp is still
+         * needed. Anyone can send us an echo reply, so one too short to hold a timestamp is
+         * just a packet that isn't ours.
+         */
+        let mut timestamp = payload;
+        let time_sent = timestamp.read_u64::<NativeEndian>()? as i64;
+
+        let clock = Time::new(self.clock);
+        let time_ms = clock.to_milliseconds() as i64;
+
+        let reflector = match self.match_payload {
+            true => embedded_reflector(payload).unwrap_or(reflector),
+            false => reflector,
+        };
+
+        let rtt: i64 = time_ms - time_sent;
+        Ok(PingReply {
+            reflector,
+            seq: echo.seq,
+            rtt,
+            current_time: time_ms,
+            down_time: rtt as f64 * (1.0 - self.upload_share),
+            up_time: rtt as f64 * self.upload_share,
+            originate_timestamp: 0,
+            receive_timestamp: 0,
+            transmit_timestamp: 0,
+            last_receive_time_s: Instant::now(),
+        })
+    }
+
+    fn icmpv6_reply(
+        &self,
+        id: u16,
+        reflector: IpAddr,
+        icmp: Icmpv6Slice,
+    ) -> Result<PingReply, PingError> {
+        match icmp.icmp_type() {
+            Icmpv6Type::EchoReply(echo) => self.echo_reply(id, reflector, echo, icmp.payload()),
+            type_ => Err(PingError::InvalidType(format!("{:?}", type_))),
+        }
+    }
+}
 
 impl PingListener for PingerICMPEchoListener {
     // Result: RTT, down time, up time
     fn parse_packet(&self, id: u16, reflector: IpAddr, buf: &[u8]) -> Result<PingReply, PingError> {
+        // Unlike with IPv4, raw ICMPv6 sockets only hand us the ICMPv6 message, without the IP header
+        if reflector.is_ipv6() {
+            let icmp = Icmpv6Slice::from_slice(buf).map_err(PingError::InvalidPacket)?;
+            return self.icmpv6_reply(id, reflector, icmp);
+        }
+
         match SlicedPacket::from_ip(buf) {
             Err(err) => Err(PingError::InvalidPacket(err)),
             Ok(value) => match value.transport {
                 Some(Icmpv4(icmp)) => match icmp.icmp_type() {
                     Icmpv4Type::EchoReply(echo) => {
-                        if echo.id != id {
-                            return Err(PingError::WrongID {
-                                expected: id,
-                                found: echo.id,
-                            });
-                        }
-
-                        let time_sent = icmp
-                            .payload()
-                            .read_u64::<NativeEndian>()
-                            .expect("Couldn't parse payload to time")
-                            as i64;
-
-                        let clock = Time::new(ClockId::Monotonic);
-                        let time_ms = clock.to_milliseconds() as i64;
-
-                        let rtt: i64 = time_ms - time_sent;
-                        Ok(PingReply {
-                            reflector,
-                            seq: echo.seq,
-                            rtt,
-                            current_time: time_ms,
-                            down_time: (rtt / 2) as f64,
-                            up_time: (rtt / 2) as f64,
-                            originate_timestamp: 0,
-                            receive_timestamp: 0,
-                            transmit_timestamp: 0,
-                            last_receive_time_s: Instant::now(),
-                        })
+                        self.echo_reply(id, reflector, echo, icmp.payload())
                     }
                     type_ => Err(PingError::InvalidType(format!("{:?}", type_))),
                 },
-                Some(Icmpv6(slice)) => Err(PingError::InvalidProtocol(format!("{:?}", slice))),
+                Some(Icmpv6(icmp)) => self.icmpv6_reply(id, reflector, icmp),
                 Some(type_) => Err(PingError::InvalidProtocol(format!("{:?}", type_))),
                 None => Err(PingError::NoTransport),
             },
         }
     }
+
+    fn reflector_from_payload(&self) -> bool {
+        self.match_payload
+    }
 }
 
 impl PingSender for PingerICMPEchoSender {
-    fn craft_packet(&self, id: u16, seq: u16) -> Vec<u8> {
-        let clock = Time::new(ClockId::Monotonic);
+    fn craft_packet(&self, id: u16, seq: u16, reflector: IpAddr) -> Vec<u8> {
+        let clock = Time::new(self.clock);
         let time_ms = clock.to_milliseconds();
-        let payload = time_ms.to_ne_bytes();
+        let mut payload = time_ms.to_ne_bytes().to_vec();
 
-        // Construct a header with checksum based on the payload
-        let hdr = Icmpv4Header::with_checksum(
-            Icmpv4Type::EchoRequest(IcmpEchoHeader { id, seq }),
-            &payload,
-        );
+        if self.embed_reflector {
+            match reflector {
+                IpAddr::V4(ip) => payload.extend_from_slice(&ip.octets()),
+                IpAddr::V6(ip) => payload.extend_from_slice(&ip.octets()),
+            }
+        }
+
+        let echo = IcmpEchoHeader { id, seq };
+        let mut result = Vec::<u8>::with_capacity(8 + payload.len());
 
-        // Create a buffer to hold the result of header + payload
-        let mut result = Vec::<u8>::with_capacity(hdr.header_len() + payload.len());
+        match reflector {
+            IpAddr::V4(_) => {
+                // Construct a header with checksum based on the payload
+                let hdr = Icmpv4Header::with_checksum(Icmpv4Type::EchoRequest(echo), &payload);
 
-        // Write the header to the buffer
-        hdr.write(&mut result).expect("Error writing packet");
+                // Write the header to the buffer
+                hdr.write(&mut result).expect("Error writing packet");
+            }
+            IpAddr::V6(_) => {
+                /*
+                 * The ICMPv6 checksum covers the source and destination addresses, which
+                 * only the kernel knows for sure. It fills the checksum in on raw ICMPv6
+                 * sockets anyway, so leave it zero.
+                 */
+                let hdr = Icmpv6Header::new(Icmpv6Type::EchoRequest(echo));
+                hdr.write(&mut result).expect("Error writing packet");
+            }
+        }
 
         // Write the payload to the buffer
-        result.append(&mut payload.to_vec());
+        result.append(&mut payload);
 
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo_reply(id: u16, payload: &[u8]) -> Vec<u8> {
+        let hdr = Icmpv6Header::new(Icmpv6Type::EchoReply(IcmpEchoHeader { id, seq: 1 }));
+        let mut buf = Vec::new();
+        hdr.write(&mut buf).unwrap();
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn short_payload_is_an_error() {
+        let listener = PingerICMPEchoListener {
+            clock: ClockId::Monotonic,
+            match_payload: false,
+            upload_share: 0.5,
+        };
+        let reflector: IpAddr = "2001:db8::1".parse().unwrap();
+
+        let reply = listener.parse_packet(7, reflector, &echo_reply(7, &[1, 2, 3]));
+        assert!(matches!(reply, Err(PingError::InvalidNumber(_))));
+
+        let time_ms = Time::new(ClockId::Monotonic).to_milliseconds();
+        let reply = listener.parse_packet(7, reflector, &echo_reply(7, &time_ms.to_ne_bytes()));
+        assert_eq!(reply.unwrap().reflector, reflector);
+    }
+}
@@ -62,7 +62,7 @@ impl PingListener for PingerICMPTimestampListener {
 }
 
 impl PingSender for PingerICMPTimestampSender {
-    fn craft_packet(&self, id: u16, seq: u16) -> Vec<u8> {
+    fn craft_packet(&self, id: u16, seq: u16, _reflector: IpAddr) -> Vec<u8> {
         let time_since_midnight = Time::new(ClockId::Realtime).get_time_since_midnight();
 
         let payload: [u8; 0] = [];
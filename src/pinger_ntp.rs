@@ -0,0 +1,131 @@
+use crate::pinger::{client_port, PingError, PingListener, PingReply, PingSender};
+use crate::time::{Time, NTP_UNIX_OFFSET};
+use etherparse::SlicedPacket;
+use etherparse::TransportSlice::Udp;
+use rustix::thread::ClockId;
+use std::net::IpAddr;
+use std::time::Instant;
+
+const NTP_PORT: u16 = 123;
+const NTP_PACKET_LEN: usize = 48;
+// LI 0 (no warning), version 4, mode 3 (client)
+const NTP_CLIENT_HEADER: u8 = 0x23;
+const NTP_MODE_SERVER: u8 = 4;
+const NTP_ORIGINATE_OFFSET: usize = 24;
+const NTP_RECEIVE_OFFSET: usize = 32;
+const NTP_TRANSMIT_OFFSET: usize = 40;
+// The low bits of the transmit fraction carry the sequence number, that's ~15 us of precision
+const SEQ_MASK: u64 = 0xFFFF;
+
+fn read_timestamp(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+// Converts an NTP timestamp to milliseconds since midnight UTC, like the ICMP timestamps use
+fn ntp_to_ms_since_midnight(timestamp: u64) -> i64 {
+    let secs = (timestamp >> 32) as i64;
+    let fraction = timestamp & 0xFFFF_FFFF;
+
+    // The seconds wrap around in 2036, which starts NTP era 1
+    let mut unix_secs = secs - NTP_UNIX_OFFSET as i64;
+    if unix_secs < 0 {
+        unix_secs += 1 << 32;
+    }
+
+    (unix_secs % 86400 * 1000) + ((fraction * 1000) >> 32) as i64
+}
+
+pub struct PingerNTPListener {}
+
+pub struct PingerNTPSender {}
+
+impl PingListener for PingerNTPListener {
+    // Result: RTT, down time, up time
+    fn parse_packet(&self, id: u16, reflector: IpAddr, buf: &[u8]) -> Result<PingReply, PingError> {
+        let packet = SlicedPacket::from_ip(buf).map_err(PingError::InvalidPacket)?;
+        let udp = match packet.transport {
+            Some(Udp(udp)) => udp,
+            Some(type_) => return Err(PingError::InvalidType(format!("{:?}", type_))),
+            None => return Err(PingError::NoTransport),
+        };
+
+        // The raw socket sees all UDP traffic, not just the replies to our probes
+        let port = client_port(id);
+        if udp.source_port() != NTP_PORT || udp.destination_port() != port {
+            return Err(PingError::WrongID {
+                expected: port,
+                found: udp.destination_port(),
+            });
+        }
+
+        let payload = packet.payload;
+        if payload.len() < NTP_PACKET_LEN {
+            return Err(PingError::InvalidProtocol(format!(
+                "NTP reply too short ({} bytes)",
+                payload.len()
+            )));
+        }
+        if payload[0] & 0x07 != NTP_MODE_SERVER {
+            return Err(PingError::InvalidType(format!(
+                "NTP mode {}",
+                payload[0] & 0x07
+            )));
+        }
+        // Stratum 0 is a Kiss-o'-Death, telling us to back off, and carries no timestamps
+        if payload[1] == 0 {
+            return Err(PingError::InvalidType("NTP Kiss-o'-Death".to_string()));
+        }
+
+        let time_now = Time::new(ClockId::Realtime);
+        let time_since_midnight = time_now.get_time_since_midnight();
+
+        let originate = read_timestamp(payload, NTP_ORIGINATE_OFFSET);
+        let seq = (originate & SEQ_MASK) as u16;
+
+        let originate_timestamp = ntp_to_ms_since_midnight(originate & !SEQ_MASK);
+        let receive_timestamp =
+            ntp_to_ms_since_midnight(read_timestamp(payload, NTP_RECEIVE_OFFSET));
+        let transmit_timestamp =
+            ntp_to_ms_since_midnight(read_timestamp(payload, NTP_TRANSMIT_OFFSET));
+
+        let rtt: i64 = time_since_midnight - originate_timestamp;
+        let dl_time: i64 = time_since_midnight - transmit_timestamp;
+        let ul_time: i64 = receive_timestamp - originate_timestamp;
+
+        Ok(PingReply {
+            reflector,
+            seq,
+            rtt,
+            current_time: time_since_midnight,
+            down_time: dl_time as f64,
+            up_time: ul_time as f64,
+            originate_timestamp,
+            receive_timestamp,
+            transmit_timestamp,
+            last_receive_time_s: Instant::now(),
+        })
+    }
+}
+
+impl PingSender for PingerNTPSender {
+    /*
+     * A bare client request. The server copies our transmit timestamp into the originate
+     * timestamp of its reply untouched, so the sequence number rides along in its lowest bits.
+     */
+    fn craft_packet(&self, _id: u16, seq: u16, _reflector: IpAddr) -> Vec<u8> {
+        let mut packet = vec![0u8; NTP_PACKET_LEN];
+        packet[0] = NTP_CLIENT_HEADER;
+
+        let transmit = (Time::new(ClockId::Realtime).to_ntp() & !SEQ_MASK) | seq as u64;
+        packet[NTP_TRANSMIT_OFFSET..NTP_TRANSMIT_OFFSET + 8]
+            .copy_from_slice(&transmit.to_be_bytes());
+
+        packet
+    }
+
+    fn port(&self) -> u16 {
+        NTP_PORT
+    }
+}
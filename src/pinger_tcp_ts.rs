@@ -0,0 +1,202 @@
+use crate::pinger::{client_port, PingError, PingListener, PingReply, PingSender};
+use crate::time::Time;
+use etherparse::TransportSlice::Tcp;
+use etherparse::{SlicedPacket, TcpHeader, TcpOptionElement};
+use rustix::thread::ClockId;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::sync::Mutex;
+use std::time::Instant;
+
+const TCP_WINDOW_SIZE: u16 = 64240;
+const TCP_MSS: u16 = 1460;
+
+/*
+ * The kernel picks the source address when it adds the IP header, but the TCP checksum
+ * covers it too, so ask the routing table which address it's going to pick. Connecting a
+ * UDP socket doesn't send anything.
+ */
+fn source_address(reflector: Ipv4Addr) -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((reflector, 9)).ok()?;
+
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(addr) => Some(addr),
+        IpAddr::V6(_) => None,
+    }
+}
+
+pub struct PingerTCPTimestampListener {
+    pub clock: ClockId,
+    pub port: u16,
+    pub upload_share: f64,
+}
+
+pub struct PingerTCPTimestampSender {
+    pub clock: ClockId,
+    pub port: u16,
+    // The source address for each reflector, looked up once rather than for every probe
+    pub sources: Mutex<HashMap<Ipv4Addr, Ipv4Addr>>,
+}
+
+impl PingerTCPTimestampSender {
+    fn source_for(&self, reflector: Ipv4Addr) -> Ipv4Addr {
+        *self
+            .sources
+            .lock()
+            .unwrap()
+            .entry(reflector)
+            .or_insert_with(|| source_address(reflector).unwrap_or(Ipv4Addr::UNSPECIFIED))
+    }
+}
+
+impl PingListener for PingerTCPTimestampListener {
+    // Result: RTT, down time, up time
+    fn parse_packet(&self, id: u16, reflector: IpAddr, buf: &[u8]) -> Result<PingReply, PingError> {
+        let packet = SlicedPacket::from_ip(buf).map_err(PingError::InvalidPacket)?;
+        let tcp = match packet.transport {
+            Some(Tcp(tcp)) => tcp,
+            Some(type_) => return Err(PingError::InvalidType(format!("{:?}", type_))),
+            None => return Err(PingError::NoTransport),
+        };
+
+        // The raw socket sees all TCP traffic, not just the replies to our probes
+        let port = client_port(id);
+        if tcp.source_port() != self.port || tcp.destination_port() != port {
+            return Err(PingError::WrongID {
+                expected: port,
+                found: tcp.destination_port(),
+            });
+        }
+
+        if !tcp.syn() || !tcp.ack() {
+            return Err(PingError::InvalidType(format!(
+                "TCP segment without SYN-ACK (rst: {})",
+                tcp.rst()
+            )));
+        }
+
+        // Our initial sequence number carries the ID and the probe's sequence number
+        let probe = tcp.acknowledgment_number().wrapping_sub(1);
+        if (probe >> 16) as u16 != id {
+            return Err(PingError::WrongID {
+                expected: id,
+                found: (probe >> 16) as u16,
+            });
+        }
+
+        let time_sent = tcp
+            .options_iterator()
+            .find_map(|option| match option {
+                Ok(TcpOptionElement::Timestamp(_, echo_reply)) => Some(echo_reply),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                PingError::InvalidProtocol("SYN-ACK without TCP timestamps".to_string())
+            })?;
+
+        /*
+         * The reflector's TSval runs on a clock with an arbitrary offset and rate, so only
+         * the echoed TSval is of any use. That gives the RTT, which is split between the
+         * directions the same way as with ICMP echo.
+         */
+        let time_ms = Time::new(self.clock).to_milliseconds();
+        let rtt = (time_ms as u32).wrapping_sub(time_sent) as i64;
+
+        Ok(PingReply {
+            reflector,
+            seq: probe as u16,
+            rtt,
+            current_time: time_ms as i64,
+            down_time: rtt as f64 * (1.0 - self.upload_share),
+            up_time: rtt as f64 * self.upload_share,
+            originate_timestamp: 0,
+            receive_timestamp: 0,
+            transmit_timestamp: 0,
+            last_receive_time_s: Instant::now(),
+        })
+    }
+}
+
+impl PingSender for PingerTCPTimestampSender {
+    /*
+     * A SYN with the timestamp option set, which a reflector listening on the port answers
+     * with a SYN-ACK echoing our TSval. Our kernel doesn't know about the connection, so it
+     * resets it right after, and the reflector never gets as far as an open connection.
+     */
+    fn craft_packet(&self, id: u16, seq: u16, reflector: IpAddr) -> Vec<u8> {
+        let (source, destination) = match reflector {
+            IpAddr::V4(addr) => (self.source_for(addr), addr),
+            IpAddr::V6(_) => return Vec::new(),
+        };
+
+        let mut hdr = TcpHeader::new(
+            client_port(id),
+            self.port,
+            ((id as u32) << 16) | seq as u32,
+            TCP_WINDOW_SIZE,
+        );
+        hdr.syn = true;
+
+        let time_ms = Time::new(self.clock).to_milliseconds() as u32;
+        hdr.set_options(&[
+            TcpOptionElement::MaximumSegmentSize(TCP_MSS),
+            TcpOptionElement::Noop,
+            TcpOptionElement::Noop,
+            TcpOptionElement::Timestamp(time_ms, 0),
+        ])
+        .expect("Error setting TCP options");
+        hdr.checksum = hdr
+            .calc_checksum_ipv4_raw(source.octets(), destination.octets(), &[])
+            .expect("Error calculating TCP checksum");
+
+        let mut result = Vec::<u8>::with_capacity(hdr.header_len() as usize);
+        hdr.write(&mut result).expect("Error writing packet");
+
+        result
+    }
+
+    fn port(&self) -> u16 {
+        self.port
+    }
+
+    // The routes may well have changed too by the time the peers are reselected
+    fn peers_changed(&self, _reflectors: &[IpAddr]) {
+        self.sources.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_is_looked_up_once_per_peer_set() {
+        let sender = PingerTCPTimestampSender {
+            clock: ClockId::Monotonic,
+            port: 443,
+            sources: Mutex::default(),
+        };
+        let reflector = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        sender.craft_packet(1, 1, reflector);
+        // A stale entry that a second lookup would've replaced
+        sender
+            .sources
+            .lock()
+            .unwrap()
+            .insert(Ipv4Addr::LOCALHOST, Ipv4Addr::new(192, 0, 2, 1));
+        sender.craft_packet(1, 2, reflector);
+        assert_eq!(
+            sender.sources.lock().unwrap()[&Ipv4Addr::LOCALHOST],
+            Ipv4Addr::new(192, 0, 2, 1)
+        );
+
+        sender.peers_changed(&[reflector]);
+        sender.craft_packet(1, 3, reflector);
+        assert_eq!(
+            sender.sources.lock().unwrap()[&Ipv4Addr::LOCALHOST],
+            Ipv4Addr::LOCALHOST
+        );
+    }
+}
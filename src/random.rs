@@ -0,0 +1,61 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// The parts of the daemon that make random choices, each of which gets its own generator
+#[derive(Clone, Copy, Debug)]
+pub enum RngStream {
+    InitialPeers,
+    Ratecontroller,
+    ReflectorSelector,
+}
+
+// The SplitMix64 finalizer, which spreads a small change in the input over all of the output
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/*
+ * All randomness in the daemon comes from here. With a seed of 0 each generator is seeded
+ * from OS entropy. Otherwise every stream is derived from the configured seed, so two runs
+ * with the same seed make the same choices regardless of the order threads start in. The
+ * seed is mixed before the stream is, so neighbouring seeds don't share any streams.
+ */
+pub fn new_rng(seed: u64, stream: RngStream) -> StdRng {
+    if seed == 0 {
+        StdRng::from_entropy()
+    } else {
+        StdRng::seed_from_u64(splitmix64(splitmix64(seed) ^ stream as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::seq::SliceRandom;
+
+    fn shuffled(seed: u64, stream: RngStream) -> Vec<u32> {
+        let mut deck: Vec<u32> = (0..32).collect();
+        deck.shuffle(&mut new_rng(seed, stream));
+        deck
+    }
+
+    #[test]
+    fn fixed_seed_shuffles_the_same() {
+        assert_eq!(
+            shuffled(42, RngStream::ReflectorSelector),
+            shuffled(42, RngStream::ReflectorSelector)
+        );
+        assert_ne!(
+            shuffled(42, RngStream::ReflectorSelector),
+            shuffled(42, RngStream::InitialPeers)
+        );
+        // Adding the stream to the seed would've made these the same
+        assert_ne!(
+            shuffled(1, RngStream::ReflectorSelector),
+            shuffled(2, RngStream::Ratecontroller)
+        );
+    }
+}
@@ -1,22 +1,28 @@
-use crate::netlink::{Netlink, NetlinkError, Qdisc};
+use crate::capacity_profile::{current_hour, CapacityProfile};
+use crate::config::{DeltaAggregation, StatsFormat, WarmupShape};
+use crate::fifo::StatsFifo;
+use crate::metrics::{Counters, RateMetrics, SafeRates};
+use crate::netlink::{Netlink, NetlinkError, Qdisc, Shaper};
+use crate::random::{new_rng, RngStream};
+use crate::state_file::{json_number, json_string};
+use crate::time::time_format;
 use crate::{Config, ReflectorStats};
-use log::{debug, error, info, warn};
+use log::{debug, info, warn};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rand::thread_rng;
-use rand::RngCore;
-use std::collections::HashMap;
+use rand::Rng;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::io::Write;
 use std::net::IpAddr;
-use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread::sleep;
 use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 use time::format_description::FormatItem;
-use time::formatting::Formattable;
 use time::macros::format_description;
-use time::OffsetDateTime;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 enum Direction {
@@ -36,35 +42,175 @@ pub enum StatsDirection {
     TX,
 }
 
+// Deltas needed before the delay signal is trusted, unless reselection is disabled with fewer peers
+const MIN_DELTAS: usize = 3;
+
 const DUMP_DATETIME_FORMAT: &[FormatItem] = format_description!(
     "[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour \
          sign:mandatory]:[offset_minute]:[offset_second]"
 );
 
-fn time_format<T>(dt: T, format: &(impl Formattable + ?Sized)) -> String
-where
-    T: Into<OffsetDateTime>,
-{
-    dt.into().format(format).unwrap()
-}
-
-fn generate_initial_speeds(base_speed: f64, size: u32) -> Vec<f64> {
+/*
+ * Seeds the safe rate history with 75% - 95% of the base rate. The seeds are kept
+ * within [min_speed, base_speed], so a misconfigured minimum above 75% of base
+ * doesn't produce seeds below what we'd ever actually set.
+ */
+fn generate_initial_speeds(
+    rng: &mut impl Rng,
+    min_speed: f64,
+    base_speed: f64,
+    size: u32,
+) -> Vec<f64> {
     let mut rates = Vec::new();
 
     for _ in 0..size {
-        rates.push((thread_rng().next_u64() as f64 * 0.2 + 0.75) * base_speed);
+        let rate = (rng.gen::<f64>() * 0.2 + 0.75) * base_speed;
+        rates.push(rate.min(base_speed).max(min_speed.min(base_speed)));
     }
 
     rates
 }
 
+// Median of deltas that are already sorted, the mean of the middle two for an even count
+fn median(sorted: &[f64]) -> f64 {
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+// Combines the sorted per-reflector deltas into one, clamping the index to the ones there are
+fn aggregate_delta(sorted: &[f64], aggregation: DeltaAggregation, index: usize) -> f64 {
+    match aggregation {
+        DeltaAggregation::Index => sorted[index.min(sorted.len() - 1)],
+        DeltaAggregation::Median => median(sorted),
+    }
+}
+
+/*
+ * The rates to warm up to and seed the safe rate history below. With a capacity profile,
+ * that's what the link usually manages at this hour, as long as it's between min and base.
+ */
+fn start_rates(config: &Config, profile: Option<&CapacityProfile>, hour: usize) -> (f64, f64) {
+    match profile.and_then(|profile| profile.rates(hour)) {
+        Some((download, upload)) => (
+            download
+                .min(config.download_base_kbits)
+                .max(config.download_min_kbits),
+            upload
+                .min(config.upload_base_kbits)
+                .max(config.upload_min_kbits),
+        ),
+        None => (config.download_base_kbits, config.upload_base_kbits),
+    }
+}
+
+/*
+ * Puts a rate on a multiple of the step. It's rounded in the direction the rate is moving,
+ * so changes smaller than the step still move it by one step rather than being rounded away,
+ * which would leave the rate stuck. Never rounds below the floor.
+ */
+fn quantize_rate(next_rate: f64, current_rate: f64, floor: f64, step: f64) -> f64 {
+    let quantized = if next_rate > current_rate {
+        (next_rate / step).ceil() * step
+    } else {
+        (next_rate / step).floor() * step
+    };
+
+    if quantized < floor {
+        (floor / step).ceil() * step
+    } else {
+        quantized
+    }
+}
+
+/*
+ * A direction limited to a short list of reflectors never gets more deltas than that, so
+ * it isn't held to more than that either.
+ */
+fn wanted_deltas(wanted: usize, direction_reflectors: &[IpAddr]) -> usize {
+    match direction_reflectors.len() {
+        0 => wanted,
+        len => wanted.min(len),
+    }
+}
+
+/*
+ * A clock glitch on a reflector can turn its EWMAs into NaN or infinity, which would
+ * otherwise end up as the chosen delta or break sorting. Such a delta tells us nothing, so
+ * it's left out like a reflector that didn't reply.
+ */
+fn push_finite(deltas: &mut Vec<f64>, delta: f64) {
+    if delta.is_finite() {
+        deltas.push(delta);
+    }
+}
+
+// Whether the reflector's delay counts toward a direction limited to these reflectors
+fn counts_for(direction_reflectors: &[IpAddr], reflector: &IpAddr) -> bool {
+    direction_reflectors.is_empty() || direction_reflectors.contains(reflector)
+}
+
+/*
+ * Caps the aggregated delta at the highest one that enough reflectors reached, for no
+ * reflector to weigh more than `max_weight` in it. The deltas are sorted, so the one
+ * `1 / max_weight` from the top is as high as that many of them agree on.
+ */
+fn cap_delta(sorted: &[f64], delta: f64, max_weight: f64) -> f64 {
+    if max_weight <= 0.0 || max_weight >= 1.0 {
+        return delta;
+    }
+
+    let agreeing = (1.0 / max_weight).ceil() as usize;
+    delta.min(sorted[sorted.len().saturating_sub(agreeing)])
+}
+
+// The rate the shaper is currently set to, in kbit/s
+pub fn shaper_rate(netlink: &mut dyn Shaper, qdisc: Qdisc) -> Option<u64> {
+    match netlink.get_qdisc_rate(qdisc) {
+        Ok(rate) => Some(rate),
+        Err(e) => {
+            debug!("Couldn't read the current shaper rate: {}", e);
+            None
+        }
+    }
+}
+
+/*
+ * The rate to pick up from, if the shaper was left at one that's worth it. main() drops the
+ * shaper to the start rate before the first ratecontroller starts, so finding it above that
+ * means a restarted ratecontroller, or an earlier run, left it there. Otherwise, it's the
+ * usual warmup.
+ */
+pub fn resume_rate(rate: Option<u64>, start_rate: f64, base_rate: f64) -> Option<f64> {
+    match rate {
+        Some(rate) if rate as f64 > start_rate => Some((rate as f64).min(base_rate)),
+        _ => None,
+    }
+}
+
+/// Rate to use at `progress` (0.0 - 1.0) through the warmup ramp from `min_rate` to `base_rate`
+fn warmup_rate(shape: WarmupShape, min_rate: f64, base_rate: f64, progress: f64) -> f64 {
+    match shape {
+        // Equal ratios per step, so the ramp is gentle at the bottom and quicker near the top
+        WarmupShape::Exponential if min_rate > 0.0 => {
+            min_rate * (base_rate / min_rate).powf(progress)
+        }
+        // There's no ratio to go by from zero, so that ramps up linearly instead
+        _ => min_rate + (base_rate - min_rate) * progress,
+    }
+}
+
 fn get_interface_stats(
+    netlink: &mut dyn Shaper,
     config: &Config,
     down_direction: StatsDirection,
     up_direction: StatsDirection,
 ) -> Result<(i128, i128), RatecontrolError> {
-    let down_stats = Netlink::get_interface_stats(config.download_interface.as_str())?;
-    let up_stats = Netlink::get_interface_stats(config.upload_interface.as_str())?;
+    let down_stats = netlink.get_interface_stats(config.download_interface.as_str())?;
+    let up_stats = netlink.get_interface_stats(config.upload_interface.as_str())?;
     let (down_rx, down_tx) = (down_stats.rx_bytes, down_stats.tx_bytes);
     let (up_rx, up_tx) = (up_stats.rx_bytes, up_stats.tx_bytes);
 
@@ -83,82 +229,161 @@ fn get_interface_stats(
 
 #[derive(Clone, Debug)]
 struct State {
+    breaker_until: Option<Instant>,
+    // (time, byte counter) samples used for the utilisation calculation, oldest first
+    byte_samples: VecDeque<(Instant, i128)>,
+    congested_at_floor_since: Option<Instant>,
     current_bytes: i128,
     current_rate: f64,
     delta_stat: f64,
     deltas: Vec<f64>,
+    floor: f64,
+    last_change_t: Instant,
     qdisc: Qdisc,
     load: f64,
     next_rate: f64,
     nrate: usize,
-    previous_bytes: i128,
-    prev_t: Instant,
     safe_rates: Vec<f64>,
+    saturated_since: Option<Instant>,
+    stuck_since: Option<Instant>,
     utilisation: f64,
 }
 
 impl State {
-    fn new(qdisc: Qdisc, previous_bytes: i128, safe_rates: Vec<f64>) -> Self {
+    fn new(
+        qdisc: Qdisc,
+        previous_bytes: i128,
+        safe_rates: Vec<f64>,
+        floor: f64,
+        now_t: Instant,
+    ) -> Self {
         State {
+            breaker_until: None,
+            byte_samples: VecDeque::from([(now_t, previous_bytes)]),
+            congested_at_floor_since: None,
             current_bytes: 0,
             current_rate: 0.0,
             delta_stat: 0.0,
             deltas: Vec::new(),
+            floor,
+            last_change_t: now_t,
             load: 0.0,
             next_rate: 0.0,
             nrate: 0,
             qdisc,
-            previous_bytes,
-            prev_t: Instant::now(),
             safe_rates,
+            saturated_since: None,
+            stuck_since: None,
             utilisation: 0.0,
         }
     }
 }
 
 pub struct Ratecontroller {
+    // Learned from the safe rates, for starting out from the usual capacity at this time of day
+    capacity_profile: Option<CapacityProfile>,
     config: Config,
+    counters: Arc<Counters>,
+    disabled_reflectors: Arc<RwLock<HashSet<IpAddr>>>,
     down_direction: StatsDirection,
+    // The reflectors each direction's delta is limited to, empty for all of the peers
+    download_reflectors: Vec<IpAddr>,
+    // Shared with the sender, which scales back probing while this is set
+    idle: Arc<AtomicBool>,
+    low_load_since: Option<Instant>,
+    // Fewer deltas than this aren't enough to go on, and send the rate to the floor
+    min_deltas: usize,
+    netlink: Box<dyn Shaper>,
     owd_baseline: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
     owd_recent: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    // Ticks of data seen from each peer, for keeping newly selected ones out while they settle
+    peer_samples: Option<HashMap<IpAddr, u32>>,
+    // The hour of the day the capacity profile is learning for
+    profile_hour: usize,
+    rate_metrics: Arc<Mutex<RateMetrics>>,
+    reference_reflectors: Vec<IpAddr>,
     reflectors_lock: Arc<RwLock<Vec<IpAddr>>>,
     reselect_trigger: Sender<bool>,
+    // Whether anything acts on the reselection requests
+    reselection_active: bool,
+    // Picked up from the rates the shaper was left at, rather than warming up again
+    resumed: bool,
+    // Seeded for replays, so they're reproducible
+    rng: StdRng,
+    safe_rates: Arc<Mutex<SafeRates>>,
+    // Set on SIGTERM/SIGINT, the main thread restores the base rates once we've stopped
+    shutdown: Arc<AtomicBool>,
     state_dl: State,
     state_ul: State,
     up_direction: StatsDirection,
+    upload_reflectors: Vec<IpAddr>,
 }
 
 impl Ratecontroller {
-    fn calculate_rate(&mut self, direction: Direction) -> anyhow::Result<()> {
-        let (base_rate, delay_ms, min_rate, state) = if direction == Direction::Down {
-            (
-                self.config.download_base_kbits,
-                self.config.download_delay_ms,
-                self.config.download_min_kbits,
-                &mut self.state_dl,
-            )
-        } else {
-            (
-                self.config.upload_base_kbits,
-                self.config.upload_delay_ms,
-                self.config.upload_min_kbits,
-                &mut self.state_ul,
-            )
-        };
+    fn calculate_rate(&mut self, direction: Direction, now_t: Instant) -> anyhow::Result<()> {
+        let (base_rate, delay_ms, delta_index, min_rate, hard_min_rate, state) =
+            if direction == Direction::Down {
+                (
+                    self.config.download_base_kbits,
+                    self.config.download_delay_ms,
+                    self.config.download_delta_index,
+                    self.config.download_min_kbits,
+                    self.config.download_hard_min_kbits,
+                    &mut self.state_dl,
+                )
+            } else {
+                (
+                    self.config.upload_base_kbits,
+                    self.config.upload_delay_ms,
+                    self.config.upload_delta_index,
+                    self.config.upload_min_kbits,
+                    self.config.upload_hard_min_kbits,
+                    &mut self.state_ul,
+                )
+            };
 
-        let now_t = Instant::now();
-        let dur = now_t.duration_since(state.prev_t);
+        /*
+         * Utilisation is measured from the oldest sample in the window. With the default
+         * window of 1 that's just the previous tick, larger windows smooth out bursts.
+         */
+        let (window_start_t, window_start_bytes) = state.byte_samples[0];
+        let dur = now_t.duration_since(window_start_t);
+
+        let in_speed_test = self.config.speed_test_load > 0.0
+            && Self::detect_speed_test(
+                direction,
+                state,
+                window_start_bytes,
+                dur,
+                self.config.speed_test_load,
+                self.config.speed_test_duration,
+                now_t,
+            );
 
-        if !state.deltas.is_empty() {
+        if in_speed_test {
+            state.next_rate = base_rate;
+        } else if !state.deltas.is_empty() {
             state.next_rate = state.current_rate;
 
-            if state.deltas.len() < 3 {
-                state.next_rate = min_rate;
+            if state.deltas.len() < self.min_deltas {
+                state.next_rate = state.floor;
             } else {
-                state.delta_stat = if state.deltas[2] > 0.0 {
-                    state.deltas[2]
+                let delta =
+                    aggregate_delta(&state.deltas, self.config.delta_aggregation, delta_index);
+                let delta = cap_delta(&state.deltas, delta, self.config.max_reflector_weight);
+                let raw_delta = if delta > 0.0 { delta } else { state.deltas[0] };
+
+                /*
+                 * Aggregating over the reflectors takes care of noise from any one of them,
+                 * but a single noisy tick still goes straight into the decision. Optionally
+                 * smooth over time too, so it takes a sustained increase to back off.
+                 */
+                state.delta_stat = if self.config.delta_smoothing > 0.0 {
+                    let elapsed = now_t.duration_since(state.last_change_t).as_secs_f64();
+                    let alpha = 1.0 - (-elapsed / self.config.delta_smoothing).exp();
+                    state.delta_stat + alpha * (raw_delta - state.delta_stat)
                 } else {
-                    state.deltas[0]
+                    raw_delta
                 };
 
                 if state.delta_stat > 0.0 {
@@ -168,15 +393,38 @@ impl Ratecontroller {
                      *    ii. ideally, see if it can be defined in terms of constants, eg ticks per second and number of active reflectors
                      */
                     state.utilisation = (8.0 / 1000.0)
-                        * (state.current_bytes as f64 - state.previous_bytes as f64)
+                        * (state.current_bytes as f64 - window_start_bytes as f64)
                         / dur.as_secs_f64();
                     state.load = state.utilisation / state.current_rate;
 
-                    if state.delta_stat > 0.0
-                        && state.delta_stat < delay_ms
-                        && state.load > self.config.high_load_level
+                    /*
+                     * Right after a backoff the current rate is low, so even modest traffic
+                     * counts as high load relative to it. Optionally judge the load against
+                     * the highest rate the link has been seen to carry instead, so the signal
+                     * doesn't swing with our own rate changes. The rate maths below still
+                     * works from the load relative to the current rate.
+                     */
+                    let high_load_signal = if self.config.load_from_capacity {
+                        let capacity = state
+                            .safe_rates
+                            .iter()
+                            .copied()
+                            .fold(state.current_rate, f64::max);
+                        state.utilisation / capacity
+                    } else {
+                        state.load
+                    };
+
+                    if state.delta_stat < delay_ms && high_load_signal > self.config.high_load_level
                     {
                         state.safe_rates[state.nrate] = (state.current_rate * state.load).round();
+                        if let Some(profile) = self.capacity_profile.as_mut() {
+                            let rate = state.safe_rates[state.nrate];
+                            match direction {
+                                Direction::Down => profile.learn_download(self.profile_hour, rate),
+                                Direction::Up => profile.learn_upload(self.profile_hour, rate),
+                            }
+                        }
                         let max_rate = state
                             .safe_rates
                             .iter()
@@ -190,8 +438,7 @@ impl Ratecontroller {
                     }
 
                     if state.delta_stat > delay_ms {
-                        let mut rng = thread_rng();
-                        match state.safe_rates.choose(&mut rng) {
+                        match state.safe_rates.choose(&mut self.rng) {
                             Some(rnd_rate) => {
                                 state.next_rate =
                                     rnd_rate.min(0.9 * state.current_rate * state.load);
@@ -205,27 +452,368 @@ impl Ratecontroller {
             }
         }
 
-        state.next_rate = state.next_rate.max(min_rate).round();
-        state.previous_bytes = state.current_bytes;
-        state.prev_t = now_t;
+        if self.config.adaptive_min_rate {
+            Self::adapt_floor(
+                direction,
+                state,
+                delay_ms,
+                min_rate,
+                hard_min_rate,
+                self.config.adaptive_min_duration,
+                now_t,
+            );
+        }
+
+        if self.config.circuit_breaker_window > 0.0
+            && Self::check_circuit_breaker(
+                direction,
+                state,
+                base_rate,
+                delay_ms,
+                self.config.circuit_breaker_window,
+                now_t,
+            )
+        {
+            self.counters.event(
+                "circuit_breaker",
+                format!("{:?} rate reverted to base", direction),
+            );
+        }
+
+        state.next_rate = state.next_rate.max(state.floor).round();
+        state.byte_samples.push_back((now_t, state.current_bytes));
+        while state.byte_samples.len() > self.config.utilisation_window.max(1) as usize {
+            state.byte_samples.pop_front();
+        }
 
         Ok(())
     }
 
-    fn update_deltas(&mut self) {
+    /*
+     * A speed test deliberately saturates the link, which would make us back off and
+     * fill the safe rate history with whatever the test happened to push through.
+     * If utilisation stays close to the shaper rate for long enough, assume that's what
+     * is going on and hold at the base rate until the load goes away again.
+     */
+    fn detect_speed_test(
+        direction: Direction,
+        state: &mut State,
+        window_start_bytes: i128,
+        dur: Duration,
+        load_level: f64,
+        duration: f64,
+        now_t: Instant,
+    ) -> bool {
+        let utilisation = (8.0 / 1000.0) * (state.current_bytes as f64 - window_start_bytes as f64)
+            / dur.as_secs_f64();
+        let load = utilisation / state.current_rate;
+
+        if load < load_level {
+            if state.saturated_since.take().is_some() {
+                info!(
+                    "{:?} saturation ended, resuming normal rate control",
+                    direction
+                );
+            }
+            return false;
+        }
+
+        let since = *state.saturated_since.get_or_insert(now_t);
+        if now_t.duration_since(since).as_secs_f64() < duration {
+            return false;
+        }
+
+        debug!(
+            "{:?} link saturated (load {:.2}), looks like a speed test, holding base rate",
+            direction, load
+        );
+        state.utilisation = utilisation;
+        state.load = load;
+
+        true
+    }
+
+    /*
+     * If we're sitting at the floor and still seeing bloat for a sustained period,
+     * the link capacity has likely collapsed below the configured minimum.
+     * Step the floor down towards the hard minimum, and put it back once the
+     * controller has recovered above the configured minimum again.
+     */
+    fn adapt_floor(
+        direction: Direction,
+        state: &mut State,
+        delay_ms: f64,
+        min_rate: f64,
+        hard_min_rate: f64,
+        duration: f64,
+        now_t: Instant,
+    ) {
+        if state.next_rate > min_rate && state.floor < min_rate {
+            info!(
+                "{:?} rate recovered above the configured minimum, restoring floor to {}",
+                direction, min_rate
+            );
+            state.floor = min_rate;
+            state.congested_at_floor_since = None;
+            return;
+        }
+
+        if state.delta_stat <= delay_ms || state.current_rate > state.floor {
+            state.congested_at_floor_since = None;
+            return;
+        }
+
+        let since = *state.congested_at_floor_since.get_or_insert(now_t);
+        if now_t.duration_since(since).as_secs_f64() < duration || state.floor <= hard_min_rate {
+            return;
+        }
+
+        state.floor = (state.floor * 0.9).max(hard_min_rate).round();
+        state.congested_at_floor_since = Some(now_t);
+        warn!(
+            "!!! {:?} link still congested at minimum rate, lowering floor to {} kbit/s !!!",
+            direction, state.floor
+        );
+    }
+
+    /*
+     * Safety net against measurement bugs: if we've been pinned near the floor for the
+     * whole window, reporting congestion while there's hardly any traffic on the link,
+     * the delay we're reacting to can't be caused by our own queue. Rather than leaving
+     * the link crippled, go back to the base rate for a window and then try again.
+     * Returns whether the breaker tripped just now.
+     */
+    fn check_circuit_breaker(
+        direction: Direction,
+        state: &mut State,
+        base_rate: f64,
+        delay_ms: f64,
+        window: f64,
+        now_t: Instant,
+    ) -> bool {
+        const LOW_LOAD: f64 = 0.1;
+
+        if let Some(until) = state.breaker_until {
+            if now_t < until {
+                state.next_rate = base_rate;
+                return false;
+            }
+
+            info!(
+                "{:?} circuit breaker released, resuming rate control",
+                direction
+            );
+            state.breaker_until = None;
+            state.stuck_since = None;
+        }
+
+        let stuck = state.current_rate <= state.floor * 1.1
+            && state.load < LOW_LOAD
+            && state.delta_stat > delay_ms;
+
+        if !stuck {
+            state.stuck_since = None;
+            return false;
+        }
+
+        let since = *state.stuck_since.get_or_insert(now_t);
+        if now_t.duration_since(since).as_secs_f64() >= window {
+            warn!(
+                "{:?} rate has been held at minimum for {}s without any real load, autorate may be malfunctioning. Reverting to base rate",
+                direction, window
+            );
+            state.breaker_until = Some(now_t + Duration::from_secs_f64(window));
+            state.next_rate = base_rate;
+            return true;
+        }
+
+        false
+    }
+
+    // Keeps the next rate from going below the practical floor for the qdisc
+    fn apply_qdisc_floor(direction: Direction, state: &mut State, floor: f64) {
+        if state.next_rate < floor {
+            warn!(
+                "{:?} rate of {:.0} kbit is below the qdisc floor, using {:.0} kbit instead",
+                direction, state.next_rate, floor
+            );
+            state.next_rate = floor;
+        }
+    }
+
+    /*
+     * Saturating both directions at once (e.g. a full-duplex speed test) tends to inflate
+     * the delay in both directions, even when only one of them is actually bloated.
+     * Backing both off then over-corrects, so only back off the direction that's
+     * furthest over its delay target, and hold the other one where it is.
+     */
+    fn coordinate_backoff(&mut self) {
+        let dl_relative = self.state_dl.delta_stat / self.config.download_delay_ms;
+        let ul_relative = self.state_ul.delta_stat / self.config.upload_delay_ms;
+
+        if dl_relative <= 1.0 || ul_relative <= 1.0 {
+            return;
+        }
+
+        let (held, direction) = if dl_relative >= ul_relative {
+            (&mut self.state_ul, Direction::Up)
+        } else {
+            (&mut self.state_dl, Direction::Down)
+        };
+
+        debug!(
+            "Congestion in both directions (D/L: {:.2}, U/L: {:.2}), holding {:?} rate",
+            dl_relative, ul_relative, direction
+        );
+        held.next_rate = held.current_rate.max(held.floor);
+    }
+
+    /*
+     * If every active reflector sits behind the same congested path, they'll all report
+     * bloat that backing off our shaper can't fix. The reference reflectors are probed
+     * independently, so when the active set says we're congested but the reference set
+     * is still well under the delay target, hold the rate instead of backing off.
+     * Without any fresh reference data we trust the active set.
+     */
+    fn check_reference(&mut self, now_t: Instant) {
+        let mut ref_dl: Vec<f64> = Vec::new();
+        let mut ref_ul: Vec<f64> = Vec::new();
+
+        {
+            let owd_baseline = self.owd_baseline.lock().unwrap();
+            let owd_recent = self.owd_recent.lock().unwrap();
+
+            for reflector in self.reference_reflectors.iter() {
+                let (baseline, recent) =
+                    match (owd_baseline.get(reflector), owd_recent.get(reflector)) {
+                        (Some(baseline), Some(recent)) => (baseline, recent),
+                        _ => continue,
+                    };
+
+                if now_t
+                    .duration_since(recent.last_receive_time_s)
+                    .as_secs_f64()
+                    < self.config.reference_interval * 2.0
+                {
+                    push_finite(&mut ref_dl, recent.down_ewma - baseline.down_ewma);
+                    push_finite(&mut ref_ul, recent.up_ewma - baseline.up_ewma);
+                }
+            }
+        }
+
+        ref_dl.sort_by(f64::total_cmp);
+        ref_ul.sort_by(f64::total_cmp);
+
+        for direction in [Direction::Down, Direction::Up] {
+            let (delay_ms, reference_deltas, state) = if direction == Direction::Down {
+                (self.config.download_delay_ms, &ref_dl, &mut self.state_dl)
+            } else {
+                (self.config.upload_delay_ms, &ref_ul, &mut self.state_ul)
+            };
+            let reference_delta = match reference_deltas.get(reference_deltas.len() / 2) {
+                Some(reference_delta) => *reference_delta,
+                None => continue,
+            };
+
+            if state.delta_stat > delay_ms && reference_delta < delay_ms / 2.0 {
+                debug!(
+                    "{:?} delta {:.2} ms disagrees with reference delta {:.2} ms, not backing off",
+                    direction, state.delta_stat, reference_delta
+                );
+                state.next_rate = state.current_rate.max(state.floor);
+            }
+        }
+    }
+
+    /*
+     * The qdisc can be replaced while we're running (e.g. by `tc qdisc replace` or the sqm
+     * init script re-applying it), which changes its handle/parent, or the interface can be
+     * re-created with a new index. Rate changes sent to the old qdisc silently go nowhere,
+     * so look it up again and start rate control over if it moved.
+     */
+    fn verify_qdiscs(&mut self) -> anyhow::Result<()> {
+        // Starting over from where a fresh start would, with the step warmup
+        let (dl_start_rate, ul_start_rate) = start_rates(
+            &self.config,
+            self.capacity_profile.as_ref(),
+            self.profile_hour,
+        );
+
+        for direction in [Direction::Down, Direction::Up] {
+            let (ifname, start_rate, state) = if direction == Direction::Down {
+                (
+                    self.config.download_interface.as_str(),
+                    dl_start_rate,
+                    &mut self.state_dl,
+                )
+            } else {
+                (
+                    self.config.upload_interface.as_str(),
+                    ul_start_rate,
+                    &mut self.state_ul,
+                )
+            };
+
+            let qdisc = match self.netlink.qdisc_from_ifname(ifname) {
+                Ok(val) => val,
+                Err(e) => {
+                    warn!("Couldn't re-verify the qdisc on {}: {}", ifname, e);
+                    continue;
+                }
+            };
+
+            if qdisc != state.qdisc {
+                warn!(
+                    "Qdisc on {} was replaced ({:?} -> {:?}), resetting rate control",
+                    ifname, state.qdisc, qdisc
+                );
+                state.qdisc = qdisc;
+                state.next_rate = start_rate * 0.6;
+                Self::apply_qdisc_floor(direction, state, self.config.qdisc_floor_kbits);
+                state.current_rate = state.next_rate;
+                state.deltas.clear();
+                self.netlink
+                    .set_qdisc_rate(state.qdisc, state.current_rate.round() as u64)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn update_deltas(&mut self, now_t: Instant) {
         let state_dl = &mut self.state_dl;
         let state_ul = &mut self.state_ul;
 
         state_dl.deltas.clear();
         state_ul.deltas.clear();
 
-        let now_t = Instant::now();
         let owd_baseline = self.owd_baseline.lock().unwrap();
         let owd_recent = self.owd_recent.lock().unwrap();
         let reflectors = self.reflectors_lock.read().unwrap();
+        let disabled = self.disabled_reflectors.read().unwrap();
+
+        /*
+         * A newly selected reflector has a freshly seeded baseline and a recent EWMA that
+         * hasn't converged yet, so its delta is mostly noise for the first few ticks. Keep it
+         * out of the aggregate until it's been around for the warmup. The peers that are
+         * there when we start have had the initial delay to settle, so they count as warm.
+         */
+        let warmup_ticks = self.config.reselect_warmup_ticks;
+        let peer_samples = self.peer_samples.get_or_insert_with(|| {
+            reflectors
+                .iter()
+                .map(|reflector| (*reflector, warmup_ticks))
+                .collect()
+        });
+        peer_samples.retain(|reflector, _| reflectors.contains(reflector));
+        let mut warming = 0;
 
         for reflector in reflectors.iter() {
-            // only consider this data if it's less than 2 * tick_duration seconds old
+            if disabled.contains(reflector) {
+                continue;
+            }
+
+            // only consider this data if it's less than 2 * tick_interval seconds old
             if owd_baseline.contains_key(reflector)
                 && owd_recent.contains_key(reflector)
                 && now_t
@@ -233,82 +821,538 @@ impl Ratecontroller {
                     .as_secs_f64()
                     < self.config.tick_interval * 2.0
             {
-                state_dl
-                    .deltas
-                    .push(owd_recent[reflector].down_ewma - owd_baseline[reflector].down_ewma);
-                state_ul
-                    .deltas
-                    .push(owd_recent[reflector].up_ewma - owd_baseline[reflector].up_ewma);
+                let samples = peer_samples.entry(*reflector).or_insert(0);
+                if *samples < warmup_ticks {
+                    *samples += 1;
+                    warming += 1;
+                    debug!("Reflector: {} still warming up, skipping", reflector);
+                    continue;
+                }
+
+                let down_delta =
+                    owd_recent[reflector].down_ewma - owd_baseline[reflector].down_ewma;
+                let up_delta = owd_recent[reflector].up_ewma - owd_baseline[reflector].up_ewma;
+                if counts_for(&self.download_reflectors, reflector) {
+                    push_finite(&mut state_dl.deltas, down_delta);
+                }
+                if counts_for(&self.upload_reflectors, reflector) {
+                    push_finite(&mut state_ul.deltas, up_delta);
+                }
 
                 debug!(
                     "Reflector: {} down_delay: {} up_delay: {}",
-                    reflector,
-                    state_dl.deltas.last().unwrap(),
-                    state_ul.deltas.last().unwrap()
+                    reflector, down_delta, up_delta
                 );
             }
         }
 
         // sort owd's lowest to highest
-        state_dl.deltas.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        state_ul.deltas.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        state_dl.deltas.sort_by(f64::total_cmp);
+        state_ul.deltas.sort_by(f64::total_cmp);
+
+        /*
+         * Without reselection, whether it's turned off or there's nothing else in the pool to
+         * pick from, the peers are all there is. Requiring more deltas than there are peers
+         * would keep the rate at the floor for good, so make do with what we've got, and
+         * don't ask for a reselection that's never going to happen.
+         */
+        if !self.reselection_active {
+            let peers = |direction_reflectors: &[IpAddr]| {
+                reflectors
+                    .iter()
+                    .filter(|r| !disabled.contains(r) && counts_for(direction_reflectors, r))
+                    .count()
+            };
+            self.min_deltas = MIN_DELTAS
+                .min(peers(&self.download_reflectors))
+                .min(peers(&self.upload_reflectors))
+                .max(1);
+            return;
+        }
 
-        if state_dl.deltas.len() < 5 || state_ul.deltas.len() < 5 {
+        // Fewer reflectors are probed while idle, so that's expected then
+        if (state_dl.deltas.len() + warming < wanted_deltas(5, &self.download_reflectors)
+            || state_ul.deltas.len() + warming < wanted_deltas(5, &self.upload_reflectors))
+            && !self.idle.load(Ordering::Relaxed)
+        {
             // trigger reselection
             warn!("Not enough delta values, triggering reselection");
             let _ = self.reselect_trigger.send(true);
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: Config,
+        counters: Arc<Counters>,
         owd_baseline: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
         owd_recent: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+        rate_metrics: Arc<Mutex<RateMetrics>>,
+        safe_rates: Arc<Mutex<SafeRates>>,
         reflectors_lock: Arc<RwLock<Vec<IpAddr>>>,
+        reference_reflectors: Vec<IpAddr>,
+        disabled_reflectors: Arc<RwLock<HashSet<IpAddr>>>,
+        idle: Arc<AtomicBool>,
         reselect_trigger: Sender<bool>,
+        reselection_active: bool,
+        shutdown: Arc<AtomicBool>,
+        left_rates: (Option<u64>, Option<u64>),
         down_direction: StatsDirection,
         up_direction: StatsDirection,
     ) -> anyhow::Result<Self> {
-        let dl_qdisc = Netlink::qdisc_from_ifname(config.download_interface.as_str())?;
-        let dl_safe_rates =
-            generate_initial_speeds(config.download_base_kbits, config.speed_hist_size);
-        let ul_qdisc = Netlink::qdisc_from_ifname(config.upload_interface.as_str())?;
-        let ul_safe_rates =
-            generate_initial_speeds(config.upload_base_kbits, config.speed_hist_size);
+        let mut rng = new_rng(config.rng_seed, RngStream::Ratecontroller);
+        let now_t = Instant::now();
+        let mut netlink = Netlink::new();
+        let capacity_profile = if config.capacity_profile.is_empty() {
+            None
+        } else {
+            // A profile that can't be read is only a missed head start, so start over
+            Some(
+                CapacityProfile::load(config.capacity_profile.as_str()).unwrap_or_else(|e| {
+                    warn!(
+                        "Couldn't load the capacity profile, starting a new one: {}",
+                        e
+                    );
+                    CapacityProfile::default()
+                }),
+            )
+        };
+        let profile_hour = current_hour();
+        let (dl_start_rate, ul_start_rate) =
+            start_rates(&config, capacity_profile.as_ref(), profile_hour);
+        let (download_reflectors, upload_reflectors) = config.load_direction_reflectors()?;
+        let min_deltas = wanted_deltas(MIN_DELTAS, &download_reflectors)
+            .min(wanted_deltas(MIN_DELTAS, &upload_reflectors));
+
+        let dl_qdisc = netlink.qdisc_from_ifname(config.download_interface.as_str())?;
+        let dl_safe_rates = generate_initial_speeds(
+            &mut rng,
+            config.download_min_kbits,
+            dl_start_rate,
+            config.speed_hist_size,
+        );
+        let ul_qdisc = netlink.qdisc_from_ifname(config.upload_interface.as_str())?;
+        let ul_safe_rates = generate_initial_speeds(
+            &mut rng,
+            config.upload_min_kbits,
+            ul_start_rate,
+            config.speed_hist_size,
+        );
+
+        let (cur_rx, cur_tx) =
+            get_interface_stats(&mut netlink, &config, down_direction, up_direction)?;
+        let (dl_min_rate, ul_min_rate) = (config.download_min_kbits, config.upload_min_kbits);
 
-        let (cur_rx, cur_tx) = get_interface_stats(&config, down_direction, up_direction)?;
+        let mut state_dl = State::new(dl_qdisc, cur_rx, dl_safe_rates, dl_min_rate, now_t);
+        let mut state_ul = State::new(ul_qdisc, cur_tx, ul_safe_rates, ul_min_rate, now_t);
+        // What an earlier run left behind, or else what a restarted ratecontroller did
+        let (dl_left_rate, ul_left_rate) = left_rates;
+        let resume_rates = (
+            resume_rate(
+                dl_left_rate.or_else(|| shaper_rate(&mut netlink, dl_qdisc)),
+                dl_min_rate.max(config.qdisc_floor_kbits),
+                config.download_base_kbits,
+            ),
+            resume_rate(
+                ul_left_rate.or_else(|| shaper_rate(&mut netlink, ul_qdisc)),
+                ul_min_rate.max(config.qdisc_floor_kbits),
+                config.upload_base_kbits,
+            ),
+        );
+        let resumed = match resume_rates {
+            (Some(dl_rate), Some(ul_rate)) => {
+                state_dl.current_rate = dl_rate;
+                state_ul.current_rate = ul_rate;
+                true
+            }
+            _ => false,
+        };
 
         Ok(Self {
+            capacity_profile,
             config,
+            counters,
+            disabled_reflectors,
             down_direction,
+            download_reflectors,
+            idle,
+            low_load_since: None,
+            min_deltas,
+            netlink: Box::new(netlink),
             owd_baseline,
             owd_recent,
+            peer_samples: None,
+            profile_hour,
+            rate_metrics,
+            reference_reflectors,
             reflectors_lock,
             reselect_trigger,
-            state_dl: State::new(dl_qdisc, cur_rx, dl_safe_rates),
-            state_ul: State::new(ul_qdisc, cur_tx, ul_safe_rates),
+            reselection_active,
+            resumed,
+            rng,
+            safe_rates,
+            shutdown,
+            state_dl,
+            state_ul,
             up_direction,
+            upload_reflectors,
+        })
+    }
+
+    /*
+     * A rate controller that isn't attached to any interface, for replaying recorded data.
+     * Time and byte counters come from the recording instead of the clock and Netlink,
+     * and the random choices are seeded, so the same recording always gives the same result.
+     * Rates start at 60% of base, like the step warmup.
+     */
+    pub fn for_replay(
+        config: Config,
+        owd_baseline: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+        owd_recent: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+        reflectors_lock: Arc<RwLock<Vec<IpAddr>>>,
+        seed: u64,
+        start_t: Instant,
+        (start_rx, start_tx): (i128, i128),
+    ) -> anyhow::Result<Self> {
+        let (download_reflectors, upload_reflectors) = config.load_direction_reflectors()?;
+        // Stands in for a live setup with a pool to reselect from, unless that's turned off
+        let reselection_active = !config.disable_reselection;
+        let min_deltas = wanted_deltas(MIN_DELTAS, &download_reflectors)
+            .min(wanted_deltas(MIN_DELTAS, &upload_reflectors));
+        let mut rng = new_rng(seed, RngStream::Ratecontroller);
+        let dl_safe_rates = generate_initial_speeds(
+            &mut rng,
+            config.download_min_kbits,
+            config.download_base_kbits,
+            config.speed_hist_size,
+        );
+        let ul_safe_rates = generate_initial_speeds(
+            &mut rng,
+            config.upload_min_kbits,
+            config.upload_base_kbits,
+            config.speed_hist_size,
+        );
+        // Nobody's listening for reselection requests, which is fine as sending ignores errors
+        let (reselect_trigger, _) = channel();
+
+        let mut state_dl = State::new(
+            Qdisc::default(),
+            start_rx,
+            dl_safe_rates,
+            config.download_min_kbits,
+            start_t,
+        );
+        let mut state_ul = State::new(
+            Qdisc::default(),
+            start_tx,
+            ul_safe_rates,
+            config.upload_min_kbits,
+            start_t,
+        );
+        state_dl.current_rate = config.download_base_kbits * 0.6;
+        state_ul.current_rate = config.upload_base_kbits * 0.6;
+
+        Ok(Self {
+            // Replays don't learn anything that should outlive them
+            capacity_profile: None,
+            config,
+            counters: Arc::new(Counters::default()),
+            disabled_reflectors: Arc::new(RwLock::new(HashSet::new())),
+            down_direction: StatsDirection::RX,
+            download_reflectors,
+            idle: Arc::new(AtomicBool::new(false)),
+            low_load_since: None,
+            min_deltas,
+            // Never connected, the replay doesn't touch any qdiscs
+            netlink: Box::new(Netlink::new()),
+            owd_baseline,
+            owd_recent,
+            peer_samples: None,
+            profile_hour: 0,
+            rate_metrics: Arc::new(Mutex::new(RateMetrics::default())),
+            reference_reflectors: Vec::new(),
+            reflectors_lock,
+            reselect_trigger,
+            reselection_active,
+            resumed: false,
+            rng,
+            safe_rates: Arc::new(Mutex::new(SafeRates::default())),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            state_dl,
+            state_ul,
+            up_direction: StatsDirection::TX,
+            upload_reflectors,
         })
     }
 
+    /*
+     * With hardly any traffic in either direction for a long while, there's no bloat to
+     * react to, so probing at the full rate is wasted effort. Flag the link as idle so
+     * the sender scales back, and clear it again as soon as either direction picks up.
+     */
+    fn update_idle(&mut self, now_t: Instant) {
+        if self.state_dl.load >= self.config.idle_load
+            || self.state_ul.load >= self.config.idle_load
+        {
+            self.low_load_since = None;
+            if self.idle.swap(false, Ordering::Relaxed) {
+                info!("Traffic picked up again, resuming full rate probing");
+            }
+            return;
+        }
+
+        let since = *self.low_load_since.get_or_insert(now_t);
+        if now_t.duration_since(since).as_secs_f64() >= self.config.idle_duration
+            && !self.idle.swap(true, Ordering::Relaxed)
+        {
+            info!(
+                "Link idle for {}s, scaling back probing",
+                self.config.idle_duration
+            );
+        }
+    }
+
+    // Whether each direction's minimum change interval has passed
+    fn changes_due(&self, now_t: Instant) -> (bool, bool) {
+        let dl_due = now_t
+            .duration_since(self.state_dl.last_change_t)
+            .as_secs_f64()
+            > self.config.download_min_change_interval;
+        let ul_due = now_t
+            .duration_since(self.state_ul.last_change_t)
+            .as_secs_f64()
+            > self.config.upload_min_change_interval;
+
+        (dl_due, ul_due)
+    }
+
+    // Runs the algorithm for one tick, leaving the new rates in next_rate
+    fn step(&mut self, now_t: Instant, dl_due: bool, ul_due: bool) -> anyhow::Result<()> {
+        self.update_deltas(now_t);
+        if dl_due {
+            self.calculate_rate(Direction::Down, now_t)?;
+            self.state_dl.last_change_t = now_t;
+        } else {
+            self.state_dl.next_rate = self.state_dl.current_rate;
+        }
+        if ul_due {
+            self.calculate_rate(Direction::Up, now_t)?;
+            self.state_ul.last_change_t = now_t;
+        } else {
+            self.state_ul.next_rate = self.state_ul.current_rate;
+        }
+        if self.config.idle_load > 0.0 {
+            self.update_idle(now_t);
+        }
+        if self.config.coordinated_backoff {
+            self.coordinate_backoff();
+        }
+        if !self.reference_reflectors.is_empty() {
+            self.check_reference(now_t);
+        }
+        // The qdisc floor below still wins over the step, if it isn't a multiple of it
+        let step = self.config.rate_step_kbits;
+        if step > 0.0 {
+            if dl_due {
+                let state = &mut self.state_dl;
+                state.next_rate =
+                    quantize_rate(state.next_rate, state.current_rate, state.floor, step);
+            }
+            if ul_due {
+                let state = &mut self.state_ul;
+                state.next_rate =
+                    quantize_rate(state.next_rate, state.current_rate, state.floor, step);
+            }
+        }
+        // Last, so nothing can take the rates below the floor again
+        if self.config.qdisc_floor_kbits > 0.0 {
+            Self::apply_qdisc_floor(
+                Direction::Down,
+                &mut self.state_dl,
+                self.config.qdisc_floor_kbits,
+            );
+            Self::apply_qdisc_floor(
+                Direction::Up,
+                &mut self.state_ul,
+                self.config.qdisc_floor_kbits,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Feeds one tick of recorded byte counters through the algorithm and returns the result
+    pub fn replay_tick(
+        &mut self,
+        now_t: Instant,
+        (rx_bytes, tx_bytes): (i128, i128),
+    ) -> anyhow::Result<RateMetrics> {
+        let (dl_due, ul_due) = self.changes_due(now_t);
+        (self.state_dl.current_bytes, self.state_ul.current_bytes) = (rx_bytes, tx_bytes);
+
+        if dl_due || ul_due {
+            self.step(now_t, dl_due, ul_due)?;
+            self.state_dl.current_rate = self.state_dl.next_rate;
+            self.state_ul.current_rate = self.state_ul.next_rate;
+        }
+
+        Ok(RateMetrics {
+            download_rate_kbit: self.state_dl.current_rate,
+            upload_rate_kbit: self.state_ul.current_rate,
+            download_load: self.state_dl.load,
+            upload_load: self.state_ul.load,
+            delta_delay_down_ms: self.state_dl.delta_stat,
+            delta_delay_up_ms: self.state_ul.delta_stat,
+        })
+    }
+
+    /*
+     * Gradually raise the rates from the minimum to the base rate over the warmup window,
+     * instead of jumping straight to 60% of base. Baselines keep building up in the
+     * meantime, and the normal algorithm takes over from the base rate afterwards.
+     */
+    fn warmup_ramp(&mut self, (dl_target, ul_target): (f64, f64)) -> anyhow::Result<()> {
+        let step = Duration::from_secs_f64(self.config.min_change_interval);
+        let start_t = Instant::now();
+        // Like everywhere else, the rates don't go below what the qdisc can manage
+        let dl_min_rate = self
+            .config
+            .download_min_kbits
+            .max(self.config.qdisc_floor_kbits);
+        let ul_min_rate = self
+            .config
+            .upload_min_kbits
+            .max(self.config.qdisc_floor_kbits);
+
+        info!(
+            "Ramping rates up to {} / {} over {}s ({:?})",
+            dl_target, ul_target, self.config.warmup_duration, self.config.warmup_shape
+        );
+
+        loop {
+            let progress = if self.config.warmup_duration > 0.0 {
+                (start_t.elapsed().as_secs_f64() / self.config.warmup_duration).min(1.0)
+            } else {
+                1.0
+            };
+
+            self.state_dl.current_rate =
+                warmup_rate(self.config.warmup_shape, dl_min_rate, dl_target, progress);
+            self.state_ul.current_rate =
+                warmup_rate(self.config.warmup_shape, ul_min_rate, ul_target, progress);
+
+            self.netlink.set_qdisc_rate(
+                self.state_dl.qdisc,
+                self.state_dl.current_rate.round() as u64,
+            )?;
+            self.netlink.set_qdisc_rate(
+                self.state_ul.qdisc,
+                self.state_ul.current_rate.round() as u64,
+            )?;
+
+            if progress >= 1.0 || self.shutdown.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+
+            sleep(step);
+        }
+    }
+
+    /*
+     * On the hour, save what was learned so far, and cap the safe rate history at what the
+     * link usually sustains in the new hour. A backoff then doesn't go for a rate from
+     * earlier in the day that the link won't manage now. Rates above it are still reached
+     * the usual way, by ramping up while there's no bloat.
+     */
+    fn update_profile_hour(&mut self) {
+        let profile = match self.capacity_profile.as_ref() {
+            Some(profile) => profile,
+            None => return,
+        };
+        let hour = current_hour();
+        if hour == self.profile_hour {
+            return;
+        }
+        self.profile_hour = hour;
+
+        if let Err(e) = profile.save(self.config.capacity_profile.as_str()) {
+            warn!("Failed to save the capacity profile: {}", e);
+        }
+
+        if let Some((download, upload)) = profile.rates(hour) {
+            debug!(
+                "Capping safe rates at the capacity for hour {} (D/L): {} / {}",
+                hour, download, upload
+            );
+            for rate in self.state_dl.safe_rates.iter_mut() {
+                *rate = rate.min(download);
+            }
+            for rate in self.state_ul.safe_rates.iter_mut() {
+                *rate = rate.min(upload);
+            }
+        }
+    }
+
+    // Sets the shaper to where rate control starts out from
+    fn start(&mut self) -> anyhow::Result<()> {
+        let (dl_start_rate, ul_start_rate) = start_rates(
+            &self.config,
+            self.capacity_profile.as_ref(),
+            self.profile_hour,
+        );
+        if self.capacity_profile.is_some() && !self.resumed {
+            info!(
+                "Starting out from the capacity for hour {} (D/L): {} / {}",
+                self.profile_hour, dl_start_rate, ul_start_rate
+            );
+        }
+
+        if self.resumed {
+            info!(
+                "Resuming from the current shaper rates (D/L): {} / {}",
+                self.state_dl.current_rate, self.state_ul.current_rate
+            );
+
+            // main() has dropped the shaper to the start rates in the meantime
+            self.netlink.set_qdisc_rate(
+                self.state_dl.qdisc,
+                self.state_dl.current_rate.round() as u64,
+            )?;
+            self.netlink.set_qdisc_rate(
+                self.state_ul.qdisc,
+                self.state_ul.current_rate.round() as u64,
+            )?;
+        } else if self.config.warmup_shape == WarmupShape::Step {
+            // set qdisc rates to 60% of base rate to make sure we start with sane baselines
+            self.state_dl.current_rate = dl_start_rate * 0.6;
+            self.state_ul.current_rate = ul_start_rate * 0.6;
+
+            self.netlink.set_qdisc_rate(
+                self.state_dl.qdisc,
+                self.state_dl.current_rate.round() as u64,
+            )?;
+            self.netlink.set_qdisc_rate(
+                self.state_ul.qdisc,
+                self.state_ul.current_rate.round() as u64,
+            )?;
+        } else {
+            self.warmup_ramp((dl_start_rate, ul_start_rate))?;
+        }
+
+        Ok(())
+    }
+
     pub fn run(&mut self) -> anyhow::Result<()> {
-        let sleep_time = Duration::from_secs_f64(self.config.min_change_interval);
+        let sleep_time = Duration::from_secs_f64(
+            self.config
+                .download_min_change_interval
+                .min(self.config.upload_min_change_interval),
+        );
 
-        let mut lastchg_t = Instant::now();
         let mut lastdump_t = Instant::now();
+        let mut lastqdisccheck_t = Instant::now();
 
-        // set qdisc rates to 60% of base rate to make sure we start with sane baselines
-        self.state_dl.current_rate = self.config.download_base_kbits * 0.6;
-        self.state_ul.current_rate = self.config.upload_base_kbits * 0.6;
-
-        Netlink::set_qdisc_rate(
-            self.state_dl.qdisc,
-            self.state_dl.current_rate.round() as u64,
-        )?;
-        Netlink::set_qdisc_rate(
-            self.state_ul.qdisc,
-            self.state_ul.current_rate.round() as u64,
-        )?;
+        self.start()?;
 
         let mut speed_hist_fd: Option<File> = None;
         let mut speed_hist_fd_inner: File;
@@ -318,6 +1362,7 @@ impl Ratecontroller {
         if !self.config.suppress_statistics {
             speed_hist_fd_inner = File::options()
                 .create(true)
+                .truncate(false)
                 .write(true)
                 .open(self.config.speed_hist_file.as_str())?;
 
@@ -328,40 +1373,92 @@ impl Ratecontroller {
 
             stats_fd_inner = File::options()
                 .create(true)
+                .append(self.config.stats_append)
+                .truncate(false)
                 .write(true)
                 .open(self.config.stats_file.as_str())?;
 
-            stats_fd_inner.write_all(
-                "times,timens,rxload,txload,deltadelaydown,deltadelayup,dlrate,uprate\n".as_bytes(),
-            )?;
-            stats_fd_inner.flush()?;
+            // When appending across restarts, the header is only written to a fresh file
+            if self.config.stats_format == StatsFormat::Csv
+                && (!self.config.stats_append || stats_fd_inner.metadata()?.len() == 0)
+            {
+                stats_fd_inner.write_all(
+                    "times,timens,rxload,txload,deltadelaydown,deltadelayup,dlrate,uprate\n"
+                        .as_bytes(),
+                )?;
+                stats_fd_inner.flush()?;
+            }
 
             stats_fd = Some(stats_fd_inner);
         }
 
-        loop {
+        let mut stats_fifo = if self.config.stats_fifo.is_empty() {
+            None
+        } else {
+            Some(StatsFifo::new(self.config.stats_fifo.as_str())?)
+        };
+
+        #[cfg(feature = "systemd")]
+        let journal = if self.config.journal_stats {
+            match crate::journal::JournalWriter::new() {
+                Ok(val) => Some(val),
+                Err(e) => {
+                    warn!(
+                        "Couldn't connect to journald, not logging stats there: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        #[cfg(not(feature = "systemd"))]
+        if self.config.journal_stats {
+            warn!(
+                "Journal stats were requested, but sqm-autorate was built without systemd support"
+            );
+        }
+
+        while !self.shutdown.load(Ordering::Relaxed) {
             sleep(sleep_time);
             let now_t = Instant::now();
 
-            if now_t.duration_since(lastchg_t).as_secs_f64() > self.config.min_change_interval {
+            self.update_profile_hour();
+
+            if now_t.duration_since(lastqdisccheck_t).as_secs_f64()
+                > self.config.qdisc_check_interval
+            {
+                self.verify_qdiscs()?;
+                lastqdisccheck_t = now_t;
+            }
+
+            // Each direction can have its own minimum interval between rate changes
+            let (dl_due, ul_due) = self.changes_due(now_t);
+
+            if dl_due || ul_due {
                 // if it's been long enough, and the stats indicate needing to change speeds
                 // change speeds here
 
-                (self.state_dl.current_bytes, self.state_ul.current_bytes) =
-                    get_interface_stats(&self.config, self.down_direction, self.up_direction)?;
+                (self.state_dl.current_bytes, self.state_ul.current_bytes) = get_interface_stats(
+                    self.netlink.as_mut(),
+                    &self.config,
+                    self.down_direction,
+                    self.up_direction,
+                )?;
                 if self.state_dl.current_bytes == -1 || self.state_ul.current_bytes == -1 {
                     warn!(
                     "One or both Netlink stats could not be read. Skipping rate control algorithm");
                     continue;
                 }
 
-                self.update_deltas();
-                self.calculate_rate(Direction::Down)?;
-                self.calculate_rate(Direction::Up)?;
+                self.step(now_t, dl_due, ul_due)?;
 
-                if self.state_dl.next_rate != self.state_dl.current_rate
-                    || self.state_ul.next_rate != self.state_ul.current_rate
-                {
+                let rates_changed = self.state_dl.next_rate != self.state_dl.current_rate
+                    || self.state_ul.next_rate != self.state_ul.current_rate;
+
+                if rates_changed {
                     info!(
                         "self.state_ul.next_rate {} self.state_dl.next_rate {}",
                         self.state_ul.next_rate, self.state_dl.next_rate
@@ -369,16 +1466,35 @@ impl Ratecontroller {
                 }
 
                 if self.state_dl.next_rate != self.state_dl.current_rate {
-                    Netlink::set_qdisc_rate(self.state_dl.qdisc, self.state_dl.next_rate as u64)?;
+                    self.netlink
+                        .set_qdisc_rate(self.state_dl.qdisc, self.state_dl.next_rate as u64)?;
                 }
 
                 if self.state_ul.next_rate != self.state_ul.current_rate {
-                    Netlink::set_qdisc_rate(self.state_ul.qdisc, self.state_ul.next_rate as u64)?;
+                    self.netlink
+                        .set_qdisc_rate(self.state_ul.qdisc, self.state_ul.next_rate as u64)?;
                 }
 
                 self.state_dl.current_rate = self.state_dl.next_rate;
                 self.state_ul.current_rate = self.state_ul.next_rate;
 
+                *self.rate_metrics.lock().unwrap() = RateMetrics {
+                    download_rate_kbit: self.state_dl.current_rate,
+                    upload_rate_kbit: self.state_ul.current_rate,
+                    download_load: self.state_dl.load,
+                    upload_load: self.state_ul.load,
+                    delta_delay_down_ms: self.state_dl.delta_stat,
+                    delta_delay_up_ms: self.state_ul.delta_stat,
+                };
+
+                {
+                    let mut safe_rates = self.safe_rates.lock().unwrap();
+                    safe_rates.download.clone_from(&self.state_dl.safe_rates);
+                    safe_rates.download_index = self.state_dl.nrate;
+                    safe_rates.upload.clone_from(&self.state_ul.safe_rates);
+                    safe_rates.upload_index = self.state_ul.nrate;
+                }
+
                 debug!(
                     "{},{},{},{},{},{},{}",
                     time_format(SystemTime::now(), DUMP_DATETIME_FORMAT),
@@ -390,29 +1506,64 @@ impl Ratecontroller {
                     self.state_ul.current_rate
                 );
 
-                if let Some(ref mut fd) = stats_fd {
-                    if let Err(e) = fd.write(
-                        format!(
-                            "{},{},{},{},{},{},{}\n",
-                            time_format(SystemTime::now(), DUMP_DATETIME_FORMAT),
-                            self.state_dl.load,
-                            self.state_ul.load,
-                            self.state_dl.delta_stat,
-                            self.state_ul.delta_stat,
-                            self.state_dl.current_rate,
-                            self.state_ul.current_rate
-                        )
-                        .as_bytes(),
-                    ) {
+                let stats_line = match self.config.stats_format {
+                    StatsFormat::Csv => format!(
+                        "{},{},{},{},{},{},{}\n",
+                        time_format(SystemTime::now(), DUMP_DATETIME_FORMAT),
+                        self.state_dl.load,
+                        self.state_ul.load,
+                        self.state_dl.delta_stat,
+                        self.state_ul.delta_stat,
+                        self.state_dl.current_rate,
+                        self.state_ul.current_rate
+                    ),
+                    StatsFormat::Json => format!(
+                        "{{\"timestamp\":{},\"rxload\":{},\"txload\":{},\"deltadelaydown\":{},\"deltadelayup\":{},\"dlrate\":{},\"uprate\":{}}}\n",
+                        json_string(&time_format(SystemTime::now(), DUMP_DATETIME_FORMAT)),
+                        json_number(self.state_dl.load),
+                        json_number(self.state_ul.load),
+                        json_number(self.state_dl.delta_stat),
+                        json_number(self.state_ul.delta_stat),
+                        json_number(self.state_dl.current_rate),
+                        json_number(self.state_ul.current_rate)
+                    ),
+                };
+
+                // The FIFO gets every tick, as nothing is stored there
+                if let Some(fifo) = stats_fifo.as_mut() {
+                    fifo.send(stats_line.as_str());
+                }
+
+                // On flash storage, only writing when something changed saves a lot of wear
+                let write_stats = rates_changed || !self.config.stats_on_change_only;
+
+                if let Some(fd) = stats_fd.as_mut().filter(|_| write_stats) {
+                    if let Err(e) = fd.write_all(stats_line.as_bytes()) {
                         warn!("Failed to write statistics: {}", e);
                     }
                 }
 
-                lastchg_t = now_t;
+                #[cfg(feature = "systemd")]
+                if let Some(journal) = journal.as_ref().filter(|_| write_stats) {
+                    if let Err(e) = journal.send(
+                        "sqm-autorate stats",
+                        &[
+                            ("DL_LOAD", self.state_dl.load),
+                            ("UL_LOAD", self.state_ul.load),
+                            ("DL_DELTA_DELAY_MS", self.state_dl.delta_stat),
+                            ("UL_DELTA_DELAY_MS", self.state_ul.delta_stat),
+                            ("DL_RATE_KBIT", self.state_dl.current_rate),
+                            ("UL_RATE_KBIT", self.state_ul.current_rate),
+                        ],
+                    ) {
+                        warn!("Failed to send statistics to journald: {}", e);
+                    }
+                }
             }
 
             if let Some(ref mut fd) = speed_hist_fd {
-                if now_t.duration_since(lastdump_t).as_secs_f64() > 300.0 {
+                if now_t.duration_since(lastdump_t).as_secs_f64() > self.config.speed_hist_interval
+                {
                     for i in 0..self.config.speed_hist_size as usize {
                         if let Err(e) = fd.write_all(
                             format!(
@@ -432,5 +1583,926 @@ impl Ratecontroller {
                 }
             }
         }
+
+        if let Some(profile) = self.capacity_profile.as_ref() {
+            if let Err(e) = profile.save(self.config.capacity_profile.as_str()) {
+                warn!("Failed to save the capacity profile: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::netlink::RtnlLinkStats64;
+    use rand::SeedableRng;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::Receiver;
+    use std::{env, fs};
+
+    // A rate controller over the given peers, each with its recent delay this far above baseline
+    fn controller(config: Config, deltas: &[(&str, f64)]) -> (Ratecontroller, Receiver<bool>) {
+        let now_t = Instant::now();
+        let stats = |delay: f64| ReflectorStats {
+            down_ewma: delay,
+            up_ewma: delay,
+            jitter: 0.0,
+            last_receive_time_s: now_t,
+            replies: 0,
+        };
+
+        let mut peers = Vec::new();
+        let mut owd_baseline = HashMap::new();
+        let mut owd_recent = HashMap::new();
+        for (reflector, delta) in deltas.iter() {
+            let reflector: IpAddr = reflector.parse().unwrap();
+            peers.push(reflector);
+            owd_baseline.insert(reflector, stats(10.0));
+            owd_recent.insert(reflector, stats(10.0 + delta));
+        }
+
+        let mut ratecontroller = Ratecontroller::for_replay(
+            config,
+            Arc::new(Mutex::new(owd_baseline)),
+            Arc::new(Mutex::new(owd_recent)),
+            Arc::new(RwLock::new(peers)),
+            1,
+            now_t,
+            (0, 0),
+        )
+        .unwrap();
+        let (reselect_trigger, reselect_receiver) = channel();
+        ratecontroller.reselect_trigger = reselect_trigger;
+
+        (ratecontroller, reselect_receiver)
+    }
+
+    // Stands in for the qdiscs, keeping track of the rates they were set to
+    #[derive(Clone, Default)]
+    struct FakeShaper {
+        qdisc: Qdisc,
+        rates: Arc<Mutex<Vec<(Qdisc, u64)>>>,
+        stats_reads: Arc<AtomicUsize>,
+        // Shuts the rate controller down after this many ticks
+        stop_after: Option<(usize, Arc<AtomicBool>)>,
+    }
+
+    impl Shaper for FakeShaper {
+        fn get_interface_stats(&mut self, _: &str) -> Result<RtnlLinkStats64, NetlinkError> {
+            // Both interfaces are read every tick
+            let reads = self.stats_reads.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Some((ticks, shutdown)) = self.stop_after.as_ref() {
+                if reads >= ticks * 2 {
+                    shutdown.store(true, Ordering::Relaxed);
+                }
+            }
+            Ok(RtnlLinkStats64::default())
+        }
+
+        fn get_qdisc_rate(&mut self, qdisc: Qdisc) -> Result<u64, NetlinkError> {
+            let rates = self.rates.lock().unwrap();
+            match rates.iter().rev().find(|(set, _)| *set == qdisc) {
+                Some((_, rate)) => Ok(*rate),
+                None => Err(NetlinkError::NoQdiscRate(String::new())),
+            }
+        }
+
+        fn qdisc_from_ifname(&mut self, _: &str) -> Result<Qdisc, NetlinkError> {
+            Ok(self.qdisc)
+        }
+
+        fn set_qdisc_rate(
+            &mut self,
+            qdisc: Qdisc,
+            bandwidth_kbit: u64,
+        ) -> Result<(), NetlinkError> {
+            self.rates.lock().unwrap().push((qdisc, bandwidth_kbit));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn glitching_reflector_is_capped() {
+        let threshold = test_config().download_delay_ms;
+        // One close reflector glitching, the rest barely moving
+        let sorted = [0.5, 1.0, 1.2, 1.5, 40.0];
+        let delta = sorted[sorted.len() - 1];
+
+        assert!(cap_delta(&sorted, delta, 1.0) > threshold);
+        assert_eq!(cap_delta(&sorted, delta, 0.5), 1.5);
+        assert_eq!(cap_delta(&sorted, delta, 0.34), 1.2);
+        assert!(cap_delta(&sorted, delta, 0.5) < threshold);
+
+        // When they all see the delay, it still comes through
+        let sorted = [30.0, 32.0, 35.0, 38.0, 40.0];
+        assert!(cap_delta(&sorted, 40.0, 0.5) > threshold);
+
+        // A lone reflector counts in full
+        assert_eq!(cap_delta(&[40.0], 40.0, 0.5), 40.0);
+    }
+
+    #[test]
+    fn learned_hour_seeds_the_start_rates() {
+        let config = test_config();
+        let mut profile = CapacityProfile::default();
+        profile.learn_download(18, 40000.0);
+        profile.learn_upload(18, 3000.0);
+        // Above base and below min respectively, which are kept in bounds
+        profile.learn_download(19, 90000.0);
+        profile.learn_upload(19, 500.0);
+
+        assert_eq!(start_rates(&config, Some(&profile), 18), (40000.0, 3000.0));
+        assert_eq!(start_rates(&config, Some(&profile), 19), (60000.0, 1000.0));
+        // Nothing learned for the hour, or no profile at all
+        assert_eq!(start_rates(&config, Some(&profile), 7), (60000.0, 5000.0));
+        assert_eq!(start_rates(&config, None, 18), (60000.0, 5000.0));
+    }
+
+    #[test]
+    fn resumes_only_above_the_start_rate() {
+        assert_eq!(resume_rate(None, 10000.0, 60000.0), None);
+        assert_eq!(resume_rate(Some(10000), 10000.0, 60000.0), None);
+        assert_eq!(resume_rate(Some(42000), 10000.0, 60000.0), Some(42000.0));
+        // Never picks up above base, in case it was lowered since
+        assert_eq!(resume_rate(Some(80000), 10000.0, 60000.0), Some(60000.0));
+    }
+
+    #[test]
+    fn resumed_rates_are_applied() {
+        let (mut ratecontroller, _) = controller(test_config(), &[]);
+        let shaper = FakeShaper::default();
+        ratecontroller.netlink = Box::new(shaper.clone());
+        ratecontroller.resumed = true;
+        ratecontroller.state_dl.current_rate = 42000.0;
+        ratecontroller.state_ul.current_rate = 21000.0;
+
+        ratecontroller.start().unwrap();
+
+        let rates: Vec<u64> = shaper
+            .rates
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, rate)| *rate)
+            .collect();
+        assert_eq!(rates, [42000, 21000]);
+    }
+
+    #[test]
+    fn warmup_ends_at_base() {
+        for shape in [WarmupShape::Linear, WarmupShape::Exponential] {
+            assert_eq!(warmup_rate(shape, 2000.0, 60000.0, 0.0), 2000.0);
+            assert!((warmup_rate(shape, 2000.0, 60000.0, 1.0) - 60000.0).abs() < 1e-6);
+            // A zero start doesn't keep the exponential ramp at zero until the very end
+            assert!(warmup_rate(shape, 0.0, 60000.0, 0.5) > 0.0);
+            assert_eq!(warmup_rate(shape, 0.0, 60000.0, 1.0), 60000.0);
+        }
+    }
+
+    #[test]
+    fn warmup_stays_above_the_floor() {
+        let mut config = test_config();
+        config.download_min_kbits = 0.0;
+        config.upload_min_kbits = 0.0;
+        config.qdisc_floor_kbits = 5000.0;
+        config.warmup_duration = 1000.0;
+        config.warmup_shape = WarmupShape::Exponential;
+        let (mut ratecontroller, _) = controller(config, &[]);
+
+        for progress in [0.0, 0.25, 0.5, 0.75, 1.0] {
+            for shape in [WarmupShape::Linear, WarmupShape::Exponential] {
+                assert!(warmup_rate(shape, 5000.0, 60000.0, progress) >= 5000.0);
+            }
+        }
+
+        // Stopping right away leaves just the first step of the ramp
+        let shaper = FakeShaper::default();
+        ratecontroller.netlink = Box::new(shaper.clone());
+        ratecontroller.shutdown.store(true, Ordering::Relaxed);
+        ratecontroller.warmup_ramp((60000.0, 30000.0)).unwrap();
+        let rates: Vec<u64> = shaper
+            .rates
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, rate)| *rate)
+            .collect();
+        assert_eq!(rates, [5000, 5000]);
+    }
+
+    // Both directions backing off from 40000 / 20000, with these deltas
+    fn backing_off(dl_delta: f64, ul_delta: f64) -> Ratecontroller {
+        let mut config = test_config();
+        config.download_delay_ms = 15.0;
+        config.upload_delay_ms = 15.0;
+        let (mut ratecontroller, _) = controller(config, &[]);
+        for (state, delta, rate) in [
+            (&mut ratecontroller.state_dl, dl_delta, 40000.0),
+            (&mut ratecontroller.state_ul, ul_delta, 20000.0),
+        ] {
+            state.delta_stat = delta;
+            state.current_rate = rate;
+            state.next_rate = rate * 0.9;
+        }
+        ratecontroller
+    }
+
+    #[test]
+    fn both_congested_holds_the_lesser_one() {
+        let mut ratecontroller = backing_off(45.0, 20.0);
+        ratecontroller.coordinate_backoff();
+        assert_eq!(ratecontroller.state_dl.next_rate, 36000.0);
+        assert_eq!(ratecontroller.state_ul.next_rate, 20000.0);
+
+        let mut ratecontroller = backing_off(20.0, 45.0);
+        ratecontroller.coordinate_backoff();
+        assert_eq!(ratecontroller.state_dl.next_rate, 40000.0);
+        assert_eq!(ratecontroller.state_ul.next_rate, 18000.0);
+    }
+
+    #[test]
+    fn one_congested_backs_off_alone() {
+        let mut ratecontroller = backing_off(45.0, 5.0);
+        ratecontroller.coordinate_backoff();
+        // Left for the usual algorithm to decide
+        assert_eq!(ratecontroller.state_dl.next_rate, 36000.0);
+        assert_eq!(ratecontroller.state_ul.next_rate, 18000.0);
+    }
+
+    #[test]
+    fn replaced_qdisc_resets_above_the_floor() {
+        let mut config = test_config();
+        config.download_base_kbits = 60000.0;
+        config.upload_base_kbits = 5000.0;
+        config.qdisc_floor_kbits = 4000.0;
+        let (mut ratecontroller, _) = controller(config, &[]);
+        ratecontroller.state_dl.current_rate = 50000.0;
+        ratecontroller.state_ul.current_rate = 4500.0;
+        ratecontroller.state_ul.deltas = vec![1.0, 2.0];
+
+        // Unchanged qdiscs are left alone
+        let shaper = FakeShaper::default();
+        ratecontroller.netlink = Box::new(shaper.clone());
+        ratecontroller.verify_qdiscs().unwrap();
+        assert!(shaper.rates.lock().unwrap().is_empty());
+        assert_eq!(ratecontroller.state_dl.current_rate, 50000.0);
+
+        let replaced = Qdisc::new(0x8001_0000, 2, 0xFFFF_FFFF);
+        let shaper = FakeShaper {
+            qdisc: replaced,
+            ..Default::default()
+        };
+        ratecontroller.netlink = Box::new(shaper.clone());
+        ratecontroller.verify_qdiscs().unwrap();
+
+        // 60% of the upload base would be below the floor
+        assert_eq!(
+            *shaper.rates.lock().unwrap(),
+            [(replaced, 36000), (replaced, 4000)]
+        );
+        assert_eq!(ratecontroller.state_dl.qdisc, replaced);
+        assert_eq!(ratecontroller.state_ul.current_rate, 4000.0);
+        assert_eq!(ratecontroller.state_ul.next_rate, 4000.0);
+        assert!(ratecontroller.state_ul.deltas.is_empty());
+    }
+
+    #[test]
+    fn stats_line_per_tick() {
+        let (ratecontroller, stats) = stats_over_ticks("per-tick", 2, |_| {});
+
+        let lines: Vec<&str> = stats.split_inclusive('\n').collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "times,timens,rxload,txload,deltadelaydown,deltadelayup,dlrate,uprate\n"
+        );
+        for line in &lines[1..] {
+            // The time, then loads, deltas and rates, each line complete and newline terminated
+            let fields: Vec<&str> = line.strip_suffix('\n').unwrap().split(',').collect();
+            assert_eq!(fields.len(), 7);
+            assert!(fields[0].starts_with(&time_format(
+                SystemTime::now(),
+                format_description!("[year]-")
+            )));
+            for field in &fields[1..] {
+                field.parse::<f64>().unwrap();
+            }
+        }
+        let last: Vec<&str> = lines[2].trim_end().split(',').collect();
+        assert_eq!(last[5], ratecontroller.state_dl.current_rate.to_string());
+        assert_eq!(last[6], ratecontroller.state_ul.current_rate.to_string());
+    }
+
+    #[test]
+    fn stats_only_on_change() {
+        let (_, stats) = stats_over_ticks("on-change", 2, |config| {
+            config.stats_on_change_only = true;
+        });
+
+        // Without any deltas the rates drop to the floor, then there's nothing new while they hold
+        let lines: Vec<&str> = stats.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].ends_with(",10000,1000"));
+    }
+
+    #[test]
+    fn exported_safe_rates_cover_the_history() {
+        let (ratecontroller, _) = stats_over_ticks("safe-rates", 2, |_| {});
+
+        let safe_rates = ratecontroller.safe_rates.lock().unwrap();
+        let size = ratecontroller.config.speed_hist_size as usize;
+        assert_eq!(safe_rates.download.len(), size);
+        assert_eq!(safe_rates.upload.len(), size);
+        assert!(safe_rates.download_index < size && safe_rates.upload_index < size);
+    }
+
+    #[test]
+    fn appending_keeps_the_single_header() {
+        let header = "times,timens,rxload,txload,deltadelaydown,deltadelayup,dlrate,uprate\n";
+        let earlier = format!("{}2024-01-01 00:00:00,0,0,0,0,60000,5000\n", header);
+        let (_, stats) = stats_over_ticks("append", 2, |config| {
+            config.stats_append = true;
+            fs::write(&config.stats_file, &earlier).unwrap();
+        });
+
+        // What an earlier run wrote is kept, followed by this run's lines without a header
+        assert!(stats.starts_with(&earlier));
+        assert_eq!(stats.matches(header).count(), 1);
+        assert_eq!(stats.lines().count(), 4);
+    }
+
+    // Runs the rate controller for a number of ticks, returning the stats file it wrote
+    fn stats_over_ticks(
+        name: &str,
+        ticks: usize,
+        configure: impl FnOnce(&mut Config),
+    ) -> (Ratecontroller, String) {
+        let temp_file = |file: &str| {
+            env::temp_dir()
+                .join(format!("sqma-{}-{}-{}", name, file, std::process::id()))
+                .to_string_lossy()
+                .into_owned()
+        };
+        let mut config = test_config();
+        config.download_min_change_interval = 0.01;
+        config.upload_min_change_interval = 0.01;
+        config.speed_hist_file = temp_file("speed-hist.csv");
+        config.stats_file = temp_file("stats.csv");
+        config.stats_format = StatsFormat::Csv;
+        config.suppress_statistics = false;
+        config.warmup_shape = WarmupShape::Step;
+        configure(&mut config);
+        let stats_file = config.stats_file.clone();
+        let speed_hist_file = config.speed_hist_file.clone();
+        let (mut ratecontroller, _) = controller(config, &[]);
+        ratecontroller.netlink = Box::new(FakeShaper {
+            stop_after: Some((ticks, ratecontroller.shutdown.clone())),
+            ..Default::default()
+        });
+
+        ratecontroller.run().unwrap();
+        let stats = fs::read_to_string(&stats_file).unwrap();
+        fs::remove_file(&stats_file).unwrap();
+        fs::remove_file(&speed_hist_file).unwrap();
+
+        (ratecontroller, stats)
+    }
+
+    #[test]
+    fn floor_decays_to_the_hard_minimum() {
+        let now_t = Instant::now();
+        let mut state = State::new(Qdisc::default(), 0, Vec::new(), 1000.0, now_t);
+        state.current_rate = 1000.0;
+        state.delta_stat = 20.0;
+        let adapt = |state: &mut State, secs: u64| {
+            adapt_floor_at(state, now_t + Duration::from_secs(secs));
+        };
+
+        // Congestion at the floor has to last before the floor moves
+        adapt(&mut state, 0);
+        adapt(&mut state, 5);
+        assert_eq!(state.floor, 1000.0);
+        adapt(&mut state, 10);
+        assert_eq!(state.floor, 900.0);
+
+        let mut last_floor = state.floor;
+        for secs in (20..500).step_by(10) {
+            state.current_rate = state.floor;
+            adapt(&mut state, secs);
+            assert!(state.floor <= last_floor);
+            assert!(state.floor >= 256.0);
+            last_floor = state.floor;
+        }
+        assert_eq!(state.floor, 256.0);
+    }
+
+    #[test]
+    fn floor_is_restored_after_recovery() {
+        let now_t = Instant::now();
+        let mut state = State::new(Qdisc::default(), 0, Vec::new(), 300.0, now_t);
+        state.current_rate = 300.0;
+        state.next_rate = 600.0;
+        adapt_floor_at(&mut state, now_t);
+        assert_eq!(state.floor, 300.0);
+
+        state.next_rate = 1500.0;
+        adapt_floor_at(&mut state, now_t);
+        assert_eq!(state.floor, 1000.0);
+        assert!(state.congested_at_floor_since.is_none());
+    }
+
+    // A minimum of 1000 and a hard minimum of 256, lowering after 10s of bloat over 15ms
+    fn adapt_floor_at(state: &mut State, now_t: Instant) {
+        Ratecontroller::adapt_floor(Direction::Down, state, 15.0, 1000.0, 256.0, 10.0, now_t);
+    }
+
+    #[test]
+    fn utilisation_is_measured_over_the_window() {
+        let mut config = test_config();
+        config.utilisation_window = 3;
+        let (mut ratecontroller, _) = controller(config, &[]);
+        ratecontroller.min_deltas = 1;
+        let start_t = Instant::now();
+        let state = &mut ratecontroller.state_dl;
+        state.current_rate = 10000.0;
+        state.deltas = vec![1.0];
+        state.byte_samples = VecDeque::from([
+            (start_t, 0),
+            (start_t + Duration::from_secs(1), 1_000_000),
+            (start_t + Duration::from_secs(2), 1_250_000),
+        ]);
+        state.current_bytes = 1_500_000;
+
+        ratecontroller
+            .calculate_rate(Direction::Down, start_t + Duration::from_secs(3))
+            .unwrap();
+
+        // 1.5 MB over the 3s since the oldest sample, rather than 250 kB over the last second
+        let state = &ratecontroller.state_dl;
+        assert_eq!(state.utilisation, 4000.0);
+        assert_eq!(state.load, 0.4);
+        assert_eq!(state.byte_samples.len(), 3);
+        assert_eq!(state.byte_samples[0].1, 1_000_000);
+    }
+
+    // The safe rates after a tick of 17 Mbit/s of traffic, right after a backoff to 20 Mbit/s
+    fn safe_rates_after_backoff(load_from_capacity: bool) -> Vec<f64> {
+        let mut config = test_config();
+        config.load_from_capacity = load_from_capacity;
+        let (mut ratecontroller, _) = controller(config, &[]);
+        ratecontroller.min_deltas = 1;
+        let start_t = Instant::now();
+        let state = &mut ratecontroller.state_dl;
+        state.current_rate = 20000.0;
+        state.deltas = vec![1.0];
+        state.safe_rates = vec![50000.0; 4];
+        state.byte_samples = VecDeque::from([(start_t, 0)]);
+        state.current_bytes = 2_125_000;
+
+        ratecontroller
+            .calculate_rate(Direction::Down, start_t + Duration::from_secs(1))
+            .unwrap();
+        ratecontroller.state_dl.safe_rates
+    }
+
+    #[test]
+    fn backoff_doesnt_drag_the_safe_rates_down() {
+        /*
+         * Against the lowered rate that's 85% load, which gets learned as a safe rate, and
+         * later backoffs and the boost work from a history that's been dragged down. Against
+         * the capacity seen before it's only 34%, and the history is left alone.
+         */
+        assert_eq!(
+            safe_rates_after_backoff(false),
+            [17000.0, 50000.0, 50000.0, 50000.0]
+        );
+        assert_eq!(safe_rates_after_backoff(true), [50000.0; 4]);
+    }
+
+    // The rate after steady ticks with a 1 ms delay increase, then a single tick at 50 ms
+    fn rate_after_spike(delta_smoothing: f64) -> f64 {
+        let mut config = test_config();
+        config.delta_smoothing = delta_smoothing;
+        let (mut ratecontroller, _) = controller(config, &[]);
+        ratecontroller.min_deltas = 1;
+        let start_t = Instant::now();
+        ratecontroller.state_dl.current_rate = 50000.0;
+
+        for (tick, delta) in [1.0, 1.0, 1.0, 1.0, 50.0].into_iter().enumerate() {
+            let now_t = start_t + Duration::from_millis(500 * (tick as u64 + 1));
+            ratecontroller.state_dl.deltas = vec![delta];
+            ratecontroller
+                .calculate_rate(Direction::Down, now_t)
+                .unwrap();
+            ratecontroller.state_dl.last_change_t = now_t;
+        }
+        ratecontroller.state_dl.next_rate
+    }
+
+    #[test]
+    fn single_tick_spike_is_smoothed() {
+        assert!(rate_after_spike(0.0) < 50000.0);
+        assert_eq!(rate_after_spike(2.0), 50000.0);
+    }
+
+    #[test]
+    fn median_vs_index_over_reflector_counts() {
+        // (reflectors, index 2, median) with deltas of 1 ms, 2 ms, ... up to the count
+        for (count, index, median) in [
+            (1, 1.0, 1.0),
+            (2, 2.0, 1.5),
+            (3, 3.0, 2.0),
+            (4, 3.0, 2.5),
+            (5, 3.0, 3.0),
+            (6, 3.0, 3.5),
+            (9, 3.0, 5.0),
+        ] {
+            let deltas: Vec<f64> = (1..=count).map(f64::from).collect();
+            assert_eq!(
+                aggregate_delta(&deltas, DeltaAggregation::Index, 2),
+                index,
+                "{} reflectors",
+                count
+            );
+            assert_eq!(
+                aggregate_delta(&deltas, DeltaAggregation::Median, 2),
+                median,
+                "{} reflectors",
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn non_finite_deltas_are_left_out() {
+        let reflectors = [
+            ("192.0.2.1", 1.0),
+            ("192.0.2.2", f64::NAN),
+            ("192.0.2.3", 2.0),
+        ];
+        let (mut ratecontroller, _) = controller(test_config(), &reflectors);
+        ratecontroller
+            .owd_recent
+            .lock()
+            .unwrap()
+            .get_mut(&"192.0.2.3".parse().unwrap())
+            .unwrap()
+            .up_ewma = f64::INFINITY;
+
+        ratecontroller.update_deltas(Instant::now());
+
+        assert_eq!(ratecontroller.state_dl.deltas, [1.0, 2.0]);
+        assert_eq!(ratecontroller.state_ul.deltas, [1.0]);
+    }
+
+    #[test]
+    fn directions_aggregate_only_their_reflectors() {
+        let reflectors = [
+            ("192.0.2.1", 1.0),
+            ("192.0.2.2", 2.0),
+            ("192.0.2.3", 3.0),
+            ("192.0.2.4", 4.0),
+        ];
+        let (mut ratecontroller, _) = controller(test_config(), &reflectors);
+        ratecontroller.download_reflectors = vec!["192.0.2.1".parse().unwrap()];
+        ratecontroller.upload_reflectors =
+            vec!["192.0.2.2".parse().unwrap(), "192.0.2.4".parse().unwrap()];
+
+        ratecontroller.update_deltas(Instant::now());
+        assert_eq!(ratecontroller.state_dl.deltas, [1.0]);
+        assert_eq!(ratecontroller.state_ul.deltas, [2.0, 4.0]);
+
+        // Without a list of its own, a direction takes all the peers
+        ratecontroller.upload_reflectors.clear();
+        ratecontroller.update_deltas(Instant::now());
+        assert_eq!(ratecontroller.state_dl.deltas, [1.0]);
+        assert_eq!(ratecontroller.state_ul.deltas, [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn non_finite_reference_deltas_are_left_out() {
+        let reflectors = [("192.0.2.1", 1.0), ("192.0.2.2", f64::NAN)];
+        let mut config = test_config();
+        config.reference_interval = 1.0;
+        let (mut ratecontroller, _) = controller(config, &reflectors);
+        ratecontroller.reference_reflectors = reflectors
+            .iter()
+            .map(|(reflector, _)| reflector.parse().unwrap())
+            .collect();
+        for state in [&mut ratecontroller.state_dl, &mut ratecontroller.state_ul] {
+            state.current_rate = 20000.0;
+            state.delta_stat = 100.0;
+            state.next_rate = 5000.0;
+        }
+
+        ratecontroller.check_reference(Instant::now());
+
+        // The reference reflector that's still making sense disagrees, so the rate holds
+        assert_eq!(ratecontroller.state_dl.next_rate, 20000.0);
+        assert_eq!(ratecontroller.state_ul.next_rate, 20000.0);
+    }
+
+    #[test]
+    fn circuit_breaker_trips_when_stuck_at_the_floor() {
+        let start_t = Instant::now();
+        let at = |secs: u64| start_t + Duration::from_secs(secs);
+        let mut state = State::new(Qdisc::default(), 0, Vec::new(), 1000.0, start_t);
+        state.current_rate = 1000.0;
+        state.delta_stat = 30.0;
+        let check = |state: &mut State, now_t: Instant| {
+            Ratecontroller::check_circuit_breaker(Direction::Up, state, 5000.0, 15.0, 60.0, now_t)
+        };
+
+        assert!(!check(&mut state, at(0)));
+        assert!(!check(&mut state, at(30)));
+        assert!(check(&mut state, at(60)));
+        assert_eq!(state.next_rate, 5000.0);
+
+        // Held at base for the window, without tripping again
+        state.next_rate = 1000.0;
+        assert!(!check(&mut state, at(90)));
+        assert_eq!(state.next_rate, 5000.0);
+
+        // Released after that, and has to be stuck for a whole window again to trip
+        state.next_rate = 1000.0;
+        assert!(!check(&mut state, at(120)));
+        assert_eq!(state.next_rate, 1000.0);
+        assert!(state.breaker_until.is_none());
+        assert!(check(&mut state, at(180)));
+    }
+
+    #[test]
+    fn circuit_breaker_ignores_real_load() {
+        let start_t = Instant::now();
+        let mut state = State::new(Qdisc::default(), 0, Vec::new(), 1000.0, start_t);
+        state.current_rate = 1000.0;
+        state.delta_stat = 30.0;
+        state.load = 0.9;
+
+        for secs in [0, 60, 120] {
+            assert!(!Ratecontroller::check_circuit_breaker(
+                Direction::Up,
+                &mut state,
+                5000.0,
+                15.0,
+                60.0,
+                start_t + Duration::from_secs(secs),
+            ));
+        }
+        assert!(state.stuck_since.is_none());
+    }
+
+    #[test]
+    fn reference_set_confirms_congestion() {
+        let reflectors = [("192.0.2.1", 1.0), ("192.0.2.2", 30.0), ("192.0.2.3", 40.0)];
+        let mut config = test_config();
+        config.reference_interval = 1.0;
+        let (mut ratecontroller, _) = controller(config, &reflectors);
+        ratecontroller.reference_reflectors = reflectors
+            .iter()
+            .map(|(reflector, _)| reflector.parse().unwrap())
+            .collect();
+        for state in [&mut ratecontroller.state_dl, &mut ratecontroller.state_ul] {
+            state.current_rate = 20000.0;
+            state.delta_stat = 100.0;
+            state.next_rate = 5000.0;
+        }
+
+        ratecontroller.check_reference(Instant::now());
+
+        // Most of the reference set sees the delay too, so the backoff goes ahead
+        assert_eq!(ratecontroller.state_dl.next_rate, 5000.0);
+        assert_eq!(ratecontroller.state_ul.next_rate, 5000.0);
+    }
+
+    #[test]
+    fn changes_are_gated_per_direction() {
+        let mut config = test_config();
+        // Upload isn't given an interval of its own, so it inherits the global 0.5 s
+        assert_eq!(config.min_change_interval, 0.5);
+        assert_eq!(config.upload_min_change_interval, 0.5);
+        config.download_min_change_interval = 1.0;
+        let (mut ratecontroller, _) = controller(config, &[]);
+        let now_t = Instant::now();
+        ratecontroller.state_dl.last_change_t = now_t;
+        ratecontroller.state_ul.last_change_t = now_t;
+
+        let after = |secs: f64| now_t + Duration::from_secs_f64(secs);
+        assert_eq!(ratecontroller.changes_due(after(0.3)), (false, false));
+        assert_eq!(ratecontroller.changes_due(after(0.7)), (false, true));
+        assert_eq!(ratecontroller.changes_due(after(1.2)), (true, true));
+
+        ratecontroller.config.upload_min_change_interval = 2.0;
+        assert_eq!(ratecontroller.changes_due(after(1.2)), (true, false));
+    }
+
+    #[test]
+    fn delta_index_per_direction() {
+        let mut config = test_config();
+        config.download_delta_index = 0;
+        config.upload_delta_index = 2;
+        let (mut ratecontroller, _) = controller(config, &[]);
+        ratecontroller.min_deltas = 1;
+        let now_t = Instant::now();
+        for state in [&mut ratecontroller.state_dl, &mut ratecontroller.state_ul] {
+            state.current_rate = 5000.0;
+            state.deltas = vec![1.0, 5.0, 30.0];
+        }
+
+        ratecontroller
+            .calculate_rate(Direction::Down, now_t)
+            .unwrap();
+        ratecontroller.calculate_rate(Direction::Up, now_t).unwrap();
+
+        assert_eq!(ratecontroller.state_dl.delta_stat, 1.0);
+        assert_eq!(ratecontroller.state_ul.delta_stat, 30.0);
+        // An index past the end of the deltas takes the highest there is
+        ratecontroller.config.upload_delta_index = 5;
+        ratecontroller.calculate_rate(Direction::Up, now_t).unwrap();
+        assert_eq!(ratecontroller.state_ul.delta_stat, 30.0);
+    }
+
+    #[test]
+    fn speed_test_is_detected_after_sustained_saturation() {
+        let start_t = Instant::now();
+        let mut state = State::new(Qdisc::default(), 0, Vec::new(), 1000.0, start_t);
+        state.current_rate = 10000.0;
+        // 1.2 MB a second is 9600 kbit/s, close to the shaper rate
+        let detect = |state: &mut State, secs: u64, bytes: i128| {
+            state.current_bytes = bytes;
+            Ratecontroller::detect_speed_test(
+                Direction::Down,
+                state,
+                bytes - 1_200_000,
+                Duration::from_secs(1),
+                0.9,
+                10.0,
+                start_t + Duration::from_secs(secs),
+            )
+        };
+
+        assert!(!detect(&mut state, 0, 1_200_000));
+        assert!(!detect(&mut state, 5, 7_200_000));
+        assert!(detect(&mut state, 10, 13_200_000));
+        assert_eq!(state.load, 0.96);
+
+        // Once the load drops off it's over, and has to last the whole duration again
+        state.current_bytes = 13_300_000;
+        assert!(!Ratecontroller::detect_speed_test(
+            Direction::Down,
+            &mut state,
+            13_200_000,
+            Duration::from_secs(1),
+            0.9,
+            10.0,
+            start_t + Duration::from_secs(11),
+        ));
+        assert!(state.saturated_since.is_none());
+        assert!(!detect(&mut state, 12, 14_500_000));
+    }
+
+    #[test]
+    fn idle_until_traffic_picks_up() {
+        let mut config = test_config();
+        config.idle_duration = 300.0;
+        config.idle_load = 0.1;
+        let (mut ratecontroller, _) = controller(config, &[]);
+        let start_t = Instant::now();
+        ratecontroller.state_dl.load = 0.05;
+        ratecontroller.state_ul.load = 0.02;
+
+        ratecontroller.update_idle(start_t);
+        ratecontroller.update_idle(start_t + Duration::from_secs(299));
+        assert!(!ratecontroller.idle.load(Ordering::Relaxed));
+        ratecontroller.update_idle(start_t + Duration::from_secs(300));
+        assert!(ratecontroller.idle.load(Ordering::Relaxed));
+
+        // Either direction picking up is enough, and the wait starts over after
+        ratecontroller.state_ul.load = 0.5;
+        ratecontroller.update_idle(start_t + Duration::from_secs(301));
+        assert!(!ratecontroller.idle.load(Ordering::Relaxed));
+        ratecontroller.state_ul.load = 0.02;
+        ratecontroller.update_idle(start_t + Duration::from_secs(302));
+        ratecontroller.update_idle(start_t + Duration::from_secs(601));
+        assert!(!ratecontroller.idle.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn disabled_reflector_is_left_out() {
+        let (mut ratecontroller, _) = controller(
+            test_config(),
+            &[("192.0.2.1", 1.0), ("192.0.2.2", 2.0), ("192.0.2.3", 50.0)],
+        );
+        ratecontroller
+            .disabled_reflectors
+            .write()
+            .unwrap()
+            .insert("192.0.2.3".parse().unwrap());
+
+        ratecontroller.update_deltas(Instant::now());
+
+        assert_eq!(ratecontroller.state_dl.deltas, [1.0, 2.0]);
+        assert_eq!(ratecontroller.state_ul.deltas, [1.0, 2.0]);
+    }
+
+    #[test]
+    fn new_peer_is_left_out_while_warming_up() {
+        let mut config = test_config();
+        config.reselect_warmup_ticks = 2;
+        let (mut ratecontroller, _) = controller(config, &[("192.0.2.1", 1.0), ("192.0.2.2", 2.0)]);
+        let now_t = Instant::now();
+
+        // The peers we start out with are warm already
+        ratecontroller.update_deltas(now_t);
+        assert_eq!(ratecontroller.state_dl.deltas, [1.0, 2.0]);
+
+        // Selected with a delay 30 ms above its baseline
+        let new_peer: IpAddr = "192.0.2.3".parse().unwrap();
+        {
+            let mut owd_baseline = ratecontroller.owd_baseline.lock().unwrap();
+            let mut owd_recent = ratecontroller.owd_recent.lock().unwrap();
+            let mut stats = owd_baseline[&IpAddr::from([192, 0, 2, 1])];
+            owd_baseline.insert(new_peer, stats);
+            stats.down_ewma += 30.0;
+            owd_recent.insert(new_peer, stats);
+        }
+        ratecontroller
+            .reflectors_lock
+            .write()
+            .unwrap()
+            .push(new_peer);
+
+        for _ in 0..2 {
+            ratecontroller.update_deltas(now_t);
+            assert_eq!(ratecontroller.state_dl.deltas, [1.0, 2.0]);
+        }
+        ratecontroller.update_deltas(now_t);
+        assert_eq!(ratecontroller.state_dl.deltas, [1.0, 2.0, 30.0]);
+    }
+
+    #[test]
+    fn small_pool_without_reselection() {
+        let (mut ratecontroller, reselect_receiver) =
+            controller(test_config(), &[("192.0.2.1", 1.0), ("192.0.2.2", 2.0)]);
+        // As main decides when the pool is no larger than num_reflectors
+        ratecontroller.reselection_active = false;
+
+        ratecontroller.update_deltas(Instant::now());
+
+        assert_eq!(ratecontroller.state_dl.deltas, [1.0, 2.0]);
+        assert_eq!(ratecontroller.min_deltas, 2);
+        assert!(reselect_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn too_few_deltas_triggers_reselection() {
+        let (mut ratecontroller, reselect_receiver) =
+            controller(test_config(), &[("192.0.2.1", 1.0), ("192.0.2.2", 2.0)]);
+        ratecontroller.reselection_active = true;
+
+        ratecontroller.update_deltas(Instant::now());
+
+        assert_eq!(ratecontroller.min_deltas, MIN_DELTAS);
+        assert!(reselect_receiver.try_recv().is_ok());
+    }
+
+    #[test]
+    fn initial_speeds_stay_within_min_and_base() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let rates = generate_initial_speeds(&mut rng, 1_000.0, 10_000.0, 50);
+        assert_eq!(rates.len(), 50);
+        assert!(rates
+            .iter()
+            .all(|&rate| (7_500.0..=9_500.0).contains(&rate)));
+
+        // A minimum above 75% of base lifts the low seeds, but never past base
+        let rates = generate_initial_speeds(&mut rng, 9_000.0, 10_000.0, 50);
+        assert!(rates
+            .iter()
+            .all(|&rate| (9_000.0..=10_000.0).contains(&rate)));
+        assert!(rates.contains(&9_000.0));
+        let rates = generate_initial_speeds(&mut rng, 20_000.0, 10_000.0, 50);
+        assert!(rates.iter().all(|&rate| rate == 10_000.0));
+    }
+
+    #[test]
+    fn quantize_rounds_in_the_direction_of_change() {
+        assert_eq!(quantize_rate(10_100.0, 10_000.0, 1_000.0, 500.0), 10_500.0);
+        assert_eq!(quantize_rate(9_900.0, 10_000.0, 1_000.0, 500.0), 9_500.0);
+        assert_eq!(quantize_rate(10_500.0, 10_000.0, 1_000.0, 500.0), 10_500.0);
+        assert_eq!(quantize_rate(10_000.0, 10_000.0, 1_000.0, 500.0), 10_000.0);
+    }
+
+    #[test]
+    fn quantize_never_goes_below_the_floor() {
+        // The floor isn't a multiple of the step, so the first step above it is used
+        assert_eq!(quantize_rate(1_100.0, 1_500.0, 1_200.0, 500.0), 1_500.0);
+        assert_eq!(quantize_rate(1_300.0, 1_500.0, 1_200.0, 500.0), 1_500.0);
+        assert_eq!(quantize_rate(2_100.0, 2_500.0, 1_200.0, 500.0), 2_000.0);
     }
 }
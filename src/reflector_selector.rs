@@ -1,44 +1,375 @@
+use crate::metrics::Counters;
+use crate::peer_cache::{now_s, PeerCache};
+use crate::pinger::{probe_once, PingListener, PingSender};
+use crate::random::{new_rng, RngStream};
 use crate::{Config, ReflectorStats};
-use log::{debug, info};
+use log::{debug, info, warn};
 use rand::seq::SliceRandom;
-use rand::{thread_rng, Rng};
-use std::collections::HashMap;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::net::IpAddr;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+// A set that goes this long without reselection being triggered counts as having held up
+const STABLE_SET_DURATION: Duration = Duration::from_secs(60);
+
+/// Why a reflector was left out of the peers, for the logs and the drop counters
+#[derive(Clone, Copy, Debug)]
+pub enum DropReason {
+    // Turned off through the control socket
+    Disabled,
+    // Drawn from the pool while it was a peer already
+    Duplicate,
+    NoData,
+    // Made the pool of fastest candidates, but wasn't drawn from it
+    NotDrawn,
+    // A peer that never replied, swapped out on its own without a full reselection
+    Silent,
+    TooFewReplies,
+    TooSlow,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 7] = [
+        DropReason::Disabled,
+        DropReason::Duplicate,
+        DropReason::NoData,
+        DropReason::NotDrawn,
+        DropReason::Silent,
+        DropReason::TooFewReplies,
+        DropReason::TooSlow,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            DropReason::Disabled => "disabled",
+            DropReason::Duplicate => "duplicate",
+            DropReason::NoData => "no_data",
+            DropReason::NotDrawn => "not_drawn",
+            DropReason::Silent => "silent",
+            DropReason::TooFewReplies => "too_few_replies",
+            DropReason::TooSlow => "too_slow",
+        }
+    }
+}
+
+/*
+ * How long to hold off a reselection that's been re-triggered unstable_count times in a row.
+ * If reselection keeps failing to produce a set that holds up, the problem is most likely
+ * the network rather than the reflectors. Say so loudly once, and back off further on each
+ * following attempt instead of churning through the pool.
+ */
+fn unstable_backoff(unstable_count: u32, max_unstable: u32) -> Option<Duration> {
+    if max_unstable == 0 || unstable_count < max_unstable {
+        return None;
+    }
+
+    if unstable_count == max_unstable {
+        warn!(
+            "Reflector reselection re-triggered {} times in a row, the network looks unstable. Backing off reselection",
+            unstable_count
+        );
+    }
+    Some(STABLE_SET_DURATION * 2_u32.pow((unstable_count - max_unstable).min(4)))
+}
+
+/*
+ * Probes each reflector in the sample once, and returns up to num_reflectors of the ones
+ * that replied, fastest first. A single RTT is a rough measure, but good enough to avoid
+ * starting out with reflectors on the other side of the world until the first reselection.
+ */
+pub fn probe_initial_peers(
+    config: &Config,
+    listener: &dyn PingListener,
+    sender: &dyn PingSender,
+    id: u16,
+    sample: &[IpAddr],
+) -> io::Result<Vec<IpAddr>> {
+    let rtts = probe_once(
+        listener,
+        sender,
+        id,
+        config.measurement_type,
+        sample,
+        Duration::from_secs_f64(config.initial_probe_timeout),
+    )?;
+
+    info!(
+        "{} of {} probed reflectors replied to the initial probe",
+        rtts.len(),
+        sample.len()
+    );
+
+    Ok(fastest_probed(rtts, config.num_reflectors))
+}
+
+// Up to num_reflectors of the reflectors that replied, by ascending RTT
+fn fastest_probed(rtts: HashMap<IpAddr, i64>, num_reflectors: u8) -> Vec<IpAddr> {
+    let mut fastest: Vec<(IpAddr, i64)> = rtts.into_iter().collect();
+    fastest.sort_by_key(|a| a.1);
+    for (reflector, rtt) in fastest.iter() {
+        debug!("Initial probe of {}: RTT {}", reflector, rtt);
+    }
+
+    fastest
+        .into_iter()
+        .take(num_reflectors as usize)
+        .map(|(reflector, _)| reflector)
+        .collect()
+}
+
+// Sampled without replacement, so a small pool doesn't get the same reflector twice
+fn pick_candidates(pool: &[IpAddr], num_candidates: u32, rng: &mut impl Rng) -> Vec<IpAddr> {
+    pool.choose_multiple(rng, (num_candidates as usize).min(pool.len()))
+        .copied()
+        .collect()
+}
+
+/// The peers drawn at reselection, and the candidates that were left out and why
+struct Draw {
+    peers: Vec<IpAddr>,
+    too_slow: Vec<IpAddr>,
+    not_drawn: Vec<IpAddr>,
+}
+
+/*
+ * Draws the new peers from the candidates, which are sorted by ascending RTT. Only the
+ * fastest 2 * num_reflectors are in the running, and those are shuffled so the best
+ * reflectors don't get overwhelmed by everyone picking them. With fewer candidates than
+ * peers wanted, all of them are taken.
+ */
+fn draw_peers(mut candidates: Vec<(IpAddr, u64)>, num_reflectors: u8, rng: &mut impl Rng) -> Draw {
+    let candidate_pool_num = 2 * num_reflectors as usize;
+    let too_slow = candidates.split_off(candidates.len().min(candidate_pool_num));
+
+    // Shuffle the deck so we avoid overwhelming good reflectors (Fisher-Yates)
+    for i in (1_usize..candidates.len()).rev() {
+        let j = rng.gen_range(0..(i + 1));
+        candidates.swap(i, j);
+    }
+
+    let not_drawn = candidates.split_off(candidates.len().min(num_reflectors as usize));
+
+    Draw {
+        peers: candidates.into_iter().map(|(peer, _)| peer).collect(),
+        too_slow: too_slow.into_iter().map(|(peer, _)| peer).collect(),
+        not_drawn: not_drawn.into_iter().map(|(peer, _)| peer).collect(),
+    }
+}
 
 pub struct ReflectorSelector {
     pub config: Config,
+    pub counters: Arc<Counters>,
+    pub disabled_reflectors: Arc<RwLock<HashSet<IpAddr>>>,
+    pub owd_baseline: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
     pub owd_recent: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    // As loaded at startup, the selector keeps it up to date from there
+    pub peer_cache: PeerCache,
     pub reflector_peers_lock: Arc<RwLock<Vec<IpAddr>>>,
-    pub reflector_pool: Vec<IpAddr>,
+    pub reflector_pool: Arc<RwLock<Vec<IpAddr>>>,
     pub trigger_channel: Receiver<bool>,
 }
 
 impl ReflectorSelector {
+    fn dropped(&self, reflector: IpAddr, reason: DropReason) {
+        debug!("Dropping reflector {}: {}", reflector, reason.label());
+        self.counters.reflector_drops[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /*
+     * A peer that hasn't produced a single reply since it was selected most likely doesn't
+     * support the probe type at all, e.g. a reflector that only answers echo requests while
+     * we're sending timestamp requests. That's a problem with the reflector rather than the
+     * link, so swap just those peers for fresh ones from the pool instead of churning the
+     * whole set. If none of the peers reply, it's more likely the link, so leave it to a
+     * full reselection, as well as when the pool doesn't have enough left to replace them
+     * all with. Returns whether the silent peers were replaced.
+     */
+    fn replace_silent_peers(
+        &self,
+        rng: &mut impl Rng,
+        silent_reflectors: &mut HashSet<IpAddr>,
+    ) -> bool {
+        // The maps are locked before the peers, so work out who's silent before taking write()
+        let current_peers = self.reflector_peers_lock.read().unwrap().clone();
+        let mut silent: Vec<IpAddr> = {
+            let owd_baseline = self.owd_baseline.lock().unwrap();
+            current_peers
+                .iter()
+                .filter(|peer| !owd_baseline.contains_key(peer))
+                .copied()
+                .collect()
+        };
+
+        let mut peers = self.reflector_peers_lock.write().unwrap();
+        // The peers may have changed in between, e.g. one disabled through the control socket
+        silent.retain(|peer| peers.contains(peer));
+
+        if silent.is_empty() || silent.len() == peers.len() {
+            return false;
+        }
+
+        silent_reflectors.extend(silent.iter());
+        let disabled = self.disabled_reflectors.read().unwrap();
+        let pool: Vec<IpAddr> = self
+            .reflector_pool
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|reflector| {
+                !disabled.contains(reflector)
+                    && !silent_reflectors.contains(reflector)
+                    && !peers.contains(reflector)
+            })
+            .copied()
+            .collect();
+
+        if pool.len() < silent.len() {
+            debug!(
+                "Only {} reflectors left to replace {} silent peers with",
+                pool.len(),
+                silent.len()
+            );
+            return false;
+        }
+
+        peers.retain(|peer| !silent.contains(peer));
+        for reflector in silent.iter() {
+            info!(
+                "Reflector {} hasn't replied to any probes, replacing it",
+                reflector
+            );
+            self.dropped(*reflector, DropReason::Silent);
+        }
+        for replacement in pool.choose_multiple(rng, silent.len()) {
+            info!("Replacement peer: {}", replacement);
+            peers.push(*replacement);
+        }
+
+        true
+    }
+
+    // Records the peers and who's been answering in the peer cache file, if there is one
+    fn save_peer_cache(&self, peer_cache: &mut PeerCache, silent_reflectors: &HashSet<IpAddr>) {
+        if self.config.peer_cache_file.is_empty() {
+            return;
+        }
+
+        // The maps are locked before the peers
+        let answering: Vec<IpAddr> = self.owd_baseline.lock().unwrap().keys().copied().collect();
+        let peers = self.reflector_peers_lock.read().unwrap().clone();
+        peer_cache.record(
+            self.config.measurement_type,
+            &peers,
+            &answering,
+            silent_reflectors,
+            now_s(),
+        );
+
+        if let Err(e) = peer_cache.save(self.config.peer_cache_file.as_str(), now_s()) {
+            warn!("Failed to write the peer cache: {}", e);
+        }
+    }
+
+    /*
+     * Ranks the candidates that have enough replies by RTT, fastest first. The recent EWMA
+     * includes any congestion going on right now, while the baseline tracks the minimum
+     * delay, i.e. how close the reflector really is.
+     */
+    fn rank_candidates(&self, next_peers: Vec<IpAddr>) -> Vec<(IpAddr, u64)> {
+        let mut candidates = Vec::new();
+        let owd_baseline = self.owd_baseline.lock().unwrap();
+        let owd_recent = self.owd_recent.lock().unwrap();
+        let owd_ranking = if self.config.rank_by_baseline {
+            &owd_baseline
+        } else {
+            &owd_recent
+        };
+
+        for peer in next_peers {
+            // A single lucky reply isn't enough to rank a reflector on
+            let replies = owd_recent.get(&peer).map_or(0, |stats| stats.replies);
+            if owd_ranking.contains_key(&peer) && replies >= self.config.reselect_min_replies {
+                let rtt = (owd_ranking[&peer].down_ewma + owd_ranking[&peer].up_ewma) as u64;
+                candidates.push((peer, rtt));
+                info!("Candidate reflector: {} RTT: {}", peer.to_string(), rtt);
+            } else if replies > 0 {
+                info!(
+                    "Only {} replies from candidate reflector: {} - skipping",
+                    replies,
+                    peer.to_string()
+                );
+                self.dropped(peer, DropReason::TooFewReplies);
+            } else {
+                info!(
+                    "No data found from candidate reflector: {} - skipping",
+                    peer.to_string()
+                );
+                self.dropped(peer, DropReason::NoData);
+            }
+        }
+
+        // Sort the candidates table now by ascending RTT
+        candidates.sort_by_key(|a| a.1);
+
+        candidates
+    }
+
     pub fn run(&self) -> anyhow::Result<()> {
         let mut selector_sleep_time = Duration::new(30, 0);
         let mut reselection_count = 0;
-        let baseline_sleep_time =
-            Duration::from_secs_f64(self.config.tick_interval * std::f64::consts::PI);
+        let mut unstable_count = 0;
+        let baseline_sleep_time = Duration::from_secs_f64(self.config.rebaseline_duration);
 
-        let mut rng = thread_rng();
+        let mut rng = new_rng(self.config.rng_seed, RngStream::ReflectorSelector);
+        // Reflectors the cache already knows don't answer aren't given another go as replacements
+        let mut peer_cache = self.peer_cache.clone();
+        let mut silent_reflectors = peer_cache.silent(self.config.measurement_type);
+        let mut last_replace_t: Option<Instant> = None;
 
         // Initial wait of several seconds to allow some OWD data to build up
         sleep(baseline_sleep_time);
+        let mut last_reselect_t = Instant::now();
 
         loop {
             /*
              * Selection is triggered either by some other thread triggering it through the channel,
-             * or it passes the timeout. In any case we don't care about the result of this function,
-             * so we ignore the result of it.
+             * or it passes the timeout. A trigger arriving soon after the previous reselection
+             * means the new set didn't hold up either.
              */
-            let _ = self
+            let triggered = self
                 .trigger_channel
                 .recv_timeout(selector_sleep_time)
-                .unwrap_or(true);
+                .is_ok();
+
+            // Replacements need a moment to start replying before the set is judged again
+            if triggered && last_replace_t.is_some_and(|t| t.elapsed() < baseline_sleep_time) {
+                continue;
+            }
+            if triggered && self.replace_silent_peers(&mut rng, &mut silent_reflectors) {
+                self.save_peer_cache(&mut peer_cache, &silent_reflectors);
+                last_replace_t = Some(Instant::now());
+                continue;
+            }
+
+            if triggered && last_reselect_t.elapsed() < STABLE_SET_DURATION {
+                unstable_count += 1;
+            } else {
+                unstable_count = 0;
+            }
+
+            if let Some(backoff) =
+                unstable_backoff(unstable_count, self.config.max_unstable_reselections)
+            {
+                debug!("Backing off reselection for {:?}", backoff);
+                sleep(backoff);
+            }
+
             reselection_count += 1;
             info!("Starting reselection [#{}]", reselection_count);
 
@@ -49,17 +380,36 @@ impl ReflectorSelector {
 
             let mut next_peers: Vec<IpAddr> = Vec::new();
             let mut reflectors_peers = self.reflector_peers_lock.write().unwrap();
+            let disabled = self.disabled_reflectors.read().unwrap().clone();
 
             // Include all current peers
             for reflector in reflectors_peers.iter() {
+                if disabled.contains(reflector) {
+                    self.dropped(*reflector, DropReason::Disabled);
+                    continue;
+                }
                 debug!("Current peer: {}", reflector.to_string());
                 next_peers.push(*reflector);
             }
 
-            for _ in 1..20 {
-                let next_candidate = self.reflector_pool.choose(&mut rng).unwrap();
+            let pool: Vec<IpAddr> = self
+                .reflector_pool
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|reflector| !disabled.contains(reflector))
+                .copied()
+                .collect();
+
+            for next_candidate in pick_candidates(&pool, self.config.reselect_candidates, &mut rng)
+            {
+                // Otherwise it would be ranked twice, and could end up with two of the peer slots
+                if next_peers.contains(&next_candidate) {
+                    self.dropped(next_candidate, DropReason::Duplicate);
+                    continue;
+                }
                 debug!("Next candidate: {}", next_candidate.to_string());
-                next_peers.push(*next_candidate);
+                next_peers.push(next_candidate);
             }
 
             // Put all the pool members back into the peers for some re-baselining...
@@ -73,58 +423,324 @@ impl ReflectorSelector {
             // Wait for several seconds to allow all reflectors to be re-baselined
             sleep(baseline_sleep_time);
 
-            // Re-acquire the lock when we wake up again
+            // Rank before re-acquiring the lock, as the maps have to be locked before the peers
+            let candidates = self.rank_candidates(next_peers);
             reflectors_peers = self.reflector_peers_lock.write().unwrap();
-            reflectors_peers.len();
-
-            let mut candidates = Vec::new();
-            let owd_recent = self.owd_recent.lock().unwrap();
-
-            for peer in next_peers {
-                if owd_recent.contains_key(&peer) {
-                    let rtt = (owd_recent[&peer].down_ewma + owd_recent[&peer].up_ewma) as u64;
-                    candidates.push((peer, rtt));
-                    info!("Candidate reflector: {} RTT: {}", peer.to_string(), rtt);
-                } else {
-                    info!(
-                        "No data found from candidate reflector: {} - skipping",
-                        peer.to_string()
-                    );
-                }
-            }
 
-            // Sort the candidates table now by ascending RTT
-            candidates.sort_by(|a, b| a.1.cmp(&b.1));
-
-            // Now we will just limit the candidates down to 2 * num_reflectors
-            let mut num_reflectors = self.config.num_reflectors;
-            let candidate_pool_num = (2 * num_reflectors) as usize;
-            candidates = candidates[0..candidate_pool_num - 1].to_vec();
-
-            for (candidate, rtt) in candidates.iter() {
+            for (candidate, rtt) in candidates
+                .iter()
+                .take(2 * self.config.num_reflectors as usize)
+            {
                 info!("Fastest candidate {}: {}", candidate, rtt);
             }
 
-            // Shuffle the deck so we avoid overwhelming good reflectors (Fisher-Yates)
-            for i in (1_usize..candidates.len()).rev() {
-                let j = rng.gen_range(0..(i + 1));
-                candidates.swap(i, j);
+            let draw = draw_peers(candidates, self.config.num_reflectors, &mut rng);
+            for candidate in draw.too_slow {
+                self.dropped(candidate, DropReason::TooSlow);
             }
-
-            if (candidates.len() as u8) < num_reflectors {
-                num_reflectors = candidates.len() as u8;
+            for candidate in draw.not_drawn {
+                self.dropped(candidate, DropReason::NotDrawn);
             }
-
-            let mut new_peers = Vec::new();
-            for i in 0..num_reflectors {
-                new_peers.push(candidates[i as usize].0);
-                info!(
-                    "New selected peer: {}",
-                    candidates[i as usize].0.to_string()
-                );
+            for peer in draw.peers.iter() {
+                info!("New selected peer: {}", peer.to_string());
             }
 
-            *reflectors_peers = new_peers;
+            self.counters.event(
+                "reselection",
+                format!("Selected {} new peers", draw.peers.len()),
+            );
+            *reflectors_peers = draw.peers;
+            drop(reflectors_peers);
+            self.save_peer_cache(&mut peer_cache, &silent_reflectors);
+
+            // Triggers queued up while the candidates were baselined are about the old set
+            while self.trigger_channel.try_recv().is_ok() {}
+            last_reselect_t = Instant::now();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{test_config, MeasurementType};
+    use crate::log::capture_logs;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::sync::mpsc::channel;
+
+    fn selector(config: Config) -> ReflectorSelector {
+        let (_, trigger_channel) = channel();
+        ReflectorSelector {
+            config,
+            counters: Arc::new(Counters::default()),
+            disabled_reflectors: Arc::new(RwLock::new(HashSet::new())),
+            owd_baseline: Arc::new(Mutex::new(HashMap::new())),
+            owd_recent: Arc::new(Mutex::new(HashMap::new())),
+            peer_cache: PeerCache::default(),
+            reflector_peers_lock: Arc::new(RwLock::new(Vec::new())),
+            reflector_pool: Arc::new(RwLock::new(Vec::new())),
+            trigger_channel,
+        }
+    }
+
+    fn stats(delay: f64, replies: u32) -> ReflectorStats {
+        ReflectorStats {
+            down_ewma: delay,
+            up_ewma: delay,
+            jitter: 0.0,
+            last_receive_time_s: Instant::now(),
+            replies,
+        }
+    }
+
+    /*
+     * The close reflector is congested right now, so its recent EWMA is above the far one's,
+     * while its baseline still shows how close it really is
+     */
+    fn congested_close_reflector(selector: &ReflectorSelector) -> (IpAddr, IpAddr) {
+        let [close, far] = ["192.0.2.1", "192.0.2.2"].map(|r| r.parse::<IpAddr>().unwrap());
+        let mut owd_baseline = selector.owd_baseline.lock().unwrap();
+        let mut owd_recent = selector.owd_recent.lock().unwrap();
+        owd_baseline.insert(close, stats(5.0, 0));
+        owd_recent.insert(close, stats(50.0, 10));
+        owd_baseline.insert(far, stats(20.0, 0));
+        owd_recent.insert(far, stats(21.0, 10));
+        (close, far)
+    }
+
+    fn candidates(count: u8) -> Vec<(IpAddr, u64)> {
+        (1..=count)
+            .map(|i| (IpAddr::from([192, 0, 2, i]), i as u64))
+            .collect()
+    }
+
+    #[test]
+    fn candidates_are_capped_at_the_pool() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let pool: Vec<IpAddr> = candidates(5).into_iter().map(|(peer, _)| peer).collect();
+
+        let mut picked = pick_candidates(&pool, 20, &mut rng);
+        assert_eq!(picked.len(), 5);
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked, pool);
+
+        let picked: HashSet<IpAddr> = pick_candidates(&pool, 3, &mut rng).into_iter().collect();
+        assert_eq!(picked.len(), 3);
+        assert!(pick_candidates(&[], 20, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn startup_takes_the_fastest_probed() {
+        // Only the ones that replied to the probe, with their RTT in ms
+        let rtts: HashMap<IpAddr, i64> = [(4, 80), (1, 35), (7, 12), (3, 150), (5, 20)]
+            .into_iter()
+            .map(|(i, rtt)| (IpAddr::from([192, 0, 2, i]), rtt))
+            .collect();
+
+        assert_eq!(
+            fastest_probed(rtts.clone(), 3),
+            [7, 5, 1].map(|i| IpAddr::from([192, 0, 2, i]))
+        );
+        // Fewer replies than peers wanted leaves the rest to be topped up
+        assert_eq!(fastest_probed(rtts, 8).len(), 5);
+        assert!(fastest_probed(HashMap::new(), 3).is_empty());
+    }
+
+    #[test]
+    fn fewer_candidates_than_peers_all_survive() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let draw = draw_peers(candidates(3), 5, &mut rng);
+
+        let mut peers = draw.peers.clone();
+        peers.sort();
+        assert_eq!(
+            peers,
+            candidates(3)
+                .into_iter()
+                .map(|(peer, _)| peer)
+                .collect::<Vec<_>>()
+        );
+        assert!(draw.too_slow.is_empty());
+        assert!(draw.not_drawn.is_empty());
+    }
+
+    #[test]
+    fn only_the_fastest_are_drawn_from() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let draw = draw_peers(candidates(12), 2, &mut rng);
+
+        assert_eq!(draw.peers.len(), 2);
+        assert_eq!(draw.not_drawn.len(), 2);
+        assert_eq!(draw.too_slow.len(), 8);
+        // The 4 fastest are the ones drawn from
+        let fastest: Vec<IpAddr> = candidates(4).into_iter().map(|(peer, _)| peer).collect();
+        assert!(draw
+            .peers
+            .iter()
+            .chain(draw.not_drawn.iter())
+            .all(|peer| fastest.contains(peer)));
+    }
+
+    #[test]
+    fn ranked_by_baseline_or_recent() {
+        let mut config = test_config();
+        config.rank_by_baseline = true;
+        let by_baseline = selector(config.clone());
+        let (close, far) = congested_close_reflector(&by_baseline);
+        assert_eq!(
+            by_baseline.rank_candidates(vec![far, close]),
+            vec![(close, 10), (far, 40)]
+        );
+
+        config.rank_by_baseline = false;
+        let by_recent = selector(config);
+        let (close, far) = congested_close_reflector(&by_recent);
+        assert_eq!(
+            by_recent.rank_candidates(vec![far, close]),
+            vec![(far, 42), (close, 100)]
+        );
+    }
+
+    #[test]
+    fn candidate_needs_enough_replies_to_be_ranked() {
+        let mut config = test_config();
+        config.reselect_min_replies = 5;
+        let selector = selector(config);
+        let [lucky, established] = ["192.0.2.1", "192.0.2.2"].map(|r| r.parse::<IpAddr>().unwrap());
+        for (reflector, stats) in [(lucky, stats(2.0, 1)), (established, stats(20.0, 10))] {
+            selector
+                .owd_baseline
+                .lock()
+                .unwrap()
+                .insert(reflector, stats);
+            selector.owd_recent.lock().unwrap().insert(reflector, stats);
+        }
+
+        assert_eq!(
+            selector.rank_candidates(vec![lucky, established]),
+            vec![(established, 40)]
+        );
+        assert_eq!(
+            selector.counters.reflector_drops[DropReason::TooFewReplies as usize]
+                .load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    // Peers 1 and 2 with the given pool, where only 1 answers the timestamp requests
+    fn mixed_capability_peers(pool: &[IpAddr]) -> ReflectorSelector {
+        let mut config = test_config();
+        config.measurement_type = MeasurementType::IcmpTimestamps;
+        let selector = selector(config);
+        selector
+            .owd_baseline
+            .lock()
+            .unwrap()
+            .insert(IpAddr::from([192, 0, 2, 1]), stats(5.0, 10));
+        *selector.reflector_peers_lock.write().unwrap() =
+            vec![IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])];
+        *selector.reflector_pool.write().unwrap() = pool.to_vec();
+        selector
+    }
+
+    #[test]
+    fn silent_peer_is_replaced_from_the_pool() {
+        let [answers, echo_only, spare] = [1, 2, 3].map(|i| IpAddr::from([192, 0, 2, i]));
+        let selector = mixed_capability_peers(&[answers, echo_only, spare]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut silent_reflectors = HashSet::new();
+
+        assert!(selector.replace_silent_peers(&mut rng, &mut silent_reflectors));
+        assert_eq!(
+            *selector.reflector_peers_lock.read().unwrap(),
+            [answers, spare]
+        );
+        assert_eq!(silent_reflectors, HashSet::from([echo_only]));
+        assert_eq!(
+            selector.counters.reflector_drops[DropReason::Silent as usize].load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn silent_peer_is_kept_without_a_replacement() {
+        let [answers, echo_only] = [1, 2].map(|i| IpAddr::from([192, 0, 2, i]));
+        let selector = mixed_capability_peers(&[answers, echo_only]);
+        let mut rng = StdRng::seed_from_u64(1);
+
+        // Left to a full reselection rather than shrinking the peers
+        assert!(!selector.replace_silent_peers(&mut rng, &mut HashSet::new()));
+        assert_eq!(
+            *selector.reflector_peers_lock.read().unwrap(),
+            [answers, echo_only]
+        );
+        assert_eq!(
+            selector.counters.reflector_drops[DropReason::Silent as usize].load(Ordering::Relaxed),
+            0
+        );
+    }
+
+    #[test]
+    fn no_data_reflector_drop_is_recorded() {
+        let selector = selector(test_config());
+        let silent: IpAddr = "192.0.2.9".parse().unwrap();
+
+        let logs = capture_logs(|| assert!(selector.rank_candidates(vec![silent]).is_empty()));
+        assert!(logs.contains(&"DEBUG Dropping reflector 192.0.2.9: no_data".to_string()));
+        assert_eq!(
+            selector.counters.reflector_drops[DropReason::NoData as usize].load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn fastest_candidate_can_be_drawn() {
+        let fastest = candidates(1)[0].0;
+
+        // Whichever position the shuffle leaves it at, the fastest one has to make it in
+        let drawn = (0..32)
+            .filter(|seed| {
+                let mut rng = StdRng::seed_from_u64(*seed);
+                draw_peers(candidates(6), 3, &mut rng)
+                    .peers
+                    .contains(&fastest)
+            })
+            .count();
+        assert!(drawn > 0);
+
+        // Starting either loop at index 1 would leave a lone candidate out altogether
+        let mut rng = StdRng::seed_from_u64(1);
+        let draw = draw_peers(candidates(1), 1, &mut rng);
+        assert_eq!(draw.peers, vec![fastest]);
+    }
+
+    #[test]
+    fn repeated_triggers_raise_the_alert_once() {
+        let mut backoffs = Vec::new();
+        let logs = capture_logs(|| {
+            backoffs = (1..=9).map(|count| unstable_backoff(count, 3)).collect();
+        });
+
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].starts_with("WARN Reflector reselection re-triggered 3 times in a row"));
+        // Doubling from the third one on, up to 16 times as long
+        let minutes = |count: u32| Some(STABLE_SET_DURATION * count);
+        assert_eq!(
+            backoffs,
+            [
+                None,
+                None,
+                minutes(1),
+                minutes(2),
+                minutes(4),
+                minutes(8),
+                minutes(16),
+                minutes(16),
+                minutes(16)
+            ]
+        );
+        assert_eq!(unstable_backoff(100, 0), None);
+    }
+}
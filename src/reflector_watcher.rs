@@ -0,0 +1,129 @@
+use crate::Config;
+use log::{info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, RwLock};
+
+/*
+ * Reloads the reflector list file when it changes, and swaps the new list in as the pool
+ * for the reflector selector. Active peers are left alone, removed reflectors just won't
+ * be picked again from the next reselection on.
+ */
+pub struct ReflectorListWatcher {
+    pub config: Config,
+    pub reflector_pool: Arc<RwLock<Vec<IpAddr>>>,
+}
+
+impl ReflectorListWatcher {
+    fn reload(&self) {
+        let mut reflectors = match self.config.load_reflectors() {
+            Ok(val) => val,
+            Err(e) => {
+                warn!(
+                    "Failed to reload reflector list, keeping the current pool: {}",
+                    e
+                );
+                return;
+            }
+        };
+
+        if self.config.use_default_reflectors || reflectors.is_empty() {
+            reflectors.extend(Config::default_reflectors());
+        }
+
+        let mut pool = self.reflector_pool.write().unwrap();
+        let old: HashSet<IpAddr> = pool.iter().copied().collect();
+        let mut new: HashSet<IpAddr> = HashSet::with_capacity(reflectors.len());
+
+        pool.clear();
+        for reflector in reflectors {
+            if new.insert(reflector) {
+                pool.push(reflector);
+            }
+        }
+
+        info!(
+            "Reloaded reflector list: {} added, {} removed, {} in pool",
+            new.difference(&old).count(),
+            old.difference(&new).count(),
+            pool.len()
+        );
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let path = Path::new(self.config.reflector_list_file.as_str());
+        // Editors tend to replace the file rather than write to it in place,
+        // which a watch on the file itself wouldn't survive, so watch the directory
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        let (event_sender, event_receiver) = channel();
+        let mut watcher = notify::recommended_watcher(event_sender)?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        for event in event_receiver {
+            let event = match event {
+                Ok(val) => val,
+                Err(e) => {
+                    warn!("Error watching reflector list: {}", e);
+                    continue;
+                }
+            };
+
+            if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                continue;
+            }
+
+            if event
+                .paths
+                .iter()
+                .any(|changed| changed.file_name() == path.file_name())
+            {
+                self.reload();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use std::{env, fs};
+
+    #[test]
+    fn reload_replaces_the_pool() {
+        let path = env::temp_dir().join(format!("sqma-watched-{}.csv", std::process::id()));
+        let mut config = test_config();
+        config.reflector_list_file = path.to_string_lossy().into_owned();
+        config.use_default_reflectors = false;
+        let watcher = ReflectorListWatcher {
+            config,
+            reflector_pool: Arc::new(RwLock::new(vec!["192.0.2.1".parse().unwrap()])),
+        };
+
+        fs::write(&path, "reflector\n192.0.2.2\n192.0.2.3\n192.0.2.2\n").unwrap();
+        watcher.reload();
+        assert_eq!(
+            *watcher.reflector_pool.read().unwrap(),
+            [
+                "192.0.2.2".parse::<IpAddr>().unwrap(),
+                "192.0.2.3".parse().unwrap()
+            ]
+        );
+
+        // A list that doesn't parse leaves the pool as it was
+        fs::write(&path, "reflector\nnot-an-address\n").unwrap();
+        watcher.reload();
+        assert_eq!(watcher.reflector_pool.read().unwrap().len(), 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
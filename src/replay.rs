@@ -0,0 +1,212 @@
+use crate::baseliner::Baseliner;
+use crate::metrics::Counters;
+use crate::pinger::PingReply;
+use crate::ratecontroller::Ratecontroller;
+use crate::{Config, ReflectorStats};
+use log::info;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("Recording {0} doesn't contain any samples")]
+    Empty(String),
+}
+
+// The initial safe rates are randomised, so fix the seed to make replays repeatable unless one is configured
+const REPLAY_SEED: u64 = 0x5157_4d41;
+
+/*
+ * One recorded sample: the OWDs from a single reply, and the interface byte
+ * counters as they were when it arrived. Times are seconds since the start of the recording.
+ */
+struct Sample {
+    time_s: f64,
+    reflector: IpAddr,
+    down_ms: f64,
+    up_ms: f64,
+    rx_bytes: i128,
+    tx_bytes: i128,
+}
+
+fn load_samples(path: &str) -> anyhow::Result<Vec<Sample>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_path(path)?;
+
+    let mut samples = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        samples.push(Sample {
+            time_s: record[0].parse()?,
+            reflector: record[1].parse()?,
+            down_ms: record[2].parse()?,
+            up_ms: record[3].parse()?,
+            rx_bytes: record[4].parse()?,
+            tx_bytes: record[5].parse()?,
+        });
+    }
+
+    samples.sort_by(|a, b| a.time_s.total_cmp(&b.time_s));
+    Ok(samples)
+}
+
+/*
+ * Offline tuning mode. Runs a recording of per-reflector OWDs and byte counters through the
+ * baseliner and ratecontroller as if it were happening live, and writes out the rates the
+ * controller would have set. Time is driven entirely by the recording, nothing is shaped
+ * and no probes are sent, so the same recording and config always give the same output.
+ */
+pub struct Replay {
+    pub config: Config,
+}
+
+impl Replay {
+    // The rates the controller would have set over the recording, as CSV
+    fn trajectory(&self) -> anyhow::Result<String> {
+        let samples = load_samples(&self.config.replay_file)?;
+        let (first, last) = match (samples.first(), samples.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(ReplayError::Empty(self.config.replay_file.clone()).into()),
+        };
+
+        let mut peers: Vec<IpAddr> = Vec::new();
+        for sample in samples.iter() {
+            if !peers.contains(&sample.reflector) {
+                peers.push(sample.reflector);
+            }
+        }
+        info!(
+            "Replaying {} samples from {} reflectors over {:.1}s from {}",
+            samples.len(),
+            peers.len(),
+            last.time_s - first.time_s,
+            self.config.replay_file
+        );
+
+        let start_t = Instant::now();
+        let at = |time_s: f64| start_t + Duration::from_secs_f64(time_s - first.time_s);
+
+        let owd_baseline = Arc::new(Mutex::new(HashMap::<IpAddr, ReflectorStats>::new()));
+        let owd_recent = Arc::new(Mutex::new(HashMap::<IpAddr, ReflectorStats>::new()));
+        let reflector_peers_lock = Arc::new(RwLock::new(peers));
+
+        // Replies are handed to the baseliner directly, and nothing acts on reselection requests
+        let (_, stats_receiver) = channel();
+        let (reselect_trigger, _) = channel();
+        let baseliner = Baseliner {
+            config: self.config.clone(),
+            counters: Arc::new(Counters::default()),
+            owd_baseline: owd_baseline.clone(),
+            owd_recent: owd_recent.clone(),
+            reflector_peers_lock: reflector_peers_lock.clone(),
+            reselect_trigger,
+            start_time: start_t,
+            stats_receiver,
+        };
+        let mut baseliner_state = baseliner.new_state(start_t);
+
+        let mut ratecontroller = Ratecontroller::for_replay(
+            self.config.clone(),
+            owd_baseline,
+            owd_recent,
+            reflector_peers_lock,
+            if self.config.rng_seed != 0 {
+                self.config.rng_seed
+            } else {
+                REPLAY_SEED
+            },
+            start_t,
+            (first.rx_bytes, first.tx_bytes),
+        )?;
+
+        let tick_s = self
+            .config
+            .download_min_change_interval
+            .min(self.config.upload_min_change_interval);
+        let mut next_tick_s = first.time_s + tick_s;
+        let mut counters = (first.rx_bytes, first.tx_bytes);
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "time_s,dlrate,uprate,rxload,txload,deltadelaydown,deltadelayup"
+        );
+
+        for sample in samples.iter() {
+            // Run every tick that falls before this sample, with the counters as they were then
+            while next_tick_s <= sample.time_s {
+                let metrics = ratecontroller.replay_tick(at(next_tick_s), counters)?;
+                let _ = writeln!(
+                    out,
+                    "{:.3},{:.0},{:.0},{:.3},{:.3},{:.3},{:.3}",
+                    next_tick_s - first.time_s,
+                    metrics.download_rate_kbit,
+                    metrics.upload_rate_kbit,
+                    metrics.download_load,
+                    metrics.upload_load,
+                    metrics.delta_delay_down_ms,
+                    metrics.delta_delay_up_ms
+                );
+                next_tick_s += tick_s;
+            }
+
+            counters = (sample.rx_bytes, sample.tx_bytes);
+            baseliner.process(
+                &mut baseliner_state,
+                PingReply {
+                    reflector: sample.reflector,
+                    seq: 0,
+                    rtt: (sample.down_ms + sample.up_ms) as i64,
+                    current_time: 0,
+                    down_time: sample.down_ms,
+                    up_time: sample.up_ms,
+                    originate_timestamp: 0,
+                    receive_timestamp: 0,
+                    transmit_timestamp: 0,
+                    last_receive_time_s: at(sample.time_s),
+                },
+            );
+        }
+
+        Ok(out)
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let out = self.trajectory()?;
+        fs::write(&self.config.replay_output, out)?;
+        info!("Wrote rate trajectory to {}", self.config.replay_output);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn sample_replay() -> Replay {
+        let mut config = test_config();
+        config.replay_file = concat!(env!("CARGO_MANIFEST_DIR"), "/replay-sample.csv").to_string();
+        Replay { config }
+    }
+
+    #[test]
+    fn replay_is_deterministic() {
+        let first = sample_replay().trajectory().unwrap();
+        let second = sample_replay().trajectory().unwrap();
+
+        // A header and more than a handful of ticks, so there's something to compare
+        assert!(first.lines().count() > 10);
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::config::SchedPolicy;
+use log::{debug, warn};
+use rustix::process::setpriority_process;
+#[cfg(target_os = "linux")]
+use rustix::process::{sched_setaffinity, CpuSet};
+use rustix::thread::gettid;
+use std::{io, thread};
+
+/*
+ * Pins the calling thread to the given CPUs, for keeping the sender and listener off the
+ * core that's busy with softirqs and NAT, so they get scheduled promptly and their timestamps
+ * stay accurate. The tradeoff is that the scheduler can no longer move them elsewhere if that
+ * CPU gets busy, so if the pinned CPU is the loaded one it makes the jitter worse instead.
+ * Failures are only logged, as the probes work the same either way.
+ */
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpus: &[usize]) {
+    if cpus.is_empty() {
+        return;
+    }
+
+    let mut cpu_set = CpuSet::new();
+    for cpu in cpus {
+        if *cpu >= CpuSet::MAX_CPU {
+            warn!("CPU {} is out of range, not setting CPU affinity", cpu);
+            return;
+        }
+        cpu_set.set(*cpu);
+    }
+
+    let name = thread::current().name().unwrap_or_default().to_string();
+    match sched_setaffinity(None, &cpu_set) {
+        Ok(()) => debug!("Pinned thread {} to CPUs {:?}", name, cpus),
+        Err(e) => warn!("Couldn't pin thread {} to CPUs {:?}: {}", name, cpus, e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(cpus: &[usize]) {
+    if !cpus.is_empty() {
+        warn!(
+            "CPU affinity is only supported on Linux, not pinning thread {}",
+            thread::current().name().unwrap_or_default()
+        );
+    }
+}
+
+fn set_realtime(policy: libc::c_int, priority: i32) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+
+    // A pid of 0 means the calling thread
+    match unsafe { libc::sched_setscheduler(0, policy, &param) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/*
+ * Raises the scheduling priority of the calling thread, so probes are sent and replies are
+ * timestamped promptly even when the router is busy, which is exactly when the measurements
+ * matter most. Anything beyond the defaults needs root or CAP_SYS_NICE. The realtime
+ * policies run ahead of every normal process, so a thread that spins at a high realtime
+ * priority can starve the rest of the system, packet processing in userspace included.
+ * The probe threads mostly sleep, but keep the priority as low as does the job.
+ */
+pub fn set_current_thread_priority(policy: SchedPolicy, priority: i32) {
+    let result = match policy {
+        SchedPolicy::Other if priority == 0 => return,
+        SchedPolicy::Other => {
+            setpriority_process(Some(gettid()), priority).map_err(io::Error::from)
+        }
+        SchedPolicy::Fifo => set_realtime(libc::SCHED_FIFO, priority),
+        SchedPolicy::RoundRobin => set_realtime(libc::SCHED_RR, priority),
+    };
+
+    let name = thread::current().name().unwrap_or_default().to_string();
+    match result {
+        Ok(()) => debug!(
+            "Set thread {} to {:?} scheduling with priority {}",
+            name, policy, priority
+        ),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => warn!(
+            "Not allowed to set thread {} to {:?} scheduling with priority {}, this needs root or CAP_SYS_NICE",
+            name, policy, priority
+        ),
+        Err(e) => warn!(
+            "Couldn't set thread {} to {:?} scheduling with priority {}: {}",
+            name, policy, priority, e
+        ),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use rustix::process::sched_getaffinity;
+
+    #[test]
+    fn affinity_is_applied() {
+        // Whichever CPU we're allowed on first, as the test may already be confined to some
+        let allowed = sched_getaffinity(None).unwrap();
+        let cpu = (0..CpuSet::MAX_CPU)
+            .find(|cpu| allowed.is_set(*cpu))
+            .unwrap();
+
+        // On a thread of its own, as it sticks for the rest of the thread's life
+        let pinned = thread::spawn(move || {
+            pin_current_thread(&[cpu]);
+            sched_getaffinity(None).unwrap()
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(pinned.count(), 1);
+        assert!(pinned.is_set(cpu));
+    }
+}
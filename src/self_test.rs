@@ -0,0 +1,98 @@
+use crate::metrics::RateMetrics;
+use crate::Config;
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// Worst loaded latency seen in one direction, and how many loaded ticks it's based on
+#[derive(Default)]
+struct LoadedLatency {
+    worst_ms: f64,
+    samples: u32,
+}
+
+impl LoadedLatency {
+    fn add(&mut self, load: f64, delay_ms: f64, high_load_level: f64) {
+        if load >= high_load_level {
+            self.worst_ms = self.worst_ms.max(delay_ms);
+            self.samples += 1;
+        }
+    }
+
+    fn verdict(&self, direction: &str, budget_ms: f64) -> bool {
+        if self.samples == 0 {
+            warn!(
+                "Self-test {}: no loaded samples, was there load during the test?",
+                direction
+            );
+            return false;
+        }
+
+        let pass = self.worst_ms <= budget_ms;
+        info!(
+            "Self-test {}: {} - worst loaded latency {:.1} ms over {} samples, budget {:.1} ms",
+            direction,
+            if pass { "PASS" } else { "FAIL" },
+            self.worst_ms,
+            self.samples,
+            budget_ms
+        );
+        pass
+    }
+}
+
+/*
+ * Answers "is it working?" by watching the delay over baseline, as measured by the
+ * pinger, while the link is loaded. The load has to come from elsewhere, typically a
+ * speed test run by the user while the self-test is going. Once the test is over the
+ * worst loaded latency in each direction is compared with the configured delay targets.
+ * Returns whether the test passed, and sets off the shutdown, so main can restore the
+ * shaper before exiting with 0 on a pass and 1 otherwise.
+ */
+pub struct SelfTest {
+    pub config: Config,
+    pub rate_metrics: Arc<Mutex<RateMetrics>>,
+    pub shutdown: Arc<AtomicBool>,
+}
+
+impl SelfTest {
+    pub fn run(&self) -> anyhow::Result<bool> {
+        let tick = Duration::from_secs_f64(self.config.tick_interval);
+        let start_t = Instant::now();
+        let mut down = LoadedLatency::default();
+        let mut up = LoadedLatency::default();
+
+        info!(
+            "Self-test running for {}s, start loading the link (e.g. with a speed test) now",
+            self.config.self_test_duration
+        );
+
+        while start_t.elapsed().as_secs_f64() < self.config.self_test_duration {
+            if self.shutdown.load(Ordering::Relaxed) {
+                warn!("Self-test interrupted before it finished");
+                return Ok(false);
+            }
+            sleep(tick);
+
+            let metrics = *self.rate_metrics.lock().unwrap();
+            down.add(
+                metrics.download_load,
+                metrics.delta_delay_down_ms,
+                self.config.high_load_level,
+            );
+            up.add(
+                metrics.upload_load,
+                metrics.delta_delay_up_ms,
+                self.config.high_load_level,
+            );
+        }
+
+        let down_pass = down.verdict("download", self.config.download_delay_ms);
+        let up_pass = up.verdict("upload", self.config.upload_delay_ms);
+
+        self.shutdown.store(true, Ordering::Relaxed);
+        Ok(down_pass && up_pass)
+    }
+}
@@ -0,0 +1,222 @@
+use crate::baseliner::{reflector_snapshot, ReflectorSnapshot};
+use crate::metrics::{Counters, RateMetrics, SafeRates};
+use crate::{Config, ReflectorStats};
+use log::warn;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, UNIX_EPOCH};
+
+// JSON has no representation for NaN or infinity, so those come out as null
+pub fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_rates(rates: &[f64]) -> String {
+    let rates: Vec<String> = rates.iter().map(|rate| json_number(*rate)).collect();
+    format!("[{}]", rates.join(","))
+}
+
+/// Renders the current state as a single JSON object
+pub fn render(
+    config: &Config,
+    rates: &RateMetrics,
+    counters: &Counters,
+    safe_rates: &SafeRates,
+    reflectors: &[ReflectorSnapshot],
+) -> String {
+    let mut out = String::new();
+
+    let _ = write!(
+        out,
+        "{{\"rates\":{{\"download_kbit\":{},\"upload_kbit\":{},\"download_load\":{},\"upload_load\":{},\"delta_delay_down_ms\":{},\"delta_delay_up_ms\":{}}}",
+        json_number(rates.download_rate_kbit),
+        json_number(rates.upload_rate_kbit),
+        json_number(rates.download_load),
+        json_number(rates.upload_load),
+        json_number(rates.delta_delay_down_ms),
+        json_number(rates.delta_delay_up_ms)
+    );
+
+    let _ = write!(
+        out,
+        ",\"safe_rates\":{{\"download\":{},\"download_index\":{},\"upload\":{},\"upload_index\":{}}}",
+        json_rates(&safe_rates.download),
+        safe_rates.download_index,
+        json_rates(&safe_rates.upload),
+        safe_rates.upload_index
+    );
+
+    let reflectors: Vec<String> = reflectors
+        .iter()
+        .map(|entry| {
+            format!(
+                "{{\"address\":{},\"active\":{},\"reachable\":{},\"last_receive_age_s\":{},\"baseline_down_ms\":{},\"baseline_up_ms\":{},\"recent_down_ms\":{},\"recent_up_ms\":{},\"jitter_ms\":{}}}",
+                json_string(&entry.reflector.to_string()),
+                entry.active,
+                entry.reachable,
+                json_number(entry.last_receive_age_s),
+                json_number(entry.baseline_down_ms),
+                json_number(entry.baseline_up_ms),
+                json_number(entry.recent_down_ms),
+                json_number(entry.recent_up_ms),
+                json_number(entry.jitter_ms)
+            )
+        })
+        .collect();
+    let _ = write!(out, ",\"reflectors\":[{}]", reflectors.join(","));
+
+    let _ = write!(
+        out,
+        ",\"counters\":{{\"duplicate_replies\":{},\"lost_probes\":{},\"parse_errors\":{},\"regime_changes\":{},\"stale_replies\":{}}}",
+        counters.duplicate_replies.load(Ordering::Relaxed),
+        counters.lost_probes.load(Ordering::Relaxed),
+        counters.parse_errors.load(Ordering::Relaxed),
+        counters.regime_changes.load(Ordering::Relaxed),
+        counters.stale_replies.load(Ordering::Relaxed)
+    );
+
+    let events: Vec<String> = counters
+        .events
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|event| {
+            let time = event
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |since| since.as_secs_f64());
+            format!(
+                "{{\"time\":{},\"kind\":{},\"detail\":{}}}",
+                json_number(time),
+                json_string(event.kind),
+                json_string(&event.detail)
+            )
+        })
+        .collect();
+    let _ = write!(out, ",\"events\":[{}]", events.join(","));
+
+    let _ = write!(
+        out,
+        ",\"config\":{{\"download_interface\":{},\"upload_interface\":{},\"download_base_kbits\":{},\"download_min_kbits\":{},\"upload_base_kbits\":{},\"upload_min_kbits\":{},\"download_delay_ms\":{},\"upload_delay_ms\":{},\"high_load_level\":{},\"tick_interval\":{}}}}}",
+        json_string(&config.download_interface),
+        json_string(&config.upload_interface),
+        json_number(config.download_base_kbits),
+        json_number(config.download_min_kbits),
+        json_number(config.upload_base_kbits),
+        json_number(config.upload_min_kbits),
+        json_number(config.download_delay_ms),
+        json_number(config.upload_delay_ms),
+        json_number(config.high_load_level),
+        json_number(config.tick_interval)
+    );
+
+    out
+}
+
+/*
+ * Periodically dumps the daemon's state to a JSON file, for things like LuCI or
+ * dashboards that would rather poll a file than talk to the control socket.
+ */
+pub struct StateFileWriter {
+    pub config: Config,
+    pub counters: Arc<Counters>,
+    pub owd_baseline: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub owd_recent: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub rate_metrics: Arc<Mutex<RateMetrics>>,
+    pub reflector_peers_lock: Arc<RwLock<Vec<IpAddr>>>,
+    pub safe_rates: Arc<Mutex<SafeRates>>,
+}
+
+impl StateFileWriter {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let sleep_time = Duration::from_secs_f64(self.config.state_file_interval);
+        // Same as the Prometheus textfile, rename into place so readers never see a partial file
+        let tmp_path = format!("{}.tmp", self.config.state_file);
+
+        loop {
+            sleep(sleep_time);
+
+            let rates = *self.rate_metrics.lock().unwrap();
+            let safe_rates = self.safe_rates.lock().unwrap().clone();
+            let reflectors = reflector_snapshot(
+                &self.owd_baseline,
+                &self.owd_recent,
+                &self.reflector_peers_lock,
+                self.config.tick_interval * 2.0,
+            );
+            let body = render(
+                &self.config,
+                &rates,
+                &self.counters,
+                &safe_rates,
+                &reflectors,
+            );
+
+            if let Err(e) = fs::write(tmp_path.as_str(), body)
+                .and_then(|_| fs::rename(tmp_path.as_str(), self.config.state_file.as_str()))
+            {
+                warn!("Failed to write state file: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    #[test]
+    fn render_is_valid_json() {
+        let counters = Counters::default();
+        counters.event("reselection", "Selected \"5\" new peers".to_string());
+
+        let body = render(
+            &test_config(),
+            &RateMetrics::default(),
+            &counters,
+            &SafeRates::default(),
+            &[],
+        );
+        let state: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        for key in [
+            "rates",
+            "safe_rates",
+            "reflectors",
+            "counters",
+            "events",
+            "config",
+        ] {
+            assert!(state.get(key).is_some(), "missing {}", key);
+        }
+        assert_eq!(state["events"][0]["kind"], "reselection");
+        assert_eq!(state["events"][0]["detail"], "Selected \"5\" new peers");
+    }
+}
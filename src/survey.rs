@@ -0,0 +1,96 @@
+use crate::baseliner::reflector_snapshot;
+use crate::{Config, ReflectorStats};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/*
+ * Measurement-only mode. Every reflector in the list is probed continuously and nothing
+ * is ever shaped. Meant to be left running for hours to characterise a candidate list,
+ * with a report ranking the reflectors by their baseline RTT rewritten periodically.
+ */
+pub struct ReflectorSurvey {
+    pub config: Config,
+    pub owd_baseline: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub owd_recent: Arc<Mutex<HashMap<IpAddr, ReflectorStats>>>,
+    pub reflector_peers_lock: Arc<RwLock<Vec<IpAddr>>>,
+}
+
+impl ReflectorSurvey {
+    fn render(&self, elapsed_s: f64) -> String {
+        let mut snapshot = reflector_snapshot(
+            &self.owd_baseline,
+            &self.owd_recent,
+            &self.reflector_peers_lock,
+            self.config.tick_interval * 2.0,
+        );
+
+        // Reachable reflectors first, closest first
+        snapshot.sort_by(|a, b| {
+            b.reachable.cmp(&a.reachable).then(
+                (a.baseline_down_ms + a.baseline_up_ms)
+                    .total_cmp(&(b.baseline_down_ms + b.baseline_up_ms)),
+            )
+        });
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "rank,reflector,reachable,baseline_rtt_ms,recent_rtt_ms,jitter_ms,last_reply_age_s,measurement_type,survey_duration_s"
+        );
+
+        let peers = self.reflector_peers_lock.read().unwrap().clone();
+        for (rank, entry) in snapshot.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{},{},{},{:.3},{:.3},{:.3},{:.1},{:?},{:.0}",
+                rank + 1,
+                entry.reflector,
+                entry.reachable,
+                entry.baseline_down_ms + entry.baseline_up_ms,
+                entry.recent_down_ms + entry.recent_up_ms,
+                entry.jitter_ms,
+                entry.last_receive_age_s,
+                self.config.measurement_type,
+                elapsed_s
+            );
+        }
+
+        // Reflectors that never replied at all don't show up in the OWD maps
+        for reflector in peers
+            .iter()
+            .filter(|peer| !snapshot.iter().any(|entry| entry.reflector == **peer))
+        {
+            let _ = writeln!(
+                out,
+                ",{},false,,,,,{:?},{:.0}",
+                reflector, self.config.measurement_type, elapsed_s
+            );
+        }
+
+        out
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        let sleep_time = Duration::from_secs_f64(self.config.survey_interval);
+        let tmp_path = format!("{}.tmp", self.config.survey_report);
+        let start_t = Instant::now();
+
+        loop {
+            sleep(sleep_time);
+
+            let body = self.render(start_t.elapsed().as_secs_f64());
+            match fs::write(tmp_path.as_str(), body)
+                .and_then(|_| fs::rename(tmp_path.as_str(), self.config.survey_report.as_str()))
+            {
+                Ok(_) => info!("Updated reflector survey report"),
+                Err(e) => warn!("Failed to write reflector survey report: {}", e),
+            }
+        }
+    }
+}
@@ -1,6 +1,39 @@
+use log::warn;
 use rustix::fs::Timespec;
 use rustix::thread::ClockId;
 use rustix::time::clock_gettime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use time::formatting::Formattable;
+use time::OffsetDateTime;
+
+// NTP counts from 1900, Unix time from 1970
+pub const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+static FORMAT_FAILED: AtomicBool = AtomicBool::new(false);
+
+/*
+ * Routers without an RTC can boot with a clock that's way off until NTP syncs, which
+ * can put the time outside what the format can represent. Fall back to the plain Unix
+ * timestamp rather than taking down logging or stats, and only complain about it once.
+ */
+pub fn time_format<T>(dt: T, format: &(impl Formattable + ?Sized)) -> String
+where
+    T: Into<OffsetDateTime>,
+{
+    let dt = dt.into();
+    match dt.format(format) {
+        Ok(val) => val,
+        Err(e) => {
+            if !FORMAT_FAILED.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Couldn't format the current time, using Unix timestamps instead: {}",
+                    e
+                );
+            }
+            dt.unix_timestamp().to_string()
+        }
+    }
+}
 
 pub struct Time {
     time_s: u64,
@@ -20,7 +53,38 @@ impl Time {
         (self.time_s as i64 % 86400 * 1000) + (self.time_ns as i64 / 1000000)
     }
 
+    // Seconds since 1900 in the upper 32 bits, and the fraction of a second in the lower 32
+    pub fn to_ntp(&self) -> u64 {
+        let secs = (self.time_s + NTP_UNIX_OFFSET) & 0xFFFF_FFFF;
+        let fraction = (self.time_ns << 32) / 1_000_000_000;
+        (secs << 32) | fraction
+    }
+
     pub fn to_milliseconds(&self) -> u64 {
         (self.time_s * 1000) + (self.time_ns / 1000000)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+    use time::format_description::well_known::Rfc2822;
+    use time::macros::format_description;
+
+    #[test]
+    fn pre_epoch_time_formats() {
+        let format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+        let formatted = time_format(UNIX_EPOCH - Duration::from_secs(86400), format);
+        assert_eq!(formatted, "1969-12-31 00:00:00");
+    }
+
+    #[test]
+    fn unformattable_time_falls_back_to_unix_timestamp() {
+        // RFC 2822 can't represent years before 1900
+        let dt = UNIX_EPOCH - Duration::from_secs(100 * 365 * 86400);
+        let expected = OffsetDateTime::from(dt).unix_timestamp().to_string();
+        assert!(OffsetDateTime::from(dt).format(&Rfc2822).is_err());
+        assert_eq!(time_format(dt, &Rfc2822), expected);
+    }
+}